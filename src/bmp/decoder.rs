@@ -5,10 +5,17 @@ use std::slice::ChunksMut;
 use byteorder::{ReadBytesExt, LittleEndian};
 
 use image::{
+    check_rect,
+    crop_rect,
+    load_rect_from_image,
     DecodingResult,
     ImageResult,
     ImageDecoder,
-    ImageError
+    ImageError,
+    ImageFormat,
+    ImageMetadata,
+    Limits,
+    UnsupportedFeature,
 };
 use color::ColorType;
 
@@ -138,6 +145,9 @@ pub struct BMPDecoder<R> {
     colors_used: u32,
     palette: Option<Vec<(u8, u8, u8)>>,
     bitfields: Option<(u32, u32, u32)>,
+    pixels_per_meter: Option<(i32, i32)>,
+
+    limits: Limits,
 }
 
 enum RLEInsn {
@@ -234,6 +244,9 @@ impl<R: Read + Seek> BMPDecoder<R> {
             colors_used: 0,
             palette: None,
             bitfields: None,
+            pixels_per_meter: None,
+
+            limits: Limits::default(),
         }
     }
 
@@ -313,14 +326,22 @@ impl<R: Read + Seek> BMPDecoder<R> {
             1 => self.image_type = ImageType::RLE8,
             2 => self.image_type = ImageType::RLE4,
             3 => self.image_type = ImageType::Bitfields,
-            _  => return Err(ImageError::UnsupportedError("Unsupported image type".to_string())),
+            // The compression method follows the bit count at byte 30 of the file
+            method => return Err(ImageError::unsupported(
+                ImageFormat::BMP, UnsupportedFeature::Compression(method)).at(30)),
         }
 
-        // The next 12 bytes represent data array size in bytes,
-        // followed the horizontal and vertical printing resolutions
+        // The next 4 bytes represent data array size in bytes,
         // We will calculate the pixel array size using width & height of image
-        // We're not interesting the horz or vert printing resolutions
-        try!(self.r.seek(SeekFrom::Current(12)));
+        try!(self.r.seek(SeekFrom::Current(4)));
+
+        // The horizontal and vertical printing resolutions in pixels per meter,
+        // 0 if they are unknown
+        let x_ppm = try!(self.r.read_i32::<LittleEndian>());
+        let y_ppm = try!(self.r.read_i32::<LittleEndian>());
+        if x_ppm > 0 && y_ppm > 0 {
+            self.pixels_per_meter = Some((x_ppm, y_ppm));
+        }
 
         self.colors_used = try!(self.r.read_u32::<LittleEndian>());
 
@@ -332,6 +353,15 @@ impl<R: Read + Seek> BMPDecoder<R> {
     }
 
 
+    fn unsupported_bit_count(&self) -> ImageError {
+        // The position of the bit count in the file depends on the header
+        let offset = match self.bmp_header_type {
+            BMPHeaderType::CoreHeader => 24,
+            _ => 28
+        };
+        ImageError::unsupported(ImageFormat::BMP, UnsupportedFeature::BitDepth(self.bit_count as u8)).at(offset)
+    }
+
     fn read_bitmasks(&mut self) -> ImageResult<()> {
         let r_mask = try!(self.r.read_u32::<LittleEndian>());
         let g_mask = try!(self.r.read_u32::<LittleEndian>());
@@ -352,7 +382,9 @@ impl<R: Read + Seek> BMPDecoder<R> {
                 BITMAPV3HEADER_SIZE => BMPHeaderType::V3Header,
                 BITMAPV4HEADER_SIZE => BMPHeaderType::V4Header,
                 BITMAPV5HEADER_SIZE => BMPHeaderType::V5Header,
-                _ => return Err(ImageError::UnsupportedError("Unsupported Bitmap Header".to_string()))
+                // The size of the info header follows the file header at byte 14
+                size => return Err(ImageError::unsupported(
+                    ImageFormat::BMP, UnsupportedFeature::HeaderVersion(size)).at(14))
             };
 
             match self.bmp_header_type {
@@ -369,19 +401,19 @@ impl<R: Read + Seek> BMPDecoder<R> {
                     match self.bit_count {
                         1 | 4 | 8  => try!(self.read_palette()),
                         16 | 24 | 32 => (),
-                        _ => return Err(ImageError::UnsupportedError(format!("Unsupported bit count: {}", self.bit_count ))),
+                        _ => return Err(self.unsupported_bit_count()),
                     };
                 },
                 ImageType::RLE8 => {
                     match self.bit_count {
                         8 => try!(self.read_palette()),
-                        _ => return Err(ImageError::UnsupportedError(format!("Unsupported bit count: {}", self.bit_count))),
+                        _ => return Err(self.unsupported_bit_count()),
                     };
                 },
                 ImageType::RLE4 => {
                     match self.bit_count {
                         4 => try!(self.read_palette()),
-                        _ => return Err(ImageError::UnsupportedError(format!("Unsupported bit count: {}", self.bit_count))),
+                        _ => return Err(self.unsupported_bit_count()),
                     };
                 },
                 ImageType::Bitfields => {
@@ -456,7 +488,9 @@ impl<R: Read + Seek> BMPDecoder<R> {
         let max_length = MAX_PALETTE_SIZE * bytes_per_color;
         let mut buf = Vec::with_capacity(max_length);
 
-        try!(self.r.by_ref().take(length as u64).read_to_end(&mut buf));
+        if try!(self.r.by_ref().take(length as u64).read_to_end(&mut buf)) < length {
+            return Err(ImageError::NotEnoughData)
+        }
 
         // Allocate 256 entries even if palette_size is smaller, to prevent corrupt files from
         // causing an out-of-bounds array access.
@@ -486,7 +520,9 @@ impl<R: Read + Seek> BMPDecoder<R> {
         try!(self.r.seek(SeekFrom::Start(self.data_offset)));
         for h in 0..self.height {
             let mut line = Vec::with_capacity(row_byte_length);
-            try!(self.r.by_ref().take(row_byte_length as u64).read_to_end(&mut line));
+            if try!(self.r.by_ref().take(row_byte_length as u64).read_to_end(&mut line)) < row_byte_length {
+                return Err(ImageError::NotEnoughData)
+            }
 
             let x = if self.top_down { h } else { self.height - h - 1 };
             let mut y = 0;
@@ -713,7 +749,7 @@ impl<R: Read + Seek> BMPDecoder<R> {
                         }
                     } else {
                         // We ran out of data while we still had rows to fill in.
-                        return Err(ImageError::FormatError("Not enough RLE data".to_string()))
+                        return Err(ImageError::NotEnoughData)
                     }
                 }
             }
@@ -723,6 +759,15 @@ impl<R: Read + Seek> BMPDecoder<R> {
     }
 
     fn read_image_data(&mut self) -> ImageResult<Vec<u8>> {
+        // The pixels, and the colour indices of uncompressed palettized images
+        let pixels = self.width as u64 * self.height as u64;
+        let indices = match (self.image_type, self.bit_count) {
+            (ImageType::RGB, 1) | (ImageType::RGB, 4) | (ImageType::RGB, 8) => pixels,
+            _ => 0
+        };
+        let mut limits = self.limits;
+        try!(limits.reserve(self.num_channels() as u64 * pixels + indices));
+
         match self.image_type {
             ImageType::RGB => {
                 match self.bit_count {
@@ -768,7 +813,8 @@ impl<R: Read + Seek> BMPDecoder<R> {
                             Some(R5_G6_B5_COLOR_MASK) => {
                                 return self.read_16_bit_pixel_data(Format16Bit::Format565)
                             },
-                            _ => return Err(ImageError::UnsupportedError("Unsupported 16-bit bitfield".to_string()))
+                            _ => return Err(ImageError::unsupported(
+                                ImageFormat::BMP, UnsupportedFeature::Other("16-bit bitfield".to_string())))
                         }
                     },
                     32 => {
@@ -776,7 +822,8 @@ impl<R: Read + Seek> BMPDecoder<R> {
                             Some(R8_G8_B8_COLOR_MASK) => {
                                 return self.read_full_byte_pixel_data(FormatFullBytes::Format888)
                             },
-                            _ => return Err(ImageError::UnsupportedError("Unsupported 32-bit bitfield".to_string()))
+                            _ => return Err(ImageError::unsupported(
+                                ImageFormat::BMP, UnsupportedFeature::Other("32-bit bitfield".to_string())))
                         }
                     },
                     _ => return Err(ImageError::FormatError("Invalid bit count for bitfield bitmap".to_string())),
@@ -787,6 +834,15 @@ impl<R: Read + Seek> BMPDecoder<R> {
 }
 
 impl<R: Read + Seek> ImageDecoder for BMPDecoder<R> {
+    fn set_limits(&mut self, limits: Limits) -> ImageResult<()> {
+        try!(self.read_metadata());
+        let color = try!(self.colortype());
+        try!(limits.check_image(self.width as u32, self.height as u32, color));
+
+        self.limits = limits;
+        Ok(())
+    }
+
     fn dimensions(&mut self) -> ImageResult<(u32, u32)> {
         try!(self.read_metadata());
         Ok((self.width as u32, self.height as u32))
@@ -805,6 +861,14 @@ impl<R: Read + Seek> ImageDecoder for BMPDecoder<R> {
         Ok(3 * self.width as usize)
     }
 
+    fn metadata(&mut self) -> ImageResult<ImageMetadata> {
+        try!(self.read_metadata());
+        Ok(ImageMetadata {
+            dpi: self.pixels_per_meter.map(|(x, y)| (x as f64 * 0.0254, y as f64 * 0.0254)),
+            ..ImageMetadata::default()
+        })
+    }
+
     fn read_scanline(&mut self, _buf: &mut [u8]) -> ImageResult<u32> {
         unimplemented!();
     }
@@ -813,4 +877,31 @@ impl<R: Read + Seek> ImageDecoder for BMPDecoder<R> {
         try!(self.read_metadata());
         self.read_image_data().map(|v| DecodingResult::U8(v) )
     }
+
+    fn load_rect(&mut self, x: u32, y: u32, width: u32, height: u32) -> ImageResult<DecodingResult> {
+        try!(self.read_metadata());
+        let dimensions = (self.width as u32, self.height as u32);
+        try!(check_rect(dimensions, x, y, width, height));
+
+        // The rows of RLE compressed images have different lengths
+        match self.image_type {
+            ImageType::RLE8 | ImageType::RLE4 => return load_rect_from_image(self, x, y, width, height),
+            ImageType::RGB | ImageType::Bitfields => ()
+        }
+
+        // Rows are padded to a multiple of 4 bytes, bottom-up images store the last row first
+        let row_byte_length = (self.bit_count as u64 * self.width as u64 + 31) / 32 * 4;
+        let first_row = if self.top_down { y } else { dimensions.1 - y - height };
+
+        // Read the rows of the region only, as if they were a whole image
+        let (data_offset, image_height) = (self.data_offset, self.height);
+        self.data_offset += first_row as u64 * row_byte_length;
+        self.height = height as i32;
+        let data = self.read_image_data();
+        self.data_offset = data_offset;
+        self.height = image_height;
+
+        let color = try!(self.colortype());
+        crop_rect(DecodingResult::U8(try!(data)), color, dimensions.0, x, 0, width, height)
+    }
 }
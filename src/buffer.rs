@@ -2,12 +2,11 @@ use std::slice::{ Chunks, ChunksMut };
 use std::ops::{ Deref, DerefMut, Index, IndexMut };
 use std::marker::PhantomData;
 use std::path::Path;
-use std::io;
 use num::Zero;
 
 use traits::Primitive;
 use color::{ Rgb, Rgba, Luma, LumaA, FromColor, ColorType };
-use image::GenericImage;
+use image::{GenericImage, ImageResult};
 use dynimage::save_buffer;
 use utils::expand_packed;
 
@@ -352,8 +351,8 @@ where P: Pixel<Subpixel=u8> + 'static,
     /// Saves the buffer to a file at the path specified.
     ///
     /// The image format is derived from the file extension.
-    /// Currently only jpeg and png files are supported.
-    pub fn save<Q>(&self, path: Q) -> io::Result<()> where Q: AsRef<Path> {
+    /// Currently only jpeg, png, gif, ppm and webp files are supported.
+    pub fn save<Q>(&self, path: Q) -> ImageResult<()> where Q: AsRef<Path> {
         // This is valid as the subpixel is u8.
         save_buffer(path,
                     self,
@@ -381,6 +381,38 @@ impl<T: Primitive + 'static> FromColor<Luma<T>> for Rgb<T> {
     }
 }
 
+/// FromColor between 8 and 16 bit pixels
+///
+/// Narrowing keeps the most significant byte of each channel, widening
+/// replicates the byte so that `255` maps to `65535`.
+
+macro_rules! impl_from_color_depth {
+    ($to: ident, $conv: ident, [$($from: ident),*]) => {$(
+        impl FromColor<$from<u16>> for $to<u8> {
+            fn from_color(&mut self, other: &$from<u16>) {
+                let wide = other.$conv();
+                for (c, &w) in self.channels_mut().iter_mut().zip(wide.channels().iter()) {
+                    *c = (w >> 8) as u8
+                }
+            }
+        }
+
+        impl FromColor<$from<u8>> for $to<u16> {
+            fn from_color(&mut self, other: &$from<u8>) {
+                let narrow = other.$conv();
+                for (c, &n) in self.channels_mut().iter_mut().zip(narrow.channels().iter()) {
+                    *c = n as u16 * 257
+                }
+            }
+        }
+    )*}
+}
+
+impl_from_color_depth!(Rgb, to_rgb, [Rgb, Rgba, Luma, LumaA]);
+impl_from_color_depth!(Rgba, to_rgba, [Rgb, Rgba, Luma, LumaA]);
+impl_from_color_depth!(Luma, to_luma, [Rgb, Rgba, Luma, LumaA]);
+impl_from_color_depth!(LumaA, to_luma_alpha, [Rgb, Rgba, Luma, LumaA]);
+
 /// Blends a color inter another one
 pub trait Blend {
     /// Blends a color in-place.
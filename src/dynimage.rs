@@ -1,5 +1,5 @@
 use std::io;
-use std::io::{Read, Write, Seek, BufReader};
+use std::io::{Read, Write, Seek, SeekFrom, BufReader};
 use std::path::Path;
 use std::fs::File;
 use std::iter;
@@ -26,17 +26,23 @@ use bmp;
 use ico;
 
 use color;
+use color::{Luma, LumaA, Rgb, Rgba, FromColor};
 use buffer::{ImageBuffer, ConvertBuffer, Pixel, GrayImage, GrayAlphaImage, RgbImage, RgbaImage};
 use imageops;
 use image;
 use image:: {
     GenericImage,
     ImageDecoder,
+    ImageEncoder,
     ImageResult,
     ImageFormat,
+    EncoderOptions,
+    ImageMetadata,
+    Limits,
+    Orientation,
 };
 
-use image::DecodingResult::{U8};
+use image::DecodingResult::{U8, U16};
 
 /// A Dynamic Image
 #[derive(Clone)]
@@ -52,6 +58,18 @@ pub enum DynamicImage {
 
     /// Each pixel in this image is 8-bit Rgb with alpha
     ImageRgba8(RgbaImage),
+
+    /// Each pixel in this image is 16-bit Luma
+    ImageLuma16(ImageBuffer<Luma<u16>, Vec<u16>>),
+
+    /// Each pixel in this image is 16-bit Luma with alpha
+    ImageLumaA16(ImageBuffer<LumaA<u16>, Vec<u16>>),
+
+    /// Each pixel in this image is 16-bit Rgb
+    ImageRgb16(ImageBuffer<Rgb<u16>, Vec<u16>>),
+
+    /// Each pixel in this image is 16-bit Rgb with alpha
+    ImageRgba16(ImageBuffer<Rgba<u16>, Vec<u16>>),
 }
 
 macro_rules! dynamic_map(
@@ -61,6 +79,10 @@ macro_rules! dynamic_map(
                         DynamicImage::ImageLumaA8(ref $image) => DynamicImage::ImageLumaA8($action),
                         DynamicImage::ImageRgb8(ref $image) => DynamicImage::ImageRgb8($action),
                         DynamicImage::ImageRgba8(ref $image) => DynamicImage::ImageRgba8($action),
+                        DynamicImage::ImageLuma16(ref $image) => DynamicImage::ImageLuma16($action),
+                        DynamicImage::ImageLumaA16(ref $image) => DynamicImage::ImageLumaA16($action),
+                        DynamicImage::ImageRgb16(ref $image) => DynamicImage::ImageRgb16($action),
+                        DynamicImage::ImageRgba16(ref $image) => DynamicImage::ImageRgba16($action),
                 }
         );
 
@@ -70,6 +92,10 @@ macro_rules! dynamic_map(
                         DynamicImage::ImageLumaA8(ref mut $image) => DynamicImage::ImageLumaA8($action),
                         DynamicImage::ImageRgb8(ref mut $image) => DynamicImage::ImageRgb8($action),
                         DynamicImage::ImageRgba8(ref mut $image) => DynamicImage::ImageRgba8($action),
+                        DynamicImage::ImageLuma16(ref mut $image) => DynamicImage::ImageLuma16($action),
+                        DynamicImage::ImageLumaA16(ref mut $image) => DynamicImage::ImageLumaA16($action),
+                        DynamicImage::ImageRgb16(ref mut $image) => DynamicImage::ImageRgb16($action),
+                        DynamicImage::ImageRgba16(ref mut $image) => DynamicImage::ImageRgba16($action),
                 }
         );
 
@@ -79,6 +105,10 @@ macro_rules! dynamic_map(
                         DynamicImage::ImageLumaA8(ref $image) => $action,
                         DynamicImage::ImageRgb8(ref $image) => $action,
                         DynamicImage::ImageRgba8(ref $image) => $action,
+                        DynamicImage::ImageLuma16(ref $image) => $action,
+                        DynamicImage::ImageLumaA16(ref $image) => $action,
+                        DynamicImage::ImageRgb16(ref $image) => $action,
+                        DynamicImage::ImageRgba16(ref $image) => $action,
                 }
         );
 
@@ -88,6 +118,10 @@ macro_rules! dynamic_map(
                         DynamicImage::ImageLumaA8(ref mut $image) => $action,
                         DynamicImage::ImageRgb8(ref mut $image) => $action,
                         DynamicImage::ImageRgba8(ref mut $image) => $action,
+                        DynamicImage::ImageLuma16(ref mut $image) => $action,
+                        DynamicImage::ImageLumaA16(ref mut $image) => $action,
+                        DynamicImage::ImageRgb16(ref mut $image) => $action,
+                        DynamicImage::ImageRgba16(ref mut $image) => $action,
                 }
         );
 );
@@ -114,6 +148,27 @@ impl DynamicImage {
         DynamicImage::ImageRgba8(ImageBuffer::new(w, h))
     }
 
+    /// Creates a dynamic image backed by a buffer of 16-bit grey pixels.
+    pub fn new_luma16(w: u32, h: u32) -> DynamicImage {
+        DynamicImage::ImageLuma16(ImageBuffer::new(w, h))
+    }
+
+    /// Creates a dynamic image backed by a buffer of 16-bit grey
+    /// pixels with transparency.
+    pub fn new_luma_a16(w: u32, h: u32) -> DynamicImage {
+        DynamicImage::ImageLumaA16(ImageBuffer::new(w, h))
+    }
+
+    /// Creates a dynamic image backed by a buffer of 16-bit RGB pixels.
+    pub fn new_rgb16(w: u32, h: u32) -> DynamicImage {
+        DynamicImage::ImageRgb16(ImageBuffer::new(w, h))
+    }
+
+    /// Creates a dynamic image backed by a buffer of 16-bit RGBA pixels.
+    pub fn new_rgba16(w: u32, h: u32) -> DynamicImage {
+        DynamicImage::ImageRgba16(ImageBuffer::new(w, h))
+    }
+
     /// Returns a copy of this image as an RGB image.
     pub fn to_rgb(&self) -> RgbImage {
         dynamic_map!(*self, ref p -> {
@@ -216,7 +271,73 @@ impl DynamicImage {
         }
     }
 
+    /// Return a reference to a 16bit Grayscale image
+    pub fn as_luma16(&self) -> Option<&ImageBuffer<Luma<u16>, Vec<u16>>> {
+        match *self {
+            DynamicImage::ImageLuma16(ref p) => Some(p),
+            _                                => None
+        }
+    }
+
+    /// Return a mutable reference to a 16bit Grayscale image
+    pub fn as_mut_luma16(&mut self) -> Option<&mut ImageBuffer<Luma<u16>, Vec<u16>>> {
+        match *self {
+            DynamicImage::ImageLuma16(ref mut p) => Some(p),
+            _                                    => None
+        }
+    }
+
+    /// Return a reference to a 16bit Grayscale image with an alpha channel
+    pub fn as_luma_alpha16(&self) -> Option<&ImageBuffer<LumaA<u16>, Vec<u16>>> {
+        match *self {
+            DynamicImage::ImageLumaA16(ref p) => Some(p),
+            _                                 => None
+        }
+    }
+
+    /// Return a mutable reference to a 16bit Grayscale image with an alpha channel
+    pub fn as_mut_luma_alpha16(&mut self) -> Option<&mut ImageBuffer<LumaA<u16>, Vec<u16>>> {
+        match *self {
+            DynamicImage::ImageLumaA16(ref mut p) => Some(p),
+            _                                     => None
+        }
+    }
+
+    /// Return a reference to a 16bit RGB image
+    pub fn as_rgb16(&self) -> Option<&ImageBuffer<Rgb<u16>, Vec<u16>>> {
+        match *self {
+            DynamicImage::ImageRgb16(ref p) => Some(p),
+            _                               => None
+        }
+    }
+
+    /// Return a mutable reference to a 16bit RGB image
+    pub fn as_mut_rgb16(&mut self) -> Option<&mut ImageBuffer<Rgb<u16>, Vec<u16>>> {
+        match *self {
+            DynamicImage::ImageRgb16(ref mut p) => Some(p),
+            _                                   => None
+        }
+    }
+
+    /// Return a reference to a 16bit RGBA image
+    pub fn as_rgba16(&self) -> Option<&ImageBuffer<Rgba<u16>, Vec<u16>>> {
+        match *self {
+            DynamicImage::ImageRgba16(ref p) => Some(p),
+            _                                => None
+        }
+    }
+
+    /// Return a mutable reference to a 16bit RGBA image
+    pub fn as_mut_rgba16(&mut self) -> Option<&mut ImageBuffer<Rgba<u16>, Vec<u16>>> {
+        match *self {
+            DynamicImage::ImageRgba16(ref mut p) => Some(p),
+            _                                    => None
+        }
+    }
+
     /// Return this image's pixels as a byte vector.
+    ///
+    /// Channels of 16-bit images are stored in big endian byte order.
     pub fn raw_pixels(&self) -> Vec<u8> {
         image_to_bytes(self)
     }
@@ -228,6 +349,10 @@ impl DynamicImage {
             DynamicImage::ImageLumaA8(_) => color::ColorType::GrayA(8),
             DynamicImage::ImageRgb8(_) => color::ColorType::RGB(8),
             DynamicImage::ImageRgba8(_) => color::ColorType::RGBA(8),
+            DynamicImage::ImageLuma16(_) => color::ColorType::Gray(16),
+            DynamicImage::ImageLumaA16(_) => color::ColorType::GrayA(16),
+            DynamicImage::ImageRgb16(_) => color::ColorType::RGB(16),
+            DynamicImage::ImageRgba16(_) => color::ColorType::RGBA(16),
         }
     }
 
@@ -238,6 +363,10 @@ impl DynamicImage {
             DynamicImage::ImageLumaA8(ref p) => DynamicImage::ImageLuma8(imageops::grayscale(p)),
             DynamicImage::ImageRgb8(ref p) => DynamicImage::ImageLuma8(imageops::grayscale(p)),
             DynamicImage::ImageRgba8(ref p) => DynamicImage::ImageLuma8(imageops::grayscale(p)),
+            DynamicImage::ImageLuma16(ref p) => DynamicImage::ImageLuma16(p.clone()),
+            DynamicImage::ImageLumaA16(ref p) => DynamicImage::ImageLuma16(imageops::grayscale(p)),
+            DynamicImage::ImageRgb16(ref p) => DynamicImage::ImageLuma16(imageops::grayscale(p)),
+            DynamicImage::ImageRgba16(ref p) => DynamicImage::ImageLuma16(imageops::grayscale(p)),
         }
     }
 
@@ -346,51 +475,72 @@ impl DynamicImage {
         dynamic_map!(*self, ref p => imageops::rotate270(p))
     }
 
+    /// Turn this image upright, ```orientation``` describes how it is stored.
+    pub fn apply_orientation(&self, orientation: Orientation) -> DynamicImage {
+        match orientation {
+            Orientation::Normal => self.clone(),
+            Orientation::FlipHorizontal => self.fliph(),
+            Orientation::Rotate180 => self.rotate180(),
+            Orientation::FlipVertical => self.flipv(),
+            Orientation::Transpose => self.rotate90().fliph(),
+            Orientation::Rotate90 => self.rotate90(),
+            Orientation::Transverse => self.rotate270().fliph(),
+            Orientation::Rotate270 => self.rotate270(),
+        }
+    }
+
     /// Encode this image and write it to ```w```
+    ///
+    /// 16-bit images are written with their full precision to PNG,
+    /// all other formats receive an 8-bit copy of the image.
     pub fn save<W: Write>(&self, w: &mut W, format: ImageFormat) -> ImageResult<()> {
-        let bytes = self.raw_pixels();
-        let (width, height) = self.dimensions();
-        let color = self.color();
-
-        match format {
-            #[cfg(feature = "png_codec")]
-            image::ImageFormat::PNG  => {
-                let p = png::PNGEncoder::new(w);
-
-                try!(p.encode(&bytes, width, height, color));
-                Ok(())
-            }
-            #[cfg(feature = "ppm")]
-            image::ImageFormat::PPM  => {
-                let mut p = ppm::PPMEncoder::new(w);
-
-                try!(p.encode(&bytes, width, height, color));
-                Ok(())
+        if format != ImageFormat::PNG {
+            if let Some(narrow) = self.to_8bit() {
+                return narrow.save(w, format)
             }
+        }
 
-            #[cfg(feature = "jpeg")]
-            image::ImageFormat::JPEG => {
-                let mut j = jpeg::JPEGEncoder::new(w);
+        let (width, height) = self.dimensions();
+        write_buffer(w, &self.raw_pixels(), width, height, self.color(), format, &ImageMetadata::default())
+    }
 
-                try!(j.encode(&bytes, width, height, color));
-                Ok(())
+    /// Encode this image and write it to ```w``` like ```save```,
+    /// the encoder stores as much of ```metadata``` as the format can hold
+    pub fn save_with_metadata<W: Write>(&self, w: &mut W, format: ImageFormat,
+                                        metadata: &ImageMetadata) -> ImageResult<()> {
+        if format != ImageFormat::PNG {
+            if let Some(narrow) = self.to_8bit() {
+                return narrow.save_with_metadata(w, format, metadata)
             }
+        }
 
-            #[cfg(feature = "gif_codec")]
-            image::ImageFormat::GIF => {
-                let g = gif::Encoder::new(w);
+        let (width, height) = self.dimensions();
+        write_buffer(w, &self.raw_pixels(), width, height, self.color(), format, metadata)
+    }
 
-                try!(g.encode(gif::Frame::from_rgba(
-                    width as u16,
-                    height as u16,
-                    &mut *self.to_rgba().iter().cloned().collect::<Vec<u8>>()
-                )));
-                Ok(())
+    /// Encode this image with the format specific ```options``` and write it to ```w```
+    ///
+    /// 16-bit images are written with their full precision to PNG,
+    /// all other formats receive an 8-bit copy of the image.
+    pub fn save_with_options<W: Write>(&self, w: &mut W, options: EncoderOptions) -> ImageResult<()> {
+        if options.format() != ImageFormat::PNG {
+            if let Some(narrow) = self.to_8bit() {
+                return narrow.save_with_options(w, options)
             }
+        }
 
-            _ => Err(image::ImageError::UnsupportedError(
-                     format!("An encoder for {:?} is not available.", format))
-                 ),
+        let (width, height) = self.dimensions();
+        write_buffer_with_options(w, &self.raw_pixels(), width, height, self.color(), options)
+    }
+
+    // Returns an 8-bit copy of a 16-bit image and `None` otherwise.
+    fn to_8bit(&self) -> Option<DynamicImage> {
+        match *self {
+            DynamicImage::ImageLuma16(ref p) => Some(DynamicImage::ImageLuma8(p.convert())),
+            DynamicImage::ImageLumaA16(ref p) => Some(DynamicImage::ImageLumaA8(p.convert())),
+            DynamicImage::ImageRgb16(ref p) => Some(DynamicImage::ImageRgb8(p.convert())),
+            DynamicImage::ImageRgba16(ref p) => Some(DynamicImage::ImageRgba8(p.convert())),
+            _ => None
         }
     }
 }
@@ -408,7 +558,16 @@ impl GenericImage for DynamicImage {
     }
 
     fn get_pixel(&self, x: u32, y: u32) -> color::Rgba<u8> {
-        dynamic_map!(*self, ref p -> p.get_pixel(x, y).to_rgba())
+        match *self {
+            DynamicImage::ImageLuma16(ref p) => narrow(p.get_pixel(x, y)),
+            DynamicImage::ImageLumaA16(ref p) => narrow(p.get_pixel(x, y)),
+            DynamicImage::ImageRgb16(ref p) => narrow(p.get_pixel(x, y)),
+            DynamicImage::ImageRgba16(ref p) => narrow(p.get_pixel(x, y)),
+            DynamicImage::ImageLuma8(ref p) => p.get_pixel(x, y).to_rgba(),
+            DynamicImage::ImageLumaA8(ref p) => p.get_pixel(x, y).to_rgba(),
+            DynamicImage::ImageRgb8(ref p) => p.get_pixel(x, y).to_rgba(),
+            DynamicImage::ImageRgba8(ref p) => p.get_pixel(x, y).to_rgba(),
+        }
     }
 
     fn put_pixel(&mut self, x: u32, y: u32, pixel: color::Rgba<u8>) {
@@ -417,6 +576,10 @@ impl GenericImage for DynamicImage {
             DynamicImage::ImageLumaA8(ref mut p) => p.put_pixel(x, y, pixel.to_luma_alpha()),
             DynamicImage::ImageRgb8(ref mut p) => p.put_pixel(x, y, pixel.to_rgb()),
             DynamicImage::ImageRgba8(ref mut p) => p.put_pixel(x, y, pixel),
+            DynamicImage::ImageLuma16(ref mut p) => p.put_pixel(x, y, widen(&pixel)),
+            DynamicImage::ImageLumaA16(ref mut p) => p.put_pixel(x, y, widen(&pixel)),
+            DynamicImage::ImageRgb16(ref mut p) => p.put_pixel(x, y, widen(&pixel)),
+            DynamicImage::ImageRgba16(ref mut p) => p.put_pixel(x, y, widen(&pixel)),
         }
     }
     /// DEPRECATED: Use iterator `pixels_mut` to blend the pixels directly.
@@ -426,6 +589,10 @@ impl GenericImage for DynamicImage {
             DynamicImage::ImageLumaA8(ref mut p) => p.blend_pixel(x, y, pixel.to_luma_alpha()),
             DynamicImage::ImageRgb8(ref mut p) => p.blend_pixel(x, y, pixel.to_rgb()),
             DynamicImage::ImageRgba8(ref mut p) => p.blend_pixel(x, y, pixel),
+            DynamicImage::ImageLuma16(ref mut p) => p.blend_pixel(x, y, widen(&pixel)),
+            DynamicImage::ImageLumaA16(ref mut p) => p.blend_pixel(x, y, widen(&pixel)),
+            DynamicImage::ImageRgb16(ref mut p) => p.blend_pixel(x, y, widen(&pixel)),
+            DynamicImage::ImageRgba16(ref mut p) => p.blend_pixel(x, y, widen(&pixel)),
         }
     }
 
@@ -435,6 +602,21 @@ impl GenericImage for DynamicImage {
     }
 }
 
+// Converts a 16-bit pixel to 8-bit RGBA
+fn narrow<P: Pixel<Subpixel=u16>>(pixel: &P) -> Rgba<u8>
+where Rgba<u8>: FromColor<P> {
+    let mut out = Rgba([0; 4]);
+    out.from_color(pixel);
+    out
+}
+
+// Converts an 8-bit RGBA pixel to a 16-bit pixel
+fn widen<P: Pixel<Subpixel=u16> + FromColor<Rgba<u8>>>(pixel: &Rgba<u8>) -> P {
+    let mut out = P::from_channels(0, 0, 0, 0);
+    out.from_color(pixel);
+    out
+}
+
 
 /// Decodes an image and stores it into a dynamic image
 pub fn decoder_to_image<I: ImageDecoder>(codec: I) -> ImageResult<DynamicImage> {
@@ -460,6 +642,22 @@ pub fn decoder_to_image<I: ImageDecoder>(codec: I) -> ImageResult<DynamicImage>
         (color::ColorType::GrayA(8), U8(buf)) => {
             ImageBuffer::from_raw(w, h, buf).map(|v| DynamicImage::ImageLumaA8(v))
         }
+
+        (color::ColorType::RGB(16), U16(buf)) => {
+            ImageBuffer::from_raw(w, h, buf).map(|v| DynamicImage::ImageRgb16(v))
+        }
+
+        (color::ColorType::RGBA(16), U16(buf)) => {
+            ImageBuffer::from_raw(w, h, buf).map(|v| DynamicImage::ImageRgba16(v))
+        }
+
+        (color::ColorType::Gray(16), U16(buf)) => {
+            ImageBuffer::from_raw(w, h, buf).map(|v| DynamicImage::ImageLuma16(v))
+        }
+
+        (color::ColorType::GrayA(16), U16(buf)) => {
+            ImageBuffer::from_raw(w, h, buf).map(|v| DynamicImage::ImageLumaA16(v))
+        }
         (color::ColorType::Gray(bit_depth), U8(ref buf)) if bit_depth == 1 || bit_depth == 2 || bit_depth == 4 => {
             // Note: this conversion assumes that the scanlines begin on byte boundaries
             let mask = (1u8 << bit_depth as usize) - 1;
@@ -509,24 +707,172 @@ fn image_to_bytes(image: &DynamicImage) -> Vec<u8> {
         DynamicImage::ImageRgba8(ref a) => {
             a.iter().map(|v| *v).collect()
         }
+
+        DynamicImage::ImageLuma16(ref a) => u16_to_bytes(a),
+
+        DynamicImage::ImageLumaA16(ref a) => u16_to_bytes(a),
+
+        DynamicImage::ImageRgb16(ref a) => u16_to_bytes(a),
+
+        DynamicImage::ImageRgba16(ref a) => u16_to_bytes(a),
     }
 }
 
+// Serializes 16-bit samples in big endian byte order
+fn u16_to_bytes(samples: &[u16]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(samples.len() * 2);
+    for &v in samples {
+        bytes.push((v >> 8) as u8);
+        bytes.push(v as u8);
+    }
+    bytes
+}
+
 /// Open the image located at the path specified.
-/// The image's format is determined from the path's file extension.
+///
+/// The image's format is determined from its contents, and from the path's file
+/// extension if the contents don't have a signature. The decoder uses the default ```Limits```.
 pub fn open<P>(path: P) -> ImageResult<DynamicImage> where P: AsRef<Path> {
-    let path = path.as_ref();
+    open_with_limits(path, Limits::default())
+}
+
+/// Open the image located at the path specified,
+/// the decoder may use the resources within ```limits```.
+pub fn open_with_limits<P>(path: P, limits: Limits) -> ImageResult<DynamicImage> where P: AsRef<Path> {
+    let (fin, format) = try!(open_file(path.as_ref()));
+    load_with_limits(fin, format, limits)
+}
+
+/// Open the image located at the path specified and turn it upright,
+/// the decoder may use the resources within ```limits```.
+///
+/// The orientation is read from the EXIF data or the TIFF ```Orientation``` tag.
+pub fn open_with_orientation<P>(path: P, limits: Limits) -> ImageResult<DynamicImage> where P: AsRef<Path> {
+    let (fin, format) = try!(open_file(path.as_ref()));
+    load_with_orientation(fin, format, limits)
+}
+
+// Opens the file at ```path``` and determines the format of the image in it
+fn open_file(path: &Path) -> ImageResult<(File, ImageFormat)> {
 
-    let fin = match File::open(path) {
+    let mut fin = match File::open(path) {
         Ok(f)  => f,
         Err(err) => return Err(image::ImageError::IoError(err))
     };
 
+    let mut start = Vec::new();
+    try!(Read::by_ref(&mut fin).take(MAGIC_BYTES_LEN as u64).read_to_end(&mut start));
+    try!(fin.seek(SeekFrom::Start(0)));
+
+    let format = match (guess_format(&start), format_from_path(path)) {
+        // The extension is more reliable than the TGA heuristic
+        (Ok(ImageFormat::TGA), Ok(format)) => format,
+        (Ok(format), _) | (Err(_), Ok(format)) => format,
+        (Err(err), Err(_)) => return Err(err),
+    };
+
+    Ok((fin, format))
+}
+
+/// Saves the supplied buffer to a file at the path specified.
+///
+/// The image format is derived from the file extension. The buffer is assumed to have
+/// the correct format according to the specified color type.
+
+/// This will lead to corrupted files if the buffer contains malformed data. Currently only
+/// jpeg, png, gif, ppm and webp files are supported.
+pub fn save_buffer<P>(path: P, buf: &[u8], width: u32, height: u32, color: color::ColorType)
+                      -> ImageResult<()> where P: AsRef<Path> {
+    let path = path.as_ref();
+    let format = try!(format_from_path(path));
+    let ref mut fout = try!(File::create(path));
+
+    write_buffer(fout, buf, width, height, color, format, &ImageMetadata::default())
+}
+
+/// Saves the supplied buffer to a file at the path specified
+/// with the format specific ```options```.
+///
+/// The image format is the one of the options, regardless of the file extension.
+/// The buffer is assumed to have the correct format according to the specified color type.
+pub fn save_buffer_with_options<P>(path: P, buf: &[u8], width: u32, height: u32,
+                                   color: color::ColorType, options: EncoderOptions)
+                                   -> ImageResult<()> where P: AsRef<Path> {
+    let ref mut fout = try!(File::create(path));
+
+    write_buffer_with_options(fout, buf, width, height, color, options)
+}
+
+// Encodes the image ```buf``` with the encoder of ```format```
+fn write_buffer<W: Write>(w: &mut W, buf: &[u8], width: u32, height: u32, color: color::ColorType,
+                          format: ImageFormat, metadata: &ImageMetadata) -> ImageResult<()> {
+    match format {
+        #[cfg(feature = "png_codec")]
+        ImageFormat::PNG  => png::PNGEncoder::new(w).write_image_with_metadata(buf, width, height, color, metadata),
+        #[cfg(feature = "ppm")]
+        ImageFormat::PPM  => ppm::PPMEncoder::new(w).write_image_with_metadata(buf, width, height, color, metadata),
+        #[cfg(feature = "jpeg")]
+        ImageFormat::JPEG => jpeg::JPEGEncoder::new(w).write_image_with_metadata(buf, width, height, color, metadata),
+        #[cfg(feature = "webp")]
+        ImageFormat::WEBP => webp::WebpEncoder::new(w).write_image_with_metadata(buf, width, height, color, metadata),
+        #[cfg(feature = "gif_codec")]
+        ImageFormat::GIF  => gif::Encoder::new(w).write_image_with_metadata(buf, width, height, color, metadata),
+        _ => Err(image::ImageError::UnsupportedError(
+                 format!("An encoder for {:?} is not available.", format))
+             ),
+    }
+}
+
+// Encodes the image ```buf``` with an encoder configured by ```options```
+fn write_buffer_with_options<W: Write>(w: &mut W, buf: &[u8], width: u32, height: u32,
+                                       color: color::ColorType, options: EncoderOptions) -> ImageResult<()> {
+    match options {
+        #[cfg(feature = "jpeg")]
+        EncoderOptions::JPEG { quality, subsampling } => {
+            let mut e = jpeg::JPEGEncoder::new_with_quality(w, quality);
+            e.set_subsampling(subsampling);
+            e.write_image(buf, width, height, color)
+        }
+
+        #[cfg(feature = "png_codec")]
+        EncoderOptions::PNG { compression, filter } => {
+            png::PNGEncoder::new_with_options(w, compression, filter).write_image(buf, width, height, color)
+        }
+
+        #[cfg(feature = "gif_codec")]
+        EncoderOptions::GIF { palette_size, dither } => {
+            let mut e = gif::Encoder::new(w);
+            e.set_palette_size(palette_size);
+            e.set_dither(dither);
+            e.write_image(buf, width, height, color)
+        }
+
+        #[cfg(feature = "webp")]
+        EncoderOptions::WEBP { lossless, quality } => {
+            let e = if lossless {
+                webp::WebpEncoder::new_lossless(w)
+            } else {
+                webp::WebpEncoder::new_with_quality(w, quality)
+            };
+
+            e.write_image(buf, width, height, color)
+        }
+
+        #[allow(unreachable_patterns)]
+        _ => Err(image::ImageError::UnsupportedError(
+                 format!("An encoder for {:?} is not available.", options))
+             ),
+    }
+}
+
+// Determines the format of an image from the extension of its path
+fn format_from_path(path: &Path) -> ImageResult<ImageFormat> {
     let ext = path.extension().and_then(|s| s.to_str())
                   .map_or("".to_string(), |s| s.to_ascii_lowercase());
 
-    let format = match &ext[..] {
+    Ok(match &ext[..] {
         "jpg" |
+        "jpe" |
         "jpeg" => image::ImageFormat::JPEG,
         "png"  => image::ImageFormat::PNG,
         "gif"  => image::ImageFormat::GIF,
@@ -536,94 +882,162 @@ pub fn open<P>(path: P) -> ImageResult<DynamicImage> where P: AsRef<Path> {
         "tga" => image::ImageFormat::TGA,
         "bmp" => image::ImageFormat::BMP,
         "ico" => image::ImageFormat::ICO,
+        "ppm" => image::ImageFormat::PPM,
         format => return Err(image::ImageError::UnsupportedError(format!(
             "Image format image/{:?} is not supported.",
             format
         )))
-    };
+    })
+}
 
-    load(fin, format)
+/// Create a new image from a Reader
+///
+/// The decoder uses the default ```Limits```.
+pub fn load<R: Read+Seek>(r: R, format: ImageFormat) -> ImageResult<DynamicImage> {
+    load_with_limits(r, format, Limits::default())
 }
 
-/// Saves the supplied buffer to a file at the path specified.
+/// Create a new image from a Reader, the decoder may use the resources within ```limits```
 ///
-/// The image format is derived from the file extension. The buffer is assumed to have
-/// the correct format according to the specified color type.
+/// The errors of the decoder record ```format```.
+pub fn load_with_limits<R: Read+Seek>(r: R, format: ImageFormat, limits: Limits) -> ImageResult<DynamicImage> {
+    decode_format(r, format, limits, None).map_err(|err| err.with_format(format))
+}
 
-/// This will lead to corrupted files if the buffer contains malformed data. Currently only
-/// jpeg and png files are supported.
-pub fn save_buffer<P>(path: P, buf: &[u8], width: u32, height: u32, color: color::ColorType)
-                      -> io::Result<()> where P: AsRef<Path> {
-    let path = path.as_ref();
-    let ref mut fout = try!(File::create(path));
-    let ext = path.extension().and_then(|s| s.to_str())
-                  .map_or("".to_string(), |s| s.to_ascii_lowercase());
+/// Create a new image from a Reader along with the metadata stored in it,
+/// the decoder may use the resources within ```limits```
+pub fn load_with_metadata<R: Read+Seek>(r: R, format: ImageFormat, limits: Limits)
+-> ImageResult<(DynamicImage, ImageMetadata)> {
+    let mut metadata = ImageMetadata::default();
+    let image = try!(decode_format(r, format, limits, Some(&mut metadata))
+                         .map_err(|err| err.with_format(format)));
+    Ok((image, metadata))
+}
 
-    match &*ext {
-        #[cfg(feature = "jpeg")]
-        "jpg" |
-        "jpeg" => jpeg::JPEGEncoder::new(fout).encode(buf, width, height, color),
-        #[cfg(feature = "png_codec")]
-        "png"  => png::PNGEncoder::new(fout).encode(buf, width, height, color),
-        #[cfg(feature = "ppm")]
-        "ppm"  => ppm::PPMEncoder::new(fout).encode(buf, width, height, color),
-        format => Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            &format!("Unsupported image format image/{:?}", format)[..],
-        ))
-    }
+/// Create a new image from a Reader and turn it upright,
+/// the decoder may use the resources within ```limits```
+///
+/// The orientation is read from the EXIF data or the TIFF ```Orientation``` tag,
+/// images without one are returned as they are stored.
+pub fn load_with_orientation<R: Read+Seek>(r: R, format: ImageFormat, limits: Limits) -> ImageResult<DynamicImage> {
+    let (image, metadata) = try!(load_with_metadata(r, format, limits));
+    Ok(match metadata.orientation {
+        Some(orientation) => image.apply_orientation(orientation),
+        None => image
+    })
 }
 
-/// Create a new image from a Reader
-pub fn load<R: Read+Seek>(r: R, format: ImageFormat) -> ImageResult<DynamicImage> {
+fn decode_format<R: Read+Seek>(r: R, format: ImageFormat, limits: Limits,
+                               metadata: Option<&mut ImageMetadata>) -> ImageResult<DynamicImage> {
     match format {
         #[cfg(feature = "png_codec")]
-        image::ImageFormat::PNG  => decoder_to_image(png::PNGDecoder::new(BufReader::new(r))),
+        image::ImageFormat::PNG  => decode(png::PNGDecoder::new(BufReader::new(r)), limits, metadata),
         #[cfg(feature = "gif_codec")]
-        image::ImageFormat::GIF  => decoder_to_image(gif::Decoder::new(BufReader::new(r))),
+        image::ImageFormat::GIF  => decode(gif::Decoder::new(BufReader::new(r)), limits, metadata),
         #[cfg(feature = "jpeg")]
-        image::ImageFormat::JPEG => decoder_to_image(jpeg::JPEGDecoder::new(BufReader::new(r))),
+        image::ImageFormat::JPEG => decode(jpeg::JPEGDecoder::new(BufReader::new(r)), limits, metadata),
         #[cfg(feature = "webp")]
-        image::ImageFormat::WEBP => decoder_to_image(webp::WebpDecoder::new(BufReader::new(r))),
+        image::ImageFormat::WEBP => decode(webp::WebpDecoder::new(BufReader::new(r)), limits, metadata),
         #[cfg(feature = "tiff")]
-        image::ImageFormat::TIFF => decoder_to_image(try!(tiff::TIFFDecoder::new(r))),
+        image::ImageFormat::TIFF => decode(try!(tiff::TIFFDecoder::new(r)), limits, metadata),
         #[cfg(feature = "tga")]
-        image::ImageFormat::TGA => decoder_to_image(tga::TGADecoder::new(r)),
+        image::ImageFormat::TGA => decode(tga::TGADecoder::new(r), limits, metadata),
         #[cfg(feature = "bmp")]
-        image::ImageFormat::BMP => decoder_to_image(bmp::BMPDecoder::new(r)),
+        image::ImageFormat::BMP => decode(bmp::BMPDecoder::new(r), limits, metadata),
         #[cfg(feature = "ico")]
-        image::ImageFormat::ICO => decoder_to_image(try!(ico::ICODecoder::new(r))),
+        image::ImageFormat::ICO => decode(try!(ico::ICODecoder::new(r)), limits, metadata),
         _ => Err(image::ImageError::UnsupportedError(format!("A decoder for {:?} is not available.", format))),
     }
 }
 
-static MAGIC_BYTES: [(&'static [u8], ImageFormat); 9] = [
+// Decodes an image once the decoder accepted the limits,
+// its metadata is read first if it is asked for
+fn decode<I: ImageDecoder>(mut decoder: I, limits: Limits,
+                           metadata: Option<&mut ImageMetadata>) -> ImageResult<DynamicImage> {
+    try!(decoder.set_limits(limits));
+    if let Some(metadata) = metadata {
+        *metadata = try!(decoder.metadata());
+    }
+    decoder_to_image(decoder)
+}
+
+static MAGIC_BYTES: [(&'static [u8], ImageFormat); 10] = [
     (b"\x89PNG\r\n\x1a\n", ImageFormat::PNG),
     (&[0xff, 0xd8, 0xff], ImageFormat::JPEG),
     (b"GIF89a", ImageFormat::GIF),
     (b"GIF87a", ImageFormat::GIF),
-    (b"WEBP", ImageFormat::WEBP),
-    (b"MM.*", ImageFormat::TIFF),
-    (b"II*.", ImageFormat::TIFF),
+    (b"MM\x00*", ImageFormat::TIFF),
+    (b"II*\x00", ImageFormat::TIFF),
     (b"BM", ImageFormat::BMP),
     (&[0, 0, 1, 0], ImageFormat::ICO),
+    (b"P3", ImageFormat::PPM),
+    (b"P6", ImageFormat::PPM),
 ];
 
-/// Create a new image from a byte slice
+// The number of bytes ```guess_format``` needs to recognize all formats but TGA files without footer
+const MAGIC_BYTES_LEN: usize = 18;
+
+/// Guesses the format of an image from its contents
 ///
-/// Makes an educated guess about the image format.
-/// TGA is not supported by this function.
-pub fn load_from_memory(buffer: &[u8]) -> ImageResult<DynamicImage> {
+/// TGA files have no signature at their start, they are recognized by the footer of
+/// TGA 2.0 files or by a plausible header if no other format matches.
+pub fn guess_format(buffer: &[u8]) -> ImageResult<ImageFormat> {
     for &(signature, format) in MAGIC_BYTES.iter() {
         if buffer.starts_with(signature) {
-            return load_from_memory_with_format(buffer, format)
+            return Ok(format)
         }
     }
+
+    // A RIFF container with a WEBP form type
+    if buffer.len() >= 12 && &buffer[..4] == b"RIFF" && &buffer[8..12] == b"WEBP" {
+        return Ok(ImageFormat::WEBP)
+    }
+
+    if buffer.ends_with(b"TRUEVISION-XFILE.\x00") || is_tga_header(buffer) {
+        return Ok(ImageFormat::TGA)
+    }
+
     Err(image::ImageError::UnsupportedError(
         "Unsupported image format".to_string())
     )
 }
 
+// Checks whether the fields of a TGA header have sensible values
+fn is_tga_header(buffer: &[u8]) -> bool {
+    if buffer.len() < 18 {
+        return false
+    }
+
+    let (map_type, image_type, map_entry_size) = (buffer[1], buffer[2], buffer[7]);
+    let (width, height) = (buffer[12] as u16 | (buffer[13] as u16) << 8,
+                           buffer[14] as u16 | (buffer[15] as u16) << 8);
+    let (pixel_depth, descriptor) = (buffer[16], buffer[17]);
+
+    let color_map = match map_type {
+        0 => false,
+        1 => true,
+        _ => return false
+    };
+
+    let valid_map = match image_type {
+        1 | 9 => color_map && [15, 16, 24, 32].contains(&map_entry_size),
+        2 | 3 | 10 | 11 => true,
+        _ => false
+    };
+
+    valid_map && width > 0 && height > 0
+        && [8, 15, 16, 24, 32].contains(&pixel_depth)
+        // The interleaving bits are reserved
+        && descriptor & 0xc0 == 0
+}
+
+/// Create a new image from a byte slice
+///
+/// Makes an educated guess about the image format with ```guess_format```.
+pub fn load_from_memory(buffer: &[u8]) -> ImageResult<DynamicImage> {
+    load_from_memory_with_format(buffer, try!(guess_format(buffer)))
+}
+
 
 /// Create a new image from a byte slice
 #[inline(always)]
@@ -652,4 +1066,350 @@ mod test {
     fn test_empty_file() {
         assert!(super::load_from_memory(b"").is_err());
     }
+
+    #[test]
+    fn test_guess_format() {
+        use std::fs::{self, File};
+        use std::io::Read;
+        use ImageFormat::*;
+
+        for &(dir, format) in [("png", PNG), ("jpg", JPEG), ("gif", GIF), ("webp", WEBP),
+                               ("tiff", TIFF), ("tga", TGA), ("bmp", BMP), ("ico", ICO)].iter() {
+            let suites = fs::read_dir(format!("tests/images/{}", dir)).unwrap();
+            let files = suites.flat_map(|suite| fs::read_dir(suite.unwrap().path()).unwrap());
+
+            for path in files.map(|file| file.unwrap().path()) {
+                if path.extension().and_then(|ext| ext.to_str()) != Some(dir) {
+                    continue
+                }
+
+                let mut data = Vec::new();
+                File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+                assert_eq!(super::guess_format(&data).unwrap(), format);
+
+                // Only the start of a TGA file is known to ```open```
+                assert_eq!(super::guess_format(&data[..super::MAGIC_BYTES_LEN]).unwrap(), format);
+            }
+        }
+
+        assert_eq!(super::guess_format(b"RIFF\x24\x00\x00\x00WEBPVP8 ").unwrap(), WEBP);
+        assert_eq!(super::guess_format(b"P6\n1 1\n255\n\x00\x00\x00").unwrap(), PPM);
+        assert!(super::guess_format(b"RIFF\x24\x00\x00\x00WAVEfmt ").is_err());
+        assert!(super::guess_format(b"WEBP").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "png_codec")]
+    fn test_open_misnamed() {
+        use std::env;
+        use std::fs;
+        use image::GenericImage;
+
+        let img = super::DynamicImage::new_rgb8(3, 2);
+        let dir = env::temp_dir();
+
+        for name in ["image-misnamed.jpg", "image-without-extension"].iter() {
+            let path = dir.join(name);
+            img.save(&mut fs::File::create(&path).unwrap(), ::ImageFormat::PNG).unwrap();
+
+            let opened = super::open(&path);
+            fs::remove_file(&path).unwrap();
+            assert_eq!(opened.unwrap().dimensions(), (3, 2));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "png_codec")]
+    fn test_16bit_png_roundtrip() {
+        use color::Rgb;
+        use ImageBuffer;
+
+        let buf = ImageBuffer::from_fn(3, 2, |x, y| Rgb([x as u16 * 0x1234, y as u16 * 0x4321, 0xff01]));
+        let img = super::DynamicImage::ImageRgb16(buf.clone());
+        let mut encoded = Vec::new();
+        img.save(&mut encoded, ::ImageFormat::PNG).unwrap();
+
+        let decoded = super::load_from_memory(&encoded).unwrap();
+        assert_eq!(decoded.color(), ::ColorType::RGB(16));
+        assert_eq!(decoded.as_rgb16().unwrap().pixels().collect::<Vec<_>>(),
+                   buf.pixels().collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[cfg(feature = "webp")]
+    fn test_lossless_webp_roundtrip() {
+        use color::Rgba;
+        use ImageBuffer;
+
+        let buf = ImageBuffer::from_fn(19, 7, |x, y| Rgba([x as u8 * 13, y as u8 * 31, (x * y) as u8, 255 - x as u8]));
+        let img = super::DynamicImage::ImageRgba8(buf.clone());
+        let mut encoded = Vec::new();
+        img.save_with_options(&mut encoded, ::EncoderOptions::WEBP { lossless: true, quality: 100 }).unwrap();
+
+        let decoded = super::load_from_memory_with_format(&encoded, ::ImageFormat::WEBP).unwrap();
+        assert_eq!(decoded.as_rgba8().unwrap().pixels().collect::<Vec<_>>(),
+                   buf.pixels().collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[cfg(all(feature = "png_codec", feature = "jpeg", feature = "gif_codec", feature = "webp"))]
+    fn test_save_formats() {
+        use color::Rgb;
+        use image::GenericImage;
+        use ImageBuffer;
+
+        let buf = ImageBuffer::from_fn(17, 9, |x, y| Rgb([x as u8 * 15, y as u8 * 28, 128]));
+        let img = super::DynamicImage::ImageRgb8(buf.clone());
+
+        for &format in [::ImageFormat::PNG, ::ImageFormat::JPEG, ::ImageFormat::GIF, ::ImageFormat::WEBP].iter() {
+            let mut encoded = Vec::new();
+            img.save(&mut encoded, format).unwrap();
+
+            let decoded = super::load_from_memory_with_format(&encoded, format).unwrap();
+            assert_eq!(decoded.dimensions(), (17, 9));
+
+            // Images with few colors are stored without loss as a GIF
+            if format == ::ImageFormat::PNG || format == ::ImageFormat::GIF {
+                assert_eq!(decoded.to_rgb().into_raw(), buf.clone().into_raw());
+            }
+        }
+
+        let mut encoded = Vec::new();
+        img.save(&mut encoded, ::ImageFormat::TGA).unwrap_err();
+        assert!(encoded.is_empty());
+    }
+
+    #[test]
+    #[cfg(all(feature = "png_codec", feature = "jpeg", feature = "gif_codec"))]
+    fn test_save_with_options() {
+        use color::Rgb;
+        use png::{CompressionType, FilterType};
+        use jpeg::Subsampling;
+        use EncoderOptions;
+        use ImageBuffer;
+
+        let buf = ImageBuffer::from_fn(32, 16, |x, y| Rgb([x as u16 * 0x0800, y as u16 * 0x1000, 0x8000]));
+        let img = super::DynamicImage::ImageRgb16(buf.clone());
+        let encode = |options| {
+            let mut encoded = Vec::new();
+            img.save_with_options(&mut encoded, options).unwrap();
+            encoded
+        };
+
+        let png = encode(EncoderOptions::PNG { compression: CompressionType::Best, filter: FilterType::Adaptive });
+        let decoded = super::load_from_memory(&png).unwrap();
+        assert_eq!(decoded.as_rgb16().unwrap().pixels().collect::<Vec<_>>(),
+                   buf.pixels().collect::<Vec<_>>());
+
+        let best = encode(EncoderOptions::JPEG { quality: 95, subsampling: Subsampling::Ratio444 });
+        let worst = encode(EncoderOptions::JPEG { quality: 5, subsampling: Subsampling::Ratio420 });
+        assert!(best.len() > worst.len());
+
+        let gif = encode(EncoderOptions::GIF { palette_size: 4, dither: false });
+        let mut colors: Vec<_> = super::load_from_memory(&gif).unwrap().to_rgb().pixels().cloned().collect();
+        colors.sort_by_key(|p| p.data);
+        colors.dedup();
+        assert!(colors.len() <= 4);
+    }
+
+    #[test]
+    #[cfg(all(feature = "png_codec", feature = "tga", feature = "bmp"))]
+    fn test_limits() {
+        use std::io::Cursor;
+        use ImageFormat::{BMP, PNG, TGA};
+        use image::{ImageError, Limits};
+
+        let img = super::DynamicImage::new_rgb8(64, 32);
+        let mut png = Vec::new();
+        img.save(&mut png, PNG).unwrap();
+
+        let load = |limits| super::load_with_limits(Cursor::new(&png), PNG, limits);
+        assert!(load(Limits::default()).is_ok());
+        let metadata_limits = Limits { max_image_width: Some(63), ..Limits::no_limits() };
+        match super::load_with_metadata(Cursor::new(&png), PNG, metadata_limits) {
+            Err(ImageError::LimitsExceeded) => (),
+            _ => panic!("the limits of load_with_metadata should be exceeded")
+        }
+        assert!(load(Limits { max_image_width: Some(64), max_image_height: Some(32), max_alloc: None }).is_ok());
+
+        for limits in [
+            Limits { max_image_width: Some(63), ..Limits::no_limits() },
+            Limits { max_image_height: Some(31), ..Limits::no_limits() },
+            Limits { max_alloc: Some(64 * 32 * 3 - 1), ..Limits::no_limits() },
+        ].iter() {
+            match load(*limits) {
+                Err(ImageError::LimitsExceeded) => (),
+                _ => panic!("{:?} should be exceeded", limits)
+            }
+        }
+
+        // A TGA header that announces a 65535x65535 image without any data
+        let tga = [0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 32, 8];
+        match super::load_from_memory_with_format(&tga, TGA) {
+            Err(ImageError::LimitsExceeded) => (),
+            _ => panic!("the default limits should be exceeded")
+        }
+
+        // A 4x4 TGA image with 8 bit indices into an RGB colour map, the
+        // decoder needs room for the indices along with the colours
+        let mut tga = vec![0, 1, 1, 0, 0, 2, 0, 24, 0, 0, 0, 0, 4, 0, 4, 0, 8, 0];
+        tga.extend_from_slice(&[0, 0, 0, 255, 255, 255]);
+        tga.extend_from_slice(&[0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0]);
+        let load_tga = |max_alloc| super::load_with_limits(
+            Cursor::new(&tga), TGA, Limits { max_alloc: Some(max_alloc), ..Limits::no_limits() });
+        assert!(load_tga(4 * 4 * 3 + 16).is_ok());
+        match load_tga(4 * 4 * 3 + 15) {
+            Err(ImageError::LimitsExceeded) => (),
+            _ => panic!("the TGA decoder should exceed the limits")
+        }
+
+        // The same image as an 8 bit palettized BMP
+        let mut bmp = b"BM".to_vec();
+        bmp.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 62, 0, 0, 0]);
+        bmp.extend_from_slice(&[40, 0, 0, 0, 4, 0, 0, 0, 4, 0, 0, 0, 1, 0, 8, 0]);
+        bmp.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0]);
+        bmp.extend_from_slice(&[0, 0, 0, 0, 255, 255, 255, 0]);
+        bmp.extend_from_slice(&[0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0]);
+        let load_bmp = |max_alloc| super::load_with_limits(
+            Cursor::new(&bmp), BMP, Limits { max_alloc: Some(max_alloc), ..Limits::no_limits() });
+        assert!(load_bmp(4 * 4 * 3 + 16).is_ok());
+        match load_bmp(4 * 4 * 3 + 15) {
+            Err(ImageError::LimitsExceeded) => (),
+            _ => panic!("the BMP decoder should exceed the limits")
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "png_codec", feature = "bmp"))]
+    fn test_error_context() {
+        use image::{ImageError, UnsupportedFeature};
+        use ImageFormat::{BMP, PNG};
+
+        // A file header followed by an info header of an unknown size
+        let mut bmp = b"BM".to_vec();
+        bmp.extend_from_slice(&[0; 12]);
+        bmp.extend_from_slice(&[20, 0, 0, 0]);
+        bmp.extend_from_slice(&[0; 16]);
+        match super::load_from_memory_with_format(&bmp, BMP) {
+            Err(ImageError::UnsupportedFeature { format: BMP, feature: UnsupportedFeature::HeaderVersion(20), offset: Some(14) }) => (),
+            other => panic!("unexpected result {:?}", other.map(|_| ()))
+        }
+
+        // Errors that are described in words record the format as well
+        let err = super::load_from_memory_with_format(b"\x89PNG\r\n\x1a\0not a png", PNG).err().unwrap();
+        assert_eq!(err.format(), Some(PNG));
+    }
+
+    #[test]
+    #[cfg(all(feature = "jpeg", feature = "webp"))]
+    fn test_metadata_roundtrip() {
+        use std::io::Cursor;
+        use image::{GenericImage, ImageMetadata, Limits};
+        use ImageFormat::{JPEG, WEBP};
+
+        let metadata = ImageMetadata {
+            dpi: Some((300.0, 150.0)),
+            text: vec![("Comment".to_string(), "resized".to_string())],
+            exif: Some(b"II*\0exif".to_vec()),
+            // An ICC profile that spans two APP2 segments of a JPEG file
+            icc_profile: Some((0..100000).map(|i| i as u8).collect()),
+            xmp: Some(b"<x:xmpmeta/>".to_vec()),
+            orientation: None,
+        };
+
+        let img = super::DynamicImage::new_rgb8(16, 16);
+        let mut jpeg = Vec::new();
+        img.save_with_metadata(&mut jpeg, JPEG, &metadata).unwrap();
+        let (decoded, jpeg_metadata) = super::load_with_metadata(Cursor::new(&jpeg), JPEG, Limits::default()).unwrap();
+        assert_eq!(decoded.dimensions(), (16, 16));
+        assert_eq!(jpeg_metadata, metadata);
+
+        // WebP has no place for the resolution and text
+        let mut webp = Vec::new();
+        img.save_with_metadata(&mut webp, WEBP, &metadata).unwrap();
+        let (_, webp_metadata) = super::load_with_metadata(Cursor::new(&webp), WEBP, Limits::default()).unwrap();
+        assert_eq!(webp_metadata, ImageMetadata { dpi: None, text: Vec::new(), ..metadata.clone() });
+
+        // Saving without metadata stores none of it
+        let mut plain = Vec::new();
+        img.save(&mut plain, JPEG).unwrap();
+        let (_, plain_metadata) = super::load_with_metadata(Cursor::new(&plain), JPEG, Limits::default()).unwrap();
+        assert_eq!(plain_metadata, ImageMetadata::default());
+    }
+
+    #[test]
+    fn test_orientation() {
+        use image::GenericImage;
+        use image::Orientation::*;
+
+        // A 2x3 image whose pixels are numbered row by row
+        let img = super::DynamicImage::ImageLuma8(
+            ::ImageBuffer::from_raw(2, 3, vec![0, 1, 2, 3, 4, 5]).unwrap());
+
+        let cases = [
+            (Normal, (2, 3), vec![0, 1, 2, 3, 4, 5]),
+            (FlipHorizontal, (2, 3), vec![1, 0, 3, 2, 5, 4]),
+            (Rotate180, (2, 3), vec![5, 4, 3, 2, 1, 0]),
+            (FlipVertical, (2, 3), vec![4, 5, 2, 3, 0, 1]),
+            (Transpose, (3, 2), vec![0, 2, 4, 1, 3, 5]),
+            (Rotate90, (3, 2), vec![4, 2, 0, 5, 3, 1]),
+            (Transverse, (3, 2), vec![5, 3, 1, 4, 2, 0]),
+            (Rotate270, (3, 2), vec![1, 3, 5, 0, 2, 4]),
+        ];
+        for &(orientation, dimensions, ref pixels) in cases.iter() {
+            let upright = img.apply_orientation(orientation);
+            assert_eq!(upright.dimensions(), dimensions);
+            assert_eq!(&upright.raw_pixels(), pixels);
+            assert_eq!(super::Orientation::from_exif(orientation.to_exif()), Some(orientation));
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "jpeg", feature = "tiff"))]
+    fn test_load_with_orientation() {
+        use std::io::Cursor;
+        use image::{GenericImage, ImageMetadata, Limits, Orientation};
+        use ImageFormat::JPEG;
+
+        // EXIF data with an Orientation tag of 6, the image has to be rotated clockwise
+        let mut exif = b"MM\0*\0\0\0\x08\0\x01".to_vec();
+        exif.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0, 0, 0, 0, 0]);
+        let metadata = ImageMetadata { exif: Some(exif), ..ImageMetadata::default() };
+
+        let mut jpeg = Vec::new();
+        super::DynamicImage::new_rgb8(16, 8).save_with_metadata(&mut jpeg, JPEG, &metadata).unwrap();
+
+        let (img, metadata) = super::load_with_metadata(Cursor::new(&jpeg), JPEG, Limits::default()).unwrap();
+        assert_eq!(metadata.orientation, Some(Orientation::Rotate90));
+        assert_eq!(img.dimensions(), (16, 8));
+
+        let img = super::load_with_orientation(Cursor::new(&jpeg), JPEG, Limits::default()).unwrap();
+        assert_eq!(img.dimensions(), (8, 16));
+
+        let limits = Limits { max_image_height: Some(7), ..Limits::no_limits() };
+        assert!(super::load_with_orientation(Cursor::new(&jpeg), JPEG, limits).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "bmp")]
+    fn test_bmp_resolution() {
+        use std::fs::File;
+        use image::Limits;
+
+        let file = File::open("tests/images/bmp/images/Info_8_Bit.bmp").unwrap();
+        let (_, metadata) = super::load_with_metadata(file, ::ImageFormat::BMP, Limits::default()).unwrap();
+        assert_eq!(metadata.dpi, Some((2834.0 * 0.0254, 2834.0 * 0.0254)));
+    }
+
+    #[test]
+    fn test_16bit_conversion() {
+        use color::{Rgb, Rgba};
+        use image::GenericImage;
+
+        let mut img = super::DynamicImage::new_rgb16(1, 1);
+        img.put_pixel(0, 0, Rgba([0x80, 0x01, 0xff, 0xff]));
+        assert_eq!(img.as_rgb16().unwrap().get_pixel(0, 0), &Rgb([0x8080, 0x0101, 0xffff]));
+        assert_eq!(img.to_rgba().get_pixel(0, 0), &Rgba([0x80, 0x01, 0xff, 0xff]));
+        assert_eq!(img.raw_pixels(), vec![0x80, 0x80, 0x01, 0x01, 0xff, 0xff]);
+    }
 }
@@ -7,13 +7,24 @@
 //!
 extern crate gif;
 
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::cmp;
+use std::borrow::Cow;
+use std::collections::HashMap;
 
-pub use self::gif::Frame;
-use self::gif::{SetParameter, ColorOutput};
+use byteorder::{LittleEndian, WriteBytesExt};
+use num::rational::Ratio;
 
-use image::{ImageError, ImageResult, DecodingResult, ImageDecoder};
-use color;
+pub use self::gif::{Frame, Repeat};
+use self::gif::{SetParameter, ColorOutput, DisposalMethod};
+
+use image::{ImageError, ImageResult, DecodingResult, ImageDecoder, ImageEncoder, ImageFormat, Limits, UnsupportedFeature};
+use buffer::{ImageBuffer, RgbaImage};
+use animation;
+use color::{self, Rgba};
+use math::nq::NeuQuant;
+use utils::lzw;
+use utils::bitstream::LsbWriter;
 
 enum Either<T, U> {
     Left(T),
@@ -22,7 +33,18 @@ enum Either<T, U> {
 
 /// GIF decoder
 pub struct Decoder<R: Read> {
-    inner: Option<Either<gif::Decoder<R>, gif::Reader<R>>>
+    inner: Option<Either<gif::Decoder<R>, gif::Reader<R>>>,
+
+    // The composited frames that have been decoded so far, the canvas they are
+    // drawn onto and the next frame once its descriptor has been read
+    frames: Vec<animation::Frame>,
+    canvas: Option<RgbaImage>,
+    next: Option<Frame<'static>>,
+    finished: bool,
+
+    // The next row of the first frame that is read by read_scanline
+    row: u32,
+    limits: Limits,
 }
 
 impl<R: Read> Decoder<R> {
@@ -31,7 +53,13 @@ impl<R: Read> Decoder<R> {
         let mut decoder = gif::Decoder::new(r);
         decoder.set(ColorOutput::RGBA);
         Decoder {
-            inner: Some(Either::Left(decoder))
+            inner: Some(Either::Left(decoder)),
+            frames: Vec::new(),
+            canvas: None,
+            next: None,
+            finished: false,
+            row: 0,
+            limits: Limits::default(),
         }
     }
 
@@ -50,10 +78,134 @@ impl<R: Read> Decoder<R> {
             _ => unreachable!()
         }
     }
+
+    // Reads the descriptor of the next frame unless it has been read already,
+    // returns whether there is another frame
+    fn peek_frame(&mut self) -> ImageResult<bool> {
+        if self.next.is_none() && !self.finished {
+            match try!(try!(self.get_reader()).next_frame_info()).cloned() {
+                Some(frame) => self.next = Some(frame),
+                None => self.finished = true
+            }
+        }
+
+        Ok(self.next.is_some())
+    }
+
+    // Decodes the next frame and draws it onto a canvas of the size of the
+    // logical screen, every frame holds the whole canvas and thus has an offset of 0.
+    // Returns whether there was another frame.
+    fn read_frame(&mut self) -> ImageResult<bool> {
+        if !try!(self.peek_frame()) {
+            return Ok(false)
+        }
+
+        let frame = self.next.take().unwrap();
+        let (width, height, buf) = {
+            let reader = try!(self.get_reader());
+            let (width, height) = (reader.width() as u32, reader.height() as u32);
+
+            let mut buf = vec![0; reader.buffer_size()];
+            try!(reader.fill_buffer(&mut buf));
+
+            if frame.interlaced {
+                buf = deinterlace(&buf, reader.line_length());
+            }
+
+            (width, height, buf)
+        };
+
+        // Every frame holds a copy of the canvas
+        let canvas_size = width as u64 * height as u64 * 4;
+        try!(self.limits.reserve(canvas_size));
+
+        // Like browsers do, the background is restored to transparent black
+        // instead of the background color of the logical screen
+        let transparent = Rgba([0, 0, 0, 0]);
+
+        if self.canvas.is_none() {
+            try!(self.limits.reserve(canvas_size));
+            self.canvas = Some(ImageBuffer::from_pixel(width, height, transparent));
+        }
+        let canvas = self.canvas.as_mut().unwrap();
+
+        let previous = match frame.dispose {
+            DisposalMethod::Previous => Some(canvas.clone()),
+            _ => None
+        };
+
+        // Parts of the frame outside of the logical screen are not drawn
+        let (left, top) = (frame.left as u32, frame.top as u32);
+        let right  = cmp::min(left + frame.width as u32, width);
+        let bottom = cmp::min(top + frame.height as u32, height);
+
+        for y in top..bottom {
+            for x in left..right {
+                let i = ((y - top) * frame.width as u32 + x - left) as usize * 4;
+                let p = &buf[i..i + 4];
+
+                // Transparent pixels leave the canvas untouched
+                if p[3] != 0 {
+                    canvas.put_pixel(x, y, Rgba([p[0], p[1], p[2], p[3]]));
+                }
+            }
+        }
+
+        // The delay is given in hundredths of a second
+        let delay = Ratio::new(frame.delay, 100);
+        self.frames.push(animation::Frame::from_parts(canvas.clone(), 0, 0, delay));
+
+        match frame.dispose {
+            DisposalMethod::Background => {
+                for y in top..bottom {
+                    for x in left..right {
+                        canvas.put_pixel(x, y, transparent);
+                    }
+                }
+            }
+            DisposalMethod::Previous => *canvas = previous.unwrap(),
+            DisposalMethod::Any | DisposalMethod::Keep => ()
+        }
+
+        Ok(true)
+    }
+
+    // The first frame drawn onto the canvas
+    fn first_frame(&mut self) -> ImageResult<&animation::Frame> {
+        if self.frames.is_empty() && !try!(self.read_frame()) {
+            return Err(ImageError::ImageEnd)
+        }
+
+        Ok(&self.frames[0])
+    }
+}
+
+// Reorders the rows of an interlaced frame, they are stored in four passes
+// that start at row 0, 4, 2 and 1 and skip 8, 8, 4 and 2 rows.
+fn deinterlace(buf: &[u8], line_length: usize) -> Vec<u8> {
+    let height = buf.len() / line_length;
+    let mut out = vec![0; buf.len()];
+    let mut rows = buf.chunks(line_length);
+
+    for &(start, step) in [(0, 8), (4, 8), (2, 4), (1, 2)].iter() {
+        for y in (start..height).filter(|y| (y - start) % step == 0) {
+            let row = rows.next().unwrap();
+            out[y * line_length..(y + 1) * line_length].copy_from_slice(row);
+        }
+    }
+
+    out
 }
 
 
 impl<R: Read> ImageDecoder for Decoder<R> {
+    fn set_limits(&mut self, limits: Limits) -> ImageResult<()> {
+        let (width, height) = try!(self.dimensions());
+        try!(limits.check_image(width, height, color::ColorType::RGBA(8)));
+        self.limits = limits;
+        Ok(())
+    }
+
     fn dimensions(&mut self) -> ImageResult<(u32, u32)> {
         let reader = try!(self.get_reader());
         Ok((reader.width() as u32, reader.height() as u32))
@@ -64,57 +216,750 @@ impl<R: Read> ImageDecoder for Decoder<R> {
     }
 
     fn row_len(&mut self) -> ImageResult<usize> {
-        let reader = try!(self.get_reader());
-        Ok(reader.line_length())
+        let (width, _) = try!(self.dimensions());
+        Ok(width as usize * 4)
     }
 
     fn read_scanline(&mut self, buf: &mut [u8]) -> ImageResult<u32> {
-        let reader = try!(self.get_reader());
-        let len = reader.line_length();
-        try!(reader.fill_buffer(&mut buf[..len]));
+        let row = self.row;
+        let pixels = {
+            let frame = try!(self.first_frame()).buffer();
+            if row >= frame.height() {
+                return Err(ImageError::ImageEnd)
+            }
+
+            let len = frame.width() as usize * 4;
+            let start = row as usize * len;
+            &(**frame)[start..start + len]
+        };
+
+        let len = pixels.len();
+        buf[..len].copy_from_slice(pixels);
+        self.row += 1;
         Ok(len as u32)
     }
 
     fn read_image(&mut self) -> ImageResult<DecodingResult> {
-        let reader = try!(self.get_reader());
-        if let Some(_) = try!(reader.next_frame_info()) {
-            let mut buf = vec![0; reader.buffer_size()];
-            try!(reader.fill_buffer(&mut buf));
-            Ok(DecodingResult::U8(buf))
-        } else {
-            Err(ImageError::ImageEnd)
-        }
+        let frame = try!(self.first_frame());
+        Ok(DecodingResult::U8(frame.buffer().clone().into_raw()))
+    }
+
+    fn is_animated(&mut self) -> ImageResult<bool> {
+        // Decoding stops at the descriptor of the second frame
+        let _ = try!(self.first_frame());
+        Ok(self.frames.len() > 1 || try!(self.peek_frame()))
+    }
+
+    fn into_frames(mut self) -> ImageResult<animation::Frames> {
+        let _ = try!(self.first_frame());
+        while try!(self.read_frame()) {}
+
+        Ok(animation::Frames::new(self.frames))
     }
 }
 
 /// GIF encoder.
 pub struct Encoder<W: Write> {
     w: W,
+    repeat: Repeat,
+    palette_size: usize,
+    dither: bool,
+    global_palette: Option<Vec<u8>>,
 }
 
 impl<W: Write> Encoder<W> {
     /// Creates a new GIF encoder.
     pub fn new(w: W) -> Encoder<W> {
         Encoder {
-            w: w
+            w: w,
+            repeat: Repeat::Infinite,
+            palette_size: 256,
+            dither: false,
+            global_palette: None,
         }
     }
+
+    /// Sets how often an animation is played, the default is to loop forever.
+    pub fn set_repeat(&mut self, repeat: Repeat) {
+        self.repeat = repeat;
+    }
+
+    /// Sets the largest number of colors of a palette, from 2 to 256, the default is 256.
+    /// A transparent image uses one of them for the transparent pixels.
+    pub fn set_palette_size(&mut self, palette_size: u16) {
+        self.palette_size = cmp::min(cmp::max(palette_size, 2), 256) as usize;
+    }
+
+    /// Enables Floyd-Steinberg dithering of images with more colors than a palette holds.
+    pub fn set_dither(&mut self, dither: bool) {
+        self.dither = dither;
+    }
+
+    /// Sets the global palette that ```encode``` writes for a frame without a palette
+    /// of its own. The default is a gray ramp with as many shades as the frame needs.
+    pub fn set_global_palette(&mut self, palette: &[u8]) {
+        self.global_palette = Some(palette.to_vec());
+    }
+
     /// Encodes a frame.
-    pub fn encode(self, frame: Frame) -> ImageResult<()> {
-        let mut encoder = try!(
-            gif::Encoder::new(self.w, frame.width, frame.height, &[])
-        );
-        encoder.write_frame(&frame).map_err(|err| err.into())
+    pub fn encode(mut self, frame: Frame) -> ImageResult<()> {
+        let global_palette = match frame.palette {
+            Some(_) => None,
+            None => Some(match self.global_palette.take() {
+                Some(palette) => palette,
+                None => gray_palette(frame.buffer.iter().cloned().max().unwrap_or(0)),
+            })
+        };
+        let global_palette = global_palette.as_ref().map(|palette| &palette[..]);
+
+        try!(write_screen(&mut self.w, frame.width, frame.height, global_palette));
+        try!(write_frame(&mut self.w, &frame, global_palette));
+        self.w.write_all(&[TRAILER]).map_err(|err| err.into())
+    }
+
+    /// Encodes the frames of an animation.
+    ///
+    /// Every frame is drawn at its offset onto a transparent logical screen that
+    /// is large enough to hold all frames. A frame stays under the next one, unless
+    /// the next frame covers it completely, then it is cleared before the next one
+    /// is shown. Pixels with an alpha value below 128 are transparent.
+    pub fn encode_frames<F>(mut self, frames: F) -> ImageResult<()>
+        where F: IntoIterator<Item = animation::Frame> {
+
+        let frames: Vec<animation::Frame> = frames.into_iter().collect();
+        if frames.is_empty() {
+            return Err(ImageError::malformed(ImageFormat::GIF, "An animation needs at least one frame."))
+        }
+
+        let (mut width, mut height) = (0, 0);
+        for frame in frames.iter() {
+            let (w, h) = frame.buffer().dimensions();
+            width  = cmp::max(width, frame.left() as u64 + w as u64);
+            height = cmp::max(height, frame.top() as u64 + h as u64);
+        }
+
+        if width > 0xffff || height > 0xffff {
+            return Err(ImageError::DimensionError)
+        }
+
+        try!(write_screen(&mut self.w, width as u16, height as u16, None));
+        try!(write_repeat(&mut self.w, self.repeat));
+
+        for (i, frame) in frames.iter().enumerate() {
+            let (w, h) = frame.buffer().dimensions();
+            let (palette, indices, transparent) = quantize(frame.buffer(), self.palette_size, self.dither);
+
+            // The delay is given in hundredths of a second
+            let delay = frame.delay();
+            let delay = (*delay.numer() as u32 * 100 + *delay.denom() as u32 / 2) / *delay.denom() as u32;
+
+            // The transparent pixels of a frame that replaces the previous one show the
+            // background, the first frame follows the last one if the animation repeats
+            let next = &frames[(i + 1) % frames.len()];
+            let dispose = if covers(next, frame) { DisposalMethod::Background } else { DisposalMethod::Keep };
+
+            try!(write_frame(&mut self.w, &Frame {
+                delay: cmp::min(delay, 0xffff) as u16,
+                dispose: dispose,
+                transparent: transparent,
+                left: frame.left() as u16,
+                top: frame.top() as u16,
+                width: w as u16,
+                height: h as u16,
+                palette: Some(palette),
+                buffer: Cow::Owned(indices),
+                ..Frame::default()
+            }, None));
+        }
+
+        self.w.write_all(&[TRAILER]).map_err(|err| err.into())
+    }
+}
+
+impl<W: Write> ImageEncoder for Encoder<W> {
+    fn write_image(mut self, buf: &[u8], width: u32, height: u32, color: color::ColorType) -> ImageResult<()> {
+        if width > 0xffff || height > 0xffff {
+            return Err(ImageError::DimensionError)
+        }
+
+        let image = try!(to_rgba(buf, width, height, color));
+        let (palette, indices, transparent) = quantize(&image, self.palette_size, self.dither);
+
+        try!(write_screen(&mut self.w, width as u16, height as u16, None));
+        try!(write_frame(&mut self.w, &Frame {
+            transparent: transparent,
+            width: width as u16,
+            height: height as u16,
+            palette: Some(palette),
+            buffer: Cow::Owned(indices),
+            ..Frame::default()
+        }, None));
+        self.w.write_all(&[TRAILER]).map_err(|err| err.into())
+    }
+}
+
+// Whether ```frame``` hides all pixels of ```other```
+fn covers(frame: &animation::Frame, other: &animation::Frame) -> bool {
+    let (w, h) = frame.buffer().dimensions();
+    let (other_w, other_h) = other.buffer().dimensions();
+
+    frame.left() <= other.left() && frame.top() <= other.top() &&
+        frame.left() as u64 + w as u64 >= other.left() as u64 + other_w as u64 &&
+        frame.top() as u64 + h as u64 >= other.top() as u64 + other_h as u64
+}
+
+const TRAILER: u8 = 0x3B;
+
+// Writes the header and the logical screen descriptor with the global palette, if there is one
+fn write_screen<W: Write>(w: &mut W, width: u16, height: u16, palette: Option<&[u8]>) -> io::Result<()> {
+    try!(w.write_all(b"GIF89a"));
+    try!(w.write_u16::<LittleEndian>(width));
+    try!(w.write_u16::<LittleEndian>(height));
+
+    // The background color and the aspect ratio are unused
+    match palette {
+        Some(palette) => {
+            let size = palette_size(palette);
+            try!(w.write_all(&[0x80 | size << 4 | size, 0, 0]));
+            write_palette(w, palette, size)
+        }
+        None => w.write_all(&[0, 0, 0])
+    }
+}
+
+// The size of the color table that holds ```palette```, the table holds 2^(size + 1) colors
+fn palette_size(palette: &[u8]) -> u8 {
+    let colors = cmp::min(palette.len() / 3, 256);
+    (0..8).find(|&size| 2 << size >= colors).unwrap()
+}
+
+// Writes a color table of 2^(size + 1) colors, unused ones are black
+fn write_palette<W: Write>(w: &mut W, palette: &[u8], size: u8) -> io::Result<()> {
+    let colors = cmp::min(palette.len() / 3, 2 << size);
+    try!(w.write_all(&palette[..colors * 3]));
+    for _ in colors..2 << size {
+        try!(w.write_all(&[0, 0, 0]));
+    }
+    Ok(())
+}
+
+// Evenly spaced shades of gray for the indices up to ```max_index```
+fn gray_palette(max_index: u8) -> Vec<u8> {
+    let colors = (1..9).map(|bits| 1u32 << bits).find(|&colors| colors > max_index as u32).unwrap();
+    (0..colors).flat_map(|i| {
+        let l = (i * 255 / (colors - 1)) as u8;
+        vec![l, l, l]
+    }).collect()
+}
+
+// Writes the NETSCAPE extension, an animation that is played once needs none
+fn write_repeat<W: Write>(w: &mut W, repeat: Repeat) -> io::Result<()> {
+    let count = match repeat {
+        Repeat::Finite(0) => return Ok(()),
+        Repeat::Finite(n) => n,
+        Repeat::Infinite => 0,
+    };
+
+    try!(w.write_all(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01"));
+    try!(w.write_u16::<LittleEndian>(count));
+    w.write_all(&[0])
+}
+
+// Writes the graphic control extension, the image descriptor, the palette and the indices of a frame.
+// The encoder of the gif crate derives the LZW code size from the largest index of a frame and picks
+// the invalid size 1 if all indices are below 2, so the code size is chosen here.
+// A frame without a palette of its own uses ```global_palette```.
+fn write_frame<W: Write>(w: &mut W, frame: &Frame, global_palette: Option<&[u8]>) -> io::Result<()> {
+    let palette = frame.palette.as_ref().map(|palette| &palette[..]);
+    let size = match palette.or(global_palette) {
+        Some(palette) if palette.len() >= 3 => palette_size(palette),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "A GIF frame needs a palette."))
+    };
+
+    let flags = (frame.dispose as u8) << 2
+              | (frame.needs_user_input as u8) << 1
+              | frame.transparent.is_some() as u8;
+    try!(w.write_all(&[0x21, 0xF9, 4, flags]));
+    try!(w.write_u16::<LittleEndian>(frame.delay));
+    try!(w.write_all(&[frame.transparent.unwrap_or(0), 0]));
+
+    try!(w.write_all(&[0x2C]));
+    for &v in [frame.left, frame.top, frame.width, frame.height].iter() {
+        try!(w.write_u16::<LittleEndian>(v));
     }
+
+    match palette {
+        Some(palette) => {
+            try!(w.write_all(&[0x80 | size]));
+            try!(write_palette(w, palette, size));
+        }
+        None => try!(w.write_all(&[0]))
+    }
+
+    // The decoder of the gif crate stops at the end of the data even if its bit reader still holds
+    // up to 7 bits, which drops the last pixels if they are coded with 3 bits. Codes start with 4 bits
+    // if the code size is at least 3, then only the end code can be left in the bit reader.
+    let max_index = frame.buffer.iter().cloned().max().unwrap_or(0);
+    let bits = (1..9).find(|&bits| 1 << bits > max_index as usize).unwrap();
+    let min_code_size = cmp::max(cmp::max(size + 1, bits), 3);
+
+    let mut data = Vec::new();
+    try!(lzw::encode(&frame.buffer[..], LsbWriter::new(&mut data), min_code_size));
+
+    // The compressed indices are split into sub-blocks of up to 255 bytes
+    try!(w.write_all(&[min_code_size]));
+    for block in data.chunks(255) {
+        try!(w.write_all(&[block.len() as u8]));
+        try!(w.write_all(block));
+    }
+    w.write_all(&[0])
+}
+
+// Converts an 8-bit image to RGBA
+fn to_rgba(buf: &[u8], width: u32, height: u32, color: color::ColorType) -> ImageResult<RgbaImage> {
+    let pixels: Vec<u8> = match color {
+        color::ColorType::RGBA(8) => buf.to_vec(),
+        color::ColorType::RGB(8) => buf.chunks(3).flat_map(|p| vec![p[0], p[1], p[2], 255]).collect(),
+        color::ColorType::GrayA(8) => buf.chunks(2).flat_map(|p| vec![p[0], p[0], p[0], p[1]]).collect(),
+        color::ColorType::Gray(8) => buf.iter().flat_map(|&l| vec![l, l, l, 255]).collect(),
+        _ => return Err(ImageError::unsupported(ImageFormat::GIF, UnsupportedFeature::Color(color)))
+    };
+
+    ImageBuffer::from_raw(width, height, pixels).ok_or(ImageError::DimensionError)
+}
+
+// NeuQuant learns from every pixel, small frames need all of them
+const SAMPLE_FACTOR: i32 = 1;
+
+// Converts an image to a palette, its indices and the transparent index.
+// Images with colors that fit into the palette keep them, others are quantized.
+fn quantize(image: &RgbaImage, palette_size: usize, dither: bool) -> (Vec<u8>, Vec<u8>, Option<u8>) {
+    let transparent = image.pixels().any(|p| p[3] < 128);
+    let max_colors = if transparent { palette_size - 1 } else { palette_size };
+
+    let mut palette = Vec::new();
+    let mut colors = HashMap::new();
+    for p in image.pixels().filter(|p| p[3] >= 128) {
+        if colors.len() > max_colors {
+            break
+        }
+
+        let next = colors.len();
+        colors.entry([p[0], p[1], p[2]]).or_insert_with(|| {
+            palette.extend_from_slice(&[p[0], p[1], p[2]]);
+            next
+        });
+    }
+
+    let (mut palette, indices): (Vec<u8>, Vec<u8>) = if colors.len() <= max_colors {
+        let indices = image.pixels().map(|p| {
+            if p[3] < 128 {
+                colors.len() as u8
+            } else {
+                colors[&[p[0], p[1], p[2]]] as u8
+            }
+        }).collect();
+
+        (palette, indices)
+    } else {
+        let opaque: Vec<u8> = image.pixels().filter(|p| p[3] >= 128)
+                                   .flat_map(|p| vec![p[0], p[1], p[2], 255]).collect();
+        let nq = NeuQuant::new(SAMPLE_FACTOR, max_colors, &opaque);
+        let palette = nq.color_map_rgb();
+
+        let indices = if dither {
+            dither_image(image, &nq, &palette, max_colors as u8)
+        } else {
+            image.pixels().map(|p| {
+                if p[3] < 128 {
+                    max_colors as u8
+                } else {
+                    nq.index_of(&[p[0], p[1], p[2], 255]) as u8
+                }
+            }).collect()
+        };
+
+        (palette, indices)
+    };
+
+    let transparent = if transparent {
+        palette.extend_from_slice(&[0, 0, 0]);
+        Some((palette.len() / 3 - 1) as u8)
+    } else {
+        None
+    };
+
+    (palette, indices, transparent)
+}
+
+// Maps the pixels of an image to the colors of ```palette``` and spreads the
+// error of every pixel onto its neighbours with the Floyd-Steinberg weights.
+fn dither_image(image: &RgbaImage, nq: &NeuQuant, palette: &[u8], transparent: u8) -> Vec<u8> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let mut errors = vec![[0i32; 3]; width * height];
+    let mut indices = Vec::with_capacity(width * height);
+
+    for (i, p) in image.pixels().enumerate() {
+        if p[3] < 128 {
+            indices.push(transparent);
+            continue
+        }
+
+        let mut color = [0; 4];
+        for c in 0..3 {
+            color[c] = cmp::min(cmp::max(p[c] as i32 + errors[i][c] / 16, 0), 255) as u8;
+        }
+        color[3] = 255;
+
+        let index = nq.index_of(&color);
+        indices.push(index as u8);
+
+        let (x, y) = (i % width, i / width);
+        for c in 0..3 {
+            let error = color[c] as i32 - palette[index * 3 + c] as i32;
+
+            if x + 1 < width {
+                errors[i + 1][c] += error * 7;
+            }
+            if y + 1 < height {
+                if x > 0 {
+                    errors[i + width - 1][c] += error * 3;
+                }
+                errors[i + width][c] += error * 5;
+                if x + 1 < width {
+                    errors[i + width + 1][c] += error;
+                }
+            }
+        }
+    }
+
+    indices
 }
 
 impl From<gif::DecodingError> for ImageError {
     fn from(err: gif::DecodingError) -> ImageError {
         use self::gif::DecodingError::*;
         match err {
-            Format(desc) => ImageError::FormatError(desc.into()),
-            Internal(desc) => ImageError::FormatError(desc.into()),
+            // The gif reader reports the end of the stream as a format error
+            Format("unexpected EOF") => ImageError::NotEnoughData,
+            Format(desc) => ImageError::malformed(ImageFormat::GIF, desc),
+            Internal(desc) => ImageError::malformed(ImageFormat::GIF, desc),
             Io(io_err) => ImageError::IoError(io_err),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+    use num::rational::Ratio;
+
+    use animation;
+    use buffer::{ImageBuffer, RgbaImage};
+    use color::Rgba;
+    use image::{ImageDecoder, ImageEncoder, DecodingResult};
+    use color::ColorType;
+    use super::gif::{self, DisposalMethod};
+    use super::{Decoder, Encoder, Repeat, deinterlace};
+
+    // Red, green, blue and black
+    const PALETTE: &'static [u8] = &[255, 0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0];
+
+    fn frame(left: u16, top: u16, width: u16, height: u16, indices: &[u8],
+             dispose: DisposalMethod, delay: u16) -> gif::Frame<'static> {
+        gif::Frame {
+            delay: delay,
+            dispose: dispose,
+            transparent: Some(3),
+            left: left,
+            top: top,
+            width: width,
+            height: height,
+            buffer: Cow::Owned(indices.to_vec()),
+            ..gif::Frame::default()
+        }
+    }
+
+    #[test]
+    fn test_into_frames() {
+        let mut data = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut data, 4, 2, PALETTE).unwrap();
+            // Every frame uses an index above 1, the encoder picks an invalid
+            // LZW code size otherwise
+            for f in [
+                frame(0, 0, 4, 2, &[0, 0, 0, 0, 0, 0, 0, 2], DisposalMethod::Keep, 10),
+                // The black pixel is transparent
+                frame(1, 0, 2, 1, &[1, 3], DisposalMethod::Background, 5),
+                frame(2, 1, 2, 1, &[1, 2], DisposalMethod::Previous, 0),
+                frame(0, 0, 2, 1, &[1, 3], DisposalMethod::Keep, 0),
+            ].iter() {
+                encoder.write_frame(f).unwrap();
+            }
+        }
+
+        let mut decoder = Decoder::new(&data[..]);
+        assert!(decoder.is_animated().unwrap());
+
+        let (r, g, b, t) = ([255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255], [0, 0, 0, 0]);
+        let expected = [
+            (Ratio::new(1, 10), [r, r, r, r, r, r, r, b]),
+            (Ratio::new(1, 20), [r, g, r, r, r, r, r, b]),
+            // The area of the previous frame is cleared
+            (Ratio::new(0, 1), [r, t, t, r, r, r, g, b]),
+            // The canvas before the previous frame is restored
+            (Ratio::new(0, 1), [g, t, t, r, r, r, r, b]),
+        ];
+
+        let frames: Vec<_> = decoder.into_frames().unwrap().collect();
+        assert_eq!(frames.len(), expected.len());
+
+        // Every frame holds the whole canvas
+        for (frame, &(delay, ref pixels)) in frames.iter().zip(expected.iter()) {
+            assert_eq!((frame.left(), frame.top(), frame.delay()), (0, 0, delay));
+            assert_eq!(frame.buffer().dimensions(), (4, 2));
+
+            let data: Vec<u8> = pixels.iter().flat_map(|p| p.iter().cloned()).collect();
+            assert_eq!(frame.buffer().clone().into_raw(), data);
+        }
+    }
+
+    #[test]
+    fn test_first_frame() {
+        let mut data = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut data, 4, 2, PALETTE).unwrap();
+            for f in [
+                frame(1, 0, 2, 1, &[2, 2], DisposalMethod::Keep, 0),
+                frame(0, 1, 4, 1, &[0, 1, 2, 0], DisposalMethod::Keep, 0),
+                frame(0, 0, 4, 2, &[0, 0, 0, 0, 0, 0, 0, 2], DisposalMethod::Keep, 0),
+            ].iter() {
+                encoder.write_frame(f).unwrap();
+            }
+        }
+
+        let (b, t) = ([0, 0, 255, 255], [0, 0, 0, 0]);
+        let first: Vec<u8> = [t, b, b, t, t, t, t, t].iter().flat_map(|p| p.iter().cloned()).collect();
+        let pixels = |decoder: &mut Decoder<&[u8]>| match decoder.read_image().unwrap() {
+            DecodingResult::U8(pixels) => pixels,
+            _ => panic!("wrong sample type")
+        };
+
+        // The first frame is drawn onto the canvas, whether the other frames are decoded or not
+        let mut decoder = Decoder::new(&data[..]);
+        assert_eq!(pixels(&mut decoder), first);
+        assert!(decoder.is_animated().unwrap());
+
+        let mut decoder = Decoder::new(&data[..]);
+        assert!(decoder.is_animated().unwrap());
+        assert_eq!(pixels(&mut decoder), first);
+        assert_eq!(decoder.into_frames().unwrap().count(), 3);
+
+        let mut decoder = Decoder::new(&data[..]);
+        let mut rows = vec![0; 2 * 16];
+        for row in rows.chunks_mut(16) {
+            assert_eq!(decoder.read_scanline(row).unwrap(), 16);
+        }
+        assert_eq!(rows, first);
+
+        // Only the first frame and the descriptor of the second one are read
+        let truncated = &data[..data.len() - 20];
+        assert!(Decoder::new(truncated).is_animated().unwrap());
+        assert!(Decoder::new(truncated).into_frames().is_err());
+    }
+
+    #[test]
+    fn test_frame_limits() {
+        use image::Limits;
+
+        let mut data = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut data, 4, 2, PALETTE).unwrap();
+            for _ in 0..4 {
+                encoder.write_frame(&frame(0, 0, 4, 2, &[0, 1, 2, 0, 1, 2, 0, 1], DisposalMethod::Keep, 0)).unwrap();
+            }
+        }
+
+        // The canvas fits, but not four copies of it
+        let limits = Limits { max_alloc: Some(4 * 2 * 4 * 3), ..Limits::default() };
+        let mut decoder = Decoder::new(&data[..]);
+        decoder.set_limits(limits).unwrap();
+        match decoder.into_frames() {
+            Err(::image::ImageError::LimitsExceeded) => (),
+            _ => panic!("the frames exceed the limits")
+        }
+
+        let mut decoder = Decoder::new(&data[..]);
+        decoder.set_limits(Limits::no_limits()).unwrap();
+        assert_eq!(decoder.into_frames().unwrap().count(), 4);
+    }
+
+    #[test]
+    fn test_deinterlace() {
+        // The rows of an image of height 10 in interlaced order
+        let interlaced = [0, 8, 4, 2, 6, 1, 3, 5, 7, 9];
+        assert_eq!(deinterlace(&interlaced, 1), (0..10).collect::<Vec<u8>>());
+    }
+
+    fn encode_frames(frames: Vec<animation::Frame>, repeat: Repeat) -> Vec<u8> {
+        let mut data = Vec::new();
+        {
+            let mut encoder = Encoder::new(&mut data);
+            encoder.set_repeat(repeat);
+            encoder.encode_frames(frames).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn test_encode_frames() {
+        let (red, blue, clear) = (Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255]), Rgba([0, 0, 0, 0]));
+
+        let solid = ImageBuffer::from_pixel(3, 2, red);
+        let dots = ImageBuffer::from_fn(2, 2, |x, y| if x == y { blue } else { clear });
+
+        let data = encode_frames(vec![
+            animation::Frame::from_parts(solid, 0, 0, Ratio::new(1, 4)),
+            animation::Frame::from_parts(dots, 2, 1, Ratio::new(3, 100)),
+        ], Repeat::Infinite);
+
+        // The NETSCAPE extension with a loop count of 0
+        assert!(data.windows(15).any(|w| w == b"NETSCAPE2.0\x03\x01\x00\x00"));
+
+        let frames: Vec<_> = Decoder::new(&data[..]).into_frames().unwrap().collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].buffer().dimensions(), (4, 3));
+        assert_eq!((frames[0].delay(), frames[1].delay()), (Ratio::new(1, 4), Ratio::new(3, 100)));
+        assert_eq!((frames[1].left(), frames[1].top()), (0, 0));
+
+        // The dots are drawn over the first frame, their transparent pixels show it
+        let expected = |frame: usize, x: u32, y: u32| match frame {
+            1 if x >= 2 && y >= 1 && x - 2 == y - 1 => blue,
+            _ if x < 3 && y < 2 => red,
+            _ => clear,
+        };
+
+        for (i, frame) in frames.iter().enumerate() {
+            for (x, y, &p) in frame.buffer().enumerate_pixels() {
+                assert_eq!(p, expected(i, x, y));
+            }
+        }
+
+        // Decoded frames cover the canvas, they can be encoded again without
+        // growing it and replace each other
+        let buffers: Vec<_> = frames.iter().map(|frame| frame.buffer().clone().into_raw()).collect();
+        let data = encode_frames(frames, Repeat::Infinite);
+        let frames: Vec<_> = Decoder::new(&data[..]).into_frames().unwrap().collect();
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().zip(buffers.iter()).all(|(frame, buffer)| frame.buffer().clone().into_raw() == *buffer));
+    }
+
+    #[test]
+    fn test_encode_global_palette() {
+        let indices = [0, 1, 2, 3, 3, 2, 1, 0];
+        let encode = |palette: Option<&[u8]>| {
+            let mut data = Vec::new();
+            {
+                let mut encoder = Encoder::new(&mut data);
+                if let Some(palette) = palette {
+                    encoder.set_global_palette(palette);
+                }
+                encoder.encode(gif::Frame {
+                    width: 4,
+                    height: 2,
+                    buffer: Cow::Borrowed(&indices[..]),
+                    ..gif::Frame::default()
+                }).unwrap();
+            }
+
+            match Decoder::new(&data[..]).read_image().unwrap() {
+                DecodingResult::U8(pixels) => pixels,
+                _ => panic!("wrong sample type")
+            }
+        };
+
+        // A frame without a palette of its own uses shades of gray by default
+        let expected: Vec<u8> = indices.iter().flat_map(|&i| vec![i * 85, i * 85, i * 85, 255]).collect();
+        assert_eq!(encode(None), expected);
+
+        let expected: Vec<u8> = indices.iter().flat_map(|&i| {
+            let mut p = PALETTE[i as usize * 3..i as usize * 3 + 3].to_vec();
+            p.push(255);
+            p
+        }).collect();
+        assert_eq!(encode(Some(PALETTE)), expected);
+    }
+
+    #[test]
+    fn test_encode_small_palettes() {
+        // The indices of small palettes fit into few bits, the last ones must not be lost
+        for colors in 1..5 {
+            for &(width, height) in [(1, 1), (3, 2), (17, 5), (64, 64)].iter() {
+                let image: RgbaImage = ImageBuffer::from_fn(width, height, |x, y| {
+                    let i = ((x * 7 + y * 3) % colors) as u8;
+                    Rgba([i * 60, 255 - i * 60, 0, 255])
+                });
+
+                let data = encode_frames(vec![animation::Frame::new(image.clone())], Repeat::Finite(0));
+                let frames: Vec<_> = Decoder::new(&data[..]).into_frames().unwrap().collect();
+                assert!(frames[0].buffer().clone().into_raw() == image.clone().into_raw(),
+                        "{} colors, {}x{}", colors, width, height);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_quantized() {
+        let image: RgbaImage = ImageBuffer::from_fn(32, 32, |x, y| {
+            Rgba([x as u8 * 8, y as u8 * 8, 128, 255])
+        });
+
+        let data = encode_frames(vec![
+            animation::Frame::new(image.clone()),
+        ], Repeat::Finite(3));
+
+        assert!(data.windows(15).any(|w| w == b"NETSCAPE2.0\x03\x01\x03\x00"));
+
+        let frames: Vec<_> = Decoder::new(&data[..]).into_frames().unwrap().collect();
+        let error: u32 = frames[0].buffer().pixels().zip(image.pixels())
+            .flat_map(|(a, b)| (0..4).map(move |c| (a[c] as i32 - b[c] as i32).abs() as u32))
+            .sum();
+
+        // The image has 1024 colors, they are approximated by 256
+        assert!(error / (32 * 32 * 4) < 8);
+    }
+
+    // Encodes a gradient with 32 shades of gray into a palette of 8 colors
+    fn encode_gradient(dither: bool) -> Vec<u8> {
+        let gradient: Vec<u8> = (0..32 * 4).map(|i| (i % 32) as u8 * 8).collect();
+
+        let mut data = Vec::new();
+        let mut encoder = Encoder::new(&mut data);
+        encoder.set_palette_size(8);
+        encoder.set_dither(dither);
+        encoder.write_image(&gradient, 32, 4, ColorType::Gray(8)).unwrap();
+
+        match Decoder::new(&data[..]).read_image().unwrap() {
+            DecodingResult::U8(pixels) => pixels,
+            _ => panic!("wrong sample type")
+        }
+    }
+
+    #[test]
+    fn test_palette_size() {
+        for &dither in [false, true].iter() {
+            let pixels = encode_gradient(dither);
+            assert_eq!(pixels.len(), 32 * 4 * 4);
+
+            let mut colors: Vec<&[u8]> = pixels.chunks(4).collect();
+            colors.sort();
+            colors.dedup();
+            assert!(colors.len() <= 8);
+        }
+
+        // Dithering mixes neighbouring colors
+        let (plain, dithered) = (encode_gradient(false), encode_gradient(true));
+        assert!(plain != dithered);
+        assert!(plain[..32 * 4].chunks(4).zip(plain[32 * 4..].chunks(4)).all(|(a, b)| a == b));
+    }
+}
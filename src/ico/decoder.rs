@@ -2,7 +2,8 @@ use byteorder::{ReadBytesExt, LittleEndian};
 use std::io::{Read, Seek, SeekFrom};
 
 use color::ColorType;
-use image::{DecodingResult, ImageResult, ImageDecoder, ImageError};
+use image::{DecodingResult, ImageResult, ImageDecoder, ImageError, ImageFormat, Limits, UnsupportedFeature};
+use image::load_rect_from_image;
 
 use bmp::BMPDecoder;
 use png::PNGDecoder;
@@ -139,6 +140,13 @@ impl DirEntry {
 }
 
 impl<R: Read + Seek> ImageDecoder for ICODecoder<R> {
+    fn set_limits(&mut self, limits: Limits) -> ImageResult<()> {
+        match self.inner_decoder {
+            BMP(ref mut decoder) => decoder.set_limits(limits),
+            PNG(ref mut decoder) => decoder.set_limits(limits)
+        }
+    }
+
     fn dimensions(&mut self) -> ImageResult<(u32, u32)> {
         match self.inner_decoder {
             BMP(ref mut decoder) => decoder.dimensions(),
@@ -167,6 +175,14 @@ impl<R: Read + Seek> ImageDecoder for ICODecoder<R> {
         }
     }
 
+    fn load_rect(&mut self, x: u32, y: u32, width: u32, height: u32) -> ImageResult<DecodingResult> {
+        match self.inner_decoder {
+            // The AND mask is applied to the whole image
+            BMP(_) => load_rect_from_image(self, x, y, width, height),
+            PNG(ref mut decoder) => decoder.load_rect(x, y, width, height)
+        }
+    }
+
     fn read_image(&mut self) -> ImageResult<DecodingResult> {
         match self.inner_decoder {
             PNG(ref mut decoder) => {
@@ -176,8 +192,9 @@ impl<R: Read + Seek> ImageDecoder for ICODecoder<R> {
                 let (width, height) = try!(decoder.dimensions());
 
                 // The ICO decoder needs an alpha chanel to apply the AND mask.
-                if try!(decoder.colortype()) != ColorType::RGBA(8) {
-                    return Err(ImageError::UnsupportedError("Unsupported color type".to_string()))
+                let color = try!(decoder.colortype());
+                if color != ColorType::RGBA(8) {
+                    return Err(ImageError::unsupported(ImageFormat::ICO, UnsupportedFeature::Color(color)))
                 }
                 let mut pixel_data = match try!(decoder.read_image()) {
                     DecodingResult::U8(v) => v,
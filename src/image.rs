@@ -16,15 +16,27 @@ use dynimage::decoder_to_image;
 #[derive(Debug)]
 pub enum ImageError {
     /// The Image is not formatted properly
+    ///
+    /// The decoders of PNG, GIF and WebP report ```MalformedImage``` instead.
+    /// The loading functions of this crate turn this error into
+    /// ```MalformedImage``` with ```with_format```, so it only reaches callers
+    /// that use a decoder directly.
     FormatError(String),
 
     /// The Image's dimensions are either too small or too large
     DimensionError,
 
     /// The Decoder does not support this image format
+    ///
+    /// The loading functions of this crate turn this error into
+    /// ```UnsupportedFeature``` with ```with_format```. Only an unknown file
+    /// extension is still reported with this error.
     UnsupportedError(String),
 
     /// The Decoder does not support this color type
+    ///
+    /// The loading functions of this crate turn this error into
+    /// ```UnsupportedFeature``` with ```with_format```.
     UnsupportedColor(ColorType),
 
     /// Not enough data was provided to the Decoder
@@ -35,7 +47,114 @@ pub enum ImageError {
     IoError(io::Error),
 
     /// The end of the image has been reached
-    ImageEnd
+    ImageEnd,
+
+    /// Decoding the image would exceed the limits set for the decoder
+    LimitsExceeded,
+
+    /// An image of ```format``` is not formatted properly
+    MalformedImage {
+        /// The format of the image
+        format: ImageFormat,
+        /// The position in the stream where the error was detected, if it is known
+        offset: Option<u64>,
+        /// A description of the error
+        message: String,
+    },
+
+    /// The decoder or encoder of ```format``` does not support a feature of the image
+    UnsupportedFeature {
+        /// The format of the image
+        format: ImageFormat,
+        /// The feature that is not supported
+        feature: UnsupportedFeature,
+        /// The position in the stream where the feature was found, if it is known
+        offset: Option<u64>,
+    },
+}
+
+/// A feature of an image that a decoder or encoder does not support
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum UnsupportedFeature {
+    /// A compression method, identified by the code the format uses for it.
+    /// For JPEG this is the start of frame marker.
+    Compression(u32),
+    /// A color type
+    Color(ColorType),
+    /// A number of bits per sample
+    BitDepth(u8),
+    /// A version of a header, identified by the code the format uses for it.
+    /// For BMP this is the size of the info header.
+    HeaderVersion(u32),
+    /// Any other feature
+    Other(String),
+}
+
+impl fmt::Display for UnsupportedFeature {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &UnsupportedFeature::Compression(c) => write!(fmt, "compression method {}", c),
+            &UnsupportedFeature::Color(ref c) => write!(fmt, "color type `{:?}`", c),
+            &UnsupportedFeature::BitDepth(n) => write!(fmt, "{} bits per sample", n),
+            &UnsupportedFeature::HeaderVersion(v) => write!(fmt, "header version {}", v),
+            &UnsupportedFeature::Other(ref f) => write!(fmt, "{}", f),
+        }
+    }
+}
+
+impl ImageError {
+    /// The error of a decoder or encoder of ```format``` that does not support ```feature```
+    pub fn unsupported(format: ImageFormat, feature: UnsupportedFeature) -> ImageError {
+        ImageError::UnsupportedFeature { format: format, feature: feature, offset: None }
+    }
+
+    /// The error of an image of ```format``` that is not formatted properly
+    pub fn malformed<S: Into<String>>(format: ImageFormat, message: S) -> ImageError {
+        ImageError::MalformedImage { format: format, offset: None, message: message.into() }
+    }
+
+    /// Records that the error was detected at byte ```offset``` of the stream
+    pub fn at(self, offset: u64) -> ImageError {
+        match self {
+            ImageError::MalformedImage { format, message, .. } =>
+                ImageError::MalformedImage { format: format, offset: Some(offset), message: message },
+            ImageError::UnsupportedFeature { format, feature, .. } =>
+                ImageError::UnsupportedFeature { format: format, feature: feature, offset: Some(offset) },
+            err => err
+        }
+    }
+
+    /// Records that the error occurred in an image of ```format```.
+    /// Errors that are described in words only become structured errors:
+    /// ```FormatError``` becomes ```MalformedImage``` and ```UnsupportedError```
+    /// and ```UnsupportedColor``` become ```UnsupportedFeature```. Code that
+    /// matches the old variants should match the new ones as well.
+    pub fn with_format(self, format: ImageFormat) -> ImageError {
+        match self {
+            ImageError::FormatError(message) => ImageError::malformed(format, message),
+            ImageError::UnsupportedError(f) => ImageError::unsupported(format, UnsupportedFeature::Other(f)),
+            ImageError::UnsupportedColor(c) => ImageError::unsupported(format, UnsupportedFeature::Color(c)),
+            err => err
+        }
+    }
+
+    /// The format of the image that caused the error, if it is known
+    pub fn format(&self) -> Option<ImageFormat> {
+        match self {
+            &ImageError::MalformedImage { format, .. } |
+            &ImageError::UnsupportedFeature { format, .. } => Some(format),
+            _ => None
+        }
+    }
+
+    /// The position in the stream where the error was detected, if it is known
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            &ImageError::MalformedImage { offset, .. } |
+            &ImageError::UnsupportedFeature { offset, .. } => offset,
+            _ => None
+        }
+    }
 }
 
 impl fmt::Display for ImageError {
@@ -51,7 +170,16 @@ impl fmt::Display for ImageError {
             &ImageError::NotEnoughData => write!(fmt, "Not enough data was provided to the \
                                                        Decoder to decode the image"),
             &ImageError::IoError(ref e) => e.fmt(fmt),
-            &ImageError::ImageEnd => write!(fmt, "The end of the image has been reached")
+            &ImageError::ImageEnd => write!(fmt, "The end of the image has been reached"),
+            &ImageError::LimitsExceeded => write!(fmt, "The image exceeds the limits set for the decoder"),
+            &ImageError::MalformedImage { format, offset, ref message } => match offset {
+                Some(offset) => write!(fmt, "{:?} format error at byte {}: {}", format, offset, message),
+                None => write!(fmt, "{:?} format error: {}", format, message),
+            },
+            &ImageError::UnsupportedFeature { format, offset, ref feature } => match offset {
+                Some(offset) => write!(fmt, "The {:?} codec does not support {} at byte {}", format, feature, offset),
+                None => write!(fmt, "The {:?} codec does not support {}", format, feature),
+            },
         }
     }
 }
@@ -65,7 +193,10 @@ impl Error for ImageError {
             ImageError::UnsupportedColor(..) => &"Unsupported color",
             ImageError::NotEnoughData => &"Not enough data",
             ImageError::IoError(..) => &"IO error",
-            ImageError::ImageEnd => &"Image end"
+            ImageError::ImageEnd => &"Image end",
+            ImageError::LimitsExceeded => &"Limits exceeded",
+            ImageError::MalformedImage { .. } => &"Format error",
+            ImageError::UnsupportedFeature { .. } => &"Unsupported feature",
         }
     }
 
@@ -144,6 +275,204 @@ pub enum ImageFormat {
     ICO
 }
 
+/// Format specific settings for encoding an image
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EncoderOptions {
+    /// A JPEG image
+    #[cfg(feature = "jpeg")]
+    JPEG {
+        /// The quality, from 1 to 100
+        quality: u8,
+        /// How the chroma components of color images are subsampled
+        subsampling: ::jpeg::Subsampling,
+    },
+
+    /// A PNG image
+    #[cfg(feature = "png_codec")]
+    PNG {
+        /// How hard the encoder tries to shrink the image
+        compression: ::png::CompressionType,
+        /// The filter applied to the rows of the image
+        filter: ::png::FilterType,
+    },
+
+    /// A GIF image
+    #[cfg(feature = "gif_codec")]
+    GIF {
+        /// The largest number of colors of the palette, from 2 to 256
+        palette_size: u16,
+        /// Dither images with more colors than the palette holds
+        dither: bool,
+    },
+
+    /// A WEBP image
+    #[cfg(feature = "webp")]
+    WEBP {
+        /// Compress the image without loss, ```quality``` is ignored then
+        lossless: bool,
+        /// The quality of a lossy image, from 1 to 100
+        quality: u8,
+    },
+}
+
+impl EncoderOptions {
+    /// The format of the image written with these options
+    pub fn format(&self) -> ImageFormat {
+        match *self {
+            #[cfg(feature = "jpeg")]
+            EncoderOptions::JPEG { .. } => ImageFormat::JPEG,
+            #[cfg(feature = "png_codec")]
+            EncoderOptions::PNG { .. } => ImageFormat::PNG,
+            #[cfg(feature = "gif_codec")]
+            EncoderOptions::GIF { .. } => ImageFormat::GIF,
+            #[cfg(feature = "webp")]
+            EncoderOptions::WEBP { .. } => ImageFormat::WEBP,
+        }
+    }
+}
+
+/// The metadata that accompanies the pixels of an image
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ImageMetadata {
+    /// The horizontal and vertical resolution in dots per inch
+    pub dpi: Option<(f64, f64)>,
+    /// Textual information as key/value pairs, e.g. the ```Author``` or a ```Comment```
+    pub text: Vec<(String, String)>,
+    /// The EXIF data, a TIFF structure without the ```Exif\0\0``` prefix of JPEG files
+    pub exif: Option<Vec<u8>>,
+    /// The ICC color profile
+    pub icc_profile: Option<Vec<u8>>,
+    /// The XMP packet
+    pub xmp: Option<Vec<u8>>,
+    /// How the image has to be turned to be displayed upright
+    pub orientation: Option<Orientation>,
+}
+
+/// The orientation of an image, as given by the EXIF and TIFF ```Orientation``` tag
+///
+/// The variants describe the transformation that turns the stored image upright.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Orientation {
+    /// The image is upright
+    Normal,
+    /// The image has to be flipped horizontally
+    FlipHorizontal,
+    /// The image has to be rotated by 180 degrees
+    Rotate180,
+    /// The image has to be flipped vertically
+    FlipVertical,
+    /// The image has to be mirrored along its main diagonal
+    Transpose,
+    /// The image has to be rotated by 90 degrees clockwise
+    Rotate90,
+    /// The image has to be mirrored along its anti-diagonal
+    Transverse,
+    /// The image has to be rotated by 270 degrees clockwise
+    Rotate270,
+}
+
+impl Orientation {
+    /// Converts the value of an ```Orientation``` tag, ```None``` if it is not in 1 to 8
+    pub fn from_exif(value: u16) -> Option<Orientation> {
+        match value {
+            1 => Some(Orientation::Normal),
+            2 => Some(Orientation::FlipHorizontal),
+            3 => Some(Orientation::Rotate180),
+            4 => Some(Orientation::FlipVertical),
+            5 => Some(Orientation::Transpose),
+            6 => Some(Orientation::Rotate90),
+            7 => Some(Orientation::Transverse),
+            8 => Some(Orientation::Rotate270),
+            _ => None
+        }
+    }
+
+    /// The value of the ```Orientation``` tag
+    pub fn to_exif(&self) -> u16 {
+        match *self {
+            Orientation::Normal => 1,
+            Orientation::FlipHorizontal => 2,
+            Orientation::Rotate180 => 3,
+            Orientation::FlipVertical => 4,
+            Orientation::Transpose => 5,
+            Orientation::Rotate90 => 6,
+            Orientation::Transverse => 7,
+            Orientation::Rotate270 => 8,
+        }
+    }
+}
+
+/// Reads the orientation from EXIF data
+#[cfg(feature = "tiff")]
+pub fn exif_orientation(exif: &[u8]) -> Option<Orientation> {
+    ::tiff::exif::Exif::parse(exif).ok().and_then(|exif| exif.orientation)
+}
+
+/// Reads the orientation from EXIF data, the parser needs the ```tiff``` feature
+#[cfg(not(feature = "tiff"))]
+pub fn exif_orientation(_: &[u8]) -> Option<Orientation> {
+    None
+}
+
+/// Limits on the resources a decoder may use
+///
+/// The default limits allow images of any size that need at most 512 MiB.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Limits {
+    /// The largest width of an image in pixels
+    pub max_image_width: Option<u32>,
+    /// The largest height of an image in pixels
+    pub max_image_height: Option<u32>,
+    /// The largest number of bytes the decoded image data may take
+    pub max_alloc: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_image_width: None,
+            max_image_height: None,
+            max_alloc: Some(512 * 1024 * 1024),
+        }
+    }
+}
+
+impl Limits {
+    /// Limits that allow images of any size
+    pub fn no_limits() -> Limits {
+        Limits {
+            max_image_width: None,
+            max_image_height: None,
+            max_alloc: None,
+        }
+    }
+
+    /// Checks whether an image with dimensions ```width``` and ```height```
+    /// and ```ColorType``` ```color``` may be decoded
+    pub fn check_image(&self, width: u32, height: u32, color: ColorType) -> ImageResult<()> {
+        if self.max_image_width.map_or(false, |max| width > max)
+            || self.max_image_height.map_or(false, |max| height > max) {
+            return Err(ImageError::LimitsExceeded)
+        }
+
+        let bytes = (width as u64 * color::bits_per_pixel(color) as u64 + 7) / 8 * height as u64;
+        self.clone().reserve(bytes)
+    }
+
+    /// Takes ```bytes``` from the allocations that are left
+    pub fn reserve(&mut self, bytes: u64) -> ImageResult<()> {
+        if let Some(max_alloc) = self.max_alloc {
+            if bytes > max_alloc {
+                return Err(ImageError::LimitsExceeded)
+            }
+
+            self.max_alloc = Some(max_alloc - bytes);
+        }
+
+        Ok(())
+    }
+}
+
 /// The trait that all decoders implement
 pub trait ImageDecoder: Sized {
     /// Returns a tuple containing the width and height of the image
@@ -161,6 +490,22 @@ pub trait ImageDecoder: Sized {
     /// Decodes the entire image and return it as a Vector
     fn read_image(&mut self) -> ImageResult<DecodingResult>;
 
+    /// Sets the limits on the resources the decoder may use
+    ///
+    /// Returns ```ImageError::LimitsExceeded``` if the image does not fit into them.
+    fn set_limits(&mut self, limits: Limits) -> ImageResult<()> {
+        let (width, height) = try!(self.dimensions());
+        let color = try!(self.colortype());
+        limits.check_image(width, height, color)
+    }
+
+    /// Returns the metadata of the image
+    ///
+    /// Decoders that do not read any metadata return an empty ```ImageMetadata```.
+    fn metadata(&mut self) -> ImageResult<ImageMetadata> {
+        Ok(ImageMetadata::default())
+    }
+
     /// Returns true if the image is animated
     fn is_animated(&mut self) -> ImageResult<bool> {
         // since most image formats do not support animation
@@ -177,45 +522,95 @@ pub trait ImageDecoder: Sized {
         ]))
     }
 
-    /// Decodes a specific region of the image, represented by the rectangle
-    /// starting from ```x``` and ```y``` and having ```length``` and ```width```
-    fn load_rect(&mut self, x: u32, y: u32, length: u32, width: u32) -> ImageResult<Vec<u8>> {
-        let (w, h) = try!(self.dimensions());
-
-        if length > h || width > w || x > w || y > h {
-            return Err(ImageError::DimensionError)
+    /// Decodes the region of the image that starts at ```x```, ```y```
+    /// and is ```width``` pixels wide and ```height``` pixels high
+    ///
+    /// Returns ```ImageError::DimensionError``` if the region is not within the image.
+    /// The default implementation reads the scanlines up to the last row of the region
+    /// and copies the region out of them, decoders without ```read_scanline``` override
+    /// it with ```load_rect_from_image```.
+    fn load_rect(&mut self, x: u32, y: u32, width: u32, height: u32) -> ImageResult<DecodingResult> {
+        let dimensions = try!(self.dimensions());
+        try!(check_rect(dimensions, x, y, width, height));
+        let color = try!(self.colortype());
+
+        let mut row = vec![0u8; try!(self.row_len())];
+        let mut data = Vec::with_capacity(row.len() * height as usize);
+        for i in 0..y + height {
+            let _ = try!(self.read_scanline(&mut row));
+            if i >= y {
+                data.extend_from_slice(&row);
+            }
         }
 
-        let c = try!(self.colortype());
-
-        let bpp = color::bits_per_pixel(c) / 8;
-
-        let rowlen  = try!(self.row_len());
+        crop_rect(DecodingResult::U8(data), color, dimensions.0, x, 0, width, height)
+    }
+}
 
-        let mut buf = vec![0u8; length as usize * width as usize * bpp];
-        let mut tmp = vec![0u8; rowlen];
+/// Decodes a region of the image like ```ImageDecoder::load_rect``` by decoding the whole image
+pub fn load_rect_from_image<D: ImageDecoder + ?Sized>(decoder: &mut D, x: u32, y: u32, width: u32, height: u32)
+-> ImageResult<DecodingResult> {
+    let dimensions = try!(decoder.dimensions());
+    try!(check_rect(dimensions, x, y, width, height));
+    let color = try!(decoder.colortype());
+    let image = try!(decoder.read_image());
+    crop_rect(image, color, dimensions.0, x, y, width, height)
+}
 
-        loop {
-            let row = try!(self.read_scanline(&mut tmp));
+/// Checks that the region at ```x```, ```y``` of ```width``` and ```height``` lies within the image
+pub fn check_rect(dimensions: (u32, u32), x: u32, y: u32, width: u32, height: u32) -> ImageResult<()> {
+    let (image_width, image_height) = dimensions;
+    if x as u64 + width as u64 > image_width as u64 || y as u64 + height as u64 > image_height as u64 {
+        return Err(ImageError::DimensionError)
+    }
+    Ok(())
+}
 
-            if row - 1 == y {
-                break
-            }
-        }
+/// Copies the region at ```x```, ```y``` of ```width``` and ```height``` out of ```image```
+///
+/// ```image``` holds consecutive rows of an image that is ```image_width``` pixels wide,
+/// ```y``` counts from its first row.
+pub fn crop_rect(image: DecodingResult, color: ColorType, image_width: u32,
+                 x: u32, y: u32, width: u32, height: u32) -> ImageResult<DecodingResult> {
+    let bits = color::bits_per_pixel(color);
+    let samples = color::num_components(color);
+    if bits % (samples * 8) != 0 {
+        return Err(ImageError::UnsupportedColor(color))
+    }
 
-        for i in 0..length as usize {
-            {
-                let from = &tmp[x as usize * bpp..width as usize * bpp];
+    Ok(match image {
+        DecodingResult::U8(data) => DecodingResult::U8(try!(crop(&data, samples, image_width, x, y, width, height))),
+        DecodingResult::U16(data) => DecodingResult::U16(try!(crop(&data, samples, image_width, x, y, width, height))),
+    })
+}
 
-                let to   = &mut buf[i * width as usize * bpp..width as usize * bpp];
+fn crop<T: Copy>(data: &[T], samples: usize, image_width: u32,
+                 x: u32, y: u32, width: u32, height: u32) -> ImageResult<Vec<T>> {
+    let row_len = image_width as usize * samples;
+    if data.len() < (y as usize + height as usize) * row_len {
+        return Err(ImageError::NotEnoughData)
+    }
 
-                ::copy_memory(from, to);
-            }
+    let mut rect = Vec::with_capacity(width as usize * height as usize * samples);
+    for row in data[y as usize * row_len..].chunks(row_len).take(height as usize) {
+        rect.extend_from_slice(&row[x as usize * samples..(x as usize + width as usize) * samples]);
+    }
+    Ok(rect)
+}
 
-            let _ = try!(self.read_scanline(&mut tmp));
-        }
+/// The trait that all encoders implement
+pub trait ImageEncoder: Sized {
+    /// Encodes the image ```buf``` that has dimensions ```width``` and ```height```
+    /// and ```ColorType``` ```color```
+    fn write_image(self, buf: &[u8], width: u32, height: u32, color: ColorType) -> ImageResult<()>;
 
-        Ok(buf)
+    /// Encodes the image ```buf``` like ```write_image``` and stores ```metadata``` along with it
+    ///
+    /// Encoders store as much of the metadata as their format can hold and ignore the rest.
+    fn write_image_with_metadata(self, buf: &[u8], width: u32, height: u32, color: ColorType,
+                                 metadata: &ImageMetadata) -> ImageResult<()> {
+        let _ = metadata;
+        self.write_image(buf, width, height, color)
     }
 }
 
@@ -570,4 +965,87 @@ mod tests {
         assert!(!target.in_bounds(0,2));
         assert!(!target.in_bounds(2,2));
     }
+
+    #[test]
+    fn test_crop_rect() {
+        use color::ColorType;
+        use super::{check_rect, crop_rect, DecodingResult, ImageError};
+
+        // A 3x2 gray image and a region in its lower right corner
+        assert!(check_rect((3, 2), 1, 1, 2, 1).is_ok());
+        assert!(check_rect((3, 2), 0, 0, 3, 2).is_ok());
+        assert!(check_rect((3, 2), 3, 2, 0, 0).is_ok());
+        assert!(match check_rect((3, 2), 2, 0, 2, 1) { Err(ImageError::DimensionError) => true, _ => false });
+        assert!(match check_rect((3, 2), 0, 1, 1, 2) { Err(ImageError::DimensionError) => true, _ => false });
+        assert!(check_rect((3, 2), 1, 0, u32::max_value(), 1).is_err());
+
+        match crop_rect(DecodingResult::U8(vec![0, 1, 2, 3, 4, 5]), ColorType::Gray(8), 3, 1, 1, 2, 1).unwrap() {
+            DecodingResult::U8(data) => assert_eq!(data, vec![4, 5]),
+            DecodingResult::U16(_) => panic!("wrong sample type")
+        }
+        match crop_rect(DecodingResult::U16(vec![0, 1, 2, 3, 4, 5]), ColorType::GrayA(16), 3, 0, 0, 1, 1).unwrap() {
+            DecodingResult::U16(data) => assert_eq!(data, vec![0, 1]),
+            DecodingResult::U8(_) => panic!("wrong sample type")
+        }
+    }
+
+    #[cfg(all(feature = "bmp", feature = "png_codec", feature = "gif_codec",
+              feature = "jpeg", feature = "tga", feature = "webp"))]
+    #[test]
+    fn test_load_rect() {
+        use std::fs::File;
+        use std::io::BufReader;
+        use bmp::BMPDecoder;
+        use png::PNGDecoder;
+        use gif::Decoder as GIFDecoder;
+        use jpeg::{JPEGDecoder, JPEGEncoder};
+        use tga::TGADecoder;
+        use webp::WebpDecoder;
+        use super::{DecodingResult, ImageDecoder};
+
+        fn check<D: ImageDecoder, F: Fn() -> D>(new_decoder: F) {
+            let mut decoder = new_decoder();
+            let (width, height) = decoder.dimensions().unwrap();
+            let samples = ::color::num_components(decoder.colortype().unwrap());
+            let image = match decoder.read_image().unwrap() {
+                DecodingResult::U8(data) => data,
+                DecodingResult::U16(_) => panic!("wrong sample type")
+            };
+
+            for &(x, y, w, h) in [(0, 0, width, height), (1, 2, 3, 4), (width - 1, height - 1, 1, 1), (2, 5, 0, 0)].iter() {
+                let expected: Vec<u8> = image.chunks(width as usize * samples).skip(y as usize).take(h as usize)
+                    .flat_map(|row| row[x as usize * samples..(x + w) as usize * samples].to_vec())
+                    .collect();
+                match new_decoder().load_rect(x, y, w, h).unwrap() {
+                    DecodingResult::U8(data) => assert_eq!(data, expected),
+                    DecodingResult::U16(_) => panic!("wrong sample type")
+                }
+            }
+            assert!(new_decoder().load_rect(1, 0, width, 1).is_err());
+        }
+
+        for name in ["Info_8_Bit.bmp", "Info_8_Bit_Top_Down.bmp", "V5_24_Bit.bmp"].iter() {
+            let path = format!("tests/images/bmp/images/{}", name);
+            check(|| BMPDecoder::new(File::open(&path).unwrap()));
+        }
+        for path in ["tests/images/png/transparency/tp0n2c08.png",
+                     "tests/images/png/interlaced/lenna_fragment_interlaced.png"].iter() {
+            check(|| PNGDecoder::new(BufReader::new(File::open(path).unwrap())));
+        }
+
+        // These decoders read scanlines
+        check(|| GIFDecoder::new(BufReader::new(File::open("tests/images/gif/simple/sample_1.gif").unwrap())));
+        for path in ["tests/images/webp/lossy/lenna_normal.webp",
+                     "tests/images/webp/extended/lenna_alpha_lossless.webp"].iter() {
+            check(|| WebpDecoder::new(BufReader::new(File::open(path).unwrap())));
+        }
+        check(|| TGADecoder::new(BufReader::new(File::open("tests/images/tga/testsuite/ctc24.tga").unwrap())));
+
+        // A sequential JPEG image is cut below the region, progressive images are not
+        let pixels: Vec<u8> = (0..80 * 120 * 3).map(|i| (i * 7 % 251) as u8).collect();
+        let mut jpeg = Vec::new();
+        JPEGEncoder::new(&mut jpeg).encode(&pixels, 80, 120, ::color::ColorType::RGB(8)).unwrap();
+        check(|| JPEGDecoder::new(&jpeg[..]));
+        check(|| JPEGDecoder::new(BufReader::new(File::open("tests/images/jpg/progressive/cat.jpg").unwrap())));
+    }
 }
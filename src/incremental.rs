@@ -0,0 +1,543 @@
+//! Decoding of images whose data arrives in parts
+//!
+//! The data is pushed into an ```IncrementalDecoder``` as it is received,
+//! the decoder reports what it could decode so far or that it needs more data.
+
+use std::cell::RefCell;
+use std::io::{self, Cursor};
+#[cfg(feature = "png_codec")]
+use std::io::Read;
+use std::rc::Rc;
+
+#[cfg(feature = "png_codec")]
+use color;
+use color::ColorType;
+use image::{DecodingResult, ImageDecoder, ImageError, ImageFormat, ImageResult, Limits};
+
+#[cfg(feature = "png_codec")]
+use png;
+#[cfg(feature = "gif_codec")]
+use gif;
+#[cfg(feature = "jpeg")]
+use jpeg;
+#[cfg(feature = "webp")]
+use webp;
+#[cfg(feature = "tiff")]
+use tiff;
+#[cfg(feature = "tga")]
+use tga;
+#[cfg(feature = "bmp")]
+use bmp;
+#[cfg(feature = "ico")]
+use ico;
+
+/// The result of decoding the data received so far
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Progress<T> {
+    /// The value could be decoded
+    Ready(T),
+    /// The data received so far does not hold the value
+    NeedMoreData,
+}
+
+/// Consecutive rows of an image
+pub struct Rows {
+    /// The index of the first row
+    pub first_row: u32,
+    /// The number of rows
+    pub count: u32,
+    /// The samples of the rows
+    pub data: DecodingResult,
+}
+
+/// A decoder that is fed the data of an image in parts
+///
+/// Rows of PNG images without interlacing are returned as soon as they arrive,
+/// the decoder keeps its state between the parts.
+/// The rows of other images are returned once the whole image has arrived.
+/// Their data is decoded from its start again until then, which is retried
+/// each time the amount of data has doubled. The same holds for the header,
+/// except for PNG and WebP images whose header is read from the chunks received so far.
+///
+/// Data that ends early is reported as ```Progress::NeedMoreData``` until ```finish``` is called,
+/// all other errors are reported at once.
+pub struct IncrementalDecoder {
+    format: ImageFormat,
+    limits: Limits,
+    data: Rc<RefCell<Vec<u8>>>,
+    finished: bool,
+    header: Option<(u32, u32, ColorType)>,
+    next_row: u32,
+    // The amount of data when decoding the whole image ran out of data the last time
+    attempted: usize,
+    // The position of the next chunk in front of the image data of a PNG image
+    #[cfg(feature = "png_codec")]
+    chunk: usize,
+    #[cfg(feature = "png_codec")]
+    animated: bool,
+    #[cfg(feature = "png_codec")]
+    rows: Option<png::PNGRowReader<Received>>,
+}
+
+impl IncrementalDecoder {
+    /// Create a new decoder for an image of ```format```
+    ///
+    /// The decoder uses the default ```Limits```.
+    pub fn new(format: ImageFormat) -> IncrementalDecoder {
+        IncrementalDecoder {
+            format: format,
+            limits: Limits::default(),
+            data: Rc::new(RefCell::new(Vec::new())),
+            finished: false,
+            header: None,
+            next_row: 0,
+            attempted: 0,
+            #[cfg(feature = "png_codec")]
+            chunk: 0,
+            #[cfg(feature = "png_codec")]
+            animated: false,
+            #[cfg(feature = "png_codec")]
+            rows: None,
+        }
+    }
+
+    /// Sets the limits on the resources the decoder may use
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Appends ```data``` to the data received so far
+    pub fn push(&mut self, data: &[u8]) {
+        self.data.borrow_mut().extend_from_slice(data);
+    }
+
+    /// Marks the data received so far as complete
+    ///
+    /// Missing data is reported as an error from then on.
+    pub fn finish(&mut self) {
+        self.finished = true;
+    }
+
+    /// Returns the dimensions and the color type of the image
+    ///
+    /// Returns ```ImageError::LimitsExceeded``` as soon as the header shows
+    /// that the image does not fit into the limits.
+    pub fn header(&mut self) -> ImageResult<Progress<(u32, u32, ColorType)>> {
+        if let Some(header) = self.header {
+            return Ok(Progress::Ready(header))
+        }
+
+        // The PNG decoder needs the chunks in front of the image data
+        #[cfg(feature = "png_codec")]
+        {
+            if self.format == ImageFormat::PNG {
+                let result = self.skip_png_chunks(b"IDAT");
+                if let Progress::NeedMoreData = try!(self.progress(result)) {
+                    return Ok(Progress::NeedMoreData)
+                }
+            }
+        }
+
+        // The header of a WebP image is read from the chunks received so far
+        #[cfg(feature = "webp")]
+        {
+            if self.format == ImageFormat::WEBP {
+                let result = webp::read_header_prefix(&self.data.borrow()).and_then(|(width, height, color)| {
+                    try!(self.limits.check_image(width, height, color));
+                    Ok((width, height, color))
+                });
+                if let Progress::Ready(header) = try!(self.progress(result)) {
+                    self.header = Some(header);
+                    return Ok(Progress::Ready(header))
+                }
+                return Ok(Progress::NeedMoreData)
+            }
+        }
+
+        if !self.retry() {
+            return Ok(Progress::NeedMoreData)
+        }
+
+        let result = decode(&self.data.borrow(), self.format, self.limits, false);
+        let header = match try!(self.progress(result)) {
+            Progress::Ready((header, _)) => header,
+            Progress::NeedMoreData => {
+                self.attempted = self.data.borrow().len();
+                return Ok(Progress::NeedMoreData)
+            }
+        };
+
+        #[cfg(feature = "png_codec")]
+        try!(self.start_png_rows());
+
+        self.header = Some(header);
+        Ok(Progress::Ready(header))
+    }
+
+    /// Returns the rows that were decoded since the last call
+    ///
+    /// Returns ```ImageError::ImageEnd``` once all rows were returned.
+    pub fn read_rows(&mut self) -> ImageResult<Progress<Rows>> {
+        if let Progress::NeedMoreData = try!(self.header()) {
+            return Ok(Progress::NeedMoreData)
+        }
+        let (_, height, _) = self.header.unwrap();
+        if self.next_row >= height {
+            return Err(ImageError::ImageEnd)
+        }
+
+        #[cfg(feature = "png_codec")]
+        {
+            if self.rows.is_some() {
+                return self.read_png_rows()
+            }
+            // Animations and interlaced images are decoded once their IEND chunk arrived
+            if self.format == ImageFormat::PNG {
+                let result = self.skip_png_chunks(b"IEND");
+                if let Progress::NeedMoreData = try!(self.progress(result)) {
+                    return Ok(Progress::NeedMoreData)
+                }
+            }
+        }
+
+        if !self.retry() {
+            return Ok(Progress::NeedMoreData)
+        }
+
+        let result = decode(&self.data.borrow(), self.format, self.limits, true);
+        match try!(self.progress(result)) {
+            Progress::Ready((_, Some(data))) => {
+                self.next_row = height;
+                Ok(Progress::Ready(Rows { first_row: 0, count: height, data: data }))
+            }
+            _ => {
+                self.attempted = self.data.borrow().len();
+                Ok(Progress::NeedMoreData)
+            }
+        }
+    }
+
+    // Whether decoding from the start is worth another try.
+    // The amount of data at least doubles between tries that run out of data,
+    // so together they take time linear in the size of the data.
+    fn retry(&self) -> bool {
+        self.finished || self.data.borrow().len() >= 2 * self.attempted
+    }
+
+    // Turns a lack of data into a request for more data unless the data is complete
+    fn progress<T>(&self, result: ImageResult<T>) -> ImageResult<Progress<T>> {
+        match result {
+            Ok(value) => Ok(Progress::Ready(value)),
+            Err(ref err) if !self.finished && is_truncated(err) => Ok(Progress::NeedMoreData),
+            Err(err) => Err(err.with_format(self.format))
+        }
+    }
+
+    // Skips the chunks of a PNG image in front of the first chunk of type ```kind```,
+    // returns ```ImageError::ImageEnd``` until that chunk arrived
+    #[cfg(feature = "png_codec")]
+    fn skip_png_chunks(&mut self, kind: &[u8]) -> ImageResult<()> {
+        let data = self.data.borrow();
+        if self.chunk == 0 {
+            if data.len() < 8 {
+                return Err(ImageError::ImageEnd)
+            }
+            if &data[..8] != b"\x89PNG\r\n\x1a\n" {
+                return Err(ImageError::malformed(ImageFormat::PNG, "invalid signature").at(0))
+            }
+            self.chunk = 8;
+        }
+
+        // The IEND chunk is empty, its CRC follows its type
+        let needed = if kind == b"IEND" { 12 } else { 8 };
+        loop {
+            if data.len() < self.chunk + needed {
+                return Err(ImageError::ImageEnd)
+            }
+
+            // The length is a big endian integer that need not be aligned
+            let length = data[self.chunk..self.chunk + 4].iter().fold(0, |n, &b| n << 8 | b as usize);
+            match &data[self.chunk + 4..self.chunk + 8] {
+                chunk if chunk == kind => return Ok(()),
+                b"acTL" => self.animated = true,
+                _ => ()
+            }
+            self.chunk += length + 12;
+        }
+    }
+
+    // Reads the rows of PNG images without interlacing as they arrive,
+    // animations and interlaced images are decoded once they are complete
+    #[cfg(feature = "png_codec")]
+    fn start_png_rows(&mut self) -> ImageResult<()> {
+        // The interlace method is the last byte of the IHDR chunk
+        let interlaced = self.data.borrow().get(28) != Some(&0);
+        if self.format == ImageFormat::PNG && !self.animated && !interlaced {
+            let received = Received { data: self.data.clone(), pos: 0 };
+            self.rows = Some(try!(png::PNGRowReader::new(received)));
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "png_codec")]
+    fn read_png_rows(&mut self) -> ImageResult<Progress<Rows>> {
+        let mut data = Vec::new();
+        let mut count = 0;
+        let mut error = None;
+
+        {
+            let rows = self.rows.as_mut().unwrap();
+            loop {
+                match rows.next_row() {
+                    Ok(Some(row)) => {
+                        data.extend_from_slice(row);
+                        count += 1;
+                    }
+                    Ok(None) => break,
+                    Err(err) => {
+                        error = Some(err);
+                        break
+                    }
+                }
+            }
+        }
+
+        match error {
+            // The rows decoded before the data ran out are returned first
+            Some(ref err) if count > 0 && is_truncated(err) => (),
+            Some(err) => return self.progress(Err(err)),
+            None => if count == 0 {
+                return Err(ImageError::malformed(ImageFormat::PNG, "image data ends before the last row"))
+            }
+        }
+
+        let (_, _, color) = self.header.unwrap();
+        let data = if color::bits_per_pixel(color) / color::num_components(color) == 16 {
+            // Rows hold the samples in network byte order
+            DecodingResult::U16(data.chunks(2).map(|v| (v[0] as u16) << 8 | v[1] as u16).collect())
+        } else {
+            DecodingResult::U8(data)
+        };
+
+        let rows = Rows { first_row: self.next_row, count: count, data: data };
+        self.next_row += count;
+        Ok(Progress::Ready(rows))
+    }
+}
+
+// Whether ```err``` is caused by data that ends early
+fn is_truncated(err: &ImageError) -> bool {
+    match *err {
+        ImageError::ImageEnd | ImageError::NotEnoughData => true,
+        ImageError::IoError(ref err) => err.kind() == io::ErrorKind::UnexpectedEof,
+        _ => false
+    }
+}
+
+// Reads the data received so far, its end is an unexpected end of the file
+#[cfg(feature = "png_codec")]
+struct Received {
+    data: Rc<RefCell<Vec<u8>>>,
+    pos: usize,
+}
+
+#[cfg(feature = "png_codec")]
+impl Read for Received {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.borrow();
+        if self.pos >= data.len() && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "more data is needed"))
+        }
+
+        let read = try!((&data[self.pos..]).read(buf));
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+// Decodes the header of the image in ```data``` and the whole image if ```image``` is set
+fn decode(data: &[u8], format: ImageFormat, limits: Limits, image: bool)
+-> ImageResult<((u32, u32, ColorType), Option<DecodingResult>)> {
+    let r = Cursor::new(data);
+    match format {
+        #[cfg(feature = "png_codec")]
+        ImageFormat::PNG => decode_image(png::PNGDecoder::new(r), limits, image),
+        #[cfg(feature = "gif_codec")]
+        ImageFormat::GIF => decode_image(gif::Decoder::new(r), limits, image),
+        #[cfg(feature = "jpeg")]
+        ImageFormat::JPEG => decode_image(jpeg::JPEGDecoder::new(r), limits, image),
+        #[cfg(feature = "webp")]
+        ImageFormat::WEBP => decode_image(webp::WebpDecoder::new(r), limits, image),
+        #[cfg(feature = "tiff")]
+        ImageFormat::TIFF => decode_image(try!(tiff::TIFFDecoder::new(r)), limits, image),
+        #[cfg(feature = "tga")]
+        ImageFormat::TGA => decode_image(tga::TGADecoder::new(r), limits, image),
+        #[cfg(feature = "bmp")]
+        ImageFormat::BMP => decode_image(bmp::BMPDecoder::new(r), limits, image),
+        #[cfg(feature = "ico")]
+        ImageFormat::ICO => decode_image(try!(ico::ICODecoder::new(r)), limits, image),
+        _ => Err(ImageError::UnsupportedError(format!("A decoder for {:?} is not available.", format))),
+    }
+}
+
+fn decode_image<I: ImageDecoder>(mut decoder: I, limits: Limits, image: bool)
+-> ImageResult<((u32, u32, ColorType), Option<DecodingResult>)> {
+    try!(decoder.set_limits(limits));
+    let (width, height) = try!(decoder.dimensions());
+    let color = try!(decoder.colortype());
+
+    let data = if image { Some(try!(decoder.read_image())) } else { None };
+    Ok(((width, height, color), data))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Read;
+
+    use image::{DecodingResult, GenericImage, ImageError, ImageFormat, Limits};
+    use super::{IncrementalDecoder, Progress};
+
+    fn read_file(path: &str) -> Vec<u8> {
+        let mut data = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    // Pushes ```data``` in parts of ```part``` bytes,
+    // returns the pixels and the number of times rows were returned
+    fn decode_in_parts(data: &[u8], format: ImageFormat, part: usize) -> (Vec<u8>, usize) {
+        let mut decoder = IncrementalDecoder::new(format);
+        let mut parts = data.chunks(part);
+        let mut pixels = Vec::new();
+        let mut next_row = 0;
+        let mut updates = 0;
+
+        loop {
+            match decoder.read_rows() {
+                Ok(Progress::Ready(rows)) => {
+                    assert_eq!(rows.first_row, next_row);
+                    next_row += rows.count;
+                    updates += 1;
+                    match rows.data {
+                        DecodingResult::U8(data) => pixels.extend_from_slice(&data),
+                        DecodingResult::U16(_) => panic!("wrong sample type")
+                    }
+                }
+                Ok(Progress::NeedMoreData) => match parts.next() {
+                    Some(part) => decoder.push(part),
+                    None => decoder.finish()
+                },
+                Err(ImageError::ImageEnd) => return (pixels, updates),
+                Err(err) => panic!("{}", err)
+            }
+        }
+    }
+
+    #[cfg(feature = "png_codec")]
+    #[test]
+    fn test_png_rows() {
+        let path = "tests/images/png/transparency/tp0n2c08.png";
+        let data = read_file(path);
+        let image = ::open(path).unwrap();
+
+        // The header is complete before the image data
+        let mut decoder = IncrementalDecoder::new(ImageFormat::PNG);
+        let mut received = 0;
+        while decoder.header().unwrap() == Progress::NeedMoreData {
+            decoder.push(&data[received..received + 10]);
+            received += 10;
+        }
+        assert!(received < data.len() / 2);
+        assert!(decoder.header().unwrap() == Progress::Ready((32, 32, image.color())));
+
+        let (pixels, _) = decode_in_parts(&data, ImageFormat::PNG, 100);
+        assert_eq!(pixels, image.raw_pixels());
+
+        // Palettes, transparency and gray levels below 8 bits are expanded like ```open``` does
+        for name in &["tbbn0g04", "tm3n3p02", "tp1n3p08", "tbrn2c08", "tp0n0g08"] {
+            let path = format!("tests/images/png/transparency/{}.png", name);
+            let (pixels, _) = decode_in_parts(&read_file(&path), ImageFormat::PNG, 16);
+            assert_eq!(pixels, ::open(&path).unwrap().raw_pixels());
+        }
+
+        // Interlaced images are returned at once
+        let path = "tests/images/png/interlaced/lenna_fragment_interlaced.png";
+        let (pixels, updates) = decode_in_parts(&read_file(path), ImageFormat::PNG, 1000);
+        assert_eq!(pixels, ::open(path).unwrap().raw_pixels());
+        assert_eq!(updates, 1);
+    }
+
+    #[cfg(feature = "png_codec")]
+    #[test]
+    fn test_malformed() {
+        let mut data = read_file("tests/images/png/transparency/tp0n2c08.png");
+
+        // A wrong signature is reported before the rest of the file arrives
+        let mut decoder = IncrementalDecoder::new(ImageFormat::PNG);
+        decoder.push(b"\x89PNG\r\n\x1a\0");
+        assert!(match decoder.header() { Err(ImageError::MalformedImage { .. }) => true, _ => false });
+
+        // So is a damaged chunk of image data
+        let len = data.len();
+        data[len - 20] ^= 0xff;
+        let mut decoder = IncrementalDecoder::new(ImageFormat::PNG);
+        decoder.push(&data[..len - 10]);
+        assert!(match decoder.read_rows() { Err(ImageError::MalformedImage { .. }) => true, _ => false });
+    }
+
+    #[cfg(feature = "bmp")]
+    #[test]
+    fn test_bmp_rows() {
+        let path = "tests/images/bmp/images/Info_8_Bit.bmp";
+        let (pixels, updates) = decode_in_parts(&read_file(path), ImageFormat::BMP, 256);
+        assert_eq!(pixels, ::open(path).unwrap().raw_pixels());
+        assert_eq!(updates, 1);
+    }
+
+    #[cfg(feature = "webp")]
+    #[test]
+    fn test_webp_header() {
+        for path in &["tests/images/webp/lossy/lenna_normal.webp",
+                      "tests/images/webp/lossless/lenna.webp",
+                      "tests/images/webp/extended/lenna_alpha_lossless.webp"] {
+            let data = read_file(path);
+            let image = ::open(path).unwrap();
+
+            // The header is complete before the image data
+            let mut decoder = IncrementalDecoder::new(ImageFormat::WEBP);
+            let mut received = 0;
+            while decoder.header().unwrap() == Progress::NeedMoreData {
+                decoder.push(&data[received..received + 10]);
+                received += 10;
+            }
+            assert!(received < data.len() / 2);
+            assert!(decoder.header().unwrap() == Progress::Ready((image.width(), image.height(), image.color())));
+
+            let (pixels, updates) = decode_in_parts(&data, ImageFormat::WEBP, 100);
+            assert_eq!(pixels, image.raw_pixels());
+            assert_eq!(updates, 1);
+        }
+    }
+
+    #[cfg(feature = "tga")]
+    #[test]
+    fn test_limits() {
+        // The header of a 65535x65535 image is rejected before its pixels arrive
+        let header = [0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 24, 0];
+        let mut decoder = IncrementalDecoder::new(ImageFormat::TGA);
+        decoder.push(&header[..9]);
+        assert!(decoder.header().unwrap() == Progress::NeedMoreData);
+        decoder.push(&header[9..]);
+        assert!(match decoder.header() { Err(ImageError::LimitsExceeded) => true, _ => false });
+
+        // Missing data is an error once the data is complete
+        let mut decoder = IncrementalDecoder::new(ImageFormat::TGA);
+        decoder.set_limits(Limits::no_limits());
+        decoder.push(&[0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 0, 4, 0, 24, 0]);
+        decoder.push(&[0; 20]);
+        assert!(match decoder.read_rows() { Ok(Progress::NeedMoreData) => true, _ => false });
+        decoder.finish();
+        assert!(decoder.read_rows().is_err());
+    }
+}
@@ -1,73 +1,277 @@
 extern crate jpeg_decoder;
 
-use std::io::Read;
+use std::cmp;
+use std::io::{self, Read};
 
 use color::{self, ColorType};
-use image::{DecodingResult, ImageDecoder, ImageError, ImageResult};
+use image::{DecodingResult, ImageDecoder, ImageError, ImageMetadata, ImageResult, ImageFormat, UnsupportedFeature};
+use image::{check_rect, crop_rect, exif_orientation};
+
+// The segments that have been read already followed by the rest of the stream
+type Stream<R> = io::Chain<io::Cursor<Vec<u8>>, R>;
+
+enum Either<T, U> {
+    Left(T),
+    Right(U)
+}
 
 /// JPEG decoder
+///
+/// ```load_rect``` stops after the rows of the region of sequential images
+/// with a single scan and without restart intervals, it decodes the whole
+/// image otherwise.
 pub struct JPEGDecoder<R> {
-    decoder: jpeg_decoder::Decoder<R>,
-    metadata: Option<jpeg_decoder::ImageInfo>,
+    decoder: Option<Either<R, jpeg_decoder::Decoder<Stream<R>>>>,
+    // The segments in front of the entropy coded data, until the jpeg decoder is created
+    head: Option<Vec<u8>>,
+    info: Option<jpeg_decoder::ImageInfo>,
+    metadata: ImageMetadata,
 }
 
 impl<R: Read> JPEGDecoder<R> {
     /// Create a new decoder that decodes from the stream ```r```
     pub fn new(r: R) -> JPEGDecoder<R> {
         JPEGDecoder {
-            decoder: jpeg_decoder::Decoder::new(r),
-            metadata: None,
+            decoder: Some(Either::Left(r)),
+            head: None,
+            info: None,
+            metadata: ImageMetadata::default(),
+        }
+    }
+
+    // Reads the segments in front of the entropy coded data and the metadata in them
+    fn read_head(&mut self) -> ImageResult<()> {
+        if let Some(Either::Left(ref mut r)) = self.decoder {
+            if self.head.is_none() {
+                let mut head = Vec::new();
+                try!(read_segments(r, &mut head, &mut self.metadata));
+                self.head = Some(head);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Converts the stream to a decoder
+    fn get_decoder(&mut self) -> ImageResult<&mut jpeg_decoder::Decoder<Stream<R>>> {
+        if let Some(Either::Left(_)) = self.decoder {
+            try!(self.read_head());
+
+            let r = match self.decoder.take() {
+                Some(Either::Left(r)) => r,
+                _ => unreachable!()
+            };
+
+            let head = self.head.take().unwrap_or_else(Vec::new);
+            self.decoder = Some(Either::Right(jpeg_decoder::Decoder::new(io::Cursor::new(head).chain(r))));
+        }
+
+        match self.decoder {
+            Some(Either::Right(ref mut decoder)) => Ok(decoder),
+            _ => unreachable!()
         }
     }
 
-    fn metadata(&mut self) -> ImageResult<jpeg_decoder::ImageInfo> {
-        match self.metadata {
-            Some(metadata) => Ok(metadata),
+    fn info(&mut self) -> ImageResult<jpeg_decoder::ImageInfo> {
+        match self.info {
+            Some(info) => Ok(info),
             None => {
-                try!(self.decoder.read_info());
-                let mut metadata = self.decoder.info().unwrap();
+                // The frame header is read from the head if it holds it,
+                // so that the frame can still be cut by ```load_rect```
+                try!(self.read_head());
+                let info = self.head.as_ref().and_then(|head| {
+                    let mut decoder = jpeg_decoder::Decoder::new(&head[..]);
+                    decoder.read_info().ok().and_then(|_| decoder.info())
+                });
+
+                let mut info = match info {
+                    Some(info) => info,
+                    None => {
+                        let decoder = try!(self.get_decoder());
+                        try!(decoder.read_info());
+                        decoder.info().unwrap()
+                    }
+                };
 
                 // We convert CMYK data to RGB before returning it to the user.
-                if metadata.pixel_format == jpeg_decoder::PixelFormat::CMYK32 {
-                    metadata.pixel_format = jpeg_decoder::PixelFormat::RGB24;
+                if info.pixel_format == jpeg_decoder::PixelFormat::CMYK32 {
+                    info.pixel_format = jpeg_decoder::PixelFormat::RGB24;
                 }
 
-                self.metadata = Some(metadata);
-                Ok(metadata)
+                self.info = Some(info);
+                Ok(info)
             },
         }
     }
 }
 
+// Reads the segments in front of the entropy coded data of the first scan into
+// ```head``` and collects the metadata in them. The jpeg decoder reports malformed files.
+fn read_segments<R: Read>(r: &mut R, head: &mut Vec<u8>, metadata: &mut ImageMetadata) -> io::Result<()> {
+    let mut icc_chunks = Vec::new();
+
+    if try!(r.by_ref().take(2).read_to_end(head)) < 2 || head[..] != [0xFF, 0xD8] {
+        return Ok(())
+    }
+
+    loop {
+        let start = head.len();
+        if try!(r.by_ref().take(4).read_to_end(head)) < 4 || head[start] != 0xFF {
+            break
+        }
+
+        let marker = head[start + 1];
+        if marker == 0xD9 {
+            break
+        }
+
+        let length = (head[start + 2] as u64) << 8 | head[start + 3] as u64;
+        let data_start = head.len();
+        if length < 2 || try!(r.by_ref().take(length - 2).read_to_end(head)) < length as usize - 2 {
+            break
+        }
+
+        let data = &head[data_start..];
+        match marker {
+            // JFIF stores the units followed by the horizontal and vertical density
+            0xE0 if data.len() >= 12 && data.starts_with(b"JFIF\0") => {
+                let x = (data[8] as f64) * 256.0 + data[9] as f64;
+                let y = (data[10] as f64) * 256.0 + data[11] as f64;
+                metadata.dpi = match data[7] {
+                    1 => Some((x, y)),
+                    2 => Some((x * 2.54, y * 2.54)),
+                    _ => metadata.dpi
+                };
+            }
+            0xE1 if data.starts_with(EXIF_HEADER) => {
+                metadata.exif = Some(data[EXIF_HEADER.len()..].to_vec());
+                metadata.orientation = exif_orientation(&data[EXIF_HEADER.len()..]);
+            }
+            0xE1 if data.starts_with(XMP_HEADER) => {
+                metadata.xmp = Some(data[XMP_HEADER.len()..].to_vec());
+            }
+            // The sequence number and the number of chunks precede the chunk of the profile
+            0xE2 if data.len() >= ICC_HEADER.len() + 2 && data.starts_with(ICC_HEADER) => {
+                icc_chunks.push((data[ICC_HEADER.len()], data[ICC_HEADER.len() + 2..].to_vec()));
+            }
+            0xFE => {
+                metadata.text.push(("Comment".to_string(), String::from_utf8_lossy(data).into_owned()));
+            }
+            // The entropy coded data follows the header of the scan
+            0xDA => break,
+            _ => ()
+        }
+    }
+
+    if !icc_chunks.is_empty() {
+        icc_chunks.sort_by_key(|&(sequence_number, _)| sequence_number);
+        metadata.icc_profile = Some(icc_chunks.into_iter().flat_map(|(_, chunk)| chunk).collect());
+    }
+
+    Ok(())
+}
+
+// Lowers the height of the frame in ```head``` to ```rows```, so that the jpeg decoder
+// stops after the MCU rows that hold them and skips the rest of the entropy coded data.
+// This is only done for a sequential frame whose first scan holds all of its components
+// without restart intervals, as no other scan can follow it then.
+fn cut_frame(head: &mut [u8], rows: u16) -> bool {
+    let mut frame = None;
+    let mut pos = 2;
+
+    while pos + 4 <= head.len() {
+        let marker = head[pos + 1];
+        let length = (head[pos + 2] as usize) << 8 | head[pos + 3] as usize;
+        let data = pos + 4;
+        if length < 2 || data + length - 2 > head.len() {
+            return false
+        }
+
+        match marker {
+            // The sample precision is followed by the height, the width and the number of components
+            0xC0 | 0xC1 if length >= 8 => frame = Some(data),
+            // DHT (0xC4), JPG (0xC8) and DAC (0xCC) are no start of frame markers
+            0xC4 | 0xC8 | 0xCC => (),
+            0xC0 | 0xC1 | 0xC2 | 0xC3 | 0xC5 | 0xC6 | 0xC7 |
+            0xC9 | 0xCA | 0xCB | 0xCD | 0xCE | 0xCF => return false,
+            0xDD if length < 4 || head[data] != 0 || head[data + 1] != 0 => return false,
+            0xDA => return match frame {
+                Some(frame) if length >= 3 && head[data] == head[frame + 5] => {
+                    head[frame + 1] = (rows >> 8) as u8;
+                    head[frame + 2] = rows as u8;
+                    true
+                }
+                _ => false
+            },
+            _ => ()
+        }
+
+        pos = data + length - 2;
+    }
+
+    false
+}
+
+/// The signature of an APP1 segment that holds EXIF data
+pub const EXIF_HEADER: &'static [u8] = b"Exif\0\0";
+/// The signature of an APP1 segment that holds an XMP packet
+pub const XMP_HEADER: &'static [u8] = b"http://ns.adobe.com/xap/1.0/\0";
+/// The signature of an APP2 segment that holds a chunk of an ICC profile
+pub const ICC_HEADER: &'static [u8] = b"ICC_PROFILE\0";
+
 impl<R: Read> ImageDecoder for JPEGDecoder<R> {
     fn dimensions(&mut self) -> ImageResult<(u32, u32)> {
-        let metadata = try!(self.metadata());
-        Ok((metadata.width as u32, metadata.height as u32))
+        let info = try!(self.info());
+        Ok((info.width as u32, info.height as u32))
     }
 
     fn colortype(&mut self) -> ImageResult<ColorType> {
-        let metadata = try!(self.metadata());
-        Ok(metadata.pixel_format.into())
+        let info = try!(self.info());
+        Ok(info.pixel_format.into())
     }
 
     fn row_len(&mut self) -> ImageResult<usize> {
-        let metadata = try!(self.metadata());
-        Ok(metadata.width as usize * color::num_components(metadata.pixel_format.into()))
+        let info = try!(self.info());
+        Ok(info.width as usize * color::num_components(info.pixel_format.into()))
     }
 
     fn read_scanline(&mut self, _buf: &mut [u8]) -> ImageResult<u32> {
         unimplemented!();
     }
 
+    fn metadata(&mut self) -> ImageResult<ImageMetadata> {
+        try!(self.read_head());
+        Ok(self.metadata.clone())
+    }
+
     fn read_image(&mut self) -> ImageResult<DecodingResult> {
-        let mut data = try!(self.decoder.decode());
-        data = match self.decoder.info().unwrap().pixel_format {
+        let decoder = try!(self.get_decoder());
+        let mut data = try!(decoder.decode());
+        data = match decoder.info().unwrap().pixel_format {
             jpeg_decoder::PixelFormat::CMYK32 => cmyk_to_rgb(&data),
             _ => data,
         };
 
         Ok(DecodingResult::U8(data))
     }
+
+    fn load_rect(&mut self, x: u32, y: u32, width: u32, height: u32) -> ImageResult<DecodingResult> {
+        let dimensions = try!(self.dimensions());
+        try!(check_rect(dimensions, x, y, width, height));
+        let color = try!(self.colortype());
+
+        // The rows below the region take part in upsampling its last rows, one
+        // more MCU row of the largest sampling factor holds them
+        let rows = cmp::min(y + height + 32, dimensions.1);
+        if let Some(ref mut head) = self.head {
+            if rows < dimensions.1 {
+                cut_frame(head, rows as u16);
+            }
+        }
+
+        let image = try!(self.read_image());
+        crop_rect(image, color, dimensions.0, x, y, width, height)
+    }
 }
 
 fn cmyk_to_rgb(input: &[u8]) -> Vec<u8> {
@@ -113,10 +317,67 @@ impl From<jpeg_decoder::Error> for ImageError {
     fn from(err: jpeg_decoder::Error) -> ImageError {
         use self::jpeg_decoder::Error::*;
         match err {
-            Format(desc)      => ImageError::FormatError(desc),
-            Unsupported(desc) => ImageError::UnsupportedError(format!("{:?}", desc)),
+            Format(desc)      => ImageError::malformed(ImageFormat::JPEG, desc),
+            Unsupported(desc) => ImageError::unsupported(ImageFormat::JPEG, unsupported_feature(desc)),
             Io(err)           => ImageError::IoError(err),
-            Internal(err)     => ImageError::FormatError(err.description().to_owned()),
+            Internal(err)     => ImageError::malformed(ImageFormat::JPEG, err.description()),
         }
     }
 }
+
+// The compression methods are identified by the start of frame marker
+fn unsupported_feature(feature: jpeg_decoder::UnsupportedFeature) -> UnsupportedFeature {
+    use self::jpeg_decoder::UnsupportedFeature::*;
+    match feature {
+        Hierarchical            => UnsupportedFeature::Compression(0xC5),
+        Lossless                => UnsupportedFeature::Compression(0xC3),
+        ArithmeticEntropyCoding => UnsupportedFeature::Compression(0xC9),
+        SamplePrecision(bits)   => UnsupportedFeature::BitDepth(bits),
+        feature                 => UnsupportedFeature::Other(format!("{:?}", feature)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use image::ImageDecoder;
+    use super::JPEGDecoder;
+
+    #[test]
+    fn test_segments_after_tables() {
+        // A Huffman table in front of a comment
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xC4, 0, 5, 0, 1, 2];
+        data.extend_from_slice(&[0xFF, 0xFE, 0, 7]);
+        data.extend_from_slice(b"hello");
+        data.extend_from_slice(&[0xFF, 0xD9]);
+
+        let metadata = JPEGDecoder::new(&data[..]).metadata().unwrap();
+        assert_eq!(metadata.text, vec![("Comment".to_string(), "hello".to_string())]);
+    }
+
+    #[test]
+    fn test_cut_frame() {
+        use std::fs::File;
+        use std::io::Read;
+        use color::ColorType;
+        use image::DecodingResult;
+        use jpeg::JPEGEncoder;
+
+        // Decoding a sequential image stops after the rows of the cut frame
+        let mut jpeg = Vec::new();
+        JPEGEncoder::new(&mut jpeg).encode(&[128; 32 * 64 * 3], 32, 64, ColorType::RGB(8)).unwrap();
+        let mut decoder = JPEGDecoder::new(&jpeg[..]);
+        assert_eq!(decoder.dimensions().unwrap(), (32, 64));
+        assert!(super::cut_frame(decoder.head.as_mut().unwrap(), 16));
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(data) => assert_eq!(data.len(), 32 * 16 * 3),
+            DecodingResult::U16(_) => panic!("wrong sample type")
+        }
+
+        // The scans of progressive images cover all rows
+        let mut data = Vec::new();
+        File::open("tests/images/jpg/progressive/cat.jpg").unwrap().read_to_end(&mut data).unwrap();
+        let mut decoder = JPEGDecoder::new(&data[..]);
+        decoder.dimensions().unwrap();
+        assert!(!super::cut_frame(decoder.head.as_mut().unwrap(), 16));
+    }
+}
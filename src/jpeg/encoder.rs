@@ -1,15 +1,20 @@
+use std::cmp;
 use std::io::{self, Write};
+use std::mem;
 use byteorder::{WriteBytesExt, BigEndian};
-use num::range_step;
 
 use color;
+use image::{ImageEncoder, ImageMetadata, ImageResult};
 
+use super::decoder::{EXIF_HEADER, XMP_HEADER, ICC_HEADER};
 use super::transform;
-use super::entropy::build_huff_lut;
+use super::entropy::{build_huff_lut, build_optimal_table};
 
 // Markers
 // Baseline DCT
 static SOF0: u8 = 0xC0;
+// Progressive DCT
+static SOF2: u8 = 0xC2;
 // Huffman Tables
 static DHT: u8 = 0xC4;
 // Start of Image (standalone)
@@ -20,8 +25,20 @@ static EOI: u8 = 0xD9;
 static SOS: u8 = 0xDA;
 // Quantization Tables
 static DQT: u8 = 0xDB;
+// Define Restart Interval
+static DRI: u8 = 0xDD;
+// Restart with modulo 8 count 0 (standalone)
+static RST0: u8 = 0xD0;
 // Application segments start and end
 static APP0: u8 = 0xE0;
+static APP1: u8 = 0xE1;
+static APP2: u8 = 0xE2;
+static APP15: u8 = 0xEF;
+// Comment
+static COM: u8 = 0xFE;
+
+// The largest amount of data a segment can hold
+const MAX_SEGMENT_LEN: usize = 65533;
 
 // section K.1
 // table K.1
@@ -120,6 +137,35 @@ static LUMAID: u8 = 1;
 static CHROMABLUEID: u8 = 2;
 static CHROMAREDID: u8 = 3;
 
+// The scans of a progressive image as libjpeg writes them: the components,
+// the first and last coefficient and the high and low bit of the successive approximation.
+// The DC coefficients come first, then a coarse version of the AC coefficients
+// and finally the refinements, luma is refined last.
+static PROGRESSIVE_COLOR_SCANS: [(&'static [usize], u8, u8, u8, u8); 10] = [
+    (&[0, 1, 2], 0,  0, 0, 1),
+    (&[0],       1,  5, 0, 2),
+    (&[2],       1, 63, 0, 1),
+    (&[1],       1, 63, 0, 1),
+    (&[0],       6, 63, 0, 2),
+    (&[0],       1, 63, 2, 1),
+    (&[0, 1, 2], 0,  0, 1, 0),
+    (&[2],       1, 63, 1, 0),
+    (&[1],       1, 63, 1, 0),
+    (&[0],       1, 63, 1, 0),
+];
+
+static PROGRESSIVE_GRAY_SCANS: [(&'static [usize], u8, u8, u8, u8); 6] = [
+    (&[0], 0,  0, 0, 1),
+    (&[0], 1,  5, 0, 2),
+    (&[0], 6, 63, 0, 2),
+    (&[0], 1, 63, 2, 1),
+    (&[0], 0,  0, 1, 0),
+    (&[0], 1, 63, 1, 0),
+];
+
+// The longest run of blocks that an EOB code can stand for
+const MAX_EOBRUN: u16 = 0x7FFF;
+
 /// The permutation of dct coefficients.
 static UNZIGZAG: [u8; 64] = [
     0,  1,  8, 16,  9,  2,  3, 10,
@@ -157,79 +203,254 @@ struct Component {
     dc_pred: i32
 }
 
+/// How the chroma components of an image are subsampled
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Subsampling {
+    /// Every pixel keeps its own chroma values
+    Ratio444,
+    /// Two horizontally adjacent pixels share their chroma values
+    Ratio422,
+    /// A square of 2x2 pixels shares its chroma values
+    Ratio420,
+}
+
+impl Subsampling {
+    // The sampling factors of the luma component, the chroma components have a factor of 1
+    fn luma_factors(self) -> (u8, u8) {
+        match self {
+            Subsampling::Ratio444 => (1, 1),
+            Subsampling::Ratio422 => (2, 1),
+            Subsampling::Ratio420 => (2, 2),
+        }
+    }
+}
+
+impl Default for Subsampling {
+    fn default() -> Subsampling {
+        Subsampling::Ratio420
+    }
+}
+
 /// The representation of a JPEG encoder
 pub struct JPEGEncoder<'a, W: 'a> {
     w: &'a mut W,
 
     components: Vec<Component>,
     tables: Vec<u8>,
+    subsampling: Subsampling,
+    optimize_huffman: bool,
+    progressive: bool,
+    restart_interval: u16,
+
+    // The APPn and COM segments that follow the JFIF header
+    segments: Vec<(u8, Vec<u8>)>,
+
+    // The width and height of the image being encoded
+    size: (usize, usize),
 
     accumulator: u32,
     nbits: u8,
 
-    luma_dctable: Vec<(u8, u16)>,
-    luma_actable: Vec<(u8, u16)>,
-    chroma_dctable: Vec<(u8, u16)>,
-    chroma_actable: Vec<(u8, u16)>,
+    // The Huffman codes of the DC and AC tables by destination
+    dc_luts: Vec<Vec<(u8, u16)>>,
+    ac_luts: Vec<Vec<(u8, u16)>>,
+
+    // While statistics are gathered symbols are counted instead of written
+    gather_statistics: bool,
+    dc_freqs: Vec<[u32; 257]>,
+    ac_freqs: Vec<[u32; 257]>,
+
+    // The number of blocks of a progressive scan without further coefficients
+    // that are not yet written, and the refinement bits of those blocks
+    eobrun: u16,
+    correction_bits: Vec<u16>,
 }
 
 impl<'a, W: Write> JPEGEncoder<'a, W> {
     /// Create a new encoder that writes its output to ```w```
+    ///
+    /// The image is compressed with the quantization tables of the JPEG specification,
+    /// which corresponds to a quality of 50.
     pub fn new(w: &mut W) -> JPEGEncoder<W> {
-        let ld = build_huff_lut(&STD_LUMA_DC_CODE_LENGTHS, &STD_LUMA_DC_VALUES);
-        let la = build_huff_lut(&STD_LUMA_AC_CODE_LENGTHS, &STD_LUMA_AC_VALUES);
+        JPEGEncoder::new_with_quality(w, 50)
+    }
+
+    /// Create a new encoder that writes its output to ```w```
+    ///
+    /// ```quality``` ranges from 1, the smallest files, to 100, the best quality.
+    /// The quantization tables of the JPEG specification are scaled the way libjpeg does.
+    pub fn new_with_quality(w: &'a mut W, quality: u8) -> JPEGEncoder<'a, W> {
+        // The tables are scaled by 5000 / quality percent below a quality of 50,
+        // and by 200 - 2 * quality percent above
+        let quality = cmp::min(cmp::max(quality, 1), 100) as u32;
+        let scale = if quality < 50 { 5000 / quality } else { 200 - quality * 2 };
+
+        let mut luma = [0u8; 64];
+        let mut chroma = [0u8; 64];
+        for i in 0..64 {
+            luma[i] = cmp::min((STD_LUMA_QTABLE[i] as u32 * scale + 50) / 100, 255) as u8;
+            chroma[i] = cmp::min((STD_CHROMA_QTABLE[i] as u32 * scale + 50) / 100, 255) as u8;
+        }
 
-        let cd = build_huff_lut(&STD_CHROMA_DC_CODE_LENGTHS, &STD_CHROMA_DC_VALUES);
-        let ca = build_huff_lut(&STD_CHROMA_AC_CODE_LENGTHS, &STD_CHROMA_AC_VALUES);
+        JPEGEncoder::new_with_tables(w, &luma, &chroma)
+    }
 
+    /// Create a new encoder that writes its output to ```w```
+    ///
+    /// The image is compressed with the quantization tables ```luma``` and ```chroma```,
+    /// their values are given row by row and a value of 0 is treated as 1.
+    pub fn new_with_tables(w: &'a mut W, luma: &[u8; 64], chroma: &[u8; 64]) -> JPEGEncoder<'a, W> {
         let components = vec![
             Component {id: LUMAID, h: 1, v: 1, tq: LUMADESTINATION, dc_table: LUMADESTINATION, ac_table: LUMADESTINATION, dc_pred: 0},
             Component {id: CHROMABLUEID, h: 1, v: 1, tq: CHROMADESTINATION, dc_table: CHROMADESTINATION, ac_table: CHROMADESTINATION, dc_pred: 0},
             Component {id: CHROMAREDID, h: 1, v: 1, tq: CHROMADESTINATION, dc_table: CHROMADESTINATION, ac_table: CHROMADESTINATION, dc_pred: 0}
         ];
 
-        let mut tables = Vec::new();
-        tables.extend(STD_LUMA_QTABLE.iter().map(|&v| v));
-        tables.extend(STD_CHROMA_QTABLE.iter().map(|&v| v));
+        let tables: Vec<u8> = luma.iter().chain(chroma.iter()).map(|&v| cmp::max(v, 1)).collect();
 
         JPEGEncoder {
             w: w,
 
             components: components,
             tables: tables,
+            subsampling: Subsampling::default(),
+            optimize_huffman: false,
+            progressive: false,
+            restart_interval: 0,
 
-            luma_dctable: ld,
-            luma_actable: la,
-            chroma_dctable: cd,
-            chroma_actable: ca,
+            segments: Vec::new(),
+
+            size: (0, 0),
 
             accumulator: 0,
             nbits: 0,
+
+            dc_luts: vec![Vec::new(); 2],
+            ac_luts: vec![Vec::new(); 2],
+
+            gather_statistics: false,
+            dc_freqs: vec![[0; 257]; 2],
+            ac_freqs: vec![[0; 257]; 2],
+
+            eobrun: 0,
+            correction_bits: Vec::new(),
         }
     }
 
+    /// Set how the chroma components of color images are subsampled, the default is 4:2:0
+    pub fn set_subsampling(&mut self, subsampling: Subsampling) {
+        self.subsampling = subsampling;
+    }
+
+    /// Set whether the Huffman tables are optimized for the image
+    ///
+    /// The image is then processed twice, first to count the symbols and then to write them
+    /// with the shortest codes. The default tables of the JPEG specification are used otherwise.
+    pub fn set_optimize_huffman(&mut self, optimize: bool) {
+        self.optimize_huffman = optimize;
+    }
+
+    /// Set whether a progressive image is written
+    ///
+    /// The DC coefficients are written first, followed by the AC coefficients in increasing
+    /// detail, as libjpeg does. The Huffman tables of progressive images are always optimized.
+    pub fn set_progressive(&mut self, progressive: bool) {
+        self.progressive = progressive;
+    }
+
+    /// Set the number of MCUs after which a restart marker is written, 0 writes none
+    ///
+    /// Decoding starts over at every restart marker, which confines the damage
+    /// done by corrupted data to a single interval.
+    pub fn set_restart_interval(&mut self, interval: u16) {
+        self.restart_interval = interval;
+    }
+
+    /// Adds an application segment APPn, ```n``` ranges from 0 to 15
+    ///
+    /// ```data``` has to start with the identifier of the application, e.g. ```Exif\0\0```
+    /// for EXIF data in APP1. The segments are written after the JFIF header in the order
+    /// they are added, followed by the segments of the metadata of the image.
+    pub fn add_app_segment(&mut self, n: u8, data: &[u8]) -> io::Result<()> {
+        let segment = try!(app_segment(n, &[], &[], data));
+        self.segments.push(segment);
+        Ok(())
+    }
+
+    /// Adds an ICC color profile, split into as many APP2 segments as it needs
+    pub fn add_icc_profile(&mut self, profile: &[u8]) -> io::Result<()> {
+        let segments = try!(icc_segments(profile));
+        self.segments.extend(segments);
+        Ok(())
+    }
+
+    /// Adds a comment segment
+    pub fn add_comment(&mut self, comment: &[u8]) -> io::Result<()> {
+        let buf = try!(build_app_segment(&[], &[], comment));
+        self.segments.push((COM, buf));
+        Ok(())
+    }
+
     /// Encodes the image ```image```
     /// that has dimensions ```width``` and ```height```
     /// and ```ColorType``` ```c```
     ///
-    /// The Image in encoded with subsampling ratio 4:2:2
+    /// Color images are encoded with the subsampling set by ```set_subsampling```.
     pub fn encode(&mut self,
                   image: &[u8],
                   width: u32,
                   height: u32,
                   c: color::ColorType) -> io::Result<()> {
+        self.encode_with_metadata(image, width, height, c, &ImageMetadata::default())
+    }
 
-        let n = color::num_components(c);
-        let num_components = if n == 1 || n == 2 {1}
+    /// Encodes the image ```image``` like ```encode``` and stores ```metadata```
+    ///
+    /// The resolution is stored in the JFIF header, the EXIF data and the XMP packet
+    /// in APP1 segments and the ICC profile in as many APP2 segments as it needs.
+    /// Text with the key ```Comment``` is stored in COM segments, other text is ignored.
+    /// The metadata follows the segments added to the encoder.
+    pub fn encode_with_metadata(&mut self,
+                                image: &[u8],
+                                width: u32,
+                                height: u32,
+                                c: color::ColorType,
+                                metadata: &ImageMetadata) -> io::Result<()> {
+
+        let bpp = match c {
+            color::ColorType::RGB(8)   => 3,
+            color::ColorType::RGBA(8)  => 4,
+            color::ColorType::Gray(8)  => 1,
+            color::ColorType::GrayA(8) => 2,
+            _  => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                &format!("Unsupported color type {:?}. Use 8 bit per channel RGB(A) or Gray(A) instead.", c)[..],
+            ))
+        };
+        let num_components = if bpp < 3 {1}
                              else {3};
 
-        let _ = try!(self.write_segment(SOI, None));
+        // Gray images consist of a single component that is never subsampled
+        let (h, v) = if num_components == 3 { self.subsampling.luma_factors() }
+                     else { (1, 1) };
+        self.components[0].h = h;
+        self.components[0].v = v;
 
-        let buf = build_jfif_header();
+        try!(self.write_segment(SOI, None));
+
+        let buf = build_jfif_header(metadata.dpi);
         let _   = try!(self.write_segment(APP0, Some(buf)));
 
+        let mut segments = self.segments.clone();
+        segments.extend(try!(metadata_segments(metadata)));
+
+        for (marker, buf) in segments {
+            try!(self.write_segment(marker, Some(buf)));
+        }
+
         let buf = build_frame_header(8, width as u16, height as u16, &self.components[..num_components]);
-        let _   = try!(self.write_segment(SOF0, Some(buf)));
+        let sof = if self.progressive { SOF2 } else { SOF0 };
+        let _   = try!(self.write_segment(sof, Some(buf)));
 
         assert!(self.tables.len() / 64 == 2);
         let numtables = if num_components == 1 {1}
@@ -242,65 +463,57 @@ impl<'a, W: Write> JPEGEncoder<'a, W> {
             let _   = try!(self.write_segment(DQT, Some(buf)));
         }
 
-        let numcodes = STD_LUMA_DC_CODE_LENGTHS;
-        let values   = STD_LUMA_DC_VALUES;
-
-        let buf = build_huffman_segment(DCCLASS, LUMADESTINATION, &numcodes, &values);
-        let _   = try!(self.write_segment(DHT, Some(buf)));
+        if self.restart_interval > 0 {
+            let mut buf = Vec::new();
+            let _ = buf.write_u16::<BigEndian>(self.restart_interval);
+            try!(self.write_segment(DRI, Some(buf)));
+        }
 
-        let numcodes = STD_LUMA_AC_CODE_LENGTHS;
-        let values   = STD_LUMA_AC_VALUES;
+        self.size = (width as usize, height as usize);
 
-        let buf = build_huffman_segment(ACCLASS, LUMADESTINATION, &numcodes, &values);
-        let _   = try!(self.write_segment(DHT, Some(buf)));
+        // Only optimized and progressive scans need the whole image, a baseline scan with
+        // the standard tables is encoded while the image is transformed
+        if !self.optimize_huffman && !self.progressive {
+            let components: Vec<usize> = (0..num_components).collect();
+            try!(self.write_scan_header(&components, 0, 63, 0, 0));
+            try!(self.encode_image(image, bpp, num_components));
+            try!(self.pad_byte());
 
-        if num_components == 3 {
-            let numcodes = STD_CHROMA_DC_CODE_LENGTHS;
-            let values   = STD_CHROMA_DC_VALUES;
+            return self.write_segment(EOI, None)
+        }
 
-            let buf = build_huffman_segment(DCCLASS, CHROMADESTINATION, &numcodes, &values);
-            let _   = try!(self.write_segment(DHT, Some(buf)));
+        let blocks = self.quantize_image(image, bpp, num_components);
 
-            let numcodes = STD_CHROMA_AC_CODE_LENGTHS;
-            let values   = STD_CHROMA_AC_VALUES;
+        if self.progressive {
+            let scans = if num_components == 1 { &PROGRESSIVE_GRAY_SCANS[..] }
+                        else { &PROGRESSIVE_COLOR_SCANS[..] };
 
-            let buf = build_huffman_segment(ACCLASS, CHROMADESTINATION, &numcodes, &values);
-            let _   = try!(self.write_segment(DHT, Some(buf)));
+            for &(components, ss, se, ah, al) in scans {
+                try!(self.write_scan(&blocks, components, ss, se, ah, al));
+            }
+        } else {
+            let components: Vec<usize> = (0..num_components).collect();
+            try!(self.write_scan(&blocks, &components, 0, 63, 0, 0));
         }
 
-        let buf = build_scan_header(&self.components[..num_components]);
-        let _   = try!(self.write_segment(SOS, Some(buf)));
-
-        match c {
-            color::ColorType::RGB(8)   => try!(self.encode_rgb(image, width as usize, height as usize, 3)),
-            color::ColorType::RGBA(8)  => try!(self.encode_rgb(image, width as usize, height as usize, 4)),
-            color::ColorType::Gray(8)  => try!(self.encode_gray(image, width as usize, height as usize, 1)),
-            color::ColorType::GrayA(8) => try!(self.encode_gray(image, width as usize, height as usize, 2)),
-            _  => return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                &format!("Unsupported color type {:?}. Use 8 bit per channel RGB(A) or Gray(A) instead.", c)[..],
-            ))
-        };
-
-        let _ = try!(self.pad_byte());
         self.write_segment(EOI, None)
     }
 
     fn write_segment(&mut self, marker: u8, data: Option<Vec<u8>>) -> io::Result<()> {
-        let _ = try!(self.w.write_all(&[0xFF]));
-        let _ = try!(self.w.write_all(&[marker]));
+        try!(self.w.write_all(&[0xFF]));
+        try!(self.w.write_all(&[marker]));
 
         if data.is_some() {
             let b = data.unwrap();
-            let _ = try!(self.w.write_u16::<BigEndian>(b.len() as u16 + 2));
-            let _ = try!(self.w.write_all(&b));
+            try!(self.w.write_u16::<BigEndian>(b.len() as u16 + 2));
+            try!(self.w.write_all(&b));
         }
 
         Ok(())
     }
 
     fn write_bits(&mut self, bits: u16, size: u8) -> io::Result<()> {
-        if size == 0 {
+        if size == 0 || self.gather_statistics {
           return Ok(())
         }
 
@@ -309,10 +522,10 @@ impl<'a, W: Write> JPEGEncoder<'a, W> {
 
         while self.nbits >= 8 {
             let byte = (self.accumulator & (0xFFFFFFFFu32 << 24)) >> 24;
-            let _ = try!(self.w.write_all(&[byte as u8]));
+            try!(self.w.write_all(&[byte as u8]));
 
             if byte == 0xFF {
-                let _ = try!(self.w.write_all(&[0x00]));
+                try!(self.w.write_all(&[0x00]));
             }
 
             self.nbits -= 8;
@@ -323,11 +536,27 @@ impl<'a, W: Write> JPEGEncoder<'a, W> {
     }
 
     fn pad_byte(&mut self) -> io::Result<()> {
-        self.write_bits(0x7F, 7)
+        try!(self.write_bits(0x7F, 7));
+
+        // The bits that are left over are padding only
+        self.accumulator = 0;
+        self.nbits = 0;
+
+        Ok(())
     }
 
-    fn huffman_encode(&mut self, val: u8, table: &[(u8, u16)]) -> io::Result<()> {
-        let (size, code) = table[val as usize];
+    fn huffman_encode(&mut self, val: u8, class: u8, destination: u8) -> io::Result<()> {
+        if self.gather_statistics {
+            let freqs = if class == DCCLASS { &mut self.dc_freqs } else { &mut self.ac_freqs };
+            freqs[destination as usize][val as usize] += 1;
+            return Ok(())
+        }
+
+        let (size, code) = if class == DCCLASS {
+            self.dc_luts[destination as usize][val as usize]
+        } else {
+            self.ac_luts[destination as usize][val as usize]
+        };
 
         if size > 16 {
             panic!("bad huffman value");
@@ -340,16 +569,16 @@ impl<'a, W: Write> JPEGEncoder<'a, W> {
         &mut self,
         block: &[i32],
         prevdc: i32,
-        dctable: &[(u8, u16)],
-        actable: &[(u8, u16)]) -> io::Result<i32> {
+        dctable: u8,
+        actable: u8) -> io::Result<i32> {
 
         // Differential DC encoding
         let dcval = block[0];
         let diff  = dcval - prevdc;
         let (size, value) = encode_coefficient(diff);
 
-        let _ = try!(self.huffman_encode(size, dctable));
-        let _ = try!(self.write_bits(value, size));
+        try!(self.huffman_encode(size, DCCLASS, dctable));
+        try!(self.write_bits(value, size));
 
         // Figure F.2
         let mut zero_run = 0;
@@ -361,22 +590,22 @@ impl<'a, W: Write> JPEGEncoder<'a, W> {
             if block[UNZIGZAG[k] as usize] == 0 {
                 if k == 63 {
 
-                let _ = try!(self.huffman_encode(0x00, actable));
+                try!(self.huffman_encode(0x00, ACCLASS, actable));
                     break
                 }
 
                 zero_run += 1;
             } else {
                 while zero_run > 15 {
-                    let _ = try!(self.huffman_encode(0xF0, actable));
+                    try!(self.huffman_encode(0xF0, ACCLASS, actable));
                     zero_run -= 16;
                 }
 
                 let (size, value) = encode_coefficient(block[UNZIGZAG[k] as usize]);
                 let symbol = (zero_run << 4) | size;
 
-                let _ = try!(self.huffman_encode(symbol, actable));
-                let _ = try!(self.write_bits(value, size));
+                try!(self.huffman_encode(symbol, ACCLASS, actable));
+                try!(self.write_bits(value, size));
 
                 zero_run = 0;
 
@@ -389,97 +618,504 @@ impl<'a, W: Write> JPEGEncoder<'a, W> {
         Ok(dcval)
     }
 
-    fn encode_gray(&mut self, image: &[u8], width: usize, height: usize, bpp: usize) -> io::Result<()> {
-        let mut yblock     = [0u8; 64];
-        let mut y_dcprev   = 0;
-        let mut dct_yblock = [0i32; 64];
+    // Writes the Huffman tables, the header and the data of the scan of the coefficients
+    // ```ss``` to ```se``` of ```components```, without the lowest ```al``` bits.
+    // A refinement scan adds bit ```al``` to the coefficients of a scan with ```ah = al + 1```.
+    fn write_scan(&mut self, blocks: &[Vec<[i32; 64]>], components: &[usize],
+                  ss: u8, se: u8, ah: u8, al: u8) -> io::Result<()> {
+        if (self.optimize_huffman || self.progressive) && !self.scan_huffman_tables(components, ss, se, ah).is_empty() {
+            // The first pass only counts the symbols of the scan
+            self.dc_freqs = vec![[0; 257]; 2];
+            self.ac_freqs = vec![[0; 257]; 2];
+            self.gather_statistics = true;
+            try!(self.encode_scan(blocks, components, ss, se, ah, al));
+            self.gather_statistics = false;
+        }
+
+        try!(self.write_scan_header(components, ss, se, ah, al));
+        try!(self.encode_scan(blocks, components, ss, se, ah, al));
+        self.pad_byte()
+    }
+
+    // The Huffman tables used by a scan, the refinement bits of DC coefficients are not Huffman coded
+    fn scan_huffman_tables(&self, components: &[usize], ss: u8, se: u8, ah: u8) -> Vec<(u8, u8)> {
+        let mut huffman_tables = Vec::new();
+        for &i in components {
+            let comp = self.components[i];
+            let mut add = |table| if !huffman_tables.contains(&table) { huffman_tables.push(table) };
+
+            if ss == 0 && ah == 0 {
+                add((DCCLASS, comp.dc_table));
+            }
+            if se > 0 {
+                add((ACCLASS, comp.ac_table));
+            }
+        }
+        huffman_tables
+    }
+
+    // Writes the Huffman tables of a scan, built from the gathered statistics
+    // if the tables are optimized, and the scan header
+    fn write_scan_header(&mut self, components: &[usize], ss: u8, se: u8, ah: u8, al: u8) -> io::Result<()> {
+        for (class, destination) in self.scan_huffman_tables(components, ss, se, ah) {
+            let (numcodes, values) = if self.optimize_huffman || self.progressive {
+                let freqs = if class == DCCLASS { &self.dc_freqs } else { &self.ac_freqs };
+                build_optimal_table(&freqs[destination as usize])
+            } else {
+                std_huffman_table(class, destination)
+            };
 
-        for y in range_step(0, height, 8) {
-            for x in range_step(0, width, 8) {
-                // RGB -> YCbCr
-                copy_blocks_gray(image, x, y, width, bpp, &mut yblock);
+            let lut = build_huff_lut(&numcodes, &values);
+            if class == DCCLASS {
+                self.dc_luts[destination as usize] = lut;
+            } else {
+                self.ac_luts[destination as usize] = lut;
+            }
 
-                // Level shift and fdct
-                // Coeffs are scaled by 8
-                transform::fdct(&yblock, &mut dct_yblock);
+            let buf = build_huffman_segment(class, destination, &numcodes, &values);
+            let _   = try!(self.write_segment(DHT, Some(buf)));
+        }
 
-                // Quantization
-                for i in 0usize..64 {
-                    dct_yblock[i]   = ((dct_yblock[i] / 8)   as f32 / self.tables[i] as f32).round() as i32;
+        let scan_components: Vec<Component> = components.iter().map(|&i| self.components[i]).collect();
+        let buf = build_scan_header(&scan_components, ss, se, ah, al);
+        let _   = try!(self.write_segment(SOS, Some(buf)));
+
+        Ok(())
+    }
+
+    // Encodes the blocks of a scan, MCU by MCU if it has several components.
+    // A scan of a single component only holds the blocks that cover the image.
+    fn encode_scan(&mut self, blocks: &[Vec<[i32; 64]>], components: &[usize],
+                   ss: u8, se: u8, ah: u8, al: u8) -> io::Result<()> {
+        let (width, height) = self.size;
+        let (mcus_x, mcus_y) = self.mcus();
+        let hmax = self.components[0].h as usize;
+        let vmax = self.components[0].v as usize;
+
+        for c in self.components.iter_mut() {
+            c.dc_pred = 0;
+        }
+        self.eobrun = 0;
+        self.correction_bits.clear();
+
+        let actable = self.components[components[0]].ac_table;
+
+        if components.len() > 1 {
+            for my in 0..mcus_y {
+                for mx in 0..mcus_x {
+                    try!(self.write_restart(my * mcus_x + mx, actable));
+
+                    for &i in components {
+                        let (h, v) = (self.components[i].h as usize, self.components[i].v as usize);
+
+                        for by in 0..v {
+                            for bx in 0..h {
+                                let block = &blocks[i][(my * v + by) * mcus_x * h + mx * h + bx];
+                                try!(self.encode_block(block, i, ss, se, ah, al));
+                            }
+                        }
+                    }
+                }
+            }
+        } else {
+            let i = components[0];
+            let (h, v) = (self.components[i].h as usize, self.components[i].v as usize);
+
+            let blocks_x = ((width * h + hmax - 1) / hmax + 7) / 8;
+            let blocks_y = ((height * v + vmax - 1) / vmax + 7) / 8;
+
+            // Every block is a MCU of its own
+            for by in 0..blocks_y {
+                for bx in 0..blocks_x {
+                    try!(self.write_restart(by * blocks_x + bx, actable));
+                    try!(self.encode_block(&blocks[i][by * mcus_x * h + bx], i, ss, se, ah, al));
                 }
+            }
+        }
+
+        self.write_eobrun(actable)
+    }
+
+    // Ends a restart interval before the MCU ```mcu``` if one is due,
+    // the predictions of the DC coefficients start over after the restart marker
+    fn write_restart(&mut self, mcu: usize, actable: u8) -> io::Result<()> {
+        let interval = self.restart_interval as usize;
+        if interval == 0 || mcu == 0 || mcu % interval != 0 {
+            return Ok(())
+        }
+
+        try!(self.write_eobrun(actable));
+        try!(self.pad_byte());
+
+        if !self.gather_statistics {
+            let n = ((mcu / interval - 1) % 8) as u8;
+            try!(self.write_segment(RST0 + n, None));
+        }
+
+        for c in self.components.iter_mut() {
+            c.dc_pred = 0;
+        }
 
-                let la = self.luma_actable.clone();
-                let ld = self.luma_dctable.clone();
+        Ok(())
+    }
+
+    fn encode_block(&mut self, block: &[i32; 64], i: usize, ss: u8, se: u8, ah: u8, al: u8) -> io::Result<()> {
+        let comp = self.components[i];
+
+        match (ss, ah) {
+            (0, 0) if se == 63 => {
+                self.components[i].dc_pred = try!(self.write_block(block, comp.dc_pred, comp.dc_table, comp.ac_table));
+            }
+            (0, 0) => {
+                // Section G.1.2.1
+                let dcval = block[0] >> al;
+                let (size, value) = encode_coefficient(dcval - comp.dc_pred);
 
-                y_dcprev  = try!(self.write_block(&dct_yblock, y_dcprev, &ld, &la));
+                try!(self.huffman_encode(size, DCCLASS, comp.dc_table));
+                try!(self.write_bits(value, size));
+
+                self.components[i].dc_pred = dcval;
+            }
+            (0, _) => {
+                try!(self.write_bits((block[0] >> al) as u16 & 1, 1));
             }
+            (_, 0) => try!(self.write_ac_first(block, comp.ac_table, ss as usize, se as usize, al)),
+            _ => try!(self.write_ac_refine(block, comp.ac_table, ss as usize, se as usize, al)),
         }
 
         Ok(())
     }
 
-    fn encode_rgb(&mut self, image: &[u8], width: usize, height: usize, bpp: usize) -> io::Result<()> {
-        let mut y_dcprev = 0;
-        let mut cb_dcprev = 0;
-        let mut cr_dcprev = 0;
+    // Section G.1.2.2
+    // Blocks without further coefficients are counted and written as one EOB run
+    fn write_ac_first(&mut self, block: &[i32; 64], actable: u8, ss: usize, se: usize, al: u8) -> io::Result<()> {
+        let mut zero_run = 0;
 
-        let mut dct_yblock   = [0i32; 64];
-        let mut dct_cb_block = [0i32; 64];
-        let mut dct_cr_block = [0i32; 64];
+        for k in ss..se + 1 {
+            // The magnitude is shifted, not the two's complement value
+            let coefficient = block[UNZIGZAG[k] as usize];
+            let value = if coefficient < 0 { -(-coefficient >> al) } else { coefficient >> al };
 
-        let mut yblock   = [0u8; 64];
-        let mut cb_block = [0u8; 64];
-        let mut cr_block = [0u8; 64];
+            if value == 0 {
+                zero_run += 1;
+                continue
+            }
+
+            try!(self.write_eobrun(actable));
+
+            while zero_run > 15 {
+                try!(self.huffman_encode(0xF0, ACCLASS, actable));
+                zero_run -= 16;
+            }
+
+            let (size, value) = encode_coefficient(value);
+            try!(self.huffman_encode((zero_run << 4) | size, ACCLASS, actable));
+            try!(self.write_bits(value, size));
+
+            zero_run = 0;
+        }
+
+        if zero_run > 0 {
+            self.eobrun += 1;
+            if self.eobrun == MAX_EOBRUN {
+                try!(self.write_eobrun(actable));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Section G.1.2.3
+    // Coefficients that become nonzero are coded like in the first scan, coefficients that
+    // already are nonzero get a correction bit that follows the next coded symbol
+    fn write_ac_refine(&mut self, block: &[i32; 64], actable: u8, ss: usize, se: usize, al: u8) -> io::Result<()> {
+        let mut absvalues = [0i32; 64];
+        let mut eob = 0;
+
+        // The last coefficient that becomes nonzero
+        for k in ss..se + 1 {
+            absvalues[k] = block[UNZIGZAG[k] as usize].abs() >> al;
+            if absvalues[k] == 1 {
+                eob = k;
+            }
+        }
+
+        let mut zero_run = 0;
+        let mut block_bits = Vec::new();
+
+        for k in ss..se + 1 {
+            let value = absvalues[k];
+
+            if value == 0 {
+                zero_run += 1;
+                continue
+            }
+
+            while zero_run > 15 && k <= eob {
+                try!(self.write_eobrun(actable));
+                try!(self.huffman_encode(0xF0, ACCLASS, actable));
+                zero_run -= 16;
+
+                for bit in block_bits.drain(..) {
+                    try!(self.write_bits(bit, 1));
+                }
+            }
+
+            if value > 1 {
+                block_bits.push(value as u16 & 1);
+                continue
+            }
 
-        for y in range_step(0, height, 8) {
-            for x in range_step(0, width, 8) {
-                // RGB -> YCbCr
-                copy_blocks_ycbcr(image, x, y, width, bpp, &mut yblock, &mut cb_block, &mut cr_block);
-
-                // Level shift and fdct
-                // Coeffs are scaled by 8
-                transform::fdct(&yblock, &mut dct_yblock);
-                transform::fdct(&cb_block, &mut dct_cb_block);
-                transform::fdct(&cr_block, &mut dct_cr_block);
-
-                // Quantization
-                for i in 0usize..64 {
-                    dct_yblock[i]   = ((dct_yblock[i] / 8)   as f32 / self.tables[i] as f32).round() as i32;
-                    dct_cb_block[i] = ((dct_cb_block[i] / 8) as f32 / self.tables[64..][i] as f32).round() as i32;
-                    dct_cr_block[i] = ((dct_cr_block[i] / 8) as f32 / self.tables[64..][i] as f32).round() as i32;
+            try!(self.write_eobrun(actable));
+            try!(self.huffman_encode((zero_run << 4) | 1, ACCLASS, actable));
+
+            let sign = if block[UNZIGZAG[k] as usize] < 0 { 0 } else { 1 };
+            try!(self.write_bits(sign, 1));
+
+            for bit in block_bits.drain(..) {
+                try!(self.write_bits(bit, 1));
+            }
+
+            zero_run = 0;
+        }
+
+        if zero_run > 0 || !block_bits.is_empty() {
+            self.eobrun += 1;
+            self.correction_bits.extend(block_bits);
+
+            // libjpeg buffers no more than 1000 correction bits
+            if self.eobrun == MAX_EOBRUN || self.correction_bits.len() > 1000 - 63 {
+                try!(self.write_eobrun(actable));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Writes the pending EOB run followed by the correction bits of its blocks
+    fn write_eobrun(&mut self, actable: u8) -> io::Result<()> {
+        if self.eobrun == 0 {
+            return Ok(())
+        }
+
+        // EOBn stands for a run of 2^n up to 2^(n+1) - 1 blocks, the low n bits of the run follow
+        let n = 15 - self.eobrun.leading_zeros() as u8;
+        try!(self.huffman_encode(n << 4, ACCLASS, actable));
+        try!(self.write_bits(self.eobrun & ((1 << n) - 1), n));
+        self.eobrun = 0;
+
+        let bits = mem::replace(&mut self.correction_bits, Vec::new());
+        for bit in bits {
+            try!(self.write_bits(bit, 1));
+        }
+
+        Ok(())
+    }
+
+    // The number of MCUs in a row and in a column of the image
+    fn mcus(&self) -> (usize, usize) {
+        let (width, height) = self.size;
+        let h = self.components[0].h as usize;
+        let v = self.components[0].v as usize;
+
+        ((width + 8 * h - 1) / (8 * h), (height + 8 * v - 1) / (8 * v))
+    }
+
+    // Encodes the interleaved baseline scan of all components MCU by MCU,
+    // every MCU is transformed and quantized right before it is written
+    fn encode_image(&mut self, image: &[u8], bpp: usize, num_components: usize) -> io::Result<()> {
+        let (mcus_x, mcus_y) = self.mcus();
+        let luma_blocks = (self.components[0].h * self.components[0].v) as usize;
+        let actable = self.components[0].ac_table;
+
+        for c in self.components.iter_mut() {
+            c.dc_pred = 0;
+        }
+
+        let mut blocks = [[0i32; 64]; 6];
+
+        for my in 0..mcus_y {
+            for mx in 0..mcus_x {
+                try!(self.write_restart(my * mcus_x + mx, actable));
+
+                let n = self.quantize_mcu(image, mx, my, bpp, num_components, &mut blocks);
+                for (j, block) in blocks[..n].iter().enumerate() {
+                    let i = if j < luma_blocks { 0 } else { j - luma_blocks + 1 };
+                    try!(self.encode_block(block, i, 0, 63, 0, 0));
                 }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Transforms and quantizes the image, the blocks of every component are stored row by row.
+    fn quantize_image(&mut self, image: &[u8], bpp: usize, num_components: usize) -> Vec<Vec<[i32; 64]>> {
+        let h = self.components[0].h as usize;
+        let v = self.components[0].v as usize;
+        let (mcus_x, mcus_y) = self.mcus();
 
-                let la = self.luma_actable.clone();
-                let ld = self.luma_dctable.clone();
-                let cd = self.chroma_dctable.clone();
-                let ca = self.chroma_actable.clone();
+        let mut blocks = vec![vec![[0i32; 64]; mcus_x * h * mcus_y * v]];
+        for _ in 1..num_components {
+            blocks.push(vec![[0i32; 64]; mcus_x * mcus_y]);
+        }
+
+        let mut mcu = [[0i32; 64]; 6];
+
+        for my in 0..mcus_y {
+            for mx in 0..mcus_x {
+                self.quantize_mcu(image, mx, my, bpp, num_components, &mut mcu);
+
+                if num_components == 1 {
+                    blocks[0][my * mcus_x + mx] = mcu[0];
+                    continue
+                }
 
-                y_dcprev  = try!(self.write_block(&dct_yblock, y_dcprev, &ld, &la));
-                cb_dcprev = try!(self.write_block(&dct_cb_block, cb_dcprev, &cd, &ca));
-                cr_dcprev = try!(self.write_block(&dct_cr_block, cr_dcprev, &cd, &ca));
+                for by in 0..v {
+                    for bx in 0..h {
+                        blocks[0][(my * v + by) * mcus_x * h + mx * h + bx] = mcu[by * h + bx];
+                    }
+                }
+
+                blocks[1][my * mcus_x + mx] = mcu[h * v];
+                blocks[2][my * mcus_x + mx] = mcu[h * v + 1];
             }
         }
 
+        blocks
+    }
+
+    // Transforms and quantizes the MCU at ```mx```, ```my``` into the luma blocks row by row
+    // followed by the chroma blocks, and returns the number of blocks.
+    // Partial MCUs at the right and bottom edges are filled up by repeating the edge pixels.
+    fn quantize_mcu(&self, image: &[u8], mx: usize, my: usize, bpp: usize,
+                    num_components: usize, blocks: &mut [[i32; 64]; 6]) -> usize {
+        let h = self.components[0].h as usize;
+        let v = self.components[0].v as usize;
+        let (width, height) = self.size;
+        let (x, y) = (mx * 8 * h, my * 8 * v);
+
+        let mut yblocks  = [[0u8; 64]; 4];
+        let mut cb_block = [0u8; 64];
+        let mut cr_block = [0u8; 64];
+
+        if num_components == 1 {
+            copy_blocks_gray(image, x, y, width, height, bpp, &mut yblocks[0]);
+            fdct_quantize(&yblocks[0], &self.tables[..64], &mut blocks[0]);
+            return 1
+        }
+
+        // RGB -> YCbCr
+        copy_blocks_ycbcr(image, x, y, width, height, bpp, h, v,
+                          &mut yblocks, &mut cb_block, &mut cr_block);
+
+        for j in 0..h * v {
+            fdct_quantize(&yblocks[j], &self.tables[..64], &mut blocks[j]);
+        }
+
+        fdct_quantize(&cb_block, &self.tables[64..], &mut blocks[h * v]);
+        fdct_quantize(&cr_block, &self.tables[64..], &mut blocks[h * v + 1]);
+
+        h * v + 2
+    }
+}
+
+impl<'a, W: Write> ImageEncoder for JPEGEncoder<'a, W> {
+    fn write_image(mut self, buf: &[u8], width: u32, height: u32, color: color::ColorType) -> ImageResult<()> {
+        try!(self.encode(buf, width, height, color));
+        Ok(())
+    }
+
+    fn write_image_with_metadata(mut self, buf: &[u8], width: u32, height: u32, color: color::ColorType,
+                                 metadata: &ImageMetadata) -> ImageResult<()> {
+        try!(self.encode_with_metadata(buf, width, height, color, metadata));
         Ok(())
     }
 }
 
-fn build_jfif_header() -> Vec<u8> {
+fn build_jfif_header(dpi: Option<(f64, f64)>) -> Vec<u8> {
     let mut m = Vec::new();
 
+    // Without a resolution only the aspect ratio of the pixels is given
+    let (units, x, y) = match dpi {
+        Some((x, y)) => (1, x.round().max(1.0).min(65535.0) as u16, y.round().max(1.0).min(65535.0) as u16),
+        None => (0, 1, 1)
+    };
+
     let _ = write!(m, "JFIF");
     let _ = m.write_all(&[0]);
     let _ = m.write_all(&[0x01]);
     let _ = m.write_all(&[0x02]);
-    let _ = m.write_all(&[0]);
-    let _ = m.write_u16::<BigEndian>(1);
-    let _ = m.write_u16::<BigEndian>(1);
+    let _ = m.write_all(&[units]);
+    let _ = m.write_u16::<BigEndian>(x);
+    let _ = m.write_u16::<BigEndian>(y);
     let _ = m.write_all(&[0]);
     let _ = m.write_all(&[0]);
 
     m
 }
 
+// The APPn segment that is identified by its ```header```
+fn app_segment(n: u8, header: &[u8], prefix: &[u8], data: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+    if n > APP15 - APP0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "application segments range from APP0 to APP15"))
+    }
+
+    let buf = try!(build_app_segment(header, prefix, data));
+    Ok((APP0 + n, buf))
+}
+
+// The APP2 segments of an ICC profile
+fn icc_segments(profile: &[u8]) -> io::Result<Vec<(u8, Vec<u8>)>> {
+    // Every chunk is preceded by its sequence number, starting at 1, and the number of chunks
+    let chunk_len = MAX_SEGMENT_LEN - ICC_HEADER.len() - 2;
+    let num_chunks = (profile.len() + chunk_len - 1) / chunk_len;
+    if num_chunks > 255 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "ICC profile too large"))
+    }
+
+    profile.chunks(chunk_len).enumerate().map(|(i, chunk)| {
+        app_segment(APP2 - APP0, ICC_HEADER, &[i as u8 + 1, num_chunks as u8], chunk)
+    }).collect()
+}
+
+// The APPn and COM segments that store ```metadata```
+fn metadata_segments(metadata: &ImageMetadata) -> io::Result<Vec<(u8, Vec<u8>)>> {
+    let mut segments = Vec::new();
+
+    if let Some(ref exif) = metadata.exif {
+        segments.push(try!(app_segment(APP1 - APP0, EXIF_HEADER, &[], exif)));
+    }
+
+    if let Some(ref xmp) = metadata.xmp {
+        segments.push(try!(app_segment(APP1 - APP0, XMP_HEADER, &[], xmp)));
+    }
+
+    if let Some(ref profile) = metadata.icc_profile {
+        segments.extend(try!(icc_segments(profile)));
+    }
+
+    for &(_, ref text) in metadata.text.iter().filter(|&&(ref key, _)| key == "Comment") {
+        segments.push((COM, try!(build_app_segment(&[], &[], text.as_bytes()))));
+    }
+
+    Ok(segments)
+}
+
+// The contents of a segment that is identified by its ```header```
+fn build_app_segment(header: &[u8], prefix: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    if header.len() + prefix.len() + data.len() > MAX_SEGMENT_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "metadata too large for a JPEG segment"))
+    }
+
+    let mut m = Vec::with_capacity(header.len() + prefix.len() + data.len());
+    m.extend_from_slice(header);
+    m.extend_from_slice(prefix);
+    m.extend_from_slice(data);
+
+    Ok(m)
+}
+
 fn build_frame_header(precision: u8,
                       width: u16,
                       height: u16,
@@ -502,7 +1138,7 @@ fn build_frame_header(precision: u8,
     m
 }
 
-fn build_scan_header(components: &[Component]) -> Vec<u8> {
+fn build_scan_header(components: &[Component], ss: u8, se: u8, ah: u8, al: u8) -> Vec<u8> {
     let mut m = Vec::new();
 
     let _ = m.write_all(&[components.len() as u8]);
@@ -514,13 +1150,23 @@ fn build_scan_header(components: &[Component]) -> Vec<u8> {
     }
 
     // spectral start and end, approx. high and low
-    let _ = m.write_all(&[0]);
-    let _ = m.write_all(&[63]);
-    let _ = m.write_all(&[0]);
+    let _ = m.write_all(&[ss]);
+    let _ = m.write_all(&[se]);
+    let _ = m.write_all(&[(ah << 4) | al]);
 
     m
 }
 
+// The code lengths and values of the Huffman tables of section K.3
+fn std_huffman_table(class: u8, destination: u8) -> (Vec<u8>, Vec<u8>) {
+    match (class == DCCLASS, destination == LUMADESTINATION) {
+        (true, true)   => (STD_LUMA_DC_CODE_LENGTHS.to_vec(), STD_LUMA_DC_VALUES.to_vec()),
+        (false, true)  => (STD_LUMA_AC_CODE_LENGTHS.to_vec(), STD_LUMA_AC_VALUES.to_vec()),
+        (true, false)  => (STD_CHROMA_DC_CODE_LENGTHS.to_vec(), STD_CHROMA_DC_VALUES.to_vec()),
+        (false, false) => (STD_CHROMA_AC_CODE_LENGTHS.to_vec(), STD_CHROMA_AC_VALUES.to_vec()),
+    }
+}
+
 fn build_huffman_segment(class: u8,
                          destination: u8,
                          numcodes: &[u8],
@@ -568,6 +1214,17 @@ fn build_quantization_segment(precision: u8,
     m
 }
 
+fn fdct_quantize(samples: &[u8; 64], qtable: &[u8], coefficients: &mut [i32; 64]) {
+    // Level shift and fdct
+    // Coeffs are scaled by 8
+    transform::fdct(samples, coefficients);
+
+    // Quantization
+    for i in 0usize..64 {
+        coefficients[i] = ((coefficients[i] / 8) as f32 / qtable[i] as f32).round() as i32;
+    }
+}
+
 fn encode_coefficient(coefficient: i32) -> (u8, u16) {
     let mut magnitude = coefficient.abs() as u16;
     let mut num_bits  = 0u8;
@@ -608,20 +1265,28 @@ fn value_at(s: &[u8], index: usize) -> u8 {
     }
 }
 
+// Converts the MCU at ```x0```, ```y0``` to h x v luma blocks and one block per chroma component.
+// Every chroma value is the average of h x v pixels, pixels outside of the image repeat the edge.
 fn copy_blocks_ycbcr(source: &[u8],
                      x0: usize,
                      y0: usize,
                      width: usize,
+                     height: usize,
                      bpp: usize,
-                     yb: &mut [u8; 64],
+                     h: usize,
+                     v: usize,
+                     yb: &mut [[u8; 64]; 4],
                      cbb: &mut [u8; 64],
                      crb: &mut [u8; 64]) {
 
-    for y in 0usize..8 {
-        let ystride = (y0 + y) * bpp * width;
+    let mut cb_sum = [0u32; 64];
+    let mut cr_sum = [0u32; 64];
 
-        for x in 0usize..8 {
-            let xstride = x0 * bpp + x * bpp;
+    for y in 0usize..8 * v {
+        let ystride = cmp::min(y0 + y, height - 1) * bpp * width;
+
+        for x in 0usize..8 * h {
+            let xstride = cmp::min(x0 + x, width - 1) * bpp;
 
             let r = value_at(source, ystride + xstride + 0);
             let g = value_at(source, ystride + xstride + 1);
@@ -629,26 +1294,305 @@ fn copy_blocks_ycbcr(source: &[u8],
 
             let (yc, cb, cr) = rgb_to_ycbcr(r, g, b);
 
-            yb[y * 8 + x]  = yc;
-            cbb[y * 8 + x] = cb;
-            crb[y * 8 + x] = cr;
+            yb[(y / 8) * h + x / 8][(y % 8) * 8 + x % 8] = yc;
+            cb_sum[(y / v) * 8 + x / h] += cb as u32;
+            cr_sum[(y / v) * 8 + x / h] += cr as u32;
         }
     }
+
+    let n = (h * v) as u32;
+    for i in 0usize..64 {
+        cbb[i] = ((cb_sum[i] + n / 2) / n) as u8;
+        crb[i] = ((cr_sum[i] + n / 2) / n) as u8;
+    }
 }
 
+// Copies the block at ```x0```, ```y0``` of a gray image, pixels outside of the image repeat the edge.
 fn copy_blocks_gray(source: &[u8],
                     x0: usize,
                     y0: usize,
                     width: usize,
+                    height: usize,
                     bpp: usize,
                     gb: &mut [u8; 64]) {
 
     for y in 0usize..8 {
-        let ystride = (y0 + y) * bpp * width;
+        let ystride = cmp::min(y0 + y, height - 1) * bpp * width;
 
         for x in 0usize..8 {
-            let xstride = x0 * bpp + x * bpp;
-            gb[y * 8 + x] = value_at(source, ystride + xstride + 1);
+            let xstride = cmp::min(x0 + x, width - 1) * bpp;
+            gb[y * 8 + x] = value_at(source, ystride + xstride);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use color::ColorType;
+    use image::{DecodingResult, ImageDecoder, ImageMetadata};
+    use super::{JPEGEncoder, Subsampling};
+    use super::super::JPEGDecoder;
+
+    // A smooth image with some detail in the middle
+    fn gradient(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                let detail = if (x / 4 + y / 4) % 2 == 0 { 40 } else { 0 };
+                data.push((x * 255 / width) as u8);
+                data.push((y * 255 / height) as u8);
+                data.push(((x + y) * 100 / (width + height)) as u8 + detail);
+            }
+        }
+        data
+    }
+
+    fn decode(data: &[u8]) -> Vec<u8> {
+        match JPEGDecoder::new(data).read_image().unwrap() {
+            DecodingResult::U8(data) => data,
+            DecodingResult::U16(_) => panic!("wrong sample type")
+        }
+    }
+
+    #[test]
+    fn test_quality() {
+        let image = gradient(64, 64);
+        let sizes: Vec<usize> = [100, 95, 90, 75, 50, 25, 10, 1].iter().map(|&quality| {
+            let mut data = Vec::new();
+            JPEGEncoder::new_with_quality(&mut data, quality).encode(&image, 64, 64, ColorType::RGB(8)).unwrap();
+            data.len()
+        }).collect();
+
+        assert!(sizes.windows(2).all(|s| s[0] > s[1]), "{:?}", sizes);
+    }
+
+    #[test]
+    fn test_custom_tables() {
+        let image = gradient(32, 32);
+        let mut luma = [1u8; 64];
+        luma[63] = 0;
+        let chroma = [2u8; 64];
+
+        let mut data = Vec::new();
+        {
+            let mut encoder = JPEGEncoder::new_with_tables(&mut data, &luma, &chroma);
+            encoder.set_subsampling(Subsampling::Ratio444);
+            encoder.encode(&image, 32, 32, ColorType::RGB(8)).unwrap();
+        }
+
+        // The tables follow their DQT markers, a value of 0 is stored as 1
+        let dqt: Vec<usize> = data.windows(2).enumerate()
+            .filter(|&(_, m)| m == [0xFF, 0xDB]).map(|(i, _)| i).collect();
+        assert_eq!(dqt.len(), 2);
+        assert!(data[dqt[0] + 5..dqt[0] + 69].iter().all(|&v| v == 1));
+        assert!(data[dqt[1] + 5..dqt[1] + 69].iter().all(|&v| v == 2));
+
+        // Fine tables keep the image close to the original
+        let decoded = decode(&data);
+        let max_diff = image.iter().zip(decoded.iter()).map(|(&a, &b)| (a as i32 - b as i32).abs()).max();
+        assert!(max_diff.unwrap() <= 8);
+    }
+
+    #[test]
+    fn test_subsampling() {
+        // Odd dimensions leave partial MCUs at the right and bottom edges
+        let image = gradient(37, 21);
+        let encode = |subsampling| {
+            let mut data = Vec::new();
+            {
+                let mut encoder = JPEGEncoder::new_with_quality(&mut data, 90);
+                encoder.set_subsampling(subsampling);
+                encoder.encode(&image, 37, 21, ColorType::RGB(8)).unwrap();
+            }
+            data
+        };
+
+        let mut sizes = Vec::new();
+        for &(subsampling, factors) in [(Subsampling::Ratio444, 0x11),
+                                        (Subsampling::Ratio422, 0x21),
+                                        (Subsampling::Ratio420, 0x22)].iter() {
+            let data = encode(subsampling);
+
+            // The sampling factors of the luma component follow its id in the frame header
+            let sof = data.windows(2).position(|m| m == [0xFF, 0xC0]).unwrap();
+            assert_eq!(&data[sof + 10..sof + 19], &[1, factors, 0, 2, 0x11, 1, 3, 0x11, 1]);
+
+            let decoded = decode(&data);
+            assert_eq!(decoded.len(), image.len());
+            let max_diff = image.iter().zip(decoded.iter()).map(|(&a, &b)| (a as i32 - b as i32).abs()).max();
+            assert!(max_diff.unwrap() <= 48, "{:?}: {:?}", subsampling, max_diff);
+
+            sizes.push(data.len());
+        }
+
+        assert!(sizes.windows(2).all(|s| s[0] > s[1]), "{:?}", sizes);
+    }
+
+    #[test]
+    fn test_gray() {
+        // Odd dimensions leave partial blocks at the right and bottom edges
+        let image = gradient(37, 21);
+        let gray: Vec<u8> = image.chunks(3).map(|p| p[2]).collect();
+        let gray_alpha: Vec<u8> = gray.iter().flat_map(|&l| vec![l, 255 - l]).collect();
+
+        for &(data, color) in [(&gray, ColorType::Gray(8)), (&gray_alpha, ColorType::GrayA(8))].iter() {
+            let mut encoded = Vec::new();
+            JPEGEncoder::new_with_quality(&mut encoded, 100).encode(data, 37, 21, color).unwrap();
+
+            let decoded = decode(&encoded);
+            assert_eq!(decoded.len(), gray.len());
+            let max_diff = gray.iter().zip(decoded.iter()).map(|(&a, &b)| (a as i32 - b as i32).abs()).max();
+            assert!(max_diff.unwrap() <= 4, "{:?}: {:?}", color, max_diff);
+        }
+    }
+
+    #[test]
+    fn test_optimize_huffman() {
+        let image = gradient(48, 40);
+        let gray: Vec<u8> = image.chunks(3).map(|p| p[1]).collect();
+
+        for &(data, color) in [(&image, ColorType::RGB(8)), (&gray, ColorType::Gray(8))].iter() {
+            let encode = |optimize| {
+                let mut encoded = Vec::new();
+                {
+                    let mut encoder = JPEGEncoder::new_with_quality(&mut encoded, 85);
+                    encoder.set_optimize_huffman(optimize);
+                    encoder.encode(data, 48, 40, color).unwrap();
+                }
+                encoded
+            };
+
+            let standard = encode(false);
+            let optimized = encode(true);
+            assert!(optimized.len() < standard.len(), "{} >= {}", optimized.len(), standard.len());
+
+            // Only the codes differ, the coefficients and thus the pixels are the same
+            assert_eq!(decode(&optimized), decode(&standard));
+        }
+    }
+
+    #[test]
+    fn test_progressive() {
+        // Odd dimensions leave partial blocks that are not part of the scans of single components
+        let image = gradient(45, 27);
+        let gray: Vec<u8> = image.chunks(3).map(|p| p[1]).collect();
+
+        for &subsampling in [Subsampling::Ratio444, Subsampling::Ratio420].iter() {
+            for &(data, color) in [(&image, ColorType::RGB(8)), (&gray, ColorType::Gray(8))].iter() {
+                let encode = |progressive| {
+                    let mut encoded = Vec::new();
+                    {
+                        let mut encoder = JPEGEncoder::new_with_quality(&mut encoded, 90);
+                        encoder.set_subsampling(subsampling);
+                        encoder.set_progressive(progressive);
+                        encoder.encode(data, 45, 27, color).unwrap();
+                    }
+                    encoded
+                };
+
+                let baseline = encode(false);
+                let progressive = encode(true);
+
+                let markers = |data: &[u8], marker| data.windows(2).filter(|m| m == &[0xFF, marker]).count();
+                assert_eq!(markers(&progressive, 0xC0), 0);
+                assert_eq!(markers(&progressive, 0xC2), 1);
+                assert_eq!(markers(&progressive, 0xDA), if data.len() == image.len() { 10 } else { 6 });
+
+                // The successive approximation restores every bit of the coefficients
+                assert_eq!(decode(&progressive), decode(&baseline));
+            }
+        }
+    }
+
+    #[test]
+    fn test_restart_interval() {
+        // 3 x 2 MCUs of 16 x 16 pixels
+        let image = gradient(45, 27);
+
+        for &progressive in [false, true].iter() {
+            let encode = |interval| {
+                let mut encoded = Vec::new();
+                {
+                    let mut encoder = JPEGEncoder::new_with_quality(&mut encoded, 90);
+                    encoder.set_progressive(progressive);
+                    encoder.set_restart_interval(interval);
+                    encoder.encode(&image, 45, 27, ColorType::RGB(8)).unwrap();
+                }
+                encoded
+            };
+
+            let plain = encode(0);
+            let restarts = encode(2);
+
+            let dri = restarts.windows(2).position(|m| m == [0xFF, 0xDD]).unwrap();
+            assert_eq!(&restarts[dri + 2..dri + 6], &[0, 4, 0, 2]);
+            assert!(!plain.windows(2).any(|m| m == [0xFF, 0xDD]));
+
+            // RST0 and RST1 in the interleaved scan of 6 MCUs, the progressive scans
+            // of a single component count every block as a MCU
+            let rst: Vec<u8> = restarts.windows(2).filter(|m| m[0] == 0xFF && m[1] & 0xF8 == 0xD0)
+                                                  .map(|m| m[1]).collect();
+            if progressive {
+                assert!(rst.len() > 10);
+                assert!(rst.contains(&0xD7));
+            } else {
+                assert_eq!(rst, vec![0xD0, 0xD1]);
+            }
+
+            assert_eq!(decode(&restarts), decode(&plain));
+        }
+    }
+
+    #[test]
+    fn test_segments() {
+        let image = gradient(16, 16);
+        let profile: Vec<u8> = (0..100000).map(|i| i as u8).collect();
+
+        let mut data = Vec::new();
+        {
+            let mut encoder = JPEGEncoder::new(&mut data);
+            encoder.add_app_segment(1, b"Exif\0\0MM\0\x2a\0\0\0\x08\0\0").unwrap();
+            encoder.add_app_segment(11, b"Custom").unwrap();
+            encoder.add_icc_profile(&profile).unwrap();
+            encoder.add_comment(b"Hello").unwrap();
+
+            assert!(encoder.add_app_segment(16, b"").is_err());
+            assert!(encoder.add_comment(&vec![0; 70000]).is_err());
+
+            let metadata = ImageMetadata {
+                text: vec![("Comment".to_string(), "World".to_string())],
+                ..Default::default()
+            };
+            encoder.encode_with_metadata(&image, 16, 16, ColorType::RGB(8), &metadata).unwrap();
+        }
+
+        // The added segments follow the JFIF header, the metadata comes last
+        let mut markers = Vec::new();
+        let mut i = 2;
+        while data[i + 1] & 0xF0 == 0xE0 || data[i + 1] == 0xFE {
+            markers.push(data[i + 1]);
+            i += 2 + ((data[i + 2] as usize) << 8 | data[i + 3] as usize);
+        }
+        assert_eq!(&markers[..], &[0xE0, 0xE1, 0xEB, 0xE2, 0xE2, 0xFE, 0xFE]);
+
+        let metadata = JPEGDecoder::new(&data[..]).metadata().unwrap();
+        assert_eq!(metadata.exif, Some(b"MM\0\x2a\0\0\0\x08\0\0".to_vec()));
+        assert_eq!(metadata.icc_profile, Some(profile));
+        assert_eq!(metadata.text, vec![("Comment".to_string(), "Hello".to_string()),
+                                       ("Comment".to_string(), "World".to_string())]);
+    }
+
+    #[test]
+    fn test_empty_image() {
+        for &(optimize, progressive) in [(false, false), (true, false), (false, true)].iter() {
+            let mut data = Vec::new();
+            {
+                let mut encoder = JPEGEncoder::new(&mut data);
+                encoder.set_optimize_huffman(optimize);
+                encoder.set_progressive(progressive);
+                encoder.encode(&[], 0, 0, ColorType::RGB(8)).unwrap();
+            }
+            assert_eq!(&data[data.len() - 2..], &[0xFF, 0xD9]);
         }
     }
 }
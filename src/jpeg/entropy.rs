@@ -59,3 +59,150 @@ pub fn build_huff_lut(bits: &[u8], huffval: &[u8]) -> Vec<(u8, u16)> {
 
     lut
 }
+
+/// Builds the Huffman table with the shortest codes for symbols that occur
+/// ```freq[symbol]``` times, no code is longer than 16 bits.
+/// The entry at 256 is reserved, it keeps any code from consisting of ones only.
+/// Returns the number of codes of each length and the symbols ordered by code length.
+pub fn build_optimal_table(freq: &[u32; 257]) -> (Vec<u8>, Vec<u8>) {
+    let mut freq = *freq;
+    let mut codesize = [0usize; 257];
+    let mut others = [None::<usize>; 257];
+
+    freq[256] = 1;
+
+    // Annex K.2
+    // Figure K.1
+    // Find the code sizes by merging the two least frequent symbols until one is left
+    loop {
+        // Ties are broken in favour of the larger symbol
+        let mut c1 = None;
+        let mut c2 = None;
+
+        for i in 0usize..257 {
+            if freq[i] == 0 {
+                continue
+            }
+
+            if c1.map_or(true, |c: usize| freq[i] <= freq[c]) {
+                c2 = c1;
+                c1 = Some(i);
+            } else if c2.map_or(true, |c: usize| freq[i] <= freq[c]) {
+                c2 = Some(i);
+            }
+        }
+
+        let (mut c1, mut c2) = match (c1, c2) {
+            (Some(c1), Some(c2)) => (c1, c2),
+            _ => break
+        };
+
+        freq[c1] += freq[c2];
+        freq[c2] = 0;
+
+        codesize[c1] += 1;
+        while let Some(c) = others[c1] {
+            c1 = c;
+            codesize[c1] += 1;
+        }
+
+        others[c1] = Some(c2);
+
+        codesize[c2] += 1;
+        while let Some(c) = others[c2] {
+            c2 = c;
+            codesize[c2] += 1;
+        }
+    }
+
+    // Figure K.2
+    // Count the codes of each size
+    let mut bits = vec![0u32; 258];
+    for &size in codesize.iter().filter(|&&size| size > 0) {
+        bits[size] += 1;
+    }
+
+    // Figure K.3
+    // Shorten codes longer than 16 bits, two codes of the longest size are replaced
+    // by one code of the next shorter size and a shorter code becomes two longer codes
+    for i in (17usize..258).rev() {
+        while bits[i] > 0 {
+            let mut j = i - 2;
+            while bits[j] == 0 {
+                j -= 1;
+            }
+
+            bits[i] -= 2;
+            bits[i - 1] += 1;
+            bits[j + 1] += 2;
+            bits[j] -= 1;
+        }
+    }
+
+    // Remove the code of the reserved symbol, it is one of the longest codes.
+    // Without any other symbol it has no code and the table stays empty.
+    let mut i = 16;
+    while i > 0 && bits[i] == 0 {
+        i -= 1;
+    }
+    if i > 0 {
+        bits[i] -= 1;
+    }
+
+    // Figure K.4
+    // Order the symbols by code size
+    let mut huffval = Vec::new();
+    for size in 1usize..258 {
+        for symbol in 0usize..256 {
+            if codesize[symbol] == size {
+                huffval.push(symbol as u8);
+            }
+        }
+    }
+
+    (bits[1..17].iter().map(|&n| n as u8).collect(), huffval)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{build_huff_lut, build_optimal_table};
+
+    #[test]
+    fn test_optimal_table() {
+        // Frequencies that follow the Fibonacci sequence give codes longer than 16 bits
+        let mut freq = [0u32; 257];
+        let (mut a, mut b) = (1, 1);
+        for i in 0..30 {
+            freq[i] = a;
+            let c = a + b;
+            a = b;
+            b = c;
+        }
+
+        let (bits, values) = build_optimal_table(&freq);
+        assert_eq!(bits.len(), 16);
+        assert_eq!(bits.iter().map(|&n| n as usize).sum::<usize>(), 30);
+        assert_eq!(values.len(), 30);
+
+        // The most frequent symbol gets one of the shortest codes
+        assert!(values[..2].contains(&29));
+
+        // The codes are a prefix code that uses no code of ones only
+        let lut = build_huff_lut(&bits, &values);
+        let kraft: f64 = values.iter().map(|&v| 0.5f64.powi(lut[v as usize].0 as i32)).sum();
+        assert!(kraft < 1.0);
+        for &v in values.iter() {
+            let (size, code) = lut[v as usize];
+            assert!(size <= 16);
+            assert!(code as u32 != (1u32 << size) - 1);
+        }
+
+        // Without symbols the table is empty
+        assert_eq!(build_optimal_table(&[0; 257]), (vec![0; 16], vec![]));
+
+        // A single symbol still gets a code
+        let mut freq = [0u32; 257];
+        freq[5] = 10;
+        assert_eq!(build_optimal_table(&freq), ({ let mut b = vec![0; 16]; b[0] = 1; b }, vec![5]));
+    }
+}
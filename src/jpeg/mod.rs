@@ -8,7 +8,7 @@
 //!
 
 pub use self::decoder::JPEGDecoder;
-pub use self::encoder::JPEGEncoder;
+pub use self::encoder::{JPEGEncoder, Subsampling};
 
 mod encoder;
 mod decoder;
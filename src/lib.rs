@@ -32,7 +32,10 @@ pub use color::{
 
 pub use image::{
     ImageDecoder,
+    ImageEncoder,
     ImageError,
+    DecodingResult,
+    UnsupportedFeature,
     ImageResult,
     SubImage,
     GenericImage,
@@ -61,6 +64,8 @@ pub use image::ImageFormat::{
     ICO
 };
 
+pub use image::{EncoderOptions, ImageMetadata, Limits, Orientation};
+
 pub use buffer::{
     Pixel,
     ConvertBuffer,
@@ -78,10 +83,17 @@ pub use traits::Primitive;
 // Opening and loading images
 pub use dynimage::{
     open,
+    open_with_limits,
+    open_with_orientation,
     load,
+    load_with_limits,
+    load_with_metadata,
+    load_with_orientation,
     load_from_memory,
     load_from_memory_with_format,
-    save_buffer
+    guess_format,
+    save_buffer,
+    save_buffer_with_options
 };
 
 pub use dynimage::DynamicImage::{
@@ -89,7 +101,11 @@ pub use dynimage::DynamicImage::{
     ImageRgb8,
     ImageRgba8,
     ImageLuma8,
-    ImageLumaA8
+    ImageLumaA8,
+    ImageRgb16,
+    ImageRgba16,
+    ImageLuma16,
+    ImageLumaA16
 };
 
 pub use animation::{
@@ -97,6 +113,13 @@ pub use animation::{
     Frames
 };
 
+// Decoding of partially received images
+pub use incremental::{
+    IncrementalDecoder,
+    Progress,
+    Rows
+};
+
 // Math utils
 pub mod math;
 
@@ -130,6 +153,7 @@ mod color;
 mod buffer;
 mod traits;
 mod animation;
+mod incremental;
 
 // Copies data from `src` to `dst`
 //
@@ -137,6 +137,17 @@ impl NeuQuant {
         }
     }
 
+    /// Returns the color map as consecutive RGB triplets
+    pub fn color_map_rgb(&self) -> Vec<u8> {
+        let mut map = Vec::with_capacity(self.netsize * 3);
+        for c in self.colormap.iter() {
+            map.push(c.r as u8);
+            map.push(c.g as u8);
+            map.push(c.b as u8);
+        }
+        map
+    }
+
     /// Move neuron i towards biased (a,b,g,r) by factor alpha
     fn altersingle(&mut self, alpha: f64, i: i32, quad: Quad<f64>) {
         let n = &mut self.network[i as usize];
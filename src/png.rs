@@ -2,56 +2,506 @@
 //! Decoding and Encoding of PNG Images
 //!
 //! PNG (Portable Network Graphics) is an image format that supports lossless compression.
+//! Animated PNGs (APNG) are supported as well.
 //!
 //! # Related Links
 //! * http://www.w3.org/TR/PNG/ - The PNG Specification
+//! * https://wiki.mozilla.org/APNG_Specification - The APNG Specification
 //!
 
 extern crate png;
+extern crate flate2;
 
 use self::png::HasParameters;
+use self::flate2::write::ZlibEncoder;
+use self::flate2::read::ZlibDecoder;
 
+use std::cmp;
+use std::iter;
 use std::io::{self, Read, Write};
+use byteorder::{ByteOrder, BigEndian};
+use num::rational::Ratio;
 
-use image::{ImageError, ImageResult, DecodingResult, ImageDecoder};
-use color::ColorType;
+use image::{ImageError, ImageFormat, ImageResult, DecodingResult, ImageDecoder, ImageEncoder, ImageMetadata, Limits};
+use image::{check_rect, crop_rect, exif_orientation, load_rect_from_image};
+use buffer::{ImageBuffer, RgbaImage, Pixel};
+use color::{self, ColorType, Rgba};
+use animation::{Frame, Frames};
+use dynimage::decoder_to_image;
+
+const SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
 
 enum Either<T, U> {
     Left(T),
     Right(U)
 }
 
+// The chunks that have been read already followed by the rest of the stream
+type Stream<R> = io::Chain<io::Cursor<Vec<u8>>, R>;
+
 /// PNG decoder
 pub struct PNGDecoder<R: Read> {
-    inner: Option<Either<png::Decoder<R>, png::Reader<R>>>
+    inner: Option<Either<R, png::Reader<Stream<R>>>>,
+
+    // The chunks in front of the image data, or the whole file of an animation.
+    // They are kept for the metadata.
+    data: Vec<u8>,
+    animated: bool,
+    limits: Limits,
 }
 
 impl<R: Read> PNGDecoder<R> {
     /// Creates a new decoder that decodes from the stream ```r```
     pub fn new(r: R) -> PNGDecoder<R> {
         PNGDecoder {
-            inner: Some(Either::Left(png::Decoder::new(r)))
+            inner: Some(Either::Left(r)),
+            data: Vec::new(),
+            animated: false,
+            limits: Limits::default(),
         }
     }
 
-    // Converts the inner decoder to a reader
-    fn get_reader(&mut self) -> Result<&mut png::Reader<R>, png::DecodingError> {
-        let inner = self.inner.take().unwrap();
-        self.inner = Some(match inner {
-            Either::Left(decoder) => {
-                let (_, reader) = try!(decoder.read_info());
-                Either::Right(reader)
-            },
-            Either::Right(reader) => Either::Right(reader)
-        });
+    // Converts the stream to a reader
+    fn get_reader(&mut self) -> ImageResult<&mut png::Reader<Stream<R>>> {
+        if let Some(Either::Left(_)) = self.inner {
+            let mut r = match self.inner.take() {
+                Some(Either::Left(r)) => r,
+                _ => unreachable!()
+            };
+
+            try!(self.read_chunks(&mut r));
+
+            let mut decoder = png::Decoder::new(io::Cursor::new(self.data.clone()).chain(r));
+            // Keep 16-bit samples instead of stripping them to 8 bits
+            decoder.set(png::TRANSFORM_EXPAND);
+            let (_, reader) = try!(decoder.read_info());
+            self.inner = Some(Either::Right(reader));
+        }
+
         match self.inner {
             Some(Either::Right(ref mut reader)) => Ok(reader),
             _ => unreachable!()
         }
     }
+
+    // Reads the chunks in front of the image data to find an acTL chunk.
+    // Animations are read completely within the limits, their frames are decoded later on.
+    // The png decoder reports malformed files.
+    fn read_chunks(&mut self, r: &mut R) -> ImageResult<()> {
+        if try!(r.by_ref().take(8).read_to_end(&mut self.data)) < 8 || self.data != SIGNATURE {
+            return Ok(())
+        }
+
+        loop {
+            let start = self.data.len();
+            if try!(r.by_ref().take(8).read_to_end(&mut self.data)) < 8 {
+                return Ok(())
+            }
+
+            let length = read_u32(&self.data[start..]) as u64;
+            match &self.data[start + 4..start + 8] {
+                b"IDAT" => return Ok(()),
+                b"acTL" => {
+                    self.animated = true;
+
+                    let max = self.limits.max_alloc.unwrap_or(u64::max_value());
+                    try!(r.by_ref().take(max).read_to_end(&mut self.data));
+                    if self.data.len() as u64 >= max {
+                        return Err(ImageError::LimitsExceeded)
+                    }
+                    return Ok(())
+                }
+                _ => ()
+            }
+
+            if try!(r.by_ref().take(length + 4).read_to_end(&mut self.data)) < length as usize + 4 {
+                return Ok(())
+            }
+        }
+    }
+}
+
+// The fcTL chunk of a frame
+struct FrameControl {
+    width: u32,
+    height: u32,
+    left: u32,
+    top: u32,
+    delay: Ratio<u16>,
+    dispose_op: u8,
+    blend_op: u8,
+}
+
+const DISPOSE_OP_BACKGROUND: u8 = 1;
+const DISPOSE_OP_PREVIOUS: u8 = 2;
+const BLEND_OP_SOURCE: u8 = 0;
+const BLEND_OP_OVER: u8 = 1;
+
+impl FrameControl {
+    fn from_chunk(data: &[u8]) -> ImageResult<FrameControl> {
+        if data.len() < 26 {
+            return Err(ImageError::malformed(ImageFormat::PNG, "fcTL chunk too short."))
+        }
+
+        // A denominator of 0 stands for hundredths of a second
+        let (numer, denom) = (read_u16(&data[20..]), read_u16(&data[22..]));
+
+        Ok(FrameControl {
+            width: read_u32(&data[4..]),
+            height: read_u32(&data[8..]),
+            left: read_u32(&data[12..]),
+            top: read_u32(&data[16..]),
+            delay: Ratio::new(numer, if denom == 0 { 100 } else { denom }),
+            dispose_op: data[24],
+            blend_op: data[25],
+        })
+    }
+
+    fn to_chunk(&self, sequence_number: u32) -> Vec<u8> {
+        let mut data = vec![0; 26];
+        BigEndian::write_u32(&mut data[0..], sequence_number);
+        BigEndian::write_u32(&mut data[4..], self.width);
+        BigEndian::write_u32(&mut data[8..], self.height);
+        BigEndian::write_u32(&mut data[12..], self.left);
+        BigEndian::write_u32(&mut data[16..], self.top);
+        BigEndian::write_u16(&mut data[20..], *self.delay.numer());
+        BigEndian::write_u16(&mut data[22..], *self.delay.denom());
+        data[24] = self.dispose_op;
+        data[25] = self.blend_op;
+        data
+    }
+}
+
+// Returns the type and the contents of the chunk at ```pos``` and the position of the next chunk
+fn read_chunk(data: &[u8], pos: usize) -> ImageResult<([u8; 4], &[u8], usize)> {
+    if data.len() < pos + 12 {
+        return Err(ImageError::malformed(ImageFormat::PNG, "PNG chunk truncated."))
+    }
+
+    let length = read_u32(&data[pos..]) as usize;
+    if data.len() - pos - 12 < length {
+        return Err(ImageError::malformed(ImageFormat::PNG, "PNG chunk truncated."))
+    }
+
+    let kind = [data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]];
+    Ok((kind, &data[pos + 8..pos + 8 + length], pos + 12 + length))
+}
+
+// Big endian integers at the start of ```data```, which need not be aligned
+fn read_u16(data: &[u8]) -> u16 {
+    (data[0] as u16) << 8 | data[1] as u16
+}
+
+// The samples of decoded rows
+fn decoding_result(data: Vec<u8>, bit_depth: png::BitDepth) -> DecodingResult {
+    match bit_depth {
+        png::BitDepth::Sixteen => {
+            // PNG stores samples in network byte order
+            DecodingResult::U16(data.chunks(2).map(|v|
+                (v[0] as u16) << 8 | v[1] as u16
+            ).collect())
+        }
+        _ => DecodingResult::U8(data)
+    }
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    (read_u16(data) as u32) << 16 | read_u16(&data[2..]) as u32
+}
+
+fn write_chunk(w: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+    let mut length = [0; 4];
+    BigEndian::write_u32(&mut length, data.len() as u32);
+
+    w.extend_from_slice(&length);
+    w.extend_from_slice(kind);
+    w.extend_from_slice(data);
+
+    let mut crc = [0; 4];
+    BigEndian::write_u32(&mut crc, crc32(&[kind, data]));
+    w.extend_from_slice(&crc);
+}
+
+// The CRC of the PNG specification over the concatenation of ```parts```
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = 0xffffffffu32;
+
+    for part in parts.iter() {
+        for &byte in part.iter() {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 { 0xedb88320 ^ (crc >> 1) } else { crc >> 1 };
+            }
+        }
+    }
+
+    crc ^ 0xffffffff
+}
+
+// The metadata in the chunks in front of the image data
+fn read_metadata(data: &[u8], limits: Limits) -> ImageResult<ImageMetadata> {
+    let mut metadata = ImageMetadata::default();
+    let mut pos = SIGNATURE.len();
+
+    while data.len() >= pos + 8 && &data[pos + 4..pos + 8] != b"IDAT" {
+        let (kind, chunk, next) = try!(read_chunk(data, pos));
+
+        match &kind {
+            // A unit of 1 stands for pixels per metre, there is no resolution otherwise
+            b"pHYs" if chunk.len() >= 9 && chunk[8] == 1 => {
+                let (x, y) = (read_u32(&chunk[0..]), read_u32(&chunk[4..]));
+                metadata.dpi = Some((x as f64 * 0.0254, y as f64 * 0.0254));
+            }
+            b"tEXt" => {
+                let (keyword, text) = try!(split_keyword(chunk));
+                metadata.text.push((keyword, latin1(text)));
+            }
+            b"zTXt" => {
+                let (keyword, rest) = try!(split_keyword(chunk));
+                // The compression method precedes the compressed text
+                let text = try!(inflate(rest.get(1..).unwrap_or(&[]), limits));
+                metadata.text.push((keyword, latin1(&text)));
+            }
+            b"iTXt" => {
+                let (keyword, rest) = try!(split_keyword(chunk));
+                if rest.len() < 2 {
+                    return Err(ImageError::malformed(ImageFormat::PNG, "iTXt chunk too short."))
+                }
+
+                // The language tag and the translated keyword are skipped
+                let compressed = rest[0] == 1;
+                let mut fields = rest[2..].splitn(3, |&b| b == 0);
+                let text = match (fields.next(), fields.next(), fields.next()) {
+                    (Some(_), Some(_), Some(text)) => text,
+                    _ => return Err(ImageError::malformed(ImageFormat::PNG, "iTXt chunk too short."))
+                };
+                let text = if compressed { try!(inflate(text, limits)) } else { text.to_vec() };
+
+                if keyword == XMP_KEYWORD {
+                    metadata.xmp = Some(text);
+                } else {
+                    metadata.text.push((keyword, String::from_utf8_lossy(&text).into_owned()));
+                }
+            }
+            b"iCCP" => {
+                let (_, rest) = try!(split_keyword(chunk));
+                metadata.icc_profile = Some(try!(inflate(rest.get(1..).unwrap_or(&[]), limits)));
+            }
+            b"eXIf" => {
+                metadata.exif = Some(chunk.to_vec());
+                metadata.orientation = exif_orientation(chunk);
+            }
+            _ => ()
+        }
+
+        pos = next;
+    }
+
+    Ok(metadata)
+}
+
+// The keyword of text and profile chunks is terminated by a null byte
+fn split_keyword(chunk: &[u8]) -> ImageResult<(String, &[u8])> {
+    match chunk.iter().position(|&b| b == 0) {
+        Some(i) => Ok((latin1(&chunk[..i]), &chunk[i + 1..])),
+        _ => Err(ImageError::malformed(ImageFormat::PNG, "Keyword of PNG chunk not terminated."))
+    }
+}
+
+fn latin1(data: &[u8]) -> String {
+    data.iter().map(|&b| b as char).collect()
+}
+
+// Decompresses the zlib stream of a text or profile chunk
+fn inflate(data: &[u8], limits: Limits) -> ImageResult<Vec<u8>> {
+    let max = limits.max_alloc.unwrap_or(u64::max_value());
+    let mut out = Vec::new();
+    try!(ZlibDecoder::new(data).take(max).read_to_end(&mut out));
+
+    if out.len() as u64 == max {
+        return Err(ImageError::LimitsExceeded)
+    }
+
+    Ok(out)
+}
+
+// The keyword of the iTXt chunk that holds an XMP packet
+const XMP_KEYWORD: &'static str = "XML:com.adobe.xmp";
+
+// Writes the chunks that hold ```metadata```
+fn write_metadata<W: Write>(writer: &mut png::Writer<W>, metadata: &ImageMetadata) -> io::Result<()> {
+    if let Some(ref profile) = metadata.icc_profile {
+        let mut data = b"ICC profile\0\0".to_vec();
+        data.extend_from_slice(&try!(deflate(profile)));
+        try!(writer.write_chunk(*b"iCCP", &data));
+    }
+
+    if let Some((x, y)) = metadata.dpi {
+        let mut data = [0; 9];
+        BigEndian::write_u32(&mut data[0..], (x / 0.0254).round() as u32);
+        BigEndian::write_u32(&mut data[4..], (y / 0.0254).round() as u32);
+        data[8] = 1;
+        try!(writer.write_chunk(*b"pHYs", &data));
+    }
+
+    for &(ref keyword, ref text) in metadata.text.iter() {
+        try!(write_text(writer, keyword, text.as_bytes()));
+    }
+
+    if let Some(ref exif) = metadata.exif {
+        try!(writer.write_chunk(*b"eXIf", exif));
+    }
+
+    if let Some(ref xmp) = metadata.xmp {
+        try!(write_text(writer, XMP_KEYWORD, xmp));
+    }
+
+    Ok(())
+}
+
+// Text in ASCII is stored in a tEXt chunk, any other text in an uncompressed iTXt chunk
+fn write_text<W: Write>(writer: &mut png::Writer<W>, keyword: &str, text: &[u8]) -> io::Result<()> {
+    if keyword.is_empty() || keyword.len() > 79 || !keyword.is_ascii() || keyword.contains('\0') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            &format!("Invalid PNG keyword {:?}.", keyword)[..]))
+    }
+
+    let mut data = keyword.as_bytes().to_vec();
+    data.push(0);
+
+    if text.is_ascii() && keyword != XMP_KEYWORD {
+        data.extend_from_slice(text);
+        return writer.write_chunk(*b"tEXt", &data).map_err(|e| e.into())
+    }
+
+    // No compression, language tag or translated keyword
+    data.extend_from_slice(&[0, 0, 0, 0]);
+    data.extend_from_slice(text);
+    writer.write_chunk(*b"iTXt", &data).map_err(|e| e.into())
+}
+
+fn deflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::Default);
+    try!(encoder.write_all(data));
+    encoder.finish()
+}
+
+// Decodes the frames of an animated PNG and draws them onto a canvas of the
+// size of the image, every returned frame holds the whole canvas at an offset of 0.
+fn read_frames(data: &[u8], mut limits: Limits) -> ImageResult<Vec<Frame>> {
+    let mut header = None;
+    let mut palette = Vec::new();
+    let mut frames: Vec<(FrameControl, Vec<u8>)> = Vec::new();
+    let mut pos = SIGNATURE.len();
+
+    // Every frame is decoded as a PNG on its own
+    while pos < data.len() {
+        let (kind, chunk, next) = try!(read_chunk(data, pos));
+
+        match &kind {
+            b"IHDR" => header = Some(chunk.to_vec()),
+            b"PLTE" | b"tRNS" => palette.extend_from_slice(&data[pos..next]),
+            b"fcTL" => frames.push((try!(FrameControl::from_chunk(chunk)), Vec::new())),
+            // The default image is the first frame only if it follows an fcTL chunk
+            b"IDAT" => if let Some(&mut (_, ref mut image)) = frames.last_mut() {
+                image.extend_from_slice(chunk)
+            },
+            b"fdAT" => match frames.last_mut() {
+                Some(&mut (_, ref mut image)) if chunk.len() >= 4 => image.extend_from_slice(&chunk[4..]),
+                _ => return Err(ImageError::malformed(ImageFormat::PNG, "fdAT chunk without fcTL chunk."))
+            },
+            b"IEND" => break,
+            _ => ()
+        }
+
+        pos = next;
+    }
+
+    let header = match header {
+        Some(ref header) if header.len() == 13 => header,
+        _ => return Err(ImageError::malformed(ImageFormat::PNG, "IHDR chunk missing."))
+    };
+
+    let (width, height) = (read_u32(&header[0..]), read_u32(&header[4..]));
+    let canvas_size = width as u64 * height as u64 * 4;
+    try!(limits.reserve(canvas_size));
+
+    let transparent = Rgba([0, 0, 0, 0]);
+
+    let mut canvas: RgbaImage = ImageBuffer::from_pixel(width, height, transparent);
+    let mut out = Vec::with_capacity(frames.len());
+
+    for (control, image) in frames.into_iter() {
+        if control.left as u64 + control.width as u64 > width as u64 ||
+           control.top as u64 + control.height as u64 > height as u64 {
+            return Err(ImageError::malformed(ImageFormat::PNG, "Animation frame outside of the image."))
+        }
+
+        try!(limits.reserve(canvas_size));
+
+        let mut png = SIGNATURE.to_vec();
+        let mut frame_header = header.clone();
+        BigEndian::write_u32(&mut frame_header[0..], control.width);
+        BigEndian::write_u32(&mut frame_header[4..], control.height);
+        write_chunk(&mut png, b"IHDR", &frame_header);
+        png.extend_from_slice(&palette);
+        write_chunk(&mut png, b"IDAT", &image);
+        write_chunk(&mut png, b"IEND", &[]);
+
+        let mut decoder = PNGDecoder::new(io::Cursor::new(png));
+        try!(decoder.set_limits(limits));
+        let image = try!(decoder_to_image(decoder)).to_rgba();
+
+        let previous = if control.dispose_op == DISPOSE_OP_PREVIOUS {
+            Some(canvas.clone())
+        } else {
+            None
+        };
+
+        for (x, y, p) in image.enumerate_pixels() {
+            let (cx, cy) = (control.left + x, control.top + y);
+
+            if control.blend_op != BLEND_OP_OVER || p[3] == 255 {
+                canvas.put_pixel(cx, cy, *p);
+            } else if p[3] != 0 {
+                canvas.get_pixel_mut(cx, cy).blend(p);
+            }
+        }
+
+        out.push(Frame::from_parts(canvas.clone(), 0, 0, control.delay));
+
+        match control.dispose_op {
+            DISPOSE_OP_BACKGROUND => {
+                for y in control.top..control.top + control.height {
+                    for x in control.left..control.left + control.width {
+                        canvas.put_pixel(x, y, transparent);
+                    }
+                }
+            }
+            DISPOSE_OP_PREVIOUS => canvas = previous.unwrap(),
+            _ => ()
+        }
+    }
+
+    Ok(out)
 }
 
 impl<R: Read> ImageDecoder for PNGDecoder<R> {
+    fn set_limits(&mut self, limits: Limits) -> ImageResult<()> {
+        // An animation is buffered within the limits while the header is read
+        self.limits = limits;
+        let (width, height) = try!(self.dimensions());
+        let color = try!(self.colortype());
+        try!(limits.check_image(width, height, color));
+
+        let mut limits = limits;
+        if self.animated {
+            try!(limits.reserve(self.data.len() as u64));
+        }
+        self.limits = limits;
+        Ok(())
+    }
+
     fn dimensions(&mut self) -> ImageResult<(u32, u32)> {
         let reader = try!(self.get_reader());
         Ok(reader.info().size())
@@ -59,7 +509,13 @@ impl<R: Read> ImageDecoder for PNGDecoder<R> {
 
     fn colortype(&mut self) -> ImageResult<ColorType> {
         let reader = try!(self.get_reader());
-        Ok(reader.output_color_type().into())
+        let (ct, bits) = reader.output_color_type();
+        // `output_color_type` reports 8 bits for every expanded image
+        let bits = match reader.info().bit_depth {
+            png::BitDepth::Sixteen => png::BitDepth::Sixteen,
+            _ => bits
+        };
+        Ok((ct, bits).into())
     }
 
     fn row_len(&mut self) -> ImageResult<usize> {
@@ -82,32 +538,437 @@ impl<R: Read> ImageDecoder for PNGDecoder<R> {
         let reader = try!(self.get_reader());
         let mut data = vec![0; reader.output_buffer_size()];
         try!(reader.next_frame(&mut data));
-        Ok(DecodingResult::U8(data))
+        Ok(decoding_result(data, reader.info().bit_depth))
+    }
+
+    fn load_rect(&mut self, x: u32, y: u32, width: u32, height: u32) -> ImageResult<DecodingResult> {
+        // The rows of interlaced images are spread over all passes
+        if try!(self.get_reader()).info().interlaced {
+            return load_rect_from_image(self, x, y, width, height)
+        }
+
+        let dimensions = try!(self.dimensions());
+        try!(check_rect(dimensions, x, y, width, height));
+        let color = try!(self.colortype());
+
+        // Decoding stops after the last row of the region
+        let reader = try!(self.get_reader());
+        let mut data = Vec::new();
+        for row in 0..y + height {
+            match try!(reader.next_row()) {
+                Some(line) => if row >= y {
+                    data.extend_from_slice(line)
+                },
+                None => return Err(ImageError::NotEnoughData)
+            }
+        }
+
+        crop_rect(decoding_result(data, reader.info().bit_depth), color, dimensions.0, x, 0, width, height)
+    }
+
+    fn metadata(&mut self) -> ImageResult<ImageMetadata> {
+        let _ = try!(self.get_reader());
+
+        read_metadata(&self.data, self.limits)
+    }
+
+    fn is_animated(&mut self) -> ImageResult<bool> {
+        let _ = try!(self.get_reader());
+
+        Ok(self.animated)
+    }
+
+    fn into_frames(mut self) -> ImageResult<Frames> {
+        let _ = try!(self.get_reader());
+
+        if !self.animated {
+            let image = try!(decoder_to_image(self)).to_rgba();
+            return Ok(Frames::new(vec![Frame::new(image)]))
+        }
+
+        Ok(Frames::new(try!(read_frames(&self.data, self.limits))))
+    }
+}
+
+/// Reader of the rows of a PNG image without interlacing
+///
+/// Unlike ```PNGDecoder``` the reader keeps its state when reading from its stream fails,
+/// so a row can be read again once the stream has received more data.
+/// The rows hold the same samples as the rows of ```PNGDecoder```.
+pub struct PNGRowReader<R: Read> {
+    reader: png::Reader<R>,
+    samples: RowSamples,
+    row: Vec<u8>,
+}
+
+// The information needed to expand the samples of a row
+struct RowSamples {
+    width: u32,
+    color_type: png::ColorType,
+    bit_depth: u8,
+    palette: Vec<u8>,
+    trns: Option<Vec<u8>>,
+}
+
+impl<R: Read> PNGRowReader<R> {
+    /// Creates a reader of the rows of the image in ```r```
+    ///
+    /// ```r``` must hold the chunks in front of the image data.
+    pub fn new(r: R) -> ImageResult<PNGRowReader<R>> {
+        let mut decoder = png::Decoder::new(r);
+        // The png reader loses a partly read row if it expands the samples, so they are expanded here
+        decoder.set(png::TRANSFORM_IDENTITY);
+        let (_, reader) = try!(decoder.read_info());
+
+        let samples = {
+            let info = reader.info();
+            if info.interlaced {
+                return Err(ImageError::UnsupportedError("Interlaced images can't be read by row.".to_string()))
+            }
+
+            RowSamples {
+                width: info.width,
+                color_type: info.color_type,
+                bit_depth: info.bit_depth as u8,
+                palette: info.palette.clone().unwrap_or(Vec::new()),
+                trns: info.trns.clone(),
+            }
+        };
+
+        Ok(PNGRowReader {
+            reader: reader,
+            samples: samples,
+            row: Vec::new(),
+        })
     }
+
+    /// Returns the next row, or ```None``` once all rows were read
+    ///
+    /// The samples of images with 16 bits per sample are in network byte order.
+    pub fn next_row(&mut self) -> ImageResult<Option<&[u8]>> {
+        match try!(self.reader.next_row()) {
+            Some(raw) => self.samples.expand(raw, &mut self.row),
+            None => return Ok(None)
+        }
+
+        Ok(Some(&self.row))
+    }
+}
+
+impl RowSamples {
+    // Expands the samples of the unfiltered row ```raw``` like ```png::TRANSFORM_EXPAND``` does
+    fn expand(&self, raw: &[u8], row: &mut Vec<u8>) {
+        use self::png::ColorType::*;
+
+        row.clear();
+        let depth = self.bit_depth as usize;
+        // The values of pixels with up to 8 bits, the first pixel is in the high bits of a byte
+        let values = (0..self.width as usize).map(|x| {
+            let bit = x * depth;
+            (raw[bit / 8] >> (8 - depth - bit % 8)) & ((1u16 << depth) - 1) as u8
+        });
+
+        match self.color_type {
+            Indexed => for index in values {
+                let index = index as usize;
+                // Indices beyond the palette are black
+                match self.palette.get(3 * index..3 * index + 3) {
+                    Some(rgb) => row.extend_from_slice(rgb),
+                    None => row.extend_from_slice(&[0; 3])
+                }
+                if let Some(ref trns) = self.trns {
+                    row.push(*trns.get(index).unwrap_or(&0xFF));
+                }
+            },
+            Grayscale if depth < 8 => {
+                let scale = 255 / ((1u16 << depth) - 1) as u8;
+                for value in values {
+                    row.push(value * scale);
+                    // The png reader compares the value with the first byte of the tRNS chunk
+                    if let Some(ref trns) = self.trns {
+                        row.push(if trns.get(0) == Some(&value) { 0 } else { 0xFF });
+                    }
+                }
+            }
+            Grayscale | RGB if self.trns.is_some() => {
+                let trns = self.trns.as_ref().unwrap();
+                let bytes = depth / 8;
+                for pixel in raw.chunks(self.color_type.samples() * bytes) {
+                    row.extend_from_slice(pixel);
+                    let alpha = if pixel == &trns[..] { 0 } else { 0xFF };
+                    row.extend(iter::repeat(alpha).take(bytes));
+                }
+            }
+            _ => row.extend_from_slice(raw)
+        }
+    }
+}
+
+/// The compression level of a PNG encoder
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CompressionType {
+    /// Optimize for the speed of encoding
+    Fast,
+    /// A balance between speed and size
+    Default,
+    /// Optimize for the size of the image
+    Best,
+}
+
+/// The filter applied to the rows of an image before they are compressed
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FilterType {
+    /// The rows are compressed as they are
+    NoFilter,
+    /// The difference to the pixel on the left
+    Sub,
+    /// The difference to the pixel above
+    Up,
+    /// The difference to the mean of the pixels on the left and above
+    Avg,
+    /// The difference to the Paeth predictor
+    Paeth,
+    /// Every row is filtered with the filter that suits it best
+    Adaptive,
 }
 
 /// PNG encoder
 pub struct PNGEncoder<W: Write> {
-    w: W
+    w: W,
+    num_plays: u32,
+    compression: CompressionType,
+    filter: FilterType,
 }
 
 impl<W: Write> PNGEncoder<W> {
     /// Create a new encoder that writes its output to ```w```
+    ///
+    /// Images are compressed fast with the ```Sub``` filter.
     pub fn new(w: W) -> PNGEncoder<W> {
+        PNGEncoder::new_with_options(w, CompressionType::Fast, FilterType::Sub)
+    }
+
+    /// Create a new encoder that writes its output to ```w```
+    /// with the given compression level and filter
+    pub fn new_with_options(w: W, compression: CompressionType, filter: FilterType) -> PNGEncoder<W> {
         PNGEncoder {
-            w: w
+            w: w,
+            num_plays: 0,
+            compression: compression,
+            filter: filter,
         }
     }
 
+    /// Sets how often an animation is played, the default 0 loops forever.
+    pub fn set_num_plays(&mut self, num_plays: u32) {
+        self.num_plays = num_plays;
+    }
+
     /// Encodes the image ```image```
     /// that has dimensions ```width``` and ```height```
     /// and ```ColorType``` ```c```
     pub fn encode(self, data: &[u8], width: u32, height: u32, color: ColorType) -> io::Result<()> {
+        self.encode_with_metadata(data, width, height, color, &ImageMetadata::default())
+    }
+
+    /// Encodes the image ```image``` like ```encode```
+    /// and stores ```metadata``` in front of the image data
+    pub fn encode_with_metadata(self, data: &[u8], width: u32, height: u32, color: ColorType,
+                                metadata: &ImageMetadata) -> io::Result<()> {
         let (ct, bits) = color.into();
+        let data = try!(compress_image(data, width, height, color, self.compression, self.filter));
+
         let mut encoder = png::Encoder::new(self.w, width, height);
         encoder.set(ct).set(bits);
         let mut writer = try!(encoder.write_header());
-        writer.write_image_data(data).map_err(|e| e.into())
+        try!(write_metadata(&mut writer, metadata));
+        writer.write_chunk(png::chunk::IDAT, &data).map_err(|e| e.into())
+    }
+
+    /// Encodes the frames of an animation as an APNG.
+    ///
+    /// Every frame is drawn at its offset onto a transparent canvas that is
+    /// large enough to hold all frames, and cleared before the next one is shown.
+    pub fn encode_frames<F>(self, frames: F) -> ImageResult<()>
+        where F: IntoIterator<Item = Frame> {
+
+        let frames: Vec<Frame> = frames.into_iter().collect();
+        if frames.is_empty() {
+            return Err(ImageError::malformed(ImageFormat::PNG, "An animation needs at least one frame."))
+        }
+
+        let (mut width, mut height) = (0, 0);
+        for frame in frames.iter() {
+            let (w, h) = frame.buffer().dimensions();
+            width  = cmp::max(width, frame.left() as u64 + w as u64);
+            height = cmp::max(height, frame.top() as u64 + h as u64);
+        }
+
+        if width > 0x7fffffff || height > 0x7fffffff {
+            return Err(ImageError::DimensionError)
+        }
+
+        let (width, height) = (width as u32, height as u32);
+        let mut encoder = png::Encoder::new(self.w, width, height);
+        encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+        let mut writer = try!(encoder.write_header());
+
+        let mut actl = [0; 8];
+        BigEndian::write_u32(&mut actl[0..], frames.len() as u32);
+        BigEndian::write_u32(&mut actl[4..], self.num_plays);
+        try!(writer.write_chunk(png::chunk::acTL, &actl));
+
+        let mut sequence_number = 0;
+
+        for (i, frame) in frames.iter().enumerate() {
+            let (w, h) = frame.buffer().dimensions();
+            let mut control = FrameControl {
+                width: w,
+                height: h,
+                left: frame.left(),
+                top: frame.top(),
+                delay: frame.delay(),
+                dispose_op: DISPOSE_OP_BACKGROUND,
+                blend_op: BLEND_OP_SOURCE,
+            };
+
+            if i == 0 {
+                // The first frame is the default image, it covers the whole canvas
+                let mut canvas: RgbaImage = ImageBuffer::new(width, height);
+                for (x, y, p) in frame.buffer().enumerate_pixels() {
+                    canvas.put_pixel(frame.left() + x, frame.top() + y, *p);
+                }
+
+                control.width = width;
+                control.height = height;
+                control.left = 0;
+                control.top = 0;
+
+                let data = try!(compress_image(&canvas, width, height, ColorType::RGBA(8),
+                                               self.compression, self.filter));
+                try!(writer.write_chunk(png::chunk::fcTL, &control.to_chunk(sequence_number)));
+                try!(writer.write_chunk(png::chunk::IDAT, &data));
+                sequence_number += 1;
+                continue
+            }
+
+            try!(writer.write_chunk(png::chunk::fcTL, &control.to_chunk(sequence_number)));
+
+            let mut data = vec![0; 4];
+            BigEndian::write_u32(&mut data, sequence_number + 1);
+            data.extend_from_slice(&try!(compress_image(frame.buffer(), w, h, ColorType::RGBA(8),
+                                                        self.compression, self.filter)));
+            try!(writer.write_chunk(png::chunk::fdAT, &data));
+            sequence_number += 2;
+        }
+
+        Ok(())
+    }
+}
+
+// Filters the rows of an image and compresses them to the contents of its IDAT chunks
+fn compress_image(data: &[u8], width: u32, height: u32, color: ColorType,
+                  compression: CompressionType, filter: FilterType) -> io::Result<Vec<u8>> {
+    let bits = color::bits_per_pixel(color);
+    let row_len = (width as usize * bits + 7) / 8;
+    let bpp = cmp::max(bits / 8, 1);
+
+    if data.len() < row_len * height as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not enough image data provided"))
+    }
+
+    let level = match compression {
+        CompressionType::Fast => flate2::Compression::Fast,
+        CompressionType::Default => flate2::Compression::Default,
+        CompressionType::Best => flate2::Compression::Best,
+    };
+
+    let mut zlib = ZlibEncoder::new(Vec::new(), level);
+    let mut previous = vec![0; row_len];
+    let mut filtered = vec![0; row_len];
+    let mut best = vec![0; row_len];
+
+    for row in data.chunks(row_len).take(height as usize) {
+        let method = match filter {
+            FilterType::NoFilter => 0,
+            FilterType::Sub => 1,
+            FilterType::Up => 2,
+            FilterType::Avg => 3,
+            FilterType::Paeth => 4,
+            FilterType::Adaptive => {
+                // The filter with the smallest sum of absolute differences
+                let mut best_method = 0;
+                let mut best_sum = u64::max_value();
+
+                for method in 0..5 {
+                    filter_row(method, bpp, &previous, row, &mut filtered);
+                    let sum = filtered.iter().map(|&v| (v as i8 as i64).abs() as u64).sum();
+
+                    if sum < best_sum {
+                        best_method = method;
+                        best_sum = sum;
+                        best.copy_from_slice(&filtered);
+                    }
+                }
+
+                try!(zlib.write_all(&[best_method]));
+                try!(zlib.write_all(&best));
+                previous.copy_from_slice(row);
+                continue
+            }
+        };
+
+        filter_row(method, bpp, &previous, row, &mut filtered);
+        try!(zlib.write_all(&[method]));
+        try!(zlib.write_all(&filtered));
+        previous.copy_from_slice(row);
+    }
+
+    zlib.finish()
+}
+
+// Applies the filter ```method``` to ```row```, ```bpp``` is the number of bytes per complete pixel
+fn filter_row(method: u8, bpp: usize, previous: &[u8], row: &[u8], filtered: &mut [u8]) {
+    for i in 0..row.len() {
+        let a = if i >= bpp { row[i - bpp] } else { 0 };
+        let b = previous[i];
+        let c = if i >= bpp { previous[i - bpp] } else { 0 };
+
+        let prediction = match method {
+            1 => a,
+            2 => b,
+            3 => ((a as u16 + b as u16) / 2) as u8,
+            4 => paeth(a, b, c),
+            _ => 0,
+        };
+
+        filtered[i] = row[i].wrapping_sub(prediction);
+    }
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+impl<W: Write> ImageEncoder for PNGEncoder<W> {
+    fn write_image(self, buf: &[u8], width: u32, height: u32, color: ColorType) -> ImageResult<()> {
+        try!(self.encode(buf, width, height, color));
+        Ok(())
+    }
+
+    fn write_image_with_metadata(self, buf: &[u8], width: u32, height: u32, color: ColorType,
+                                 metadata: &ImageMetadata) -> ImageResult<()> {
+        try!(self.encode_with_metadata(buf, width, height, color, metadata));
+        Ok(())
     }
 }
 
@@ -139,16 +1000,295 @@ impl From<ColorType> for (png::ColorType, png::BitDepth) {
     }
 }
 
+impl From<png::EncodingError> for ImageError {
+    fn from(err: png::EncodingError) -> ImageError {
+        use self::png::EncodingError::*;
+        match err {
+            IoError(err) => ImageError::IoError(err),
+            Format(desc) => ImageError::malformed(ImageFormat::PNG, desc.into_owned()),
+        }
+    }
+}
+
 impl From<png::DecodingError> for ImageError {
     fn from(err: png::DecodingError) -> ImageError {
         use self::png::DecodingError::*;
         match err {
             IoError(err) => ImageError::IoError(err),
-            Format(desc) => ImageError::FormatError(desc.into_owned()),
-            InvalidSignature => ImageError::FormatError("invalid signature".into()),
-            CrcMismatch { .. } => ImageError::FormatError("CRC error".into()),
-            Other(desc) => ImageError::FormatError(desc.into_owned()),
-            CorruptFlateStream => ImageError::FormatError("compressed data stream corrupted".into())
+            // The png reader reports the end of the stream as a format error
+            Format(ref desc) if desc == "unexpected EOF" => ImageError::NotEnoughData,
+            Format(desc) => ImageError::malformed(ImageFormat::PNG, desc.into_owned()),
+            InvalidSignature => ImageError::malformed(ImageFormat::PNG, "invalid signature").at(0),
+            CrcMismatch { .. } => ImageError::malformed(ImageFormat::PNG, "CRC error"),
+            Other(desc) => ImageError::malformed(ImageFormat::PNG, desc.into_owned()),
+            CorruptFlateStream => ImageError::malformed(ImageFormat::PNG, "compressed data stream corrupted")
+        }
+    }
+}
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use num::rational::Ratio;
+
+    use animation::Frame;
+    use buffer::{ImageBuffer, RgbaImage, Pixel};
+    use color::Rgba;
+    use image::{ImageDecoder, DecodingResult, ImageMetadata};
+    use color::ColorType;
+    use super::{PNGDecoder, PNGEncoder, FrameControl, SIGNATURE, read_chunk, write_chunk};
+
+    fn decode_frames(data: &[u8]) -> Vec<Frame> {
+        let mut decoder = PNGDecoder::new(Cursor::new(data));
+        assert!(decoder.is_animated().unwrap());
+        decoder.into_frames().unwrap().collect()
+    }
+
+    fn pixels(frame: &Frame) -> Vec<Rgba<u8>> {
+        frame.buffer().pixels().cloned().collect()
+    }
+
+    // The compressed image data of ```image```
+    fn image_data(image: &RgbaImage) -> Vec<u8> {
+        let mut png = Vec::new();
+        PNGEncoder::new(&mut png).encode(image, image.width(), image.height(), ::ColorType::RGBA(8)).unwrap();
+
+        let mut pos = SIGNATURE.len();
+        let mut data = Vec::new();
+        while pos < png.len() {
+            let (kind, chunk, next) = read_chunk(&png, pos).unwrap();
+            if &kind == b"IDAT" {
+                data.extend_from_slice(chunk);
+            }
+            pos = next;
+        }
+        data
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (red, clear) = (Rgba([255, 0, 0, 255]), Rgba([0, 0, 0, 0]));
+        let translucent = Rgba([10, 20, 30, 40]);
+
+        let mut data = Vec::new();
+        {
+            let mut encoder = PNGEncoder::new(&mut data);
+            encoder.set_num_plays(3);
+            encoder.encode_frames(vec![
+                Frame::from_parts(ImageBuffer::from_pixel(2, 1, red), 1, 0, Ratio::new(1, 2)),
+                Frame::from_parts(ImageBuffer::from_pixel(1, 2, translucent), 0, 1, Ratio::new(3, 7)),
+            ]).unwrap();
+        }
+
+        let frames = decode_frames(&data);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].buffer().dimensions(), (3, 3));
+        assert_eq!((frames[0].delay(), frames[1].delay()), (Ratio::new(1, 2), Ratio::new(3, 7)));
+        // The frames hold the whole canvas
+        assert_eq!((frames[1].left(), frames[1].top()), (0, 0));
+
+        // Every frame is shown on its own
+        assert_eq!(pixels(&frames[0]), vec![clear, red, red, clear, clear, clear, clear, clear, clear]);
+        assert_eq!(pixels(&frames[1]), vec![clear, clear, clear, translucent, clear, clear, translucent, clear, clear]);
+
+        // The acTL chunk with 2 frames and 3 plays
+        let (kind, chunk, _) = read_chunk(&data, SIGNATURE.len() + 25).unwrap();
+        assert_eq!((&kind, chunk), (b"acTL", &[0, 0, 0, 2, 0, 0, 0, 3][..]));
+    }
+
+    #[test]
+    fn test_animation_limits() {
+        use image::{ImageError, Limits};
+
+        let mut data = Vec::new();
+        PNGEncoder::new(&mut data).encode_frames(vec![
+            Frame::new(ImageBuffer::from_pixel(16, 16, Rgba([1, 2, 3, 4]))),
+            Frame::new(ImageBuffer::from_pixel(16, 16, Rgba([5, 6, 7, 8]))),
+        ]).unwrap();
+
+        // The file, the canvas and the two composited frames
+        let needed = data.len() as u64 + 3 * 16 * 16 * 4;
+        let decode = |max_alloc| {
+            let mut decoder = PNGDecoder::new(Cursor::new(&data));
+            try!(decoder.set_limits(Limits { max_alloc: Some(max_alloc), ..Limits::default() }));
+            decoder.into_frames().map(|frames| frames.count())
+        };
+
+        // Every frame is decoded on its own within the limits that are left
+        assert_eq!(decode(needed + 16 * 16 * 4).unwrap(), 2);
+        match decode(needed + 16 * 16 * 4 - 1) {
+            Err(ImageError::LimitsExceeded) => (),
+            _ => panic!("the frames exceed the limits")
+        }
+
+        // The animation is not buffered beyond the limits
+        match decode(16) {
+            Err(ImageError::LimitsExceeded) => (),
+            _ => panic!("the file exceeds the limits")
+        }
+    }
+
+    #[test]
+    fn test_dispose_and_blend() {
+        let (red, green, yellow) = (Rgba([255, 0, 0, 255]), Rgba([0, 255, 0, 255]), Rgba([255, 255, 0, 255]));
+        let (blue, white) = (Rgba([0, 0, 255, 128]), Rgba([255, 255, 255, 128]));
+
+        let frame = |width, height, left, top, dispose_op, blend_op, sequence_number| FrameControl {
+            width: width,
+            height: height,
+            left: left,
+            top: top,
+            delay: Ratio::new(1, 100),
+            dispose_op: dispose_op,
+            blend_op: blend_op,
+        }.to_chunk(sequence_number);
+
+        let fdat = |image: &RgbaImage, sequence_number: u8| {
+            let mut data = vec![0, 0, 0, sequence_number];
+            data.extend_from_slice(&image_data(image));
+            data
+        };
+
+        let mut data = SIGNATURE.to_vec();
+        write_chunk(&mut data, b"IHDR", &[0, 0, 0, 2, 0, 0, 0, 1, 8, 6, 0, 0, 0]);
+        write_chunk(&mut data, b"acTL", &[0, 0, 0, 3, 0, 0, 0, 0]);
+        // The default image is not part of the animation
+        write_chunk(&mut data, b"IDAT", &image_data(&ImageBuffer::from_pixel(2, 1, green)));
+        write_chunk(&mut data, b"fcTL", &frame(2, 1, 0, 0, 0, 0, 0));
+        write_chunk(&mut data, b"fdAT", &fdat(&ImageBuffer::from_fn(2, 1, |x, _| if x == 0 { red } else { blue }), 1));
+        write_chunk(&mut data, b"fcTL", &frame(1, 1, 1, 0, 2, 1, 2));
+        write_chunk(&mut data, b"fdAT", &fdat(&ImageBuffer::from_pixel(1, 1, white), 3));
+        write_chunk(&mut data, b"fcTL", &frame(1, 1, 0, 0, 1, 0, 4));
+        write_chunk(&mut data, b"fdAT", &fdat(&ImageBuffer::from_pixel(1, 1, yellow), 5));
+        write_chunk(&mut data, b"IEND", &[]);
+
+        let mut blended = blue;
+        blended.blend(&white);
+
+        let frames = decode_frames(&data);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(pixels(&frames[0]), vec![red, blue]);
+        assert_eq!(pixels(&frames[1]), vec![red, blended]);
+        // The canvas before the previous frame is restored
+        assert_eq!(pixels(&frames[2]), vec![yellow, blue]);
+    }
+
+    #[test]
+    fn test_still_image() {
+        let image = ImageBuffer::from_pixel(2, 2, Rgba([1, 2, 3, 4]));
+        let mut data = Vec::new();
+        PNGEncoder::new(&mut data).encode(&image, 2, 2, ::ColorType::RGBA(8)).unwrap();
+
+        let mut decoder = PNGDecoder::new(Cursor::new(&data));
+        assert!(!decoder.is_animated().unwrap());
+
+        let frames: Vec<Frame> = decoder.into_frames().unwrap().collect();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].buffer().clone().into_raw(), image.into_raw());
+    }
+
+    #[test]
+    fn test_filters() {
+        use super::CompressionType::*;
+        use super::FilterType::*;
+
+        let image: Vec<u8> = (0..23 * 11 * 3).map(|i| ((i * 7) % 251 + i / 69) as u8).collect();
+        let mut sizes = Vec::new();
+
+        for &compression in [Fast, Default, Best].iter() {
+            for &filter in [NoFilter, Sub, Up, Avg, Paeth, Adaptive].iter() {
+                let mut data = Vec::new();
+                PNGEncoder::new_with_options(&mut data, compression, filter)
+                    .encode(&image, 23, 11, ::ColorType::RGB(8)).unwrap();
+
+                match PNGDecoder::new(Cursor::new(&data)).read_image().unwrap() {
+                    DecodingResult::U8(decoded) => assert!(decoded == image),
+                    _ => panic!("wrong sample type")
+                }
+
+                if filter == Adaptive {
+                    sizes.push(data.len());
+                }
+            }
+        }
+
+        assert!(sizes[2] <= sizes[0]);
+    }
+
+    #[test]
+    fn test_incremental_rows() {
+        use image::{ImageError, ImageFormat};
+        use {IncrementalDecoder, Progress};
+
+        let image = ImageBuffer::from_fn(64, 64, |x, y| Rgba([(x * 4) as u8, (y * 4) as u8, (x ^ y) as u8, 255]));
+
+        // Rows are decoded once the IDAT chunk that holds them arrived
+        let mut png = SIGNATURE.to_vec();
+        write_chunk(&mut png, b"IHDR", &[0, 0, 0, 64, 0, 0, 0, 64, 8, 6, 0, 0, 0]);
+        for data in image_data(&image).chunks(256) {
+            write_chunk(&mut png, b"IDAT", data);
+        }
+        write_chunk(&mut png, b"IEND", &[]);
+
+        let mut decoder = IncrementalDecoder::new(ImageFormat::PNG);
+        let mut pixels = Vec::new();
+        let mut updates = 0;
+        for part in png.chunks(256) {
+            decoder.push(part);
+            match decoder.read_rows() {
+                Ok(Progress::Ready(rows)) => {
+                    assert_eq!(rows.first_row as usize * 64 * 4, pixels.len());
+                    match rows.data {
+                        DecodingResult::U8(data) => pixels.extend_from_slice(&data),
+                        DecodingResult::U16(_) => panic!("wrong sample type")
+                    }
+                    updates += 1;
+                }
+                Ok(Progress::NeedMoreData) | Err(ImageError::ImageEnd) => (),
+                Err(err) => panic!("{}", err)
+            }
         }
+
+        assert!(updates > 1);
+        assert_eq!(pixels, image.into_raw());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_load_rect() {
+        let image = ImageBuffer::from_fn(64, 64, |x, y| Rgba([(x * 4) as u8, (y * 4) as u8, (x ^ y) as u8, 255]));
+
+        let mut png = SIGNATURE.to_vec();
+        write_chunk(&mut png, b"IHDR", &[0, 0, 0, 64, 0, 0, 0, 64, 8, 6, 0, 0, 0]);
+        write_chunk(&mut png, b"IDAT", &image_data(&image)[..256]);
+
+        // The file ends after the first rows, a region within them can be decoded
+        let mut decoder = PNGDecoder::new(Cursor::new(&png));
+        match decoder.load_rect(1, 0, 2, 1).unwrap() {
+            DecodingResult::U8(data) => assert_eq!(data, vec![4, 0, 1, 255, 8, 0, 2, 255]),
+            DecodingResult::U16(_) => panic!("wrong sample type")
+        }
+        assert!(PNGDecoder::new(Cursor::new(&png)).read_image().is_err());
+    }
+
+    #[test]
+    fn test_metadata() {
+        let metadata = ImageMetadata {
+            dpi: Some((254.0, 127.0)),
+            text: vec![("Title".to_string(), "Gray".to_string()),
+                       ("Author".to_string(), "Zoë".to_string())],
+            exif: Some(b"MM\0*exif".to_vec()),
+            icc_profile: Some(vec![7; 1000]),
+            xmp: Some(b"<x:xmpmeta/>".to_vec()),
+            orientation: None,
+        };
+
+        let mut data = Vec::new();
+        PNGEncoder::new(&mut data).encode_with_metadata(&[0; 4], 2, 2, ColorType::Gray(8), &metadata).unwrap();
+
+        let mut decoder = PNGDecoder::new(Cursor::new(data));
+        assert_eq!(decoder.metadata().unwrap(), metadata);
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(pixels) => assert_eq!(pixels, vec![0; 4]),
+            _ => panic!("wrong sample type")
+        }
+    }
+}
@@ -4,6 +4,7 @@ use std::io;
 use std::io::Write;
 
 use color;
+use image::{ImageEncoder, ImageResult};
 use color::ColorType:: {
     Gray,
     Palette,
@@ -31,7 +32,7 @@ impl<'a, W: Write> PPMEncoder<'a, W> {
         let _ = try!(self.write_magic_number());
         let _ = try!(self.write_metadata(width, height, color));
 
-        self.write_image(im, color, width, height)
+        self.write_pixels(im, color, width, height)
     }
 
     fn write_magic_number(&mut self) -> io::Result<()> {
@@ -43,7 +44,7 @@ impl<'a, W: Write> PPMEncoder<'a, W> {
         write!(self.w, "{0} {1}\n{2}\n", width, height, m)
     }
 
-    fn write_image(
+    fn write_pixels(
         &mut self,
         buf: &[u8],
         pixel_type: color::ColorType,
@@ -80,6 +81,13 @@ impl<'a, W: Write> PPMEncoder<'a, W> {
     }
 }
 
+impl<'a, W: Write> ImageEncoder for PPMEncoder<'a, W> {
+    fn write_image(mut self, buf: &[u8], width: u32, height: u32, color: color::ColorType) -> ImageResult<()> {
+        try!(self.encode(buf, width, height, color));
+        Ok(())
+    }
+}
+
 fn max_pixel_value(pixel_type: color::ColorType) -> u16 {
     match pixel_type {
         Gray(n)    => 2u16.pow(n as u32) - 1,
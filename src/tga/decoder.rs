@@ -2,10 +2,12 @@ use std::io;
 use std::io::{Read, Seek};
 use byteorder::{ReadBytesExt, LittleEndian};
 
-use image::ImageError;
+use image::{ImageError, ImageFormat, UnsupportedFeature};
 use image::ImageResult;
 use image::ImageDecoder;
 use image::DecodingResult;
+use image::Limits;
+use image::load_rect_from_image;
 use color::ColorType;
 
 enum ImageType {
@@ -173,6 +175,8 @@ pub struct TGADecoder<R> {
 
     header: Header,
     color_map: Option<ColorMap>,
+
+    limits: Limits,
 }
 
 impl<R: Read + Seek> TGADecoder<R> {
@@ -191,6 +195,8 @@ impl<R: Read + Seek> TGADecoder<R> {
 
             header: Header::new(),
             color_map: None,
+
+            limits: Limits::default(),
         }
     }
 
@@ -220,12 +226,14 @@ impl<R: Read + Seek> TGADecoder<R> {
     /// by 8 and are less than 32.
     fn read_color_information(&mut self) -> ImageResult<()> {
         if self.header.pixel_depth % 8 != 0 {
-            return Err(ImageError::UnsupportedError("\
-                Bit depth must be divisible by 8".to_string()));
+            // Bit depths must be divisible by 8
+            return Err(ImageError::unsupported(
+                ImageFormat::TGA, UnsupportedFeature::BitDepth(self.header.pixel_depth)).at(16));
         }
         if self.header.pixel_depth > 32 {
-            return Err(ImageError::UnsupportedError("\
-                Bit depth must be less than 32".to_string()));
+            // Bit depths must be at most 32
+            return Err(ImageError::unsupported(
+                ImageFormat::TGA, UnsupportedFeature::BitDepth(self.header.pixel_depth)).at(16));
         }
 
         let num_alpha_bits = self.header.image_desc & 0b1111;
@@ -244,9 +252,9 @@ impl<R: Read + Seek> TGADecoder<R> {
             (0, 24, true) => self.color_type = ColorType::RGB(8),
             (8, 8, false) => self.color_type = ColorType::GrayA(8),
             (0, 8, false) => self.color_type = ColorType::Gray(8),
-            _ => return Err(ImageError::UnsupportedError(format!("\
-                    Color format not supported. Bit depth: {}, Alpha bits: {}",
-                    other_channel_bits, num_alpha_bits).to_string())),
+            _ => return Err(ImageError::unsupported(ImageFormat::TGA, UnsupportedFeature::Other(format!(
+                    "color format with a bit depth of {} and {} alpha bits",
+                    other_channel_bits, num_alpha_bits)))),
         }
         Ok(())
     }
@@ -300,6 +308,14 @@ impl<R: Read + Seek> TGADecoder<R> {
     }
 
     fn read_image_data(&mut self) -> ImageResult<Vec<u8>> {
+        // The pixels, and the colours of colour mapped images
+        let mut limits = self.limits;
+        try!(limits.reserve((self.width * self.height * self.bytes_per_pixel) as u64));
+        if self.image_type.is_color_mapped() {
+            let bytes_per_entry = (self.header.map_entry_size as usize + 7) / 8;
+            try!(limits.reserve((self.width * self.height * bytes_per_entry) as u64));
+        }
+
         // read the pixels from the data region
         let mut pixel_data = if self.image_type.is_encoded() {
             try!(self.read_encoded_data())
@@ -310,6 +326,11 @@ impl<R: Read + Seek> TGADecoder<R> {
             buf
         };
 
+        // the file ended before all pixels were read
+        if pixel_data.len() < self.width * self.height * self.bytes_per_pixel {
+            return Err(ImageError::NotEnoughData)
+        }
+
         // expand the indices using the color map if necessary
         if self.image_type.is_color_mapped() {
             pixel_data = self.expand_color_map(pixel_data)
@@ -357,17 +378,18 @@ impl<R: Read + Seek> TGADecoder<R> {
     /// TGA files are stored in the BGRA encoding. This function swaps
     /// the blue and red bytes in the `pixels` array.
     fn reverse_encoding(&mut self, pixels: &mut [u8]) {
-        // We only need to reverse the encoding of color images
+        // We only need to reverse the encoding of color images,
+        // a pixel of a colour mapped image has the size of its colour
         match self.color_type {
             ColorType::RGB(8) => {
-                for chunk in pixels.chunks_mut(self.bytes_per_pixel) {
+                for chunk in pixels.chunks_mut(3) {
                     let r = chunk[0];
                     chunk[0] = chunk[2];
                     chunk[2] = r;
                 }
             }
             ColorType::RGBA(8) => {
-                for chunk in pixels.chunks_mut(self.bytes_per_pixel) {
+                for chunk in pixels.chunks_mut(4) {
                     let r = chunk[0];
                     chunk[0] = chunk[2];
                     chunk[2] = r;
@@ -379,6 +401,14 @@ impl<R: Read + Seek> TGADecoder<R> {
 }
 
 impl<R: Read + Seek> ImageDecoder for TGADecoder<R> {
+    fn set_limits(&mut self, limits: Limits) -> ImageResult<()> {
+        try!(self.read_metadata());
+        try!(limits.check_image(self.width as u32, self.height as u32, self.color_type));
+
+        self.limits = limits;
+        Ok(())
+    }
+
     fn dimensions(&mut self) -> ImageResult<(u32, u32)> {
         try!(self.read_metadata());
 
@@ -405,4 +435,8 @@ impl<R: Read + Seek> ImageDecoder for TGADecoder<R> {
         try!(self.read_metadata());
         self.read_image_data().map(|v| DecodingResult::U8(v) )
     }
+
+    fn load_rect(&mut self, x: u32, y: u32, width: u32, height: u32) -> ImageResult<DecodingResult> {
+        load_rect_from_image(self, x, y, width, height)
+    }
 }
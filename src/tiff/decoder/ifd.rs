@@ -1,11 +1,13 @@
 //! Function for reading TIFF tags
 
 use std::io::{self, Read, Seek};
+use std::cmp;
 use std::collections::{HashMap};
+use num::FromPrimitive;
 
 use super::stream::{ByteOrder, SmartReader, EndianReader};
 
-use self::Value::{Unsigned, List};
+use self::Value::{Unsigned, List, Ascii, Rational};
 
 macro_rules! tags {
     {$(
@@ -32,7 +34,7 @@ macro_rules! tags {
 // Note: These tags appear in the order they are mentioned in the TIFF reference
 tags!{
     // Baseline tags:
-    Artist 315; // TODO add support
+    Artist 315;
     // grayscale images PhotometricInterpretation 1 or 3
     BitsPerSample 258;
     CellLength 265; // TODO add support
@@ -40,27 +42,27 @@ tags!{
     // palette-color images (PhotometricInterpretation 3)
     ColorMap 320; // TODO add support
     Compression 259; // TODO add support for 2 and 32773
-    Copyright 33432; // TODO add support
-    DateTime 306; // TODO add support
+    Copyright 33432;
+    DateTime 306;
     ExtraSamples 338; // TODO add support
     FillOrder 266; // TODO add support
     FreeByteCounts 289; // TODO add support
     FreeOffsets 288; // TODO add support
     GrayResponseCurve 291; // TODO add support
     GrayResponseUnit 290; // TODO add support
-    HostComputer 316; // TODO add support
-    ImageDescription 270; // TODO add support
+    HostComputer 316;
+    ImageDescription 270;
     ImageLength 257;
     ImageWidth 256;
-    Make 271; // TODO add support
+    Make 271;
     MaxSampleValue 281; // TODO add support
     MinSampleValue 280; // TODO add support
-    Model 272; // TODO add support
+    Model 272;
     NewSubfileType 254; // TODO add support
-    Orientation 274; // TODO add support
+    Orientation 274;
     PhotometricInterpretation 262;
     PlanarConfiguration 284;
-    ResolutionUnit 296; // TODO add support
+    ResolutionUnit 296;
     RowsPerStrip 278;
     SamplesPerPixel 277;
     Software 305;
@@ -72,6 +74,12 @@ tags!{
     YResolution 283;
     // Advanced tags
     Predictor 317;
+    // Extension tags
+    ExifIfd 34665;
+    GpsIfd 34853;
+    DateTimeOriginal 36867;
+    IccProfile 34675;
+    XMP 700;
 }
 
 enum_from_primitive! {
@@ -82,6 +90,7 @@ pub enum Type {
     SHORT = 3,
     LONG = 4,
     RATIONAL = 5,
+    UNDEFINED = 7,
 }
 }
 
@@ -91,7 +100,9 @@ pub enum Type {
 pub enum Value {
     //Signed(i32),
     Unsigned(u32),
-    List(Vec<Value>)
+    List(Vec<Value>),
+    Ascii(String),
+    Rational(u32, u32),
 }
 
 impl Value {
@@ -113,9 +124,45 @@ impl Value {
                 Ok(new_vec)
             },
             Unsigned(val) => Ok(vec![val]),
-            //_ => Err(::image::FormatError("Tag data malformed.".to_string()))
+            val => Err(::image::ImageError::FormatError(format!(
+                "Expected unsigned integers, {:?} found.", val
+            )))
+        }
+    }
+    pub fn as_string(self) -> ::image::ImageResult<String> {
+        match self {
+            Ascii(val) => Ok(val),
+            val => Err(::image::ImageError::FormatError(format!(
+                "Expected a string, {:?} found.", val
+            )))
+        }
+    }
+    pub fn as_rational(self) -> ::image::ImageResult<(u32, u32)> {
+        match self {
+            Rational(n, d) => Ok((n, d)),
+            val => Err(::image::ImageError::FormatError(format!(
+                "Expected a rational number, {:?} found.", val
+            )))
+        }
+    }
+    pub fn as_rational_vec(self) -> ::image::ImageResult<Vec<(u32, u32)>> {
+        match self {
+            List(vec) => {
+                let mut new_vec = Vec::with_capacity(vec.len());
+                for v in vec.into_iter() {
+                    new_vec.push(try!(v.as_rational()))
+                }
+                Ok(new_vec)
+            },
+            Rational(n, d) => Ok(vec![(n, d)]),
+            val => Err(::image::ImageError::FormatError(format!(
+                "Expected rational numbers, {:?} found.", val
+            )))
         }
     }
+    pub fn as_bytes(self) -> ::image::ImageResult<Vec<u8>> {
+        Ok(try!(self.as_u32_vec()).into_iter().map(|v| v as u8).collect())
+    }
 }
 
 pub struct Entry {
@@ -151,9 +198,33 @@ impl Entry {
         )
     }
 
-    pub fn val<R: Read + Seek>(&self, decoder: &mut super::TIFFDecoder<R>)
+    // The values of ```n``` bytes, they are stored in the offset field if they fit into it
+    fn bytes<R: Read + Seek>(&self, reader: &mut SmartReader<R>, n: u32)
+    -> ::image::ImageResult<Vec<u8>> {
+        if n <= 4 {
+            return Ok(self.offset[..n as usize].to_vec())
+        }
+
+        try!(self.goto_value(reader));
+
+        let mut bytes = Vec::new();
+        try!(reader.by_ref().take(n as u64).read_to_end(&mut bytes));
+        if bytes.len() < n as usize {
+            return Err(::image::ImageError::FormatError("Tag data truncated.".to_string()))
+        }
+        Ok(bytes)
+    }
+
+    // Moves the reader to the values that do not fit into the offset field
+    fn goto_value<R: Read + Seek>(&self, reader: &mut SmartReader<R>) -> ::image::ImageResult<()> {
+        let offset = try!(self.r(reader.byte_order).read_u32());
+        try!(reader.seek(io::SeekFrom::Start(offset as u64)));
+        Ok(())
+    }
+
+    pub fn val<R: Read + Seek>(&self, reader: &mut SmartReader<R>)
     -> ::image::ImageResult<Value> {
-        let bo = decoder.byte_order();
+        let bo = reader.byte_order;
         match (self.type_, self.count) {
             // TODO check if this could give wrong results
             // at a different endianess of file/computer.
@@ -167,26 +238,91 @@ impl Entry {
                 ]))
             },
             (Type::SHORT, n) => {
-                let mut v = Vec::with_capacity(n as usize);
-                try!(decoder.goto_offset(try!(self.r(bo).read_u32())));
+                // The count is not trusted before the values have been read
+                let mut v = Vec::with_capacity(cmp::min(n, 1 << 16) as usize);
+                try!(self.goto_value(reader));
                 for _ in 0 .. n {
-                    v.push(Unsigned(try!(decoder.read_short()) as u32))
+                    v.push(Unsigned(try!(reader.read_u16()) as u32))
                 }
                 Ok(List(v))
             },
             (Type::LONG, 1) => Ok(Unsigned(try!(self.r(bo).read_u32()))),
             (Type::LONG, n) => {
-                let mut v = Vec::with_capacity(n as usize);
-                try!(decoder.goto_offset(try!(self.r(bo).read_u32())));
+                // The count is not trusted before the values have been read
+                let mut v = Vec::with_capacity(cmp::min(n, 1 << 16) as usize);
+                try!(self.goto_value(reader));
                 for _ in 0 .. n {
-                    v.push(Unsigned(try!(decoder.read_long())))
+                    v.push(Unsigned(try!(reader.read_u32())))
                 }
                 Ok(List(v))
             }
-            _ => Err(::image::ImageError::UnsupportedError("Unsupported data type.".to_string()))
+            (Type::BYTE, n) | (Type::UNDEFINED, n) => {
+                let bytes = try!(self.bytes(reader, n));
+                Ok(List(bytes.into_iter().map(|b| Unsigned(b as u32)).collect()))
+            },
+            (Type::ASCII, n) => {
+                // The string is terminated by a null byte
+                let mut bytes = try!(self.bytes(reader, n));
+                if let Some(end) = bytes.iter().position(|&b| b == 0) {
+                    bytes.truncate(end)
+                }
+                Ok(Ascii(String::from_utf8_lossy(&bytes).into_owned()))
+            },
+            (Type::RATIONAL, n) => {
+                try!(self.goto_value(reader));
+                let mut v = Vec::with_capacity(cmp::min(n, 1 << 16) as usize);
+                for _ in 0 .. n {
+                    let numerator = try!(reader.read_u32());
+                    v.push(Rational(numerator, try!(reader.read_u32())))
+                }
+                Ok(if n == 1 { v.pop().unwrap() } else { List(v) })
+            },
         }
     }
 }
 
 /// Type representing an Image File Directory
 pub type Directory = HashMap<Tag, Entry>;
+
+// Reads an IFD entry, entries of an unknown type are skipped.
+// An IFD entry has four fields:
+//
+// Tag   2 bytes
+// Type  2 bytes
+// Count 4 bytes
+// Value 4 bytes either a pointer the value itself
+fn read_entry<R: Read + Seek>(reader: &mut SmartReader<R>) -> ::image::ImageResult<Option<(Tag, Entry)>> {
+    let tag = Tag::from_u16(try!(reader.read_u16()));
+    let type_: Type = match FromPrimitive::from_u16(try!(reader.read_u16())) {
+        Some(t) => t,
+        None => {
+            // Unknown type. Skip this entry according to spec.
+            try!(reader.read_u32());
+            try!(reader.read_u32());
+            return Ok(None)
+        }
+    };
+    let count = try!(reader.read_u32());
+    let mut offset = [0; 4];
+    try!(reader.read_exact(&mut offset));
+    Ok(Some((tag, Entry::new(type_, count, offset))))
+}
+
+/// Reads the IFD at ```offset```, returns it and the offset of the next IFD
+pub fn read_ifd<R: Read + Seek>(reader: &mut SmartReader<R>, offset: u32)
+-> ::image::ImageResult<(Directory, Option<u32>)> {
+    let mut dir: Directory = HashMap::new();
+    try!(reader.seek(io::SeekFrom::Start(offset as u64)));
+    for _ in 0..try!(reader.read_u16()) {
+        let (tag, entry) = match try!(read_entry(reader)) {
+            Some(val) => val,
+            None => continue // Unknown data type in tag, skip
+        };
+        dir.insert(tag, entry);
+    }
+    let next = match try!(reader.read_u32()) {
+        0 => None,
+        n => Some(n)
+    };
+    Ok((dir, next))
+}
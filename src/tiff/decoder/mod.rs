@@ -1,7 +1,6 @@
 use std::io::{self, Read, Seek};
-use std::mem;
+use std::cmp;
 use num::FromPrimitive;
-use std::collections::HashMap;
 use byteorder;
 use num;
 
@@ -11,7 +10,12 @@ use image::{
     ImageResult,
     ImageDecoder,
     DecodingResult,
-    DecodingBuffer
+    DecodingBuffer,
+    ImageFormat,
+    ImageMetadata,
+    Limits,
+    Orientation,
+    UnsupportedFeature,
 };
 
 use color::{ColorType};
@@ -25,8 +29,8 @@ use self::stream::{
     LZWReader
 };
 
-mod ifd;
-mod stream;
+pub mod ifd;
+pub mod stream;
 
 enum_from_primitive! {
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -85,7 +89,8 @@ pub struct TIFFDecoder<R> where R: Read + Seek {
     bits_per_sample: Vec<u8>,
     samples: u8,
     photometric_interpretation: PhotometricInterpretation,
-    compression_method: CompressionMethod
+    compression_method: CompressionMethod,
+    limits: Limits
 }
 
 fn rev_hpredict_nsamp<T>(mut image: Vec<T>,
@@ -110,9 +115,9 @@ fn rev_hpredict(image: DecodingResult, size: (u32, u32), color_type: ColorType)
         ColorType::Gray(8) | ColorType::Gray(16) => 1,
         ColorType::RGB(8) | ColorType::RGB(16) => 3,
         ColorType::RGBA(8) | ColorType::RGBA(16) => 4,
-        _ => return Err(ImageError::UnsupportedError(format!(
-            "Horizontal predictor for {:?} is unsupported.", color_type
-        )))
+        _ => return Err(ImageError::unsupported(ImageFormat::TIFF, UnsupportedFeature::Other(format!(
+            "horizontal predictor for {:?}", color_type
+        ))))
     };
     Ok(match image {
         DecodingResult::U8(buf) => {
@@ -137,13 +142,16 @@ impl<R: Read + Seek> TIFFDecoder<R> {
             bits_per_sample: vec![1],
             samples: 1,
             photometric_interpretation: PhotometricInterpretation::BlackIsZero,
-            compression_method: CompressionMethod::None
+            compression_method: CompressionMethod::None,
+            limits: Limits::default()
         }.init()
     }
 
     fn read_header(&mut self) -> ImageResult<()> {
         let mut endianess = Vec::with_capacity(2);
-        try!(self.reader.by_ref().take(2).read_to_end(&mut endianess));
+        if try!(self.reader.by_ref().take(2).read_to_end(&mut endianess)) < 2 {
+            return Err(ImageError::NotEnoughData)
+        }
         match &*endianess {
             b"II" => {
                 self.byte_order = ByteOrder::LittleEndian;
@@ -182,17 +190,17 @@ impl<R: Read + Seek> TIFFDecoder<R> {
             try!(self.get_tag_u32(ifd::Tag::PhotometricInterpretation))
         ) {
             Some(val) => val,
-            None => return Err(image::ImageError::UnsupportedError(
-                "The image is using an unknown photometric interpretation.".to_string()
-            ))
+            None => return Err(ImageError::unsupported(ImageFormat::TIFF, UnsupportedFeature::Other(
+                "unknown photometric interpretation".to_string()
+            )))
         };
         match try!(self.find_tag_u32(ifd::Tag::Compression)) {
             Some(val) => match FromPrimitive::from_u32(val) {
                 Some(method) =>  {
                     self.compression_method = method
                 },
-                None => return Err(image::ImageError::UnsupportedError(
-                    "Unknown compression method.".to_string()
+                None => return Err(ImageError::unsupported(
+                    ImageFormat::TIFF, UnsupportedFeature::Compression(val)
                 ))
             },
             None => {}
@@ -221,9 +229,9 @@ impl<R: Read + Seek> TIFFDecoder<R> {
                 }
 
             }
-            _ => return Err(image::ImageError::UnsupportedError(
-                format!("{} samples per pixel is supported.", self.samples)
-            ))
+            _ => return Err(ImageError::unsupported(ImageFormat::TIFF, UnsupportedFeature::Other(
+                format!("{} samples per pixel", self.samples)
+            )))
         }
         Ok(self)
     }
@@ -269,68 +277,26 @@ impl<R: Read + Seek> TIFFDecoder<R> {
         self.reader.seek(io::SeekFrom::Start(offset as u64)).map(|_| ())
     }
 
-    /// Reads a IFD entry.
-    // An IFD entry has four fields:
-    //
-    // Tag   2 bytes
-    // Type  2 bytes
-    // Count 4 bytes
-    // Value 4 bytes either a pointer the value itself
-    fn read_entry(&mut self) -> ImageResult<Option<(ifd::Tag, ifd::Entry)>> {
-        let tag = ifd::Tag::from_u16(try!(self.read_short()));
-        let type_: ifd::Type = match FromPrimitive::from_u16(try!(self.read_short())) {
-            Some(t) => t,
-            None => {
-                // Unknown type. Skip this entry according to spec.
-                try!(self.read_long());
-                try!(self.read_long());
-                return Ok(None)
-
-            }
-        };
-        Ok(Some((tag, ifd::Entry::new(
-            type_,
-            try!(self.read_long()), // count
-            try!(self.read_offset())  // offset
-        ))))
-    }
-
     /// Reads the next IFD
     fn read_ifd(&mut self) -> ImageResult<Directory> {
-        let mut dir: Directory = HashMap::new();
-        match self.next_ifd {
+        let offset = match self.next_ifd {
             None => return Err(image::ImageError::FormatError(
                 "Image file directory not found.".to_string())
             ),
-            Some(offset) => try!(self.goto_offset(offset))
-        }
-        for _ in 0..try!(self.read_short()) {
-            let (tag, entry) = match try!(self.read_entry()) {
-                Some(val) => val,
-                None => continue // Unknown data type in tag, skip
-            };
-            dir.insert(tag, entry);
-        }
-        self.next_ifd = match try!(self.read_long()) {
-            0 => None,
-            n => Some(n)
+            Some(offset) => offset
         };
+        let (dir, next_ifd) = try!(ifd::read_ifd(&mut self.reader, offset));
+        self.next_ifd = next_ifd;
         Ok(dir)
     }
 
     /// Tries to retrieve a tag.
     /// Return `Ok(None)` if the tag is not present.
     fn find_tag(&mut self, tag: ifd::Tag) -> ImageResult<Option<ifd::Value>> {
-        let ifd: &Directory = unsafe {
-            let ifd = self.ifd.as_ref().unwrap(); // Ok to fail
-            // Get a immutable borrow of self
-            // This is ok because entry val only changes the stream
-            // but not the directory.
-            mem::transmute_copy(&ifd)
-        };
+        let ifd = self.ifd.as_ref().unwrap(); // Ok to fail
         match ifd.get(&tag) {
             None => Ok(None),
-            Some(entry) => Ok(Some(try!(entry.val(self))))
+            Some(entry) => Ok(Some(try!(entry.val(&mut self.reader))))
         }
     }
 
@@ -350,6 +316,30 @@ impl<R: Read + Seek> TIFFDecoder<R> {
         }
     }
 
+    /// Tries to retrieve a tag and convert it to the desired type.
+    fn find_tag_string(&mut self, tag: ifd::Tag) -> ImageResult<Option<String>> {
+        match try!(self.find_tag(tag)) {
+            Some(val) => Ok(Some(try!(val.as_string()))),
+            None => Ok(None)
+        }
+    }
+
+    /// Tries to retrieve a tag and convert it to the desired type.
+    fn find_tag_rational(&mut self, tag: ifd::Tag) -> ImageResult<Option<(u32, u32)>> {
+        match try!(self.find_tag(tag)) {
+            Some(val) => Ok(Some(try!(val.as_rational()))),
+            None => Ok(None)
+        }
+    }
+
+    /// Tries to retrieve a tag and convert it to the desired type.
+    fn find_tag_bytes(&mut self, tag: ifd::Tag) -> ImageResult<Option<Vec<u8>>> {
+        match try!(self.find_tag(tag)) {
+            Some(val) => Ok(Some(try!(val.as_bytes()))),
+            None => Ok(None)
+        }
+    }
+
     /// Tries to retrieve a tag.
     /// Returns an error if the tag is not present
     fn get_tag(&mut self, tag: ifd::Tag) -> ImageResult<ifd::Value> {
@@ -371,6 +361,83 @@ impl<R: Read + Seek> TIFFDecoder<R> {
         (try!(self.get_tag(tag))).as_u32_vec()
     }
 
+    /// Decodes the ```rows``` rows of the strips from ```first_strip``` on
+    fn read_strips(&mut self, first_strip: usize, rows: u32) -> ImageResult<DecodingResult> {
+        let buffer_size =
+            self.width  as usize
+            * rows as usize
+            * self.bits_per_sample.iter().count();
+        let max_bits = self.bits_per_sample.iter().map(|&x| x).max().unwrap_or(8);
+        let mut result = match (max_bits as f32/8.0).ceil() as u8 {
+            n if n <= 8 => DecodingResult::U8(Vec::with_capacity(buffer_size)),
+            n if n <= 16 => DecodingResult::U16(Vec::with_capacity(buffer_size)),
+            _ => return Err(
+                ImageError::unsupported(ImageFormat::TIFF, UnsupportedFeature::BitDepth(max_bits))
+            )
+        };
+        if let Ok(config) = self.get_tag_u32(ifd::Tag::PlanarConfiguration) {
+            match FromPrimitive::from_u32(config) {
+                Some(PlanarConfiguration::Chunky) => {},
+                config => return Err(ImageError::unsupported(ImageFormat::TIFF, UnsupportedFeature::Other(
+                    format!("planar configuration “{:?}”", config)
+                )))
+            }
+        }
+        // Safe since the uninizialized values are never read.
+        match result {
+            DecodingResult::U8(ref mut buffer) =>
+                unsafe { buffer.set_len(buffer_size) },
+            DecodingResult::U16(ref mut buffer) =>
+                unsafe { buffer.set_len(buffer_size) },
+        }
+        let mut units_read = 0;
+        for (&offset, &byte_count) in try!(self.get_tag_u32_vec(ifd::Tag::StripOffsets))
+        .iter().zip(try!(self.get_tag_u32_vec(ifd::Tag::StripByteCounts)).iter()).skip(first_strip) {
+            units_read += match result {
+                DecodingResult::U8(ref mut buffer) => {
+                    try!(self.expand_strip(
+                        DecodingBuffer::U8(&mut buffer[units_read..]),
+                        offset, byte_count
+                    ))
+                },
+                DecodingResult::U16(ref mut buffer) => {
+                    try!(self.expand_strip(
+                        DecodingBuffer::U16(&mut buffer[units_read..]),
+                        offset, byte_count
+                    ))
+                },
+            };
+            if units_read == buffer_size {
+                break
+            }
+        }
+        // Shrink length such that the uninitialized memory is not exposed.
+        if units_read < buffer_size {
+            match result {
+                DecodingResult::U8(ref mut buffer) =>
+                    unsafe { buffer.set_len(units_read) },
+                DecodingResult::U16(ref mut buffer) =>
+                    unsafe { buffer.set_len(units_read) },
+            }
+        }
+        if let Ok(predictor) = self.get_tag_u32(ifd::Tag::Predictor) {
+            result = match FromPrimitive::from_u32(predictor) {
+                Some(Predictor::None) => result,
+                Some(Predictor::Horizontal) => {
+                    try!(rev_hpredict(
+                        result,
+                        (self.width, rows),
+                        try!(self.colortype())
+                    ))
+                },
+                None => return Err(ImageError::FormatError(
+                    format!("Unkown predictor “{}” encountered", predictor)
+                ))
+            }
+        }
+        Ok(result)
+    }
+
     /// Decompresses the strip into the supplied buffer.
     /// Returns the number of bytes read.
     fn expand_strip<'a>(&mut self, buffer: DecodingBuffer<'a>, offset: u32, length: u32) -> ImageResult<usize> {
@@ -382,12 +449,27 @@ impl<R: Read + Seek> TIFFDecoder<R> {
                 (length as usize, Box::new(SmartReader::wrap(&mut self.reader, order)))
             },
             CompressionMethod::LZW => {
-                let (bytes, reader) = try!(LZWReader::new(&mut self.reader));
+                // A strip holds no more than RowsPerStrip rows
+                let rows_per_strip = match try!(self.find_tag_u32(ifd::Tag::RowsPerStrip)) {
+                    Some(rows) if rows > 0 => cmp::min(rows, self.height),
+                    _ => self.height
+                };
+                let bits_per_pixel: u64 = self.bits_per_sample.iter().map(|&b| b as u64).sum();
+                let max_bytes = (self.width as u64 * bits_per_pixel + 7) / 8 * rows_per_strip as u64;
+                try!(self.limits.clone().reserve(max_bytes));
+
+                let (bytes, reader) = match LZWReader::new(&mut self.reader, length, max_bytes as usize) {
+                    Ok(result) => result,
+                    Err(ref err) if err.kind() == io::ErrorKind::InvalidData => return Err(
+                        ImageError::malformed(ImageFormat::TIFF, "LZW strip expands beyond its size").at(offset as u64)
+                    ),
+                    Err(err) => return Err(err.into())
+                };
                 (bytes, Box::new(reader))
             }
-            method => return Err(::image::ImageError::UnsupportedError(format!(
-                "Compression method {:?} is unsupported", method
-            )))
+            method => return Err(ImageError::unsupported(
+                ImageFormat::TIFF, UnsupportedFeature::Compression(method as u32)
+            ).at(offset as u64))
         };
         Ok(match (color_type, buffer) {
             (ColorType:: RGB(8), DecodingBuffer::U8(ref mut buffer)) |
@@ -419,9 +501,9 @@ impl<R: Read + Seek> TIFFDecoder<R> {
                 }
                 bytes
             }
-            (type_, _) => return Err(::image::ImageError::UnsupportedError(format!(
-                "Color type {:?} is unsupported", type_
-            )))
+            (type_, _) => return Err(ImageError::unsupported(
+                ImageFormat::TIFF, UnsupportedFeature::Color(type_)
+            ))
         })
     }
 }
@@ -442,9 +524,9 @@ impl<R: Read + Seek> ImageDecoder for TIFFDecoder<R> {
             PhotometricInterpretation::BlackIsZero | PhotometricInterpretation::WhiteIsZero
                                            if self.bits_per_sample.len() == 1 => Ok(ColorType::Gray(self.bits_per_sample[0])),
 
-            _ => return Err(::image::ImageError::UnsupportedError(format!(
-                "{:?} with {:?} bits per sample is unsupported", self.bits_per_sample, self.photometric_interpretation
-            ))) // TODO: this is bad we should not fail at this point}
+            _ => return Err(ImageError::unsupported(ImageFormat::TIFF, UnsupportedFeature::Other(format!(
+                "{:?} with {:?} bits per sample", self.photometric_interpretation, self.bits_per_sample
+            )))) // TODO: this is bad we should not fail at this point}
         }
     }
 
@@ -452,87 +534,236 @@ impl<R: Read + Seek> ImageDecoder for TIFFDecoder<R> {
         unimplemented!()
     }
 
+    fn set_limits(&mut self, limits: Limits) -> ImageResult<()> {
+        let (width, height) = try!(self.dimensions());
+        let color = try!(self.colortype());
+        try!(limits.check_image(width, height, color));
+        self.limits = limits;
+        Ok(())
+    }
+
+    // The EXIF data of a TIFF image is part of the file itself and is not returned
+    fn metadata(&mut self) -> ImageResult<ImageMetadata> {
+        let mut metadata = ImageMetadata::default();
+
+        let x = try!(self.find_tag_rational(ifd::Tag::XResolution));
+        let y = try!(self.find_tag_rational(ifd::Tag::YResolution));
+        if let (Some((xn, xd)), Some((yn, yd))) = (x, y) {
+            // The resolution is given per inch unless the unit says otherwise
+            let scale = match try!(self.find_tag_u32(ifd::Tag::ResolutionUnit)).unwrap_or(2) {
+                2 => Some(1.0),
+                3 => Some(2.54),
+                _ => None
+            };
+            if let (Some(scale), true) = (scale, xd != 0 && yd != 0) {
+                metadata.dpi = Some((xn as f64 / xd as f64 * scale, yn as f64 / yd as f64 * scale));
+            }
+        }
+
+        for &tag in [ifd::Tag::ImageDescription, ifd::Tag::Make, ifd::Tag::Model, ifd::Tag::Software,
+                     ifd::Tag::DateTime, ifd::Tag::Artist, ifd::Tag::HostComputer, ifd::Tag::Copyright].iter() {
+            if let Some(text) = try!(self.find_tag_string(tag)) {
+                metadata.text.push((format!("{:?}", tag), text));
+            }
+        }
+
+        metadata.icc_profile = try!(self.find_tag_bytes(ifd::Tag::IccProfile));
+        metadata.xmp = try!(self.find_tag_bytes(ifd::Tag::XMP));
+        metadata.orientation = try!(self.find_tag_u32(ifd::Tag::Orientation))
+            .and_then(|v| Orientation::from_exif(v as u16));
+
+        Ok(metadata)
+    }
+
     fn read_scanline(&mut self, _: &mut [u8]) -> ImageResult<u32> {
         unimplemented!()
     }
 
     fn read_image(&mut self) -> ImageResult<DecodingResult> {
-        let buffer_size =
-            self.width  as usize
-            * self.height as usize
-            * self.bits_per_sample.iter().count();
-        let mut result = match (self.bits_per_sample.iter()
-                                               .map(|&x| x)
-                                               .max()
-                                               .unwrap_or(8) as f32/8.0).ceil() as u8 {
-            n if n <= 8 => DecodingResult::U8(Vec::with_capacity(buffer_size)),
-            n if n <= 16 => DecodingResult::U16(Vec::with_capacity(buffer_size)),
-            n => return Err(
-                ImageError::UnsupportedError(
-                    format!("{} bits per channel not supported", n)
-                )
-            )
+        let height = self.height;
+        self.read_strips(0, height)
+    }
+
+    fn load_rect(&mut self, x: u32, y: u32, width: u32, height: u32) -> ImageResult<DecodingResult> {
+        try!(image::check_rect((self.width, self.height), x, y, width, height));
+        if height == 0 {
+            return image::load_rect_from_image(self, x, y, width, height)
+        }
+
+        // Only the strips that hold rows of the region are decoded
+        let rows_per_strip = match try!(self.find_tag_u32(ifd::Tag::RowsPerStrip)) {
+            Some(rows) if rows > 0 => rows,
+            _ => self.height
         };
-        if let Ok(config) = self.get_tag_u32(ifd::Tag::PlanarConfiguration) {
-            match FromPrimitive::from_u32(config) {
-                Some(PlanarConfiguration::Chunky) => {},
-                config => return Err(ImageError::UnsupportedError(
-                    format!("Unsupported planar configuration “{:?}”.", config)
-                ))
-            }
+        let first_strip = y / rows_per_strip;
+        let last_strip = (y + height - 1) / rows_per_strip;
+        let end = cmp::min((last_strip as u64 + 1) * rows_per_strip as u64, self.height as u64) as u32;
+
+        let rows = try!(self.read_strips(first_strip as usize, end - first_strip * rows_per_strip));
+        let color = try!(self.colortype());
+        image::crop_rect(rows, color, self.width, x, y - first_strip * rows_per_strip, width, height)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use image::{ImageDecoder, Orientation};
+    use super::TIFFDecoder;
+
+    // Appends an IFD entry in little endian order
+    fn entry(data: &mut Vec<u8>, tag: u16, type_: u16, count: u32, value: u32) {
+        data.extend_from_slice(&[tag as u8, (tag >> 8) as u8, type_ as u8, (type_ >> 8) as u8]);
+        for &v in [count, value].iter() {
+            data.extend_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
         }
-        // Safe since the uninizialized values are never read.
-        match result {
-            DecodingResult::U8(ref mut buffer) =>
-                unsafe { buffer.set_len(buffer_size) },
-            DecodingResult::U16(ref mut buffer) =>
-                unsafe { buffer.set_len(buffer_size) },
+    }
+
+    #[test]
+    fn test_metadata() {
+        // A 1x1 gray image, the values that do not fit into the IFD follow it at byte 194
+        let mut data = b"II*\0\x08\0\0\0\x0f\0".to_vec();
+        entry(&mut data, 256, 3, 1, 1);
+        entry(&mut data, 257, 3, 1, 1);
+        entry(&mut data, 258, 3, 1, 8);
+        entry(&mut data, 259, 3, 1, 1);
+        entry(&mut data, 262, 3, 1, 1);
+        entry(&mut data, 271, 2, 8, 194);
+        entry(&mut data, 273, 4, 1, 218);
+        // Rotated by 270 degrees
+        entry(&mut data, 274, 3, 1, 8);
+        entry(&mut data, 277, 3, 1, 1);
+        entry(&mut data, 278, 3, 1, 1);
+        entry(&mut data, 279, 4, 1, 1);
+        entry(&mut data, 282, 5, 1, 202);
+        entry(&mut data, 283, 5, 1, 210);
+        // Centimeters
+        entry(&mut data, 296, 3, 1, 3);
+        entry(&mut data, 34675, 7, 3, 0x636369);
+        data.extend_from_slice(&[0; 4]);
+        assert_eq!(data.len(), 194);
+
+        data.extend_from_slice(b"TestCam\0");
+        data.extend_from_slice(&[44, 1, 0, 0, 2, 0, 0, 0]);
+        data.extend_from_slice(&[100, 0, 0, 0, 1, 0, 0, 0]);
+        data.push(0x80);
+
+        let mut decoder = TIFFDecoder::new(Cursor::new(data)).unwrap();
+        let metadata = decoder.metadata().unwrap();
+        assert_eq!(metadata.dpi, Some((150.0 * 2.54, 100.0 * 2.54)));
+        assert_eq!(metadata.text, vec![("Make".to_string(), "TestCam".to_string())]);
+        assert_eq!(metadata.icc_profile, Some(b"icc".to_vec()));
+        assert_eq!(metadata.xmp, None);
+        assert_eq!(metadata.orientation, Some(Orientation::Rotate270));
+    }
+
+    #[test]
+    fn test_bit_depth() {
+        use image::{ImageError, UnsupportedFeature};
+
+        // A 1x1 gray image with 255 bits per sample
+        let mut data = b"II*\0\x08\0\0\0\x09\0".to_vec();
+        entry(&mut data, 256, 3, 1, 1);
+        entry(&mut data, 257, 3, 1, 1);
+        entry(&mut data, 258, 3, 1, 255);
+        entry(&mut data, 259, 3, 1, 1);
+        entry(&mut data, 262, 3, 1, 1);
+        entry(&mut data, 273, 4, 1, 122);
+        entry(&mut data, 277, 3, 1, 1);
+        entry(&mut data, 278, 3, 1, 1);
+        entry(&mut data, 279, 4, 1, 32);
+        data.extend_from_slice(&[0; 4 + 32]);
+
+        let mut decoder = TIFFDecoder::new(Cursor::new(data)).unwrap();
+        match decoder.read_image() {
+            Err(ImageError::UnsupportedFeature { feature: UnsupportedFeature::BitDepth(255), .. }) => (),
+            _ => panic!("the bit depth should be reported")
         }
-        let mut units_read = 0;
-        for (&offset, &byte_count) in try!(self.get_tag_u32_vec(ifd::Tag::StripOffsets))
-        .iter().zip(try!(self.get_tag_u32_vec(ifd::Tag::StripByteCounts)).iter()) {
-            units_read += match result {
-                DecodingResult::U8(ref mut buffer) => {
-                    try!(self.expand_strip(
-                        DecodingBuffer::U8(&mut buffer[units_read..]),
-                        offset, byte_count
-                    ))
-                },
-                DecodingResult::U16(ref mut buffer) => {
-                    try!(self.expand_strip(
-                        DecodingBuffer::U16(&mut buffer[units_read..]),
-                        offset, byte_count
-                    ))
-                },
-            };
-            if units_read == buffer_size {
-                break
-            }
+    }
+
+    #[test]
+    fn test_load_rect() {
+        use image::DecodingResult;
+
+        // A 2x4 gray image with a strip per row, only the last two strips are in the file
+        let mut data = b"II*\0\x08\0\0\0\x09\0".to_vec();
+        entry(&mut data, 256, 3, 1, 2);
+        entry(&mut data, 257, 3, 1, 4);
+        entry(&mut data, 258, 3, 1, 8);
+        entry(&mut data, 259, 3, 1, 1);
+        entry(&mut data, 262, 3, 1, 1);
+        entry(&mut data, 273, 4, 4, 122);
+        entry(&mut data, 277, 3, 1, 1);
+        entry(&mut data, 278, 3, 1, 1);
+        entry(&mut data, 279, 4, 4, 138);
+        data.extend_from_slice(&[0; 4]);
+        assert_eq!(data.len(), 122);
+
+        for &offset in [1000u32, 1000, 154, 156].iter() {
+            data.extend_from_slice(&[offset as u8, (offset >> 8) as u8, 0, 0]);
         }
-        // Shrink length such that the uninitialized memory is not exposed.
-        if units_read < buffer_size {
-            match result {
-                DecodingResult::U8(ref mut buffer) =>
-                    unsafe { buffer.set_len(units_read) },
-                DecodingResult::U16(ref mut buffer) =>
-                    unsafe { buffer.set_len(units_read) },
-            }
+        for _ in 0..4 {
+            data.extend_from_slice(&[2, 0, 0, 0]);
         }
-        if let Ok(predictor) = self.get_tag_u32(ifd::Tag::Predictor) {
-            result = match FromPrimitive::from_u32(predictor) {
-                Some(Predictor::None) => result,
-                Some(Predictor::Horizontal) => {
-                    try!(rev_hpredict(
-                        result,
-                        try!(self.dimensions()),
-                        try!(self.colortype())
-                    ))
-                },
-                None => return Err(ImageError::FormatError(
-                    format!("Unkown predictor “{}” encountered", predictor)
-                ))
+        data.extend_from_slice(&[20, 21, 30, 31]);
+
+        let mut decoder = TIFFDecoder::new(Cursor::new(data)).unwrap();
+        match decoder.load_rect(1, 2, 1, 2).unwrap() {
+            DecodingResult::U8(pixels) => assert_eq!(pixels, vec![21, 31]),
+            DecodingResult::U16(_) => panic!("wrong sample type")
+        }
+        assert!(decoder.load_rect(0, 3, 2, 2).is_err());
+    }
+
+    #[test]
+    fn test_lzw_limit() {
+        use image::{DecodingResult, ImageError};
+
+        // Packs 9 bit LZW codes with the most significant bit first
+        fn lzw(codes: &[u16]) -> Vec<u8> {
+            let mut data = Vec::new();
+            let (mut acc, mut nbits) = (0u32, 0);
+            for &code in codes {
+                acc = (acc << 9) | code as u32;
+                nbits += 9;
+                while nbits >= 8 {
+                    data.push((acc >> (nbits - 8)) as u8);
+                    nbits -= 8;
+                }
+            }
+            if nbits > 0 {
+                data.push((acc << (8 - nbits)) as u8);
             }
+            data
+        }
+
+        // A 1x1 gray image with a single LZW compressed strip at byte 122
+        let image = |strip: Vec<u8>| {
+            let mut data = b"II*\0\x08\0\0\0\x09\0".to_vec();
+            entry(&mut data, 256, 3, 1, 1);
+            entry(&mut data, 257, 3, 1, 1);
+            entry(&mut data, 258, 3, 1, 8);
+            entry(&mut data, 259, 3, 1, 5);
+            entry(&mut data, 262, 3, 1, 1);
+            entry(&mut data, 273, 4, 1, 122);
+            entry(&mut data, 277, 3, 1, 1);
+            entry(&mut data, 278, 3, 1, 1);
+            entry(&mut data, 279, 4, 1, strip.len() as u32);
+            data.extend_from_slice(&[0; 4]);
+            data.extend_from_slice(&strip);
+            TIFFDecoder::new(Cursor::new(data)).unwrap()
+        };
+
+        // Clear, 7, end
+        match image(lzw(&[256, 7, 257])).read_image().unwrap() {
+            DecodingResult::U8(pixels) => assert_eq!(pixels, vec![7]),
+            DecodingResult::U16(_) => panic!("wrong sample type")
+        }
+
+        // Every code repeats the previous string with one more byte, six bytes for a single pixel
+        match image(lzw(&[256, 0, 258, 259, 257])).read_image() {
+            Err(ImageError::MalformedImage { offset, .. }) => assert_eq!(offset, Some(122)),
+            _ => panic!("LZW strip not rejected")
         }
-        Ok(result)
     }
 }
@@ -1,7 +1,7 @@
 //! All IO functionality needed for TIFF decoding
 
 use std::io;
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, Write};
 use byteorder::{self, ReadBytesExt, BigEndian, LittleEndian};
 use utils::{lzw, bitstream};
 
@@ -46,11 +46,14 @@ pub struct LZWReader {
 }
 
 impl LZWReader {
-    /// Wraps a reader
-    pub fn new<R>(reader: &mut SmartReader<R>) -> io::Result<(usize, LZWReader)> where R: Read + Seek {
-        let mut buffer = Vec::new();
+    /// Wraps a reader, at most ```length``` compressed bytes are read from it
+    ///
+    /// Returns an error of kind ```InvalidData``` if the data expands to more than ```max_bytes```.
+    pub fn new<R>(reader: &mut SmartReader<R>, length: u32, max_bytes: usize) -> io::Result<(usize, LZWReader)> where R: Read + Seek {
+        let mut buffer = BoundedWriter { buffer: Vec::new(), max_bytes: max_bytes };
         let order = reader.byte_order;
-        try!(lzw::decode_early_change(bitstream::MsbReader::new(reader), &mut buffer, 8));
+        try!(lzw::decode_early_change(bitstream::MsbReader::new(reader.take(length as u64)), &mut buffer, 8));
+        let buffer = buffer.buffer;
         let bytes = buffer.len();
         Ok((bytes, LZWReader {
             buffer: io::Cursor::new(buffer),
@@ -59,6 +62,26 @@ impl LZWReader {
     }
 }
 
+// Collects the decompressed data, a small strip could otherwise expand without bounds
+struct BoundedWriter {
+    buffer: Vec<u8>,
+    max_bytes: usize
+}
+
+impl Write for BoundedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.len() + buf.len() > self.max_bytes {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "LZW data expands beyond the size of the strip"))
+        }
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 impl Read for LZWReader {
     #[inline]
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
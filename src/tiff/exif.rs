@@ -0,0 +1,211 @@
+//! Parsing of EXIF data
+//!
+//! EXIF data is a TIFF structure without any image data. Its first IFD holds
+//! the tags that describe the camera, further IFDs hold the capture settings
+//! and the GPS position.
+//!
+//! # Related Links
+//! * http://www.cipa.jp/std/documents/e/DC-008-2012_E.pdf - The EXIF specification
+//!
+
+use std::io::{Cursor, Read};
+
+use image::{ImageResult, ImageError, Orientation};
+
+use super::decoder::ifd::{self, Directory, Tag};
+use super::decoder::stream::{ByteOrder, EndianReader, SmartReader};
+
+// Tags of the GPS IFD, they overlap with the tags of the TIFF IFD
+const GPS_LATITUDE_REF: u16 = 1;
+const GPS_LATITUDE: u16 = 2;
+const GPS_LONGITUDE_REF: u16 = 3;
+const GPS_LONGITUDE: u16 = 4;
+const GPS_ALTITUDE_REF: u16 = 5;
+const GPS_ALTITUDE: u16 = 6;
+
+/// The commonly used tags of EXIF data
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Exif {
+    /// How the image has to be turned to be displayed upright
+    pub orientation: Option<Orientation>,
+    /// The date and time the image was taken, formatted as ```YYYY:MM:DD HH:MM:SS```
+    pub date_time_original: Option<String>,
+    /// The manufacturer of the camera
+    pub make: Option<String>,
+    /// The model of the camera
+    pub model: Option<String>,
+    /// The position the image was taken at
+    pub gps: Option<GpsInfo>,
+}
+
+/// A position on earth
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct GpsInfo {
+    /// The latitude in degrees, negative south of the equator
+    pub latitude: Option<f64>,
+    /// The longitude in degrees, negative west of Greenwich
+    pub longitude: Option<f64>,
+    /// The altitude in meters, negative below sea level
+    pub altitude: Option<f64>,
+}
+
+impl Exif {
+    /// Parses EXIF data, a TIFF structure without the ```Exif\0\0``` prefix of JPEG files
+    ///
+    /// Tags that are missing or malformed are ```None```.
+    pub fn parse(data: &[u8]) -> ImageResult<Exif> {
+        let mut reader = SmartReader::wrap(Cursor::new(data), ByteOrder::LittleEndian);
+
+        let mut signature = [0; 2];
+        try!(reader.read_exact(&mut signature));
+        reader.byte_order = match &signature {
+            b"II" => ByteOrder::LittleEndian,
+            b"MM" => ByteOrder::BigEndian,
+            _ => return Err(ImageError::FormatError("EXIF signature not found.".to_string()))
+        };
+        if try!(reader.read_u16()) != 42 {
+            return Err(ImageError::FormatError("EXIF signature invalid.".to_string()))
+        }
+        let offset = try!(reader.read_u32());
+        let (dir, _) = try!(ifd::read_ifd(&mut reader, offset));
+
+        let mut exif = Exif::default();
+        exif.orientation = find_u32(&mut reader, &dir, Tag::Orientation)
+            .and_then(|v| Orientation::from_exif(v as u16));
+        exif.make = find_string(&mut reader, &dir, Tag::Make);
+        exif.model = find_string(&mut reader, &dir, Tag::Model);
+
+        if let Some(offset) = find_u32(&mut reader, &dir, Tag::ExifIfd) {
+            if let Ok((exif_dir, _)) = ifd::read_ifd(&mut reader, offset) {
+                exif.date_time_original = find_string(&mut reader, &exif_dir, Tag::DateTimeOriginal);
+            }
+        }
+
+        if let Some(offset) = find_u32(&mut reader, &dir, Tag::GpsIfd) {
+            if let Ok((gps_dir, _)) = ifd::read_ifd(&mut reader, offset) {
+                exif.gps = Some(read_gps(&mut reader, &gps_dir));
+            }
+        }
+
+        Ok(exif)
+    }
+}
+
+fn read_gps(reader: &mut SmartReader<Cursor<&[u8]>>, dir: &Directory) -> GpsInfo {
+    // The degrees, minutes and seconds of a coordinate, the reference
+    // gives the hemisphere
+    let mut coordinate = |value: u16, reference: u16, negative: &str| {
+        let angle = match find_rationals(reader, dir, Tag::Unknown(value)) {
+            Some(ref v) if v.len() == 3 => v[0] + v[1] / 60.0 + v[2] / 3600.0,
+            _ => return None
+        };
+        match find_string(reader, dir, Tag::Unknown(reference)) {
+            Some(ref r) if r == negative => Some(-angle),
+            _ => Some(angle)
+        }
+    };
+
+    let latitude = coordinate(GPS_LATITUDE, GPS_LATITUDE_REF, "S");
+    let longitude = coordinate(GPS_LONGITUDE, GPS_LONGITUDE_REF, "W");
+
+    let altitude = find_rationals(reader, dir, Tag::Unknown(GPS_ALTITUDE))
+        .and_then(|v| v.first().cloned())
+        .map(|altitude| {
+            // A reference of 1 means below sea level
+            match find_u32(reader, dir, Tag::Unknown(GPS_ALTITUDE_REF)) {
+                Some(1) => -altitude,
+                _ => altitude
+            }
+        });
+
+    GpsInfo {
+        latitude: latitude,
+        longitude: longitude,
+        altitude: altitude,
+    }
+}
+
+fn find_u32(reader: &mut SmartReader<Cursor<&[u8]>>, dir: &Directory, tag: Tag) -> Option<u32> {
+    dir.get(&tag).and_then(|entry| entry.val(reader).ok()).and_then(|v| v.as_u32().ok())
+}
+
+fn find_string(reader: &mut SmartReader<Cursor<&[u8]>>, dir: &Directory, tag: Tag) -> Option<String> {
+    dir.get(&tag).and_then(|entry| entry.val(reader).ok()).and_then(|v| v.as_string().ok())
+}
+
+fn find_rationals(reader: &mut SmartReader<Cursor<&[u8]>>, dir: &Directory, tag: Tag) -> Option<Vec<f64>> {
+    dir.get(&tag)
+       .and_then(|entry| entry.val(reader).ok())
+       .and_then(|v| v.as_rational_vec().ok())
+       .map(|v| v.into_iter().map(|(n, d)| n as f64 / d as f64).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use image::Orientation;
+    use super::Exif;
+
+    // Appends an IFD entry in big endian byte order
+    fn entry(data: &mut Vec<u8>, tag: u16, type_: u16, count: u32, value: [u8; 4]) {
+        data.extend_from_slice(&[(tag >> 8) as u8, tag as u8, (type_ >> 8) as u8, type_ as u8]);
+        data.extend_from_slice(&[(count >> 24) as u8, (count >> 16) as u8, (count >> 8) as u8, count as u8]);
+        data.extend_from_slice(&value);
+    }
+
+    fn long(v: u32) -> [u8; 4] {
+        [(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]
+    }
+
+    fn rationals(data: &mut Vec<u8>, values: &[(u32, u32)]) {
+        for &(n, d) in values {
+            data.extend_from_slice(&long(n));
+            data.extend_from_slice(&long(d));
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        // IFD0 at 8 with 5 entries ends at 8 + 2 + 5 * 12 + 4 = 74,
+        // the EXIF IFD with 1 entry at 74 ends at 92,
+        // the GPS IFD with 5 entries at 92 ends at 158
+        let mut data = b"MM\0\x2a\0\0\0\x08".to_vec();
+        data.extend_from_slice(&[0, 5]);
+        entry(&mut data, 271, 2, 6, long(158));
+        entry(&mut data, 272, 2, 4, *b"X10\0");
+        entry(&mut data, 274, 3, 1, [0, 6, 0, 0]);
+        entry(&mut data, 34665, 4, 1, long(74));
+        entry(&mut data, 34853, 4, 1, long(92));
+        data.extend_from_slice(&long(0));
+
+        data.extend_from_slice(&[0, 1]);
+        entry(&mut data, 36867, 2, 20, long(164));
+        data.extend_from_slice(&long(0));
+
+        data.extend_from_slice(&[0, 5]);
+        entry(&mut data, 1, 2, 2, *b"S\0\0\0");
+        entry(&mut data, 2, 5, 3, long(184));
+        entry(&mut data, 3, 2, 2, *b"W\0\0\0");
+        entry(&mut data, 4, 5, 3, long(208));
+        entry(&mut data, 6, 5, 1, long(232));
+        data.extend_from_slice(&long(0));
+
+        data.extend_from_slice(b"Maker\0");
+        data.extend_from_slice(b"2016:05:04 03:02:01\0");
+        rationals(&mut data, &[(33, 1), (30, 1), (0, 1)]);
+        rationals(&mut data, &[(70, 1), (3, 2), (36, 1)]);
+        rationals(&mut data, &[(1255, 10)]);
+
+        let exif = Exif::parse(&data).unwrap();
+        assert_eq!(exif.orientation, Some(Orientation::Rotate90));
+        assert_eq!(exif.make, Some("Maker".to_string()));
+        assert_eq!(exif.model, Some("X10".to_string()));
+        assert_eq!(exif.date_time_original, Some("2016:05:04 03:02:01".to_string()));
+
+        let gps = exif.gps.unwrap();
+        assert_eq!(gps.latitude, Some(-33.5));
+        assert!((gps.longitude.unwrap() + 70.035).abs() < 1e-9);
+        assert_eq!(gps.altitude, Some(125.5));
+
+        assert!(Exif::parse(b"GIF89a").is_err());
+    }
+}
@@ -3,6 +3,8 @@
 //!  TIFF (Tagged Image File Format) is a versatile image format that supports
 //!  lossless and lossy compression.
 //!
+//!  The ```exif``` module parses the TIFF structure that stores EXIF data.
+//!
 //!  # Related Links
 //!  * http://partners.adobe.com/public/developer/tiff/index.html - The TIFF specification
 //!
@@ -10,3 +12,4 @@
 pub use self::decoder::TIFFDecoder;
 
 mod decoder;
+pub mod exif;
@@ -1,38 +1,105 @@
 use std::io;
 use std::io::Read;
-use std::default::Default;
+use std::cmp;
 use byteorder::{ReadBytesExt, LittleEndian};
+use num::rational::Ratio;
 
 use image;
 use image::ImageResult;
 use image::ImageDecoder;
 
+use buffer::{ImageBuffer, ConvertBuffer, RgbImage, RgbaImage};
 use color;
+use color::Rgba;
+use animation::{Frame, Frames};
 
-use super::vp8::Frame;
 use super::vp8::VP8Decoder;
+use super::lossless::LosslessDecoder;
+use super::extended;
+use super::extended::{AnimationHeader, AnimationFrameHeader};
+
+/// The kind of image data stored in a Webp file
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ChunkType {
+    /// A lossy VP8 keyframe
+    Lossy,
+    /// A lossless VP8L image
+    Lossless,
+}
 
+// The chunks that make up a still image or a frame of an animation
+struct ImageChunks {
+    chunk: ChunkType,
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+    alpha: Option<Vec<u8>>,
+}
 
+impl ImageChunks {
+    fn new(chunk: ChunkType, data: Vec<u8>, alpha: Option<Vec<u8>>) -> ImageResult<ImageChunks> {
+        let (width, height) = try!(image_size(chunk, &data));
+
+        Ok(ImageChunks {
+            chunk: chunk,
+            width: width,
+            height: height,
+            data: data,
+            alpha: alpha,
+        })
+    }
+
+    // Lossy images without an alpha channel are decoded to RGB
+    fn color(&self) -> color::ColorType {
+        match (self.chunk, &self.alpha) {
+            (ChunkType::Lossy, &None) => color::ColorType::RGB(8),
+            _ => color::ColorType::RGBA(8)
+        }
+    }
+}
 
 /// A Representation of a Webp Image format decoder.
 pub struct WebpDecoder<R> {
     r: R,
-    frame: Frame,
+    width: u32,
+    height: u32,
+    color: color::ColorType,
+    data: Vec<u8>,
+    have_header: bool,
     have_frame: bool,
     decoded_rows: u32,
+    icc_profile: Option<Vec<u8>>,
+    exif_metadata: Option<Vec<u8>>,
+    xmp_metadata: Option<Vec<u8>>,
+    animation: Option<AnimationHeader>,
+    // The chunks of the still image or of the frames until they are decoded
+    image: Option<ImageChunks>,
+    frame_chunks: Vec<(AnimationFrameHeader, ImageChunks)>,
+    frames: Vec<Frame>,
+    limits: image::Limits,
 }
 
 impl<R: Read> WebpDecoder<R> {
     /// Create a new WebpDecoder from the Reader ```r```.
     /// This function takes ownership of the Reader.
     pub fn new(r: R) -> WebpDecoder<R> {
-        let f: Frame = Default::default();
-
         WebpDecoder {
             r: r,
+            have_header: false,
             have_frame: false,
-            frame: f,
-            decoded_rows: 0
+            width: 0,
+            height: 0,
+            color: color::ColorType::RGB(8),
+            data: Vec::new(),
+            decoded_rows: 0,
+            icc_profile: None,
+            exif_metadata: None,
+            xmp_metadata: None,
+            animation: None,
+            image: None,
+            frame_chunks: Vec::new(),
+            frames: Vec::new(),
+            limits: image::Limits::default(),
         }
     }
 
@@ -43,82 +110,452 @@ impl<R: Read> WebpDecoder<R> {
         let mut webp = Vec::with_capacity(4);
         try!(self.r.by_ref().take(4).read_to_end(&mut webp));
 
+        if webp.len() < 4 {
+            return Err(image::ImageError::NotEnoughData)
+        }
+
         if &*riff != "RIFF".as_bytes() {
-            return Err(image::ImageError::FormatError("Invalid RIFF signature.".to_string()))
+            return Err(image::ImageError::malformed(image::ImageFormat::WEBP, "Invalid RIFF signature.").at(0))
         }
 
         if &*webp != "WEBP".as_bytes() {
-            return Err(image::ImageError::FormatError("Invalid WEBP signature.".to_string()))
+            return Err(image::ImageError::malformed(image::ImageFormat::WEBP, "Invalid WEBP signature.").at(8))
         }
 
         Ok(size)
     }
 
-    fn read_vp8_header(&mut self) -> ImageResult<()> {
-        let mut vp8 = Vec::with_capacity(4);
-        try!(self.r.by_ref().take(4).read_to_end(&mut vp8));
-
-        if &*vp8 != "VP8 ".as_bytes() {
-            return Err(image::ImageError::FormatError("Invalid VP8 signature.".to_string()))
+    // Reads the chunks of the file and the dimensions stored in their headers,
+    // the pixels are only decoded by ```read_frames```
+    fn read_header(&mut self) -> ImageResult<()> {
+        if !self.have_header {
+            // The chunks fill the RIFF container after its form type
+            let size = try!(self.read_riff_header());
+            let mut r = self.r.by_ref().take(size.saturating_sub(4) as u64);
+
+            let mut extended = None;
+            let mut image = None;
+            let mut alpha = None;
+            let mut frames = Vec::new();
+
+            while let Some((fourcc, data)) = try!(read_chunk(&mut r)) {
+                match &fourcc {
+                    b"VP8 " if image.is_none() => image = Some((ChunkType::Lossy, data)),
+                    b"VP8L" if image.is_none() => image = Some((ChunkType::Lossless, data)),
+                    b"VP8X" => extended = Some(try!(extended::ExtendedHeader::from_chunk(&data))),
+                    b"ALPH" => alpha = Some(data),
+                    b"ANIM" => self.animation = Some(try!(AnimationHeader::from_chunk(&data))),
+                    b"ANMF" => frames.push(try!(read_animation_frame(&data))),
+                    b"ICCP" => self.icc_profile = Some(data),
+                    b"EXIF" => self.exif_metadata = Some(data),
+                    b"XMP " => self.xmp_metadata = Some(data),
+                    // Unknown chunks are ignored
+                    _ => ()
+                }
+            }
+
+            match extended {
+                Some(header) if header.animation => {
+                    if self.animation.is_none() || frames.is_empty() {
+                        return Err(image::ImageError::malformed(
+                            image::ImageFormat::WEBP, "Animation without frames."))
+                    }
+
+                    // The first frame stands in for the whole animation
+                    self.width  = header.canvas_width;
+                    self.height = header.canvas_height;
+                    self.color  = color::ColorType::RGBA(8);
+                    self.frame_chunks = frames;
+                }
+
+                _ => {
+                    let image = match image {
+                        Some((chunk, data)) => try!(ImageChunks::new(chunk, data, alpha)),
+                        // The file ends before the container
+                        None if r.limit() > 0 => return Err(image::ImageError::NotEnoughData),
+                        None => return Err(image::ImageError::malformed(
+                            image::ImageFormat::WEBP, "No image data found."))
+                    };
+
+                    self.width  = image.width;
+                    self.height = image.height;
+                    self.color  = image.color();
+                    self.image  = Some(image);
+                }
+            }
+
+            self.have_header = true;
         }
 
-        let _len = try!(self.r.read_u32::<LittleEndian>());
+        Ok(())
+    }
+
+    fn read_frames(&mut self) -> ImageResult<()> {
+        try!(self.read_header());
+
+        if !self.have_frame {
+            match self.image {
+                Some(ref image) => self.data = try!(decode_image(image, self.limits)),
+                None => {
+                    self.frames = try!(composite_frames(
+                        self.width, self.height, &self.frame_chunks, self.limits));
+                    self.data = self.frames[0].buffer().clone().into_raw();
+                }
+            }
+
+            self.image = None;
+            self.frame_chunks.clear();
+            self.have_frame = true;
+        }
 
         Ok(())
     }
 
-    fn read_frame(&mut self) -> ImageResult<()> {
-        let mut framedata = Vec::new();
-        try!(self.r.read_to_end(&mut framedata));
-        let m = io::Cursor::new(framedata);
+    /// Returns the embedded ICC profile, if there is one
+    pub fn icc_profile(&mut self) -> ImageResult<Option<&[u8]>> {
+        try!(self.read_header());
+
+        Ok(self.icc_profile.as_ref().map(|v| &v[..]))
+    }
+
+    /// Returns the raw EXIF metadata, if there is any
+    pub fn exif_metadata(&mut self) -> ImageResult<Option<&[u8]>> {
+        try!(self.read_header());
 
-        let mut v = VP8Decoder::new(m);
-        let frame = try!(v.decode_frame());
+        Ok(self.exif_metadata.as_ref().map(|v| &v[..]))
+    }
 
-        self.frame = frame.clone();
+    /// Returns the XMP metadata, if there is any
+    pub fn xmp_metadata(&mut self) -> ImageResult<Option<&[u8]>> {
+        try!(self.read_header());
 
-        Ok(())
+        Ok(self.xmp_metadata.as_ref().map(|v| &v[..]))
     }
 
-    fn read_metadata(&mut self) -> ImageResult<()> {
-        if !self.have_frame {
-            let _ = try!(self.read_riff_header());
-            let _ = try!(self.read_vp8_header());
-            let _ = try!(self.read_frame());
+    /// Returns how often an animation is repeated, 0 means infinitely.
+    /// Returns ```None``` for still images.
+    pub fn loop_count(&mut self) -> ImageResult<Option<u16>> {
+        try!(self.read_header());
 
-            self.have_frame = true;
+        Ok(self.animation.map(|a| a.loop_count))
+    }
+
+    /// Returns the background colour suggested by an animation.
+    /// Returns ```None``` for still images.
+    ///
+    /// The colour is only a hint, like web browsers the decoder composites
+    /// the frames on a transparent canvas.
+    pub fn background_color(&mut self) -> ImageResult<Option<Rgba<u8>>> {
+        try!(self.read_header());
+
+        Ok(self.animation.map(|a| a.background_color))
+    }
+}
+
+// Reads the next chunk, returns None at the end of the file
+fn read_chunk<R: Read>(r: &mut R) -> ImageResult<Option<([u8; 4], Vec<u8>)>> {
+    let mut fourcc = Vec::with_capacity(4);
+    try!(r.by_ref().take(4).read_to_end(&mut fourcc));
+
+    if fourcc.is_empty() {
+        return Ok(None)
+    } else if fourcc.len() < 4 {
+        return Err(image::ImageError::NotEnoughData)
+    }
+
+    let len = try!(r.read_u32::<LittleEndian>());
+
+    // The length is not trusted before the data has been read
+    let mut data = Vec::new();
+    try!(r.by_ref().take(len as u64).read_to_end(&mut data));
+
+    if data.len() < len as usize {
+        return Err(image::ImageError::NotEnoughData)
+    }
+
+    // Chunks are padded to an even size
+    if len % 2 == 1 {
+        let mut padding = Vec::with_capacity(1);
+        try!(r.by_ref().take(1).read_to_end(&mut padding));
+    }
+
+    Ok(Some(([fourcc[0], fourcc[1], fourcc[2], fourcc[3]], data)))
+}
+
+/// Reads the dimensions and the color type from the first chunks of a Webp file
+///
+/// Returns ```ImageError::NotEnoughData``` if ```data``` ends in front of them.
+/// Used by the ```IncrementalDecoder``` to report the header before the image data arrived.
+#[doc(hidden)]
+pub fn read_header_prefix(data: &[u8]) -> ImageResult<(u32, u32, color::ColorType)> {
+    if data.len() < 12 {
+        return Err(image::ImageError::NotEnoughData)
+    }
+
+    if &data[..4] != b"RIFF" {
+        return Err(image::ImageError::malformed(image::ImageFormat::WEBP, "Invalid RIFF signature.").at(0))
+    }
+
+    if &data[8..12] != b"WEBP" {
+        return Err(image::ImageError::malformed(image::ImageFormat::WEBP, "Invalid WEBP signature.").at(8))
+    }
+
+    let mut pos = 12;
+    let mut alpha = false;
+    loop {
+        if data.len() < pos + 8 {
+            return Err(image::ImageError::NotEnoughData)
         }
 
-        Ok(())
+        let len = data[pos + 4] as usize | (data[pos + 5] as usize) << 8 |
+                  (data[pos + 6] as usize) << 16 | (data[pos + 7] as usize) << 24;
+        let chunk = &data[pos + 8..cmp::min(data.len(), (pos + 8).saturating_add(len))];
+
+        // The headers of the chunks holding the dimensions are at most 10 bytes long
+        if chunk.len() < cmp::min(len, 10) {
+            return Err(image::ImageError::NotEnoughData)
+        }
+
+        match &data[pos..pos + 4] {
+            b"VP8 " => {
+                let (width, height) = try!(image_size(ChunkType::Lossy, chunk));
+                let color = if alpha { color::ColorType::RGBA(8) } else { color::ColorType::RGB(8) };
+                return Ok((width, height, color))
+            }
+            b"VP8L" => {
+                let (width, height) = try!(image_size(ChunkType::Lossless, chunk));
+                return Ok((width, height, color::ColorType::RGBA(8)))
+            }
+            b"VP8X" => {
+                let header = try!(extended::ExtendedHeader::from_chunk(chunk));
+                if header.animation {
+                    return Ok((header.canvas_width, header.canvas_height, color::ColorType::RGBA(8)))
+                }
+            }
+            // The alpha channel of an extended image precedes its VP8 chunk
+            b"ALPH" => alpha = true,
+            _ => ()
+        }
+
+        // Chunks are padded to an even size
+        pos = (pos + 8).saturating_add(len + len % 2);
+    }
+}
+
+fn read_animation_frame(data: &[u8]) -> ImageResult<(AnimationFrameHeader, ImageChunks)> {
+    let header = try!(AnimationFrameHeader::from_chunk(data));
+
+    let mut r = io::Cursor::new(&data[extended::ANMF_HEADER_SIZE..]);
+    let mut image = None;
+    let mut alpha = None;
+
+    while let Some((fourcc, data)) = try!(read_chunk(&mut r)) {
+        match &fourcc {
+            b"VP8 " if image.is_none() => image = Some((ChunkType::Lossy, data)),
+            b"VP8L" if image.is_none() => image = Some((ChunkType::Lossless, data)),
+            b"ALPH" => alpha = Some(data),
+            _ => ()
+        }
+    }
+
+    let image = match image {
+        Some((chunk, data)) => try!(ImageChunks::new(chunk, data, alpha)),
+        None => return Err(image::ImageError::malformed(
+            image::ImageFormat::WEBP, "Animation frame without image data."))
+    };
+
+    if image.width != header.width || image.height != header.height {
+        return Err(image::ImageError::malformed(
+            image::ImageFormat::WEBP, "Animation frame size does not match its header."))
     }
+
+    Ok((header, image))
+}
+
+// Reads the dimensions from the header of a VP8 or VP8L chunk
+fn image_size(chunk: ChunkType, data: &[u8]) -> ImageResult<(u32, u32)> {
+    match chunk {
+        ChunkType::Lossy => {
+            // The frame tag is followed by the start code and the 14 bit dimensions of a keyframe
+            if data.len() < 10 {
+                return Err(image::ImageError::malformed(image::ImageFormat::WEBP, "VP8 chunk too short."))
+            }
+
+            if data[0] & 1 != 0 {
+                return Err(image::ImageError::malformed(
+                    image::ImageFormat::WEBP, "VP8 chunk does not hold a keyframe."))
+            }
+
+            if &data[3..6] != &[0x9d, 0x01, 0x2a] {
+                return Err(image::ImageError::malformed(
+                    image::ImageFormat::WEBP, "Invalid VP8 start code.").at(3))
+            }
+
+            let width  = (data[6] as u32 | (data[7] as u32) << 8) & 0x3fff;
+            let height = (data[8] as u32 | (data[9] as u32) << 8) & 0x3fff;
+
+            Ok((width, height))
+        }
+
+        ChunkType::Lossless => {
+            // The signature is followed by the 14 bit dimensions, minus one
+            if data.len() < 5 {
+                return Err(image::ImageError::malformed(image::ImageFormat::WEBP, "VP8L chunk too short."))
+            }
+
+            if data[0] != 0x2f {
+                return Err(image::ImageError::malformed(
+                    image::ImageFormat::WEBP, "Invalid VP8L signature.").at(0))
+            }
+
+            let bits = data[1] as u32 | (data[2] as u32) << 8 |
+                       (data[3] as u32) << 16 | (data[4] as u32) << 24;
+
+            Ok(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+        }
+    }
+}
+
+// Returns the pixels of an image, the decoders may use the resources within ```limits```
+fn decode_image(image: &ImageChunks, mut limits: image::Limits) -> ImageResult<Vec<u8>> {
+    let pixels = image.width as usize * image.height as usize;
+    let m = io::Cursor::new(&image.data[..]);
+
+    match image.chunk {
+        ChunkType::Lossy => {
+            try!(limits.reserve(3 * pixels as u64));
+            if image.alpha.is_some() {
+                try!(limits.reserve(5 * pixels as u64));
+            }
+
+            let mut v = VP8Decoder::new(m);
+            v.set_limits(limits);
+            let frame = try!(v.decode_frame().map_err(vp8_error));
+
+            let mut rgb = vec![0u8; 3 * pixels];
+            frame.fill_rgb(&mut rgb);
+
+            match image.alpha {
+                Some(ref alpha) => {
+                    let alpha = try!(extended::read_alpha_chunk(alpha, frame.width, frame.height, limits));
+                    let mut data = Vec::with_capacity(4 * pixels);
+
+                    for (p, &a) in rgb.chunks(3).zip(alpha.iter()) {
+                        data.extend_from_slice(p);
+                        data.push(a);
+                    }
+
+                    Ok(data)
+                }
+
+                None => Ok(rgb)
+            }
+        }
+
+        ChunkType::Lossless => {
+            try!(limits.reserve(4 * pixels as u64));
+
+            let mut l = LosslessDecoder::new(m);
+            l.set_limits(limits);
+            let frame = try!(l.decode_frame());
+
+            let mut data = vec![0u8; 4 * pixels];
+            frame.fill_rgba(&mut data);
+
+            Ok(data)
+        }
+    }
+}
+
+// The VP8 decoder reports exceeded limits as an io error
+fn vp8_error(err: io::Error) -> image::ImageError {
+    match err.get_ref().and_then(|e| e.downcast_ref::<image::ImageError>()) {
+        Some(&image::ImageError::LimitsExceeded) => image::ImageError::LimitsExceeded,
+        _ => image::ImageError::IoError(err)
+    }
+}
+
+fn to_rgba_image(width: u32, height: u32, color: color::ColorType, data: Vec<u8>) -> RgbaImage {
+    if color == color::ColorType::RGBA(8) {
+        ImageBuffer::from_raw(width, height, data).unwrap()
+    } else {
+        let rgb: RgbImage = ImageBuffer::from_raw(width, height, data).unwrap();
+        rgb.convert()
+    }
+}
+
+// Draws the frames of an animation, every returned frame holds the
+// whole canvas and thus has an offset of 0.
+fn composite_frames(width: u32, height: u32, frames: &[(AnimationFrameHeader, ImageChunks)],
+                    mut limits: image::Limits) -> ImageResult<Vec<Frame>> {
+    let canvas_size = width as u64 * height as u64 * 4;
+    try!(limits.reserve(canvas_size));
+
+    let transparent = Rgba([0, 0, 0, 0]);
+
+    let mut canvas = ImageBuffer::from_pixel(width, height, transparent);
+    let mut previous: Option<AnimationFrameHeader> = None;
+    let mut out = Vec::with_capacity(frames.len());
+
+    for &(header, ref image) in frames.iter() {
+        try!(limits.reserve(canvas_size));
+
+        if let Some(previous) = previous {
+            if previous.dispose {
+                extended::dispose_frame(&mut canvas, &previous, transparent);
+            }
+        }
+
+        let data = try!(decode_image(image, limits));
+        let frame = to_rgba_image(image.width, image.height, image.color(), data);
+        extended::draw_frame(&mut canvas, &frame, &header);
+
+        let delay = Ratio::new(cmp::min(header.duration, 0xffff) as u16, 1000);
+        out.push(Frame::from_parts(canvas.clone(), 0, 0, delay));
+
+        previous = Some(header);
+    }
+
+    Ok(out)
 }
 
 impl<R: Read> ImageDecoder for WebpDecoder<R> {
+    fn set_limits(&mut self, limits: image::Limits) -> ImageResult<()> {
+        // Only the headers are read, the pixels are decoded within the limits
+        try!(self.read_header());
+        try!(limits.check_image(self.width, self.height, self.color));
+
+        self.limits = limits;
+        Ok(())
+    }
+
     fn dimensions(&mut self) -> ImageResult<(u32, u32)> {
-        let _ = try!(self.read_metadata());
+        try!(self.read_header());
 
-        Ok((self.frame.width as u32, self.frame.height as u32))
+        Ok((self.width, self.height))
     }
 
     fn colortype(&mut self) -> ImageResult<color::ColorType> {
-        Ok(color::ColorType::Gray(8))
+        try!(self.read_header());
+
+        Ok(self.color)
     }
 
     fn row_len(&mut self) -> ImageResult<usize> {
-        let _ = try!(self.read_metadata());
+        try!(self.read_header());
 
-        Ok(self.frame.width as usize)
+        Ok(color::bits_per_pixel(self.color) / 8 * self.width as usize)
     }
 
     fn read_scanline(&mut self, buf: &mut [u8]) -> ImageResult<u32> {
-        let _ = try!(self.read_metadata());
+        try!(self.read_frames());
 
-        if self.decoded_rows > self.frame.height as u32 {
+        if self.decoded_rows >= self.height {
             return Err(image::ImageError::ImageEnd)
         }
 
-        let rlen  = buf.len();
-        let slice = &self.frame.ybuf[
+        let rlen  = try!(self.row_len());
+        let slice = &self.data[
             self.decoded_rows as usize * rlen..
             self.decoded_rows as usize * rlen + rlen
         ];
@@ -130,8 +567,179 @@ impl<R: Read> ImageDecoder for WebpDecoder<R> {
     }
 
     fn read_image(&mut self) -> ImageResult<image::DecodingResult> {
-        let _ = try!(self.read_metadata());
+        try!(self.read_frames());
+
+        Ok(image::DecodingResult::U8(self.data.clone()))
+    }
+
+    fn metadata(&mut self) -> ImageResult<image::ImageMetadata> {
+        try!(self.read_header());
+
+        Ok(image::ImageMetadata {
+            exif: self.exif_metadata.clone(),
+            icc_profile: self.icc_profile.clone(),
+            xmp: self.xmp_metadata.clone(),
+            orientation: self.exif_metadata.as_ref().and_then(|exif| image::exif_orientation(exif)),
+            ..image::ImageMetadata::default()
+        })
+    }
+
+    fn is_animated(&mut self) -> ImageResult<bool> {
+        try!(self.read_header());
+
+        Ok(!self.frame_chunks.is_empty() || !self.frames.is_empty())
+    }
+
+    fn into_frames(mut self) -> ImageResult<Frames> {
+        try!(self.read_frames());
+
+        if self.frames.is_empty() {
+            let (width, height, color) = (self.width, self.height, self.color);
+            let buffer = to_rgba_image(width, height, color, self.data);
+
+            return Ok(Frames::new(vec![Frame::new(buffer)]))
+        }
+
+        Ok(Frames::new(self.frames))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use byteorder::{WriteBytesExt, LittleEndian};
+    use image::ImageDecoder;
+    use super::WebpDecoder;
+
+    // A 2x1 lossless image with a red and a half transparent blue pixel
+    const VP8L: &'static [u8] = &[
+        0x2f, 0x01, 0x00, 0x00, 0x10, 0x0f, 0x10, 0xfb, 0x3f, 0xff,
+        0x0f, 0xfc, 0x8f, 0x0a, 0x15, 0x88, 0xe8, 0x7f, 0x00
+    ];
+
+    fn riff(chunks: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut body = b"WEBP".to_vec();
+
+        for &(fourcc, data) in chunks.iter() {
+            body.extend_from_slice(fourcc);
+            body.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+            body.extend_from_slice(data);
+
+            if data.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+
+        let mut file = b"RIFF".to_vec();
+        file.write_u32::<LittleEndian>(body.len() as u32).unwrap();
+        file.extend_from_slice(&body);
+        file
+    }
+
+    #[test]
+    fn test_extended_metadata() {
+        let vp8x = [0x2c, 0, 0, 0, 1, 0, 0, 0, 0, 0];
+        let file = riff(&[(b"VP8X", &vp8x), (b"ICCP", b"icc"), (b"VP8L", VP8L),
+                          (b"EXIF", b"exif"), (b"XMP ", b"<xmp/>")]);
+
+        let mut decoder = WebpDecoder::new(&*file);
+        assert_eq!(decoder.dimensions().unwrap(), (2, 1));
+        assert_eq!(decoder.icc_profile().unwrap(), Some(&b"icc"[..]));
+        assert_eq!(decoder.exif_metadata().unwrap(), Some(&b"exif"[..]));
+        assert_eq!(decoder.xmp_metadata().unwrap(), Some(&b"<xmp/>"[..]));
+
+        match decoder.read_image().unwrap() {
+            ::image::DecodingResult::U8(data) => assert_eq!(data, [255, 0, 0, 255, 0, 0, 255, 128]),
+            _ => panic!("wrong sample type")
+        }
+    }
+
+    fn anmf(left: u8, duration: u8, flags: u8) -> Vec<u8> {
+        let mut data = vec![left / 2, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, duration, 0, 0, flags];
+        data.extend_from_slice(b"VP8L");
+        data.write_u32::<LittleEndian>(VP8L.len() as u32).unwrap();
+        data.extend_from_slice(VP8L);
+        data.push(0);
+        data
+    }
+
+    #[test]
+    fn test_animation() {
+        use num::rational::Ratio;
+        use color::Rgba;
+
+        let vp8x = [0x12, 0, 0, 0, 3, 0, 0, 0, 0, 0];
+        let anim = [0x10, 0x20, 0x30, 0xff, 3, 0];
+        let file = riff(&[(b"VP8X", &vp8x), (b"ANIM", &anim),
+                          (b"ANMF", &anmf(0, 100, 0x03)),
+                          (b"ANMF", &anmf(2, 50, 0x00)),
+                          (b"ANMF", &anmf(0, 0, 0x00))]);
+
+        let mut decoder = WebpDecoder::new(&*file);
+        assert!(decoder.is_animated().unwrap());
+        assert_eq!(decoder.dimensions().unwrap(), (4, 1));
+        assert_eq!(decoder.loop_count().unwrap(), Some(3));
+        assert_eq!(decoder.background_color().unwrap(), Some(Rgba([0x30, 0x20, 0x10, 0xff])));
+
+        let frames: Vec<_> = decoder.into_frames().unwrap().collect();
+        assert_eq!(frames.len(), 3);
+
+        let (red, blue, empty) = ([255, 0, 0, 255], [0, 0, 255, 128], [0, 0, 0, 0]);
+        let expected: [(Ratio<u16>, Vec<[u8; 4]>); 3] = [
+            // The first frame is disposed before the second one is drawn
+            (Ratio::new(1, 10), vec![red, blue, empty, empty]),
+            (Ratio::new(1, 20), vec![empty, empty, red, blue]),
+            (Ratio::new(0, 1), vec![red, blue, red, blue]),
+        ];
+
+        // Every frame holds the whole canvas
+        for (frame, &(delay, ref pixels)) in frames.iter().zip(expected.iter()) {
+            assert_eq!((frame.left(), frame.top()), (0, 0));
+            assert_eq!(frame.delay(), delay);
+            assert_eq!(frame.buffer().pixels().map(|p| p.data).collect::<Vec<_>>(), *pixels);
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        use image::{ImageError, Limits};
+
+        // The header claims 16384x16384 pixels, the image data is garbage
+        let mut vp8l = vec![0x2f, 0xff, 0xff, 0xff, 0x0f];
+        vp8l.extend_from_slice(&[0xff; 64]);
+        let file = riff(&[(b"VP8L", &vp8l)]);
+
+        let mut decoder = WebpDecoder::new(&*file);
+        assert_eq!(decoder.dimensions().unwrap(), (16384, 16384));
+        match decoder.set_limits(Limits::default()) {
+            Err(ImageError::LimitsExceeded) => (),
+            _ => panic!("the limits were not checked before decoding")
+        }
+
+        // The lossless decoder needs more than the decoded image
+        let file = riff(&[(b"VP8L", VP8L)]);
+        let mut limits = Limits::default();
+        limits.max_alloc = Some(8);
+
+        let mut decoder = WebpDecoder::new(&*file);
+        decoder.set_limits(limits).unwrap();
+        match decoder.read_image() {
+            Err(ImageError::LimitsExceeded) => (),
+            _ => panic!("the lossless decoder exceeded the limits")
+        }
+
+        limits.max_alloc = Some(1024);
+        let mut decoder = WebpDecoder::new(&*file);
+        decoder.set_limits(limits).unwrap();
+        assert!(decoder.read_image().is_ok());
+    }
+
+    #[test]
+    fn test_no_metadata() {
+        let file = riff(&[(b"VP8L", VP8L)]);
 
-        Ok(image::DecodingResult::U8(self.frame.ybuf.clone()))
+        let mut decoder = WebpDecoder::new(&*file);
+        assert_eq!(decoder.icc_profile().unwrap(), None);
+        assert_eq!(decoder.exif_metadata().unwrap(), None);
+        assert_eq!(decoder.xmp_metadata().unwrap(), None);
     }
 }
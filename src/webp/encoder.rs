@@ -0,0 +1,346 @@
+//! Encoding of WebP images
+//!
+//! Lossy images are compressed as a VP8 keyframe, an alpha channel
+//! is stored uncompressed in an ```ALPH``` chunk next to it.
+//! Lossless images are stored as a single ```VP8L``` chunk.
+//! An ICC profile, EXIF data and XMP packet are stored in chunks around them.
+//!
+//! # Related Links
+//! * [RIFF Container](https://developers.google.com/speed/webp/docs/riff_container)
+//!
+
+use std::io::{self, Write};
+use byteorder::{WriteBytesExt, LittleEndian};
+
+use color;
+use image::{ImageEncoder, ImageMetadata, ImageResult};
+
+use super::vp8::{Frame, VP8Encoder};
+use super::lossless::{LosslessFrame, LosslessEncoder};
+
+/// The quality used by ```WebpEncoder::new```, the same as the one of cwebp
+const DEFAULT_QUALITY: u8 = 75;
+
+/// The representation of a WebP encoder
+pub struct WebpEncoder<'a, W: 'a> {
+    w: &'a mut W,
+    quality: u8,
+    lossless: bool,
+}
+
+impl<'a, W: Write> WebpEncoder<'a, W> {
+    /// Create a new encoder that writes its output to ```w```
+    pub fn new(w: &'a mut W) -> WebpEncoder<'a, W> {
+        WebpEncoder::new_with_quality(w, DEFAULT_QUALITY)
+    }
+
+    /// Create a new encoder that writes its output to ```w```
+    ///
+    /// ```quality``` ranges from 1, the smallest files, to 100, the best quality.
+    pub fn new_with_quality(w: &'a mut W, quality: u8) -> WebpEncoder<'a, W> {
+        WebpEncoder {
+            w: w,
+            quality: quality,
+            lossless: false,
+        }
+    }
+
+    /// Create a new encoder that writes its output to ```w```
+    ///
+    /// The image is compressed without loss.
+    pub fn new_lossless(w: &'a mut W) -> WebpEncoder<'a, W> {
+        WebpEncoder {
+            w: w,
+            quality: 100,
+            lossless: true,
+        }
+    }
+
+    /// Encodes the image ```image```
+    /// that has dimensions ```width``` and ```height```
+    /// and ```ColorType``` ```c```
+    pub fn encode(&mut self,
+                  image: &[u8],
+                  width: u32,
+                  height: u32,
+                  c: color::ColorType) -> io::Result<()> {
+        self.encode_with_metadata(image, width, height, c, &ImageMetadata::default())
+    }
+
+    /// Encodes the image ```image``` like ```encode```
+    /// and stores the ICC profile, EXIF data and XMP packet of ```metadata```
+    pub fn encode_with_metadata(&mut self,
+                                image: &[u8],
+                                width: u32,
+                                height: u32,
+                                c: color::ColorType,
+                                metadata: &ImageMetadata) -> io::Result<()> {
+
+        if width == 0 || height == 0 || width > 0x3FFF || height > 0x3FFF {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                &format!("Invalid image dimensions {}x{}.", width, height)[..],
+            ))
+        }
+
+        let pixels = width as usize * height as usize;
+
+        let bpp = match c {
+            color::ColorType::RGB(8) => 3,
+            color::ColorType::RGBA(8) => 4,
+            color::ColorType::Gray(8) => 1,
+            color::ColorType::GrayA(8) => 2,
+            _ => 0
+        };
+        if image.len() < pixels * bpp {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not enough image data provided"))
+        }
+
+        let (rgb, alpha): (Vec<u8>, Option<Vec<u8>>) = match c {
+            color::ColorType::RGB(8) => (image[..pixels * 3].to_vec(), None),
+            color::ColorType::RGBA(8) => (
+                image.chunks(4).take(pixels).flat_map(|p| p[..3].iter().cloned()).collect(),
+                Some(image.chunks(4).take(pixels).map(|p| p[3]).collect())
+            ),
+            color::ColorType::Gray(8) => (
+                image[..pixels].iter().flat_map(|&l| vec![l, l, l]).collect(),
+                None
+            ),
+            color::ColorType::GrayA(8) => (
+                image.chunks(2).take(pixels).flat_map(|p| vec![p[0], p[0], p[0]]).collect(),
+                Some(image.chunks(2).take(pixels).map(|p| p[1]).collect())
+            ),
+            _ => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                &format!("Unsupported color type {:?}. Use 8 bit per channel RGB(A) or Gray(A) instead.", c)[..],
+            ))
+        };
+
+        // Opaque images don't need an alpha channel
+        let alpha = alpha.and_then(|a| if a.iter().all(|&v| v == 255) { None } else { Some(a) });
+
+        let mut image_chunks = Vec::new();
+
+        if self.lossless {
+            let rgba: Vec<u8> = match alpha {
+                Some(ref alpha) => rgb.chunks(3).zip(alpha.iter())
+                                      .flat_map(|(p, &a)| vec![p[0], p[1], p[2], a]).collect(),
+                None => rgb.chunks(3).flat_map(|p| vec![p[0], p[1], p[2], 255]).collect(),
+            };
+
+            let frame = LosslessFrame::from_rgba(&rgba, width as u16, height as u16);
+            let mut vp8l = Vec::new();
+            try!(LosslessEncoder::new(&mut vp8l).encode_frame(&frame));
+            try!(write_chunk(&mut image_chunks, b"VP8L", &vp8l));
+        } else {
+            let frame = Frame::from_rgb(&rgb, width as u16, height as u16);
+            let mut vp8 = Vec::new();
+            try!(VP8Encoder::new(&mut vp8, self.quality).encode_frame(&frame));
+
+            if let Some(ref alpha) = alpha {
+                // No preprocessing, filtering or compression
+                let mut alph = vec![0u8];
+                alph.extend_from_slice(alpha);
+                try!(write_chunk(&mut image_chunks, b"ALPH", &alph));
+            }
+
+            try!(write_chunk(&mut image_chunks, b"VP8 ", &vp8));
+        }
+
+        // Simple files hold the image only, the extended format is needed for
+        // an alpha channel of a lossy image and for metadata
+        let has_metadata = metadata.icc_profile.is_some() || metadata.exif.is_some() || metadata.xmp.is_some();
+        if !has_metadata && (self.lossless || alpha.is_none()) {
+            return self.write_riff(&image_chunks)
+        }
+
+        let mut flags = 0;
+        if metadata.icc_profile.is_some() { flags |= 0x20 }
+        if alpha.is_some() { flags |= 0x10 }
+        if metadata.exif.is_some() { flags |= 0x08 }
+        if metadata.xmp.is_some() { flags |= 0x04 }
+
+        let mut chunks = Vec::new();
+        let mut vp8x = vec![flags, 0, 0, 0];
+        try!(vp8x.write_uint::<LittleEndian>(width as u64 - 1, 3));
+        try!(vp8x.write_uint::<LittleEndian>(height as u64 - 1, 3));
+        try!(write_chunk(&mut chunks, b"VP8X", &vp8x));
+
+        if let Some(ref profile) = metadata.icc_profile {
+            try!(write_chunk(&mut chunks, b"ICCP", profile));
+        }
+
+        chunks.extend_from_slice(&image_chunks);
+
+        if let Some(ref exif) = metadata.exif {
+            try!(write_chunk(&mut chunks, b"EXIF", exif));
+        }
+
+        if let Some(ref xmp) = metadata.xmp {
+            try!(write_chunk(&mut chunks, b"XMP ", xmp));
+        }
+
+        self.write_riff(&chunks)
+    }
+
+    fn write_riff(&mut self, chunks: &[u8]) -> io::Result<()> {
+        try!(self.w.write_all(b"RIFF"));
+        try!(self.w.write_u32::<LittleEndian>(chunks.len() as u32 + 4));
+        try!(self.w.write_all(b"WEBP"));
+        self.w.write_all(chunks)
+    }
+}
+
+impl<'a, W: Write> ImageEncoder for WebpEncoder<'a, W> {
+    fn write_image(mut self, buf: &[u8], width: u32, height: u32, color: color::ColorType) -> ImageResult<()> {
+        try!(self.encode(buf, width, height, color));
+        Ok(())
+    }
+
+    fn write_image_with_metadata(mut self, buf: &[u8], width: u32, height: u32, color: color::ColorType,
+                                 metadata: &ImageMetadata) -> ImageResult<()> {
+        try!(self.encode_with_metadata(buf, width, height, color, metadata));
+        Ok(())
+    }
+}
+
+// Writes a RIFF chunk, chunks are padded to an even size
+fn write_chunk<W: Write>(w: &mut W, fourcc: &[u8], data: &[u8]) -> io::Result<()> {
+    try!(w.write_all(fourcc));
+    try!(w.write_u32::<LittleEndian>(data.len() as u32));
+    try!(w.write_all(data));
+
+    if data.len() % 2 == 1 {
+        try!(w.write_all(&[0]));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use image::{ImageDecoder, DecodingResult};
+    use color::ColorType;
+    use super::WebpEncoder;
+    use super::super::WebpDecoder;
+
+    // A smooth image with some detail in the middle
+    fn gradient(width: u32, height: u32, alpha: bool) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        for y in 0..height {
+            for x in 0..width {
+                let detail = if (x / 4 + y / 4) % 2 == 0 { 40 } else { 0 };
+
+                data.push((x * 255 / width) as u8);
+                data.push((y * 255 / height) as u8);
+                data.push(((x + y) * 100 / (width + height)) as u8 + detail);
+
+                if alpha {
+                    data.push(if x < width / 2 { 255 } else { (y * 255 / height) as u8 });
+                }
+            }
+        }
+
+        data
+    }
+
+    fn encode(data: &[u8], width: u32, height: u32, c: ColorType, quality: u8) -> Vec<u8> {
+        let mut out = Vec::new();
+        WebpEncoder::new_with_quality(&mut out, quality).encode(data, width, height, c).unwrap();
+        out
+    }
+
+    fn decode(data: &[u8]) -> (u32, u32, Vec<u8>) {
+        let mut decoder = WebpDecoder::new(data);
+        let (width, height) = decoder.dimensions().unwrap();
+
+        match decoder.read_image().unwrap() {
+            DecodingResult::U8(data) => (width, height, data),
+            _ => panic!("wrong sample type")
+        }
+    }
+
+    fn psnr(a: &[u8], b: &[u8]) -> f64 {
+        let se: f64 = a.iter().zip(b.iter())
+                       .map(|(&a, &b)| (a as f64 - b as f64) * (a as f64 - b as f64))
+                       .sum();
+
+        10.0 * (255.0 * 255.0 * a.len() as f64 / se).log10()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let image = gradient(45, 37, false);
+        let file = encode(&image, 45, 37, ColorType::RGB(8), 90);
+
+        assert_eq!(&file[..4], b"RIFF");
+        assert_eq!(&file[8..16], b"WEBPVP8 ");
+
+        let (width, height, data) = decode(&file);
+        assert_eq!((width, height), (45, 37));
+        assert!(psnr(&image, &data) > 30.0);
+    }
+
+    #[test]
+    fn test_quality() {
+        let image = gradient(64, 64, false);
+        let sizes: Vec<usize> = [100, 75, 50, 10].iter()
+            .map(|&q| encode(&image, 64, 64, ColorType::RGB(8), q).len())
+            .collect();
+
+        assert!(sizes.windows(2).all(|s| s[0] > s[1]));
+    }
+
+    #[test]
+    fn test_alpha() {
+        let image = gradient(20, 10, true);
+        let file = encode(&image, 20, 10, ColorType::RGBA(8), 75);
+        let (_, _, data) = decode(&file);
+
+        let alpha: Vec<u8> = image.chunks(4).map(|p| p[3]).collect();
+        let decoded: Vec<u8> = data.chunks(4).map(|p| p[3]).collect();
+        assert_eq!(alpha, decoded);
+
+        // Opaque images are stored without an alpha channel
+        let opaque: Vec<u8> = image.chunks(4).flat_map(|p| vec![p[0], p[1], p[2], 255]).collect();
+        let file = encode(&opaque, 20, 10, ColorType::RGBA(8), 75);
+        assert_eq!(&file[12..16], b"VP8 ");
+    }
+
+    #[test]
+    fn test_lossless() {
+        let image = gradient(45, 37, true);
+        let mut file = Vec::new();
+        WebpEncoder::new_lossless(&mut file).encode(&image, 45, 37, ColorType::RGBA(8)).unwrap();
+        assert_eq!(&file[8..16], b"WEBPVP8L");
+
+        let (width, height, data) = decode(&file);
+        assert_eq!((width, height), (45, 37));
+        assert!(data == image);
+
+        // Gray images are stored as RGBA
+        let gray: Vec<u8> = image.chunks(4).map(|p| p[0]).collect();
+        let mut file = Vec::new();
+        WebpEncoder::new_lossless(&mut file).encode(&gray, 45, 37, ColorType::Gray(8)).unwrap();
+
+        let (_, _, data) = decode(&file);
+        assert!(data.chunks(4).zip(gray.iter()).all(|(p, &l)| p == [l, l, l, 255]));
+    }
+
+    #[test]
+    fn test_short_buffer() {
+        use std::io::ErrorKind;
+
+        for &(color, bpp) in [(ColorType::RGB(8), 3), (ColorType::RGBA(8), 4),
+                              (ColorType::Gray(8), 1), (ColorType::GrayA(8), 2)].iter() {
+            let mut out = Vec::new();
+            let err = WebpEncoder::new(&mut out).encode(&vec![0; 16 * bpp - 1], 4, 4, color).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+            let mut out = Vec::new();
+            let err = WebpEncoder::new_lossless(&mut out).encode(&[0; 10], 4, 4, color).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! The extended file format of WebP
+//!
+//! Extended files start with a ```VP8X``` chunk that lists the features
+//! used by the file. The image may then be accompanied by an alpha
+//! channel and by metadata chunks, or be replaced by an animation.
+//!
+//! # Related Links
+//! * [Extended File Format](https://developers.google.com/speed/webp/docs/riff_container#extended_file_format)
+//!
+
+use std::io;
+use std::cmp;
+use byteorder::{ReadBytesExt, LittleEndian};
+
+use image;
+use image::ImageResult;
+
+use buffer::{RgbaImage, Pixel};
+use color::Rgba;
+
+use super::lossless::LosslessDecoder;
+
+/// The contents of a VP8X chunk
+///
+/// The flags for the ICC profile, alpha and metadata are not kept,
+/// the decoder looks for the chunks themselves.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExtendedHeader {
+    /// The file is an animation
+    pub animation: bool,
+
+    /// The width of the canvas
+    pub canvas_width: u32,
+
+    /// The height of the canvas
+    pub canvas_height: u32,
+}
+
+impl ExtendedHeader {
+    /// Parses the contents of a VP8X chunk
+    pub fn from_chunk(data: &[u8]) -> ImageResult<ExtendedHeader> {
+        if data.len() < 10 {
+            return Err(image::ImageError::malformed(image::ImageFormat::WEBP, "VP8X chunk too short."))
+        }
+
+        let mut r = io::Cursor::new(&data[4..]);
+        let flags = data[0];
+
+        Ok(ExtendedHeader {
+            animation:     flags & 0x02 != 0,
+            canvas_width:  try!(r.read_uint::<LittleEndian>(3)) as u32 + 1,
+            canvas_height: try!(r.read_uint::<LittleEndian>(3)) as u32 + 1,
+        })
+    }
+}
+
+/// The contents of an ANIM chunk
+#[derive(Clone, Copy, Debug)]
+pub struct AnimationHeader {
+    /// The suggested colour of the canvas
+    pub background_color: Rgba<u8>,
+
+    /// The number of times to loop the animation, 0 means infinitely
+    pub loop_count: u16,
+}
+
+impl AnimationHeader {
+    /// Parses the contents of an ANIM chunk
+    pub fn from_chunk(data: &[u8]) -> ImageResult<AnimationHeader> {
+        if data.len() < 6 {
+            return Err(image::ImageError::malformed(image::ImageFormat::WEBP, "ANIM chunk too short."))
+        }
+
+        // The colour is stored in blue, green, red, alpha order
+        Ok(AnimationHeader {
+            background_color: Rgba([data[2], data[1], data[0], data[3]]),
+            loop_count: data[4] as u16 | (data[5] as u16) << 8,
+        })
+    }
+}
+
+/// The header of an ANMF chunk
+#[derive(Clone, Copy, Debug)]
+pub struct AnimationFrameHeader {
+    /// The x offset of the frame on the canvas
+    pub left: u32,
+
+    /// The y offset of the frame on the canvas
+    pub top: u32,
+
+    /// The width of the frame
+    pub width: u32,
+
+    /// The height of the frame
+    pub height: u32,
+
+    /// How long the frame is shown, in milliseconds
+    pub duration: u32,
+
+    /// Whether the frame is alpha blended with the canvas or replaces it
+    pub blend: bool,
+
+    /// Whether the area of the frame is cleared after it was shown
+    pub dispose: bool,
+}
+
+/// The size of the header of an ANMF chunk, the frame data follows it
+pub const ANMF_HEADER_SIZE: usize = 16;
+
+impl AnimationFrameHeader {
+    /// Parses the header of an ANMF chunk
+    pub fn from_chunk(data: &[u8]) -> ImageResult<AnimationFrameHeader> {
+        if data.len() < ANMF_HEADER_SIZE {
+            return Err(image::ImageError::malformed(image::ImageFormat::WEBP, "ANMF chunk too short."))
+        }
+
+        let mut r = io::Cursor::new(data);
+
+        // The offsets are stored divided by two
+        let left     = try!(r.read_uint::<LittleEndian>(3)) as u32 * 2;
+        let top      = try!(r.read_uint::<LittleEndian>(3)) as u32 * 2;
+        let width    = try!(r.read_uint::<LittleEndian>(3)) as u32 + 1;
+        let height   = try!(r.read_uint::<LittleEndian>(3)) as u32 + 1;
+        let duration = try!(r.read_uint::<LittleEndian>(3)) as u32;
+        let flags    = data[15];
+
+        Ok(AnimationFrameHeader {
+            left: left,
+            top: top,
+            width: width,
+            height: height,
+            duration: duration,
+            blend: flags & 0x02 == 0,
+            dispose: flags & 0x01 != 0,
+        })
+    }
+}
+
+/// Draws ```frame``` onto the ```canvas``` at the position given by ```header```
+pub fn draw_frame(canvas: &mut RgbaImage, frame: &RgbaImage, header: &AnimationFrameHeader) {
+    for (x, y, p) in frame.enumerate_pixels() {
+        let (cx, cy) = (header.left + x, header.top + y);
+
+        if cx >= canvas.width() || cy >= canvas.height() {
+            continue
+        }
+
+        let pixel = if header.blend {
+            blend_pixel(*p, *canvas.get_pixel(cx, cy))
+        } else {
+            *p
+        };
+
+        canvas.put_pixel(cx, cy, pixel);
+    }
+}
+
+/// Fills the area of a frame on the canvas with ```color```
+pub fn dispose_frame(canvas: &mut RgbaImage, header: &AnimationFrameHeader, color: Rgba<u8>) {
+    for y in header.top..cmp::min(header.top + header.height, canvas.height()) {
+        for x in header.left..cmp::min(header.left + header.width, canvas.width()) {
+            canvas.put_pixel(x, y, color);
+        }
+    }
+}
+
+// Blends ```src``` over ```dst```. The alpha values are not premultiplied,
+// the integer approximations are the same as the ones of libwebp.
+fn blend_pixel(src: Rgba<u8>, dst: Rgba<u8>) -> Rgba<u8> {
+    let src_a = src[3] as u32;
+
+    if src_a == 0 {
+        return dst
+    } else if src_a == 255 {
+        return src
+    }
+
+    let dst_a   = ((dst[3] as u32) * (256 - src_a)) >> 8;
+    let blend_a = src_a + dst_a;
+    let scale   = (1u32 << 24) / blend_a;
+
+    let mut out = src;
+    for (o, (&s, &d)) in out.channels_mut()[..3].iter_mut()
+                            .zip(src.channels()[..3].iter().zip(dst.channels()[..3].iter())) {
+        *o = (((s as u32 * src_a + d as u32 * dst_a) * scale) >> 24) as u8;
+    }
+    out[3] = blend_a as u8;
+
+    out
+}
+
+/// Decodes the contents of an ALPH chunk into one alpha value per pixel,
+/// the decoder may use the resources within ```limits```
+pub fn read_alpha_chunk(data: &[u8], width: u16, height: u16, limits: image::Limits) -> ImageResult<Vec<u8>> {
+    if data.is_empty() {
+        return Err(image::ImageError::malformed(image::ImageFormat::WEBP, "ALPH chunk too short."))
+    }
+
+    let compression = data[0] & 0x03;
+    let filtering   = (data[0] >> 2) & 0x03;
+
+    let (w, h) = (width as usize, height as usize);
+
+    let mut alpha = match compression {
+        0 => {
+            if data.len() - 1 < w * h {
+                return Err(image::ImageError::malformed(image::ImageFormat::WEBP, "ALPH chunk too short."))
+            }
+
+            data[1..1 + w * h].to_vec()
+        }
+
+        1 => {
+            // The alpha values are stored in the green channel of a lossless image
+            let mut l = LosslessDecoder::new(io::Cursor::new(&data[1..]));
+            l.set_limits(limits);
+            let frame = try!(l.decode_headerless_frame(width, height));
+
+            frame.buf.iter().map(|&p| (p >> 8) as u8).collect()
+        }
+
+        method => return Err(image::ImageError::unsupported(image::ImageFormat::WEBP,
+            image::UnsupportedFeature::Compression(method as u32)))
+    };
+
+    unfilter(&mut alpha, filtering, w, h);
+
+    Ok(alpha)
+}
+
+// Undoes the spatial prediction of the alpha values
+fn unfilter(alpha: &mut [u8], filtering: u8, w: usize, h: usize) {
+    if filtering == 0 {
+        return
+    }
+
+    for y in 0..h {
+        for x in 0..w {
+            let i = y * w + x;
+
+            let prediction = if x == 0 && y == 0 {
+                0
+            } else if y == 0 {
+                alpha[i - 1]
+            } else if x == 0 {
+                alpha[i - w]
+            } else {
+                let (left, top, top_left) = (alpha[i - 1], alpha[i - w], alpha[i - w - 1]);
+
+                match filtering {
+                    1 => left,
+                    2 => top,
+                    _ => {
+                        let g = left as i32 + top as i32 - top_left as i32;
+
+                        if g < 0 { 0 } else if g > 255 { 255 } else { g as u8 }
+                    }
+                }
+            };
+
+            alpha[i] = alpha[i].wrapping_add(prediction);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! The VP8 loop filters as defined in Section 15 of RFC-6386
+//!
+//! Every function filters the edge between the pixel at ```point``` and the
+//! one ```step``` bytes before it. ```step``` is 1 for vertical edges and
+//! the stride of the plane for horizontal edges.
+
+use math::utils::clamp;
+
+// Clamps to the range of a signed byte
+fn c(v: i32) -> i32 {
+    clamp(v, -128, 127)
+}
+
+// Clamps to the range of a pixel value
+fn u(v: i32) -> u8 {
+    clamp(v, 0, 255) as u8
+}
+
+fn diff(a: u8, b: u8) -> i32 {
+    (a as i32 - b as i32).abs()
+}
+
+// The common adjustment, returns (a1, a2) where a1 is subtracted
+// from q0 and a2 is added to p0
+fn common_adjust(a: i32) -> (i32, i32) {
+    (c(a + 4) >> 3, c(a + 3) >> 3)
+}
+
+/// Section 15.2: filters only p0 and q0
+fn filter2(buf: &mut [u8], point: usize, step: usize) {
+    let p1 = buf[point - 2 * step] as i32;
+    let p0 = buf[point - step] as i32;
+    let q0 = buf[point] as i32;
+    let q1 = buf[point + step] as i32;
+
+    let a = 3 * (q0 - p0) + c(p1 - q1);
+    let (a1, a2) = common_adjust(a);
+
+    buf[point - step] = u(p0 + a2);
+    buf[point]        = u(q0 - a1);
+}
+
+/// Section 15.3: the subblock filter for pixels without high edge variance
+fn filter4(buf: &mut [u8], point: usize, step: usize) {
+    let p1 = buf[point - 2 * step] as i32;
+    let p0 = buf[point - step] as i32;
+    let q0 = buf[point] as i32;
+    let q1 = buf[point + step] as i32;
+
+    let (a1, a2) = common_adjust(3 * (q0 - p0));
+    let a3 = (a1 + 1) >> 1;
+
+    buf[point - 2 * step] = u(p1 + a3);
+    buf[point - step]     = u(p0 + a2);
+    buf[point]            = u(q0 - a1);
+    buf[point + step]     = u(q1 - a3);
+}
+
+/// Section 15.3: the macroblock filter for pixels without high edge variance
+fn filter6(buf: &mut [u8], point: usize, step: usize) {
+    let p2 = buf[point - 3 * step] as i32;
+    let p1 = buf[point - 2 * step] as i32;
+    let p0 = buf[point - step] as i32;
+    let q0 = buf[point] as i32;
+    let q1 = buf[point + step] as i32;
+    let q2 = buf[point + 2 * step] as i32;
+
+    let w = c(3 * (q0 - p0) + c(p1 - q1));
+
+    let a1 = (27 * w + 63) >> 7;
+    let a2 = (18 * w + 63) >> 7;
+    let a3 = (9 * w + 63) >> 7;
+
+    buf[point - 3 * step] = u(p2 + a3);
+    buf[point - 2 * step] = u(p1 + a2);
+    buf[point - step]     = u(p0 + a1);
+    buf[point]            = u(q0 - a1);
+    buf[point + step]     = u(q1 - a2);
+    buf[point + 2 * step] = u(q2 - a3);
+}
+
+fn simple_threshold(buf: &[u8], point: usize, step: usize, edge_limit: i32) -> bool {
+    let p1 = buf[point - 2 * step];
+    let p0 = buf[point - step];
+    let q0 = buf[point];
+    let q1 = buf[point + step];
+
+    diff(p0, q0) * 2 + diff(p1, q1) / 2 <= edge_limit
+}
+
+fn normal_threshold(buf: &[u8], point: usize, step: usize,
+                    edge_limit: i32, interior_limit: i32) -> bool {
+    if !simple_threshold(buf, point, step, edge_limit) {
+        return false
+    }
+
+    let p = |i: usize| buf[point - (i + 1) * step];
+    let q = |i: usize| buf[point + i * step];
+
+    diff(p(3), p(2)) <= interior_limit && diff(p(2), p(1)) <= interior_limit &&
+    diff(p(1), p(0)) <= interior_limit && diff(q(3), q(2)) <= interior_limit &&
+    diff(q(2), q(1)) <= interior_limit && diff(q(1), q(0)) <= interior_limit
+}
+
+fn high_edge_variance(buf: &[u8], point: usize, step: usize, threshold: i32) -> bool {
+    diff(buf[point - 2 * step], buf[point - step]) > threshold ||
+    diff(buf[point + step], buf[point]) > threshold
+}
+
+/// Section 15.2: the simple filter, used on both macroblock and subblock edges
+pub fn simple_segment(buf: &mut [u8], point: usize, step: usize, edge_limit: i32) {
+    if simple_threshold(buf, point, step, edge_limit) {
+        filter2(buf, point, step);
+    }
+}
+
+/// Section 15.3: the normal filter for the edges between subblocks
+pub fn subblock_filter(buf: &mut [u8], point: usize, step: usize,
+                       edge_limit: i32, interior_limit: i32, hev_threshold: i32) {
+    if normal_threshold(buf, point, step, edge_limit, interior_limit) {
+        if high_edge_variance(buf, point, step, hev_threshold) {
+            filter2(buf, point, step);
+        } else {
+            filter4(buf, point, step);
+        }
+    }
+}
+
+/// Section 15.3: the normal filter for the edges between macroblocks
+pub fn macroblock_filter(buf: &mut [u8], point: usize, step: usize,
+                         edge_limit: i32, interior_limit: i32, hev_threshold: i32) {
+    if normal_threshold(buf, point, step, edge_limit, interior_limit) {
+        if high_edge_variance(buf, point, step, hev_threshold) {
+            filter2(buf, point, step);
+        } else {
+            filter6(buf, point, step);
+        }
+    }
+}
@@ -0,0 +1,1551 @@
+//! Decoding and encoding of lossless WebP images
+//!
+//! The lossless format stores the image as ARGB pixels that are entropy
+//! coded with Huffman codes and LZ77 style backward references. Up to four
+//! transforms may be applied to the pixels before they are coded.
+//!
+//! # Related Links
+//! * [Lossless Bitstream Specification](https://developers.google.com/speed/webp/docs/webp_lossless_bitstream_specification)
+//!
+
+use std::io::{self, Read, Write};
+use std::cmp;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use image;
+use image::ImageResult;
+
+const SIGNATURE: u8 = 0x2f;
+
+const NUM_LITERAL_CODES: usize = 256;
+const NUM_LENGTH_CODES: usize = 24;
+const NUM_DISTANCE_CODES: usize = 40;
+const NUM_CODE_LENGTH_CODES: usize = 19;
+
+const MAX_CACHE_BITS: u32 = 11;
+
+const CODE_LENGTH_CODE_ORDER: [usize; NUM_CODE_LENGTH_CODES] = [
+    17, 18, 0, 1, 2, 3, 4, 5, 16, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15
+];
+
+// Maps the distance codes below 121 to (x, y) offsets of the
+// neighbourhood of the current pixel
+const DISTANCE_MAP: [(i8, i8); 120] = [
+    (0, 1),  (1, 0),  (1, 1),  (-1, 1), (0, 2),  (2, 0),  (1, 2),  (-1, 2),
+    (2, 1),  (-2, 1), (2, 2),  (-2, 2), (0, 3),  (3, 0),  (1, 3),  (-1, 3),
+    (3, 1),  (-3, 1), (2, 3),  (-2, 3), (3, 2),  (-3, 2), (0, 4),  (4, 0),
+    (1, 4),  (-1, 4), (4, 1),  (-4, 1), (3, 3),  (-3, 3), (2, 4),  (-2, 4),
+    (4, 2),  (-4, 2), (0, 5),  (3, 4),  (-3, 4), (4, 3),  (-4, 3), (5, 0),
+    (1, 5),  (-1, 5), (5, 1),  (-5, 1), (2, 5),  (-2, 5), (5, 2),  (-5, 2),
+    (4, 4),  (-4, 4), (3, 5),  (-3, 5), (5, 3),  (-5, 3), (0, 6),  (6, 0),
+    (1, 6),  (-1, 6), (6, 1),  (-6, 1), (2, 6),  (-2, 6), (6, 2),  (-6, 2),
+    (4, 5),  (-4, 5), (5, 4),  (-5, 4), (3, 6),  (-3, 6), (6, 3),  (-6, 3),
+    (0, 7),  (7, 0),  (1, 7),  (-1, 7), (5, 5),  (-5, 5), (7, 1),  (-7, 1),
+    (4, 6),  (-4, 6), (6, 4),  (-6, 4), (2, 7),  (-2, 7), (7, 2),  (-7, 2),
+    (3, 7),  (-3, 7), (7, 3),  (-7, 3), (5, 6),  (-5, 6), (6, 5),  (-6, 5),
+    (8, 0),  (4, 7),  (-4, 7), (7, 4),  (-7, 4), (8, 1),  (8, 2),  (6, 6),
+    (-6, 6), (8, 3),  (5, 7),  (-5, 7), (7, 5),  (-7, 5), (8, 4),  (6, 7),
+    (-6, 7), (7, 6),  (-7, 6), (8, 5),  (7, 7),  (-7, 7), (8, 6),  (8, 7),
+];
+
+// Huffman codes of at most this length are decoded with a single table lookup
+const TABLE_BITS: u8 = 8;
+
+fn format_error<T>(msg: &str) -> ImageResult<T> {
+    Err(image::ImageError::malformed(image::ImageFormat::WEBP, msg))
+}
+
+/// Reads the bits of the stream starting at the least significant bit of every byte
+struct BitReader {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl BitReader {
+    fn new(buf: Vec<u8>) -> BitReader {
+        BitReader {
+            buf: buf,
+            pos: 0,
+        }
+    }
+
+    // Returns the next ```n``` bits without consuming them.
+    // The stream is padded with zeros.
+    fn peek(&self, n: u8) -> u32 {
+        let byte = self.pos / 8;
+        let mut v = 0u64;
+
+        for i in 0usize..5 {
+            if let Some(&b) = self.buf.get(byte + i) {
+                v |= (b as u64) << (8 * i);
+            }
+        }
+
+        ((v >> (self.pos % 8)) & ((1u64 << n) - 1)) as u32
+    }
+
+    fn consume(&mut self, n: u8) -> ImageResult<()> {
+        self.pos += n as usize;
+
+        if self.pos > 8 * self.buf.len() {
+            return format_error("Unexpected end of the lossless bitstream.")
+        }
+
+        Ok(())
+    }
+
+    fn read_bits(&mut self, n: u8) -> ImageResult<u32> {
+        let v = self.peek(n);
+        try!(self.consume(n));
+
+        Ok(v)
+    }
+
+    fn read_flag(&mut self) -> ImageResult<bool> {
+        Ok(try!(self.read_bits(1)) == 1)
+    }
+}
+
+/// A canonical Huffman code
+struct HuffmanTree {
+    // The symbol and code length for every code prefix of TABLE_BITS bits.
+    // A length of 0 marks prefixes of longer codes.
+    table: Vec<(u16, u8)>,
+
+    // The number of codes of every length
+    counts: [u16; 16],
+
+    // The symbols ordered by their codes
+    symbols: Vec<u16>,
+
+    // The symbol of a code with only one symbol, it takes no bits to decode
+    single: Option<u16>,
+}
+
+impl HuffmanTree {
+    fn from_code_lengths(lengths: &[u8]) -> ImageResult<HuffmanTree> {
+        let mut counts = [0u16; 16];
+        for &l in lengths.iter() {
+            counts[l as usize] += 1;
+        }
+
+        let used = lengths.len() - counts[0] as usize;
+        counts[0] = 0;
+
+        if used == 0 {
+            return format_error("Huffman code without symbols.")
+        }
+
+        if used == 1 {
+            let symbol = lengths.iter().position(|&l| l != 0).unwrap();
+
+            return Ok(HuffmanTree {
+                table: Vec::new(),
+                counts: counts,
+                symbols: Vec::new(),
+                single: Some(symbol as u16),
+            })
+        }
+
+        // The code must be complete
+        let mut left = 1i32;
+        for len in 1usize..16 {
+            left = (left << 1) - counts[len] as i32;
+
+            if left < 0 {
+                return format_error("Over-subscribed Huffman code.")
+            }
+        }
+
+        if left > 0 {
+            return format_error("Incomplete Huffman code.")
+        }
+
+        let mut next_code = [0u16; 16];
+        let mut offsets   = [0usize; 16];
+        for len in 1usize..15 {
+            next_code[len + 1] = (next_code[len] + counts[len]) << 1;
+            offsets[len + 1]   = offsets[len] + counts[len] as usize;
+        }
+
+        let mut symbols = vec![0u16; used];
+        let mut table   = vec![(0u16, 0u8); 1 << TABLE_BITS];
+
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len == 0 {
+                continue
+            }
+
+            let len = len as usize;
+            symbols[offsets[len]] = symbol as u16;
+            offsets[len] += 1;
+
+            let code = next_code[len];
+            next_code[len] += 1;
+
+            if len <= TABLE_BITS as usize {
+                // The codes are stored starting with their most significant bit
+                let mut reversed = 0usize;
+                for i in 0..len {
+                    reversed |= ((code as usize >> i) & 1) << (len - 1 - i);
+                }
+
+                let mut i = reversed;
+                while i < table.len() {
+                    table[i] = (symbol as u16, len as u8);
+                    i += 1 << len;
+                }
+            }
+        }
+
+        Ok(HuffmanTree {
+            table: table,
+            counts: counts,
+            symbols: symbols,
+            single: None,
+        })
+    }
+
+    fn read_symbol(&self, r: &mut BitReader) -> ImageResult<u16> {
+        if let Some(symbol) = self.single {
+            return Ok(symbol)
+        }
+
+        let (symbol, len) = self.table[r.peek(TABLE_BITS) as usize];
+        if len > 0 {
+            try!(r.consume(len));
+            return Ok(symbol)
+        }
+
+        // Walk the canonical code one bit at a time
+        let mut code  = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+
+        for len in 1usize..16 {
+            code |= try!(r.read_bits(1)) as i32;
+            let count = self.counts[len] as i32;
+
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize])
+            }
+
+            index += count;
+            first  = (first + count) << 1;
+            code <<= 1;
+        }
+
+        format_error("Invalid Huffman code.")
+    }
+}
+
+// The five Huffman codes used to decode one pixel
+struct HuffmanGroup {
+    green: HuffmanTree,
+    red: HuffmanTree,
+    blue: HuffmanTree,
+    alpha: HuffmanTree,
+    distance: HuffmanTree,
+}
+
+enum Transform {
+    Predictor { bits: u8, data: Vec<u32> },
+    Color { bits: u8, data: Vec<u32> },
+    SubtractGreen,
+    ColorIndexing { bits: u8, table: Vec<u32> },
+}
+
+/// A decoded lossless image
+#[derive(Default, Debug, Clone)]
+pub struct LosslessFrame {
+    /// The width of the image
+    pub width: u16,
+
+    /// The height of the image
+    pub height: u16,
+
+    /// The ARGB pixels of the image
+    pub buf: Vec<u32>,
+}
+
+impl LosslessFrame {
+    /// Creates a frame from the RGBA pixels in ```buf```
+    pub fn from_rgba(buf: &[u8], width: u16, height: u16) -> LosslessFrame {
+        LosslessFrame {
+            width: width,
+            height: height,
+            buf: buf.chunks(4).take(width as usize * height as usize).map(|p| {
+                (p[3] as u32) << 24 | (p[0] as u32) << 16 | (p[1] as u32) << 8 | p[2] as u32
+            }).collect(),
+        }
+    }
+
+    /// Writes the pixels of the frame as RGBA into ```buf```
+    pub fn fill_rgba(&self, buf: &mut [u8]) {
+        for (p, out) in self.buf.iter().zip(buf.chunks_mut(4)) {
+            out[0] = (p >> 16) as u8;
+            out[1] = (p >> 8) as u8;
+            out[2] = *p as u8;
+            out[3] = (p >> 24) as u8;
+        }
+    }
+}
+
+/// Lossless WebP Decoder
+pub struct LosslessDecoder<R> {
+    r: R,
+    b: BitReader,
+    frame: LosslessFrame,
+    limits: image::Limits,
+}
+
+impl<R: Read> LosslessDecoder<R> {
+    /// Create a new decoder.
+    /// The reader must present the contents of a VP8L chunk
+    pub fn new(r: R) -> LosslessDecoder<R> {
+        LosslessDecoder {
+            r: r,
+            b: BitReader::new(Vec::new()),
+            frame: Default::default(),
+            limits: image::Limits::default(),
+        }
+    }
+
+    /// Sets the resources the decoder may use
+    pub fn set_limits(&mut self, limits: image::Limits) {
+        self.limits = limits;
+    }
+
+    fn read_data(&mut self) -> ImageResult<()> {
+        let mut buf = Vec::new();
+        try!(self.r.read_to_end(&mut buf));
+        self.b = BitReader::new(buf);
+
+        Ok(())
+    }
+
+    fn read_header(&mut self) -> ImageResult<()> {
+        if try!(self.b.read_bits(8)) as u8 != SIGNATURE {
+            return format_error("Invalid VP8L signature.")
+        }
+
+        self.frame.width  = try!(self.b.read_bits(14)) as u16 + 1;
+        self.frame.height = try!(self.b.read_bits(14)) as u16 + 1;
+
+        let _alpha_is_used = try!(self.b.read_flag());
+
+        let version = try!(self.b.read_bits(3)) as u32;
+        if version != 0 {
+            return Err(image::ImageError::unsupported(image::ImageFormat::WEBP,
+                image::UnsupportedFeature::HeaderVersion(version)))
+        }
+
+        Ok(())
+    }
+
+    /// Decodes the image and returns a reference to it
+    pub fn decode_frame(&mut self) -> ImageResult<&LosslessFrame> {
+        try!(self.read_data());
+        try!(self.read_header());
+        try!(self.decode_image());
+
+        Ok(&self.frame)
+    }
+
+    /// Decodes an image stream without the VP8L header, as used by the
+    /// alpha channel of lossy images. The dimensions are known from
+    /// the enclosing image.
+    pub fn decode_headerless_frame(&mut self, width: u16, height: u16) -> ImageResult<&LosslessFrame> {
+        try!(self.read_data());
+        self.frame.width  = width;
+        self.frame.height = height;
+        try!(self.decode_image());
+
+        Ok(&self.frame)
+    }
+
+    fn decode_image(&mut self) -> ImageResult<()> {
+        let width  = self.frame.width as usize;
+        let height = self.frame.height as usize;
+
+        let mut transforms = Vec::new();
+        let mut xsize = width;
+
+        // The transforms are listed in the order they were applied by the encoder
+        while try!(self.b.read_flag()) {
+            let kind = try!(self.b.read_bits(2));
+
+            if transforms.iter().any(|&(k, _, _)| k == kind) {
+                return format_error("Repeated VP8L transform.")
+            }
+
+            let transform_xsize = xsize;
+            let transform = try!(self.read_transform(kind, &mut xsize, height));
+            transforms.push((kind, transform_xsize, transform));
+        }
+
+        let mut data = try!(self.decode_image_stream(xsize, height, true));
+
+        // Colour indexing unpacks the pixels into a new buffer
+        let unpacks = transforms.iter().any(|&(_, _, ref transform)| match *transform {
+            Transform::ColorIndexing { .. } => true,
+            _ => false
+        });
+        if unpacks {
+            try!(self.limits.reserve(4 * width as u64 * height as u64));
+        }
+
+        for &(_, xsize, ref transform) in transforms.iter().rev() {
+            data = apply_inverse_transform(transform, data, xsize, height);
+        }
+
+        self.frame.buf = data;
+
+        Ok(())
+    }
+
+    // Reads a transform, ```xsize``` is the width of the image at this point
+    // and gets updated when colour indexing packs several pixels into one.
+    fn read_transform(&mut self, kind: u32, xsize: &mut usize, ysize: usize) -> ImageResult<Transform> {
+        let width = *xsize;
+
+        Ok(match kind {
+            0 | 1 => {
+                let bits = try!(self.b.read_bits(3)) as u8 + 2;
+                let data = try!(self.decode_image_stream(
+                    subsample_size(width, bits), subsample_size(ysize, bits), false));
+
+                if kind == 0 {
+                    Transform::Predictor { bits: bits, data: data }
+                } else {
+                    Transform::Color { bits: bits, data: data }
+                }
+            }
+
+            2 => Transform::SubtractGreen,
+
+            _ => {
+                let size = try!(self.b.read_bits(8)) as usize + 1;
+                let mut table = try!(self.decode_image_stream(size, 1, false));
+
+                // The table is coded as differences between consecutive entries
+                for i in 1..size {
+                    table[i] = add_pixels(table[i], table[i - 1]);
+                }
+
+                let bits = if size <= 2 { 3 }
+                           else if size <= 4 { 2 }
+                           else if size <= 16 { 1 }
+                           else { 0 };
+
+                *xsize = subsample_size(width, bits);
+
+                Transform::ColorIndexing { bits: bits, table: table }
+            }
+        })
+    }
+
+    fn read_color_cache_bits(&mut self) -> ImageResult<u32> {
+        if !try!(self.b.read_flag()) {
+            return Ok(0)
+        }
+
+        let bits = try!(self.b.read_bits(4));
+        if bits < 1 || bits > MAX_CACHE_BITS {
+            return format_error("Invalid VP8L color cache size.")
+        }
+
+        Ok(bits)
+    }
+
+    // Decodes an entropy coded image. Only the main image may use
+    // meta Huffman codes, the images of the transforms have one group
+    fn decode_image_stream(&mut self, xsize: usize, ysize: usize, is_main: bool) -> ImageResult<Vec<u32>> {
+        let cache_bits = try!(self.read_color_cache_bits());
+
+        let mut meta_bits = 0u8;
+        let mut meta_codes = Vec::new();
+        let mut num_groups = 1usize;
+
+        if is_main && try!(self.b.read_flag()) {
+            meta_bits = try!(self.b.read_bits(3)) as u8 + 2;
+            let entropy_image = try!(self.decode_image_stream(
+                subsample_size(xsize, meta_bits), subsample_size(ysize, meta_bits), false));
+
+            meta_codes = entropy_image.iter().map(|&p| ((p >> 8) & 0xffff) as usize).collect();
+            num_groups = meta_codes.iter().fold(0, |m, &c| cmp::max(m, c)) + 1;
+        }
+
+        let cache_size = if cache_bits > 0 { 1usize << cache_bits } else { 0 };
+
+        let mut groups = Vec::with_capacity(num_groups);
+        for _ in 0..num_groups {
+            groups.push(HuffmanGroup {
+                green: try!(self.read_huffman_code(
+                    NUM_LITERAL_CODES + NUM_LENGTH_CODES + cache_size)),
+                red: try!(self.read_huffman_code(NUM_LITERAL_CODES)),
+                blue: try!(self.read_huffman_code(NUM_LITERAL_CODES)),
+                alpha: try!(self.read_huffman_code(NUM_LITERAL_CODES)),
+                distance: try!(self.read_huffman_code(NUM_DISTANCE_CODES)),
+            });
+        }
+
+        try!(self.limits.reserve(4 * xsize as u64 * ysize as u64));
+
+        let mut cache = vec![0u32; cache_size];
+        let mut data  = Vec::with_capacity(xsize * ysize);
+        let meta_xsize = subsample_size(xsize, meta_bits);
+
+        while data.len() < xsize * ysize {
+            let pos = data.len();
+            let group = if meta_codes.is_empty() {
+                &groups[0]
+            } else {
+                let (x, y) = (pos % xsize, pos / xsize);
+                &groups[meta_codes[(y >> meta_bits) * meta_xsize + (x >> meta_bits)]]
+            };
+
+            let green = try!(group.green.read_symbol(&mut self.b)) as usize;
+
+            if green < NUM_LITERAL_CODES {
+                let red   = try!(group.red.read_symbol(&mut self.b)) as u32;
+                let blue  = try!(group.blue.read_symbol(&mut self.b)) as u32;
+                let alpha = try!(group.alpha.read_symbol(&mut self.b)) as u32;
+
+                data.push((alpha << 24) | (red << 16) | ((green as u32) << 8) | blue);
+            } else if green < NUM_LITERAL_CODES + NUM_LENGTH_CODES {
+                let length = try!(self.read_prefix_coded((green - NUM_LITERAL_CODES) as u32)) as usize;
+
+                let symbol = try!(group.distance.read_symbol(&mut self.b)) as u32;
+                let code   = try!(self.read_prefix_coded(symbol)) as usize;
+                let dist   = plane_code_to_distance(xsize, code);
+
+                if dist > pos || pos + length > xsize * ysize {
+                    return format_error("Invalid VP8L backward reference.")
+                }
+
+                for i in 0..length {
+                    let p = data[pos + i - dist];
+                    data.push(p);
+                }
+            } else {
+                let index = green - NUM_LITERAL_CODES - NUM_LENGTH_CODES;
+                let p = cache[index];
+                data.push(p);
+            }
+
+            // Every decoded pixel is inserted into the colour cache
+            if cache_bits > 0 {
+                for &p in data[pos..].iter() {
+                    cache[(0x1e35a7bdu32.wrapping_mul(p) >> (32 - cache_bits)) as usize] = p;
+                }
+            }
+        }
+
+        Ok(data)
+    }
+
+    // Decodes lengths and distances of backward references
+    fn read_prefix_coded(&mut self, symbol: u32) -> ImageResult<u32> {
+        if symbol < 4 {
+            return Ok(symbol + 1)
+        }
+
+        let extra_bits = (symbol - 2) >> 1;
+        let offset = (2 + (symbol & 1)) << extra_bits;
+
+        Ok(offset + try!(self.b.read_bits(extra_bits as u8)) + 1)
+    }
+
+    fn read_huffman_code(&mut self, alphabet_size: usize) -> ImageResult<HuffmanTree> {
+        let mut lengths = vec![0u8; alphabet_size];
+
+        if try!(self.b.read_flag()) {
+            // A simple code of one or two symbols
+            let num_symbols = try!(self.b.read_bits(1)) + 1;
+            let first_bits  = if try!(self.b.read_flag()) { 8 } else { 1 };
+
+            let first = try!(self.b.read_bits(first_bits)) as usize;
+            if first >= alphabet_size {
+                return format_error("Invalid Huffman code.")
+            }
+            lengths[first] = 1;
+
+            if num_symbols == 2 {
+                let second = try!(self.b.read_bits(8)) as usize;
+                if second >= alphabet_size {
+                    return format_error("Invalid Huffman code.")
+                }
+                lengths[second] = 1;
+            }
+
+            return HuffmanTree::from_code_lengths(&lengths)
+        }
+
+        let mut code_length_lengths = [0u8; NUM_CODE_LENGTH_CODES];
+        let num_codes = try!(self.b.read_bits(4)) as usize + 4;
+
+        for &i in CODE_LENGTH_CODE_ORDER[..num_codes].iter() {
+            code_length_lengths[i] = try!(self.b.read_bits(3)) as u8;
+        }
+
+        let tree = try!(HuffmanTree::from_code_lengths(&code_length_lengths));
+
+        let mut max_symbol = if try!(self.b.read_flag()) {
+            let length_bits = 2 + 2 * try!(self.b.read_bits(3)) as u8;
+            let max_symbol  = 2 + try!(self.b.read_bits(length_bits)) as usize;
+
+            if max_symbol > alphabet_size {
+                return format_error("Invalid Huffman code.")
+            }
+
+            max_symbol
+        } else {
+            alphabet_size
+        };
+
+        let mut previous = 8u8;
+        let mut symbol = 0usize;
+
+        while symbol < alphabet_size && max_symbol > 0 {
+            max_symbol -= 1;
+
+            let code = try!(tree.read_symbol(&mut self.b)) as u8;
+
+            if code < 16 {
+                lengths[symbol] = code;
+                symbol += 1;
+
+                if code != 0 {
+                    previous = code;
+                }
+
+                continue
+            }
+
+            let (bits, offset) = match code {
+                16 => (2, 3),
+                17 => (3, 3),
+                _  => (7, 11),
+            };
+
+            let repeat = try!(self.b.read_bits(bits)) as usize + offset;
+            if symbol + repeat > alphabet_size {
+                return format_error("Invalid Huffman code.")
+            }
+
+            let length = if code == 16 { previous } else { 0 };
+            for l in lengths[symbol..symbol + repeat].iter_mut() {
+                *l = length;
+            }
+
+            symbol += repeat;
+        }
+
+        HuffmanTree::from_code_lengths(&lengths)
+    }
+}
+
+/// Writes the bits of the stream starting at the least significant bit of every byte
+struct BitWriter {
+    buf: Vec<u8>,
+    bits: u64,
+    nbits: u8,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            buf: Vec::new(),
+            bits: 0,
+            nbits: 0,
+        }
+    }
+
+    fn write_bits(&mut self, v: u32, n: u8) {
+        self.bits |= (v as u64) << self.nbits;
+        self.nbits += n;
+
+        while self.nbits >= 8 {
+            self.buf.push(self.bits as u8);
+            self.bits >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    fn write_flag(&mut self, flag: bool) {
+        self.write_bits(flag as u32, 1);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.nbits > 0 {
+            self.buf.push(self.bits as u8);
+        }
+
+        self.buf
+    }
+}
+
+/// A canonical Huffman code for writing symbols
+struct HuffmanCode {
+    lengths: Vec<u8>,
+
+    // The codes with their bits reversed, the stream starts with the most significant bit
+    codes: Vec<u16>,
+}
+
+impl HuffmanCode {
+    // Builds a code with lengths of at most ```limit``` bits from the symbol counts
+    fn from_histogram(histogram: &[u32], limit: u8) -> HuffmanCode {
+        let lengths = huffman_code_lengths(histogram, limit);
+
+        let mut counts = [0u16; 16];
+        for &l in lengths.iter() {
+            counts[l as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut next_code = [0u16; 16];
+        for len in 1usize..15 {
+            next_code[len + 1] = (next_code[len] + counts[len]) << 1;
+        }
+
+        let codes = lengths.iter().map(|&len| {
+            let len = len as usize;
+            if len == 0 {
+                return 0
+            }
+
+            let code = next_code[len];
+            next_code[len] += 1;
+
+            let mut reversed = 0u16;
+            for i in 0..len {
+                reversed |= ((code >> i) & 1) << (len - 1 - i);
+            }
+
+            reversed
+        }).collect();
+
+        HuffmanCode {
+            lengths: lengths,
+            codes: codes,
+        }
+    }
+
+    fn write_symbol(&self, w: &mut BitWriter, symbol: usize) {
+        // A code with a single symbol takes no bits, like ```HuffmanTree::single```
+        if self.lengths.iter().filter(|&&l| l > 0).count() > 1 {
+            w.write_bits(self.codes[symbol] as u32, self.lengths[symbol]);
+        }
+    }
+
+    // The counterpart of ```read_huffman_code```
+    fn write(&self, w: &mut BitWriter) {
+        let used: Vec<usize> = (0..self.lengths.len()).filter(|&s| self.lengths[s] > 0).collect();
+
+        if used.len() <= 2 && used.iter().all(|&s| s < 256) {
+            // A simple code of one or two symbols
+            let first = used.first().cloned().unwrap_or(0);
+
+            w.write_flag(true);
+            w.write_bits(cmp::max(used.len(), 1) as u32 - 1, 1);
+
+            if first < 2 {
+                w.write_flag(false);
+                w.write_bits(first as u32, 1);
+            } else {
+                w.write_flag(true);
+                w.write_bits(first as u32, 8);
+            }
+
+            if used.len() == 2 {
+                w.write_bits(used[1] as u32, 8);
+            }
+
+            return
+        }
+
+        w.write_flag(false);
+
+        // Run length code the lengths with the symbols 16, 17 and 18
+        let mut tokens: Vec<(u8, u32)> = Vec::new();
+        let mut previous = 8u8;
+        let mut i = 0;
+
+        while i < self.lengths.len() {
+            let length = self.lengths[i];
+            let mut run = self.lengths[i..].iter().take_while(|&&l| l == length).count();
+            i += run;
+
+            if length == 0 {
+                while run >= 11 {
+                    let repeat = cmp::min(run, 138);
+                    tokens.push((18, repeat as u32 - 11));
+                    run -= repeat;
+                }
+
+                if run >= 3 {
+                    tokens.push((17, run as u32 - 3));
+                    run = 0;
+                }
+            } else {
+                if length != previous {
+                    tokens.push((length, 0));
+                    previous = length;
+                    run -= 1;
+                }
+
+                while run >= 3 {
+                    let repeat = cmp::min(run, 6);
+                    tokens.push((16, repeat as u32 - 3));
+                    run -= repeat;
+                }
+            }
+
+            for _ in 0..run {
+                tokens.push((length, 0));
+            }
+        }
+
+        let mut histogram = [0u32; NUM_CODE_LENGTH_CODES];
+        for &(code, _) in tokens.iter() {
+            histogram[code as usize] += 1;
+        }
+
+        let code_length_code = HuffmanCode::from_histogram(&histogram, 7);
+        let num_codes = cmp::max(4, CODE_LENGTH_CODE_ORDER.iter()
+            .rposition(|&c| code_length_code.lengths[c] > 0).unwrap() + 1);
+
+        w.write_bits(num_codes as u32 - 4, 4);
+        for &c in CODE_LENGTH_CODE_ORDER[..num_codes].iter() {
+            w.write_bits(code_length_code.lengths[c] as u32, 3);
+        }
+
+        // All symbols are coded
+        w.write_flag(false);
+
+        for &(code, extra) in tokens.iter() {
+            code_length_code.write_symbol(w, code as usize);
+
+            match code {
+                16 => w.write_bits(extra, 2),
+                17 => w.write_bits(extra, 3),
+                18 => w.write_bits(extra, 7),
+                _  => (),
+            }
+        }
+    }
+}
+
+// The lengths of a Huffman code for the symbol counts in ```histogram```. The
+// counts of rare symbols are raised until no code is longer than ```limit```.
+fn huffman_code_lengths(histogram: &[u32], limit: u8) -> Vec<u8> {
+    let mut lengths = vec![0u8; histogram.len()];
+    let used: Vec<usize> = (0..histogram.len()).filter(|&s| histogram[s] > 0).collect();
+
+    if used.len() == 1 {
+        lengths[used[0]] = 1;
+    }
+
+    if used.len() < 2 {
+        return lengths
+    }
+
+    let mut min_count = 1;
+
+    loop {
+        // The nodes of the tree, leaves first, with their parent
+        let mut parents = vec![0usize; 2 * used.len() - 1];
+        let mut heap: BinaryHeap<Reverse<(u32, usize)>> = used.iter().enumerate()
+            .map(|(i, &s)| Reverse((cmp::max(histogram[s], min_count), i)))
+            .collect();
+
+        let mut next = used.len();
+        while heap.len() > 1 {
+            let Reverse((a, i)) = heap.pop().unwrap();
+            let Reverse((b, j)) = heap.pop().unwrap();
+
+            parents[i] = next;
+            parents[j] = next;
+            heap.push(Reverse((a.saturating_add(b), next)));
+            next += 1;
+        }
+
+        // The root is the last node, every other node is one level below its parent
+        let mut depths = vec![0u8; parents.len()];
+        for node in (0..parents.len() - 1).rev() {
+            depths[node] = depths[parents[node]] + 1;
+        }
+
+        if depths[..used.len()].iter().all(|&d| d <= limit) {
+            for (i, &s) in used.iter().enumerate() {
+                lengths[s] = depths[i];
+            }
+
+            return lengths
+        }
+
+        min_count *= 2;
+    }
+}
+
+// An entropy coded pixel
+#[derive(Clone, Copy)]
+enum PixOrCopy {
+    Literal(u32),
+    CacheIndex(u32),
+    Copy { length: usize, code: usize },
+}
+
+// The number of pixels a backward reference must copy to be used
+const MIN_LENGTH: usize = 3;
+const MAX_LENGTH: usize = 4096;
+
+// The largest distance the 40 prefix codes can express
+const MAX_DISTANCE: usize = (1 << 20) - DISTANCE_MAP.len();
+
+// How many earlier positions with the same hash are searched for a match
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: u32 = 16;
+
+/// Lossless WebP Encoder
+pub struct LosslessEncoder<W> {
+    w: W,
+}
+
+impl<W: Write> LosslessEncoder<W> {
+    /// Create a new encoder that writes the contents of a VP8L chunk to ```w```
+    pub fn new(w: W) -> LosslessEncoder<W> {
+        LosslessEncoder {
+            w: w,
+        }
+    }
+
+    /// Encodes ```frame```
+    pub fn encode_frame(&mut self, frame: &LosslessFrame) -> io::Result<()> {
+        if frame.width == 0 || frame.height == 0 || frame.width > 0x4000 || frame.height > 0x4000 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                &format!("Invalid frame dimensions {}x{}.", frame.width, frame.height)[..]
+            ))
+        }
+
+        let mut b = BitWriter::new();
+
+        b.write_bits(SIGNATURE as u32, 8);
+        b.write_bits(frame.width as u32 - 1, 14);
+        b.write_bits(frame.height as u32 - 1, 14);
+        b.write_flag(frame.buf.iter().any(|&p| p >> 24 != 0xff));
+        b.write_bits(0, 3);
+
+        encode_image(&mut b, &frame.buf, frame.width as usize, frame.height as usize);
+
+        self.w.write_all(&b.finish())
+    }
+}
+
+// Applies the subtract green and the predictor transform
+// and writes them followed by the main image
+fn encode_image(b: &mut BitWriter, pixels: &[u32], xsize: usize, ysize: usize) {
+    let mut data: Vec<u32> = pixels.iter().map(|&p| {
+        let green = (p >> 8) & 0xff;
+        subtract_pixels(p, (green << 16) | green)
+    }).collect();
+
+    b.write_flag(true);
+    b.write_bits(2, 2);
+
+    let bits = PREDICTOR_BITS;
+    let modes = choose_predictors(&data, xsize, ysize, bits);
+
+    b.write_flag(true);
+    b.write_bits(0, 2);
+    b.write_bits(bits as u32 - 2, 3);
+    encode_image_stream(b, &modes, subsample_size(xsize, bits), subsample_size(ysize, bits), false);
+
+    data = apply_predictors(&data, &modes, xsize, ysize, bits);
+
+    b.write_flag(false);
+    encode_image_stream(b, &data, xsize, ysize, true);
+}
+
+// The predictor transform uses one mode for blocks of 16x16 pixels
+const PREDICTOR_BITS: u8 = 4;
+
+// Subtracts two pixels channel by channel, modulo 256
+fn subtract_pixels(a: u32, b: u32) -> u32 {
+    let alpha_green = (a | 0x00ff00ff).wrapping_sub(b & 0xff00ff00);
+    let red_blue    = (a | 0xff00ff00).wrapping_sub(b & 0x00ff00ff);
+
+    (alpha_green & 0xff00ff00) | (red_blue & 0x00ff00ff)
+}
+
+// The prediction of the pixel at (x, y) the decoder makes with ```mode```
+fn prediction(data: &[u32], x: usize, y: usize, xsize: usize, mode: u32) -> u32 {
+    let i = y * xsize + x;
+
+    if x == 0 && y == 0 {
+        0xff000000
+    } else if y == 0 {
+        data[i - 1]
+    } else if x == 0 {
+        data[i - xsize]
+    } else {
+        predict(mode, data[i - 1], data[i - xsize], data[i - xsize + 1], data[i - xsize - 1])
+    }
+}
+
+// Chooses the mode with the smallest residuals for every block
+fn choose_predictors(data: &[u32], xsize: usize, ysize: usize, bits: u8) -> Vec<u32> {
+    let (bw, bh) = (subsample_size(xsize, bits), subsample_size(ysize, bits));
+    let mut modes = Vec::with_capacity(bw * bh);
+
+    for by in 0..bh {
+        for bx in 0..bw {
+            let mut best = (u32::max_value(), 0);
+
+            for mode in 0u32..14 {
+                let mut cost = 0;
+
+                for y in by << bits..cmp::min((by + 1) << bits, ysize) {
+                    for x in bx << bits..cmp::min((bx + 1) << bits, xsize) {
+                        let residual = subtract_pixels(data[y * xsize + x],
+                                                       prediction(data, x, y, xsize, mode));
+
+                        for shift in [0, 8, 16, 24].iter() {
+                            cost += ((residual >> shift) as u8 as i8 as i32).abs() as u32;
+                        }
+                    }
+                }
+
+                if cost < best.0 {
+                    best = (cost, mode);
+                }
+            }
+
+            modes.push(0xff000000 | (best.1 << 8));
+        }
+    }
+
+    modes
+}
+
+fn apply_predictors(data: &[u32], modes: &[u32], xsize: usize, ysize: usize, bits: u8) -> Vec<u32> {
+    let block_xsize = subsample_size(xsize, bits);
+    let mut residuals = Vec::with_capacity(data.len());
+
+    for y in 0..ysize {
+        for x in 0..xsize {
+            let mode = (modes[(y >> bits) * block_xsize + (x >> bits)] >> 8) & 0xf;
+            residuals.push(subtract_pixels(data[y * xsize + x], prediction(data, x, y, xsize, mode)));
+        }
+    }
+
+    residuals
+}
+
+// Finds backward references with a hash chain over pairs of pixels
+fn backward_references(data: &[u32], xsize: usize) -> Vec<PixOrCopy> {
+    let hash = |i: usize| -> usize {
+        let h = data[i].wrapping_mul(0x1e35a7bd) ^ data[i + 1].wrapping_mul(0x9e3779b1);
+        (h >> (32 - HASH_BITS)) as usize
+    };
+
+    // The distances of the 120 short codes, the first code of every distance wins
+    let mut short_codes: Vec<(usize, usize)> = Vec::new();
+    for code in 1..DISTANCE_MAP.len() + 1 {
+        let dist = plane_code_to_distance(xsize, code);
+        if !short_codes.iter().any(|&(d, _)| d == dist) {
+            short_codes.push((dist, code));
+        }
+    }
+
+    let mut head  = vec![usize::max_value(); 1 << HASH_BITS];
+    let mut chain = vec![usize::max_value(); data.len()];
+    let mut refs  = Vec::new();
+
+    let insert = |i: usize, head: &mut Vec<usize>, chain: &mut Vec<usize>| {
+        if i + 1 < data.len() {
+            let h = hash(i);
+            chain[i] = head[h];
+            head[h] = i;
+        }
+    };
+
+    let mut i = 0;
+    while i < data.len() {
+        let max_length = cmp::min(MAX_LENGTH, data.len() - i);
+        let mut best = (0, 0);
+
+        if max_length >= MIN_LENGTH {
+            let match_length = |j: usize| {
+                (0..max_length).take_while(|&k| data[j + k] == data[i + k]).count()
+            };
+
+            // The pixel above is always a candidate, it is cheap to code
+            if i >= xsize {
+                best = (match_length(i - xsize), i - xsize);
+            }
+
+            let mut candidate = head[hash(i)];
+            for _ in 0..MAX_CHAIN {
+                if candidate == usize::max_value() || i - candidate > MAX_DISTANCE {
+                    break
+                }
+
+                let length = match_length(candidate);
+                if length > best.0 {
+                    best = (length, candidate);
+                }
+
+                candidate = chain[candidate];
+            }
+        }
+
+        if best.0 >= MIN_LENGTH {
+            let dist = i - best.1;
+            let code = short_codes.iter().find(|&&(d, _)| d == dist)
+                                  .map_or(dist + DISTANCE_MAP.len(), |&(_, c)| c);
+
+            refs.push(PixOrCopy::Copy { length: best.0, code: code });
+
+            for k in i..i + best.0 {
+                insert(k, &mut head, &mut chain);
+            }
+
+            i += best.0;
+        } else {
+            refs.push(PixOrCopy::Literal(data[i]));
+            insert(i, &mut head, &mut chain);
+            i += 1;
+        }
+    }
+
+    refs
+}
+
+// Replaces literals that are found in the colour cache by their index.
+// The cache is updated the same way the decoder does it.
+fn apply_color_cache(refs: &[PixOrCopy], data: &[u32], cache_bits: u32) -> Vec<PixOrCopy> {
+    if cache_bits == 0 {
+        return refs.to_vec()
+    }
+
+    let mut cache = vec![0u32; 1 << cache_bits];
+    let mut out = Vec::with_capacity(refs.len());
+    let mut pos = 0;
+
+    for r in refs.iter() {
+        let length = match *r {
+            PixOrCopy::Literal(p) => {
+                let index = 0x1e35a7bdu32.wrapping_mul(p) >> (32 - cache_bits);
+
+                out.push(if cache[index as usize] == p {
+                    PixOrCopy::CacheIndex(index)
+                } else {
+                    *r
+                });
+
+                1
+            }
+
+            PixOrCopy::Copy { length, .. } => {
+                out.push(*r);
+                length
+            }
+
+            PixOrCopy::CacheIndex(_) => unreachable!(),
+        };
+
+        for &p in data[pos..pos + length].iter() {
+            cache[(0x1e35a7bdu32.wrapping_mul(p) >> (32 - cache_bits)) as usize] = p;
+        }
+
+        pos += length;
+    }
+
+    out
+}
+
+// Returns the prefix symbol of a length or distance, and the number and value
+// of the extra bits. The counterpart of ```read_prefix_coded```.
+fn prefix_encode(value: usize) -> (usize, u8, u32) {
+    let d = value as u32 - 1;
+
+    if d < 4 {
+        return (d as usize, 0, 0)
+    }
+
+    let highest = 31 - d.leading_zeros();
+    let second  = (d >> (highest - 1)) & 1;
+    let extra_bits = highest - 1;
+
+    ((2 * highest + second) as usize, extra_bits as u8, d & ((1 << extra_bits) - 1))
+}
+
+// The histograms of the five alphabets
+fn histograms(refs: &[PixOrCopy], cache_bits: u32) -> [Vec<u32>; 5] {
+    let cache_size = if cache_bits > 0 { 1usize << cache_bits } else { 0 };
+
+    let mut h = [
+        vec![0u32; NUM_LITERAL_CODES + NUM_LENGTH_CODES + cache_size],
+        vec![0u32; NUM_LITERAL_CODES],
+        vec![0u32; NUM_LITERAL_CODES],
+        vec![0u32; NUM_LITERAL_CODES],
+        vec![0u32; NUM_DISTANCE_CODES],
+    ];
+
+    for r in refs.iter() {
+        match *r {
+            PixOrCopy::Literal(p) => {
+                h[0][((p >> 8) & 0xff) as usize] += 1;
+                h[1][((p >> 16) & 0xff) as usize] += 1;
+                h[2][(p & 0xff) as usize] += 1;
+                h[3][(p >> 24) as usize] += 1;
+            }
+
+            PixOrCopy::CacheIndex(index) => {
+                h[0][NUM_LITERAL_CODES + NUM_LENGTH_CODES + index as usize] += 1;
+            }
+
+            PixOrCopy::Copy { length, code } => {
+                h[0][NUM_LITERAL_CODES + prefix_encode(length).0] += 1;
+                h[4][prefix_encode(code).0] += 1;
+            }
+        }
+    }
+
+    h
+}
+
+// An estimate of the bits needed to code the symbols, without the extra bits
+fn entropy(histograms: &[Vec<u32>; 5]) -> f64 {
+    histograms.iter().map(|h| {
+        let total: u32 = h.iter().sum();
+
+        h.iter().filter(|&&c| c > 0)
+                .map(|&c| -(c as f64) * (c as f64 / total as f64).log2())
+                .sum::<f64>()
+    }).sum()
+}
+
+// Writes an entropy coded image. Only the main image uses the colour cache.
+fn encode_image_stream(b: &mut BitWriter, data: &[u32], xsize: usize, ysize: usize, is_main: bool) {
+    debug_assert_eq!(data.len(), xsize * ysize);
+
+    let refs = backward_references(data, xsize);
+
+    // Try the cache sizes and keep the one that codes the image with the fewest bits
+    let mut best = (0, histograms(&refs, 0));
+    let mut best_refs = refs.clone();
+
+    if is_main {
+        let mut best_entropy = entropy(&best.1);
+
+        for cache_bits in 1..MAX_CACHE_BITS {
+            let cached = apply_color_cache(&refs, data, cache_bits);
+            let h = histograms(&cached, cache_bits);
+            let e = entropy(&h);
+
+            if e < best_entropy {
+                best_entropy = e;
+                best = (cache_bits, h);
+                best_refs = cached;
+            }
+        }
+    }
+
+    let (cache_bits, histograms) = best;
+
+    b.write_flag(cache_bits > 0);
+    if cache_bits > 0 {
+        b.write_bits(cache_bits, 4);
+    }
+
+    // A single group of Huffman codes
+    if is_main {
+        b.write_flag(false);
+    }
+
+    let codes: Vec<HuffmanCode> = histograms.iter()
+        .map(|h| HuffmanCode::from_histogram(h, 15))
+        .collect();
+
+    for code in codes.iter() {
+        code.write(b);
+    }
+
+    for r in best_refs.iter() {
+        match *r {
+            PixOrCopy::Literal(p) => {
+                codes[0].write_symbol(b, ((p >> 8) & 0xff) as usize);
+                codes[1].write_symbol(b, ((p >> 16) & 0xff) as usize);
+                codes[2].write_symbol(b, (p & 0xff) as usize);
+                codes[3].write_symbol(b, (p >> 24) as usize);
+            }
+
+            PixOrCopy::CacheIndex(index) => {
+                codes[0].write_symbol(b, NUM_LITERAL_CODES + NUM_LENGTH_CODES + index as usize);
+            }
+
+            PixOrCopy::Copy { length, code } => {
+                let (symbol, bits, extra) = prefix_encode(length);
+                codes[0].write_symbol(b, NUM_LITERAL_CODES + symbol);
+                b.write_bits(extra, bits);
+
+                let (symbol, bits, extra) = prefix_encode(code);
+                codes[4].write_symbol(b, symbol);
+                b.write_bits(extra, bits);
+            }
+        }
+    }
+}
+
+fn subsample_size(size: usize, bits: u8) -> usize {
+    (size + (1 << bits) - 1) >> bits
+}
+
+fn plane_code_to_distance(xsize: usize, code: usize) -> usize {
+    if code > DISTANCE_MAP.len() {
+        return code - DISTANCE_MAP.len()
+    }
+
+    let (dx, dy) = DISTANCE_MAP[code - 1];
+    let dist = dx as isize + dy as isize * xsize as isize;
+
+    cmp::max(dist, 1) as usize
+}
+
+// Adds two pixels channel by channel, modulo 256
+fn add_pixels(a: u32, b: u32) -> u32 {
+    let alpha_green = (a & 0xff00ff00).wrapping_add(b & 0xff00ff00);
+    let red_blue    = (a & 0x00ff00ff).wrapping_add(b & 0x00ff00ff);
+
+    (alpha_green & 0xff00ff00) | (red_blue & 0x00ff00ff)
+}
+
+fn channels(p: u32) -> [i32; 4] {
+    [(p >> 24) as i32, ((p >> 16) & 0xff) as i32, ((p >> 8) & 0xff) as i32, (p & 0xff) as i32]
+}
+
+fn from_channels(c: [i32; 4]) -> u32 {
+    ((c[0] as u32 & 0xff) << 24) | ((c[1] as u32 & 0xff) << 16) |
+    ((c[2] as u32 & 0xff) << 8) | (c[3] as u32 & 0xff)
+}
+
+fn average2(a: u32, b: u32) -> u32 {
+    (((a ^ b) & 0xfefefefe) >> 1) + (a & b)
+}
+
+fn select(l: u32, t: u32, tl: u32) -> u32 {
+    let (cl, ct, ctl) = (channels(l), channels(t), channels(tl));
+
+    // The distance of the gradient estimate to the left and the top pixel
+    let mut pl = 0;
+    let mut pt = 0;
+    for i in 0..4 {
+        pl += (ct[i] - ctl[i]).abs();
+        pt += (cl[i] - ctl[i]).abs();
+    }
+
+    if pl < pt { l } else { t }
+}
+
+fn clamp_add_subtract_full(a: u32, b: u32, c: u32) -> u32 {
+    let (ca, cb, cc) = (channels(a), channels(b), channels(c));
+    let mut out = [0i32; 4];
+
+    for i in 0..4 {
+        out[i] = clamp(ca[i] + cb[i] - cc[i]);
+    }
+
+    from_channels(out)
+}
+
+fn clamp_add_subtract_half(a: u32, b: u32) -> u32 {
+    let (ca, cb) = (channels(a), channels(b));
+    let mut out = [0i32; 4];
+
+    for i in 0..4 {
+        out[i] = clamp(ca[i] + (ca[i] - cb[i]) / 2);
+    }
+
+    from_channels(out)
+}
+
+fn clamp(v: i32) -> i32 {
+    cmp::min(cmp::max(v, 0), 255)
+}
+
+fn predict(mode: u32, l: u32, t: u32, tr: u32, tl: u32) -> u32 {
+    match mode {
+        1  => l,
+        2  => t,
+        3  => tr,
+        4  => tl,
+        5  => average2(average2(l, tr), t),
+        6  => average2(l, tl),
+        7  => average2(l, t),
+        8  => average2(tl, t),
+        9  => average2(t, tr),
+        10 => average2(average2(l, tl), average2(t, tr)),
+        11 => select(l, t, tl),
+        12 => clamp_add_subtract_full(l, t, tl),
+        13 => clamp_add_subtract_half(average2(l, t), tl),
+        _  => 0xff000000,
+    }
+}
+
+fn color_transform_delta(t: u8, c: u8) -> i32 {
+    (t as i8 as i32 * c as i8 as i32) >> 5
+}
+
+// Undoes a transform. ```xsize``` is the width of the image the
+// transform was applied to.
+fn apply_inverse_transform(transform: &Transform, mut data: Vec<u32>,
+                           xsize: usize, ysize: usize) -> Vec<u32> {
+    match *transform {
+        Transform::Predictor { bits, data: ref modes } => {
+            let block_xsize = subsample_size(xsize, bits);
+
+            for y in 0..ysize {
+                for x in 0..xsize {
+                    let i = y * xsize + x;
+
+                    let prediction = if x == 0 && y == 0 {
+                        0xff000000
+                    } else if y == 0 {
+                        data[i - 1]
+                    } else if x == 0 {
+                        data[i - xsize]
+                    } else {
+                        let mode = (modes[(y >> bits) * block_xsize + (x >> bits)] >> 8) & 0xf;
+
+                        // The top right pixel of the last column is the
+                        // first pixel of the current row
+                        predict(mode, data[i - 1], data[i - xsize],
+                                data[i - xsize + 1], data[i - xsize - 1])
+                    };
+
+                    data[i] = add_pixels(data[i], prediction);
+                }
+            }
+
+            data
+        }
+
+        Transform::Color { bits, data: ref elements } => {
+            let block_xsize = subsample_size(xsize, bits);
+
+            for y in 0..ysize {
+                for x in 0..xsize {
+                    let e = elements[(y >> bits) * block_xsize + (x >> bits)];
+                    let (green_to_red, green_to_blue, red_to_blue) =
+                        (e as u8, (e >> 8) as u8, (e >> 16) as u8);
+
+                    let p = &mut data[y * xsize + x];
+                    let green = (*p >> 8) as u8;
+
+                    let red = (((*p >> 16) & 0xff) as i32 +
+                               color_transform_delta(green_to_red, green)) as u8;
+                    let blue = ((*p & 0xff) as i32 +
+                                color_transform_delta(green_to_blue, green) +
+                                color_transform_delta(red_to_blue, red)) as u8;
+
+                    *p = (*p & 0xff00ff00) | ((red as u32) << 16) | blue as u32;
+                }
+            }
+
+            data
+        }
+
+        Transform::SubtractGreen => {
+            for p in data.iter_mut() {
+                let green = (*p >> 8) & 0xff;
+                *p = add_pixels(*p, (green << 16) | green);
+            }
+
+            data
+        }
+
+        Transform::ColorIndexing { bits, ref table } => {
+            let pixels_per_byte = 1usize << bits;
+            let bits_per_pixel  = 8 >> bits;
+            let mask = (1u32 << bits_per_pixel) - 1;
+            let packed_xsize = subsample_size(xsize, bits);
+
+            let mut out = Vec::with_capacity(xsize * ysize);
+
+            for y in 0..ysize {
+                for x in 0..xsize {
+                    let packed = (data[y * packed_xsize + x / pixels_per_byte] >> 8) & 0xff;
+                    let shift  = (x % pixels_per_byte) * bits_per_pixel;
+                    let index  = ((packed >> shift) & mask) as usize;
+
+                    // Indices outside of the table are transparent black
+                    out.push(*table.get(index).unwrap_or(&0));
+                }
+            }
+
+            out
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LosslessFrame, LosslessDecoder, LosslessEncoder, huffman_code_lengths};
+
+    fn round_trip(frame: &LosslessFrame) {
+        let mut data = Vec::new();
+        LosslessEncoder::new(&mut data).encode_frame(frame).unwrap();
+
+        let mut decoder = LosslessDecoder::new(&data[..]);
+        let decoded = decoder.decode_frame().unwrap();
+
+        assert_eq!((decoded.width, decoded.height), (frame.width, frame.height));
+        assert!(decoded.buf == frame.buf);
+    }
+
+    fn frame<F: Fn(u32, u32) -> u32>(width: u16, height: u16, f: F) -> LosslessFrame {
+        LosslessFrame {
+            width: width,
+            height: height,
+            buf: (0..height as u32).flat_map(|y| (0..width as u32).map(move |x| (x, y)))
+                                   .map(|(x, y)| f(x, y))
+                                   .collect(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // A linear congruential generator for noise
+        let noise = |x: u32, y: u32| (x * 7919 + y * 104729).wrapping_mul(1103515245).wrapping_add(12345);
+
+        round_trip(&frame(1, 1, |_, _| 0x80402010));
+        round_trip(&frame(40, 30, |_, _| 0xff102030));
+        round_trip(&frame(37, 23, |x, y| 0xff000000 | (x * 6) << 16 | (y * 11) << 8 | (x + y)));
+        round_trip(&frame(29, 31, |x, y| noise(x, y)));
+        round_trip(&frame(64, 17, |x, y| if (x / 8 + y / 4) % 2 == 0 { 0 } else { noise(x, y) | 0xff }));
+        round_trip(&frame(3, 50, |x, y| ((x * y * 40) as u32) << 24 | noise(x, y) & 0xffffff));
+    }
+
+    #[test]
+    fn test_code_length_limit() {
+        // Fibonacci counts give the deepest possible Huffman trees
+        let mut histogram = vec![1u32, 1];
+        for i in 2..30 {
+            let next = histogram[i - 1] + histogram[i - 2];
+            histogram.push(next);
+        }
+
+        let lengths = huffman_code_lengths(&histogram, 15);
+        assert!(lengths.iter().all(|&l| l > 0 && l <= 15));
+
+        // The code is complete
+        let kraft: u32 = lengths.iter().map(|&l| 1 << (15 - l)).sum();
+        assert_eq!(kraft, 1 << 15);
+    }
+}
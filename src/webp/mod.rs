@@ -1,8 +1,15 @@
-//! Decoding of Webp Images
+//! Decoding and Encoding of Webp Images
 
 pub use self::decoder::WebpDecoder as WebpDecoder;
+pub use self::encoder::WebpEncoder as WebpEncoder;
+#[doc(hidden)]
+pub use self::decoder::read_header_prefix;
 
 mod decoder;
+mod encoder;
 mod transform;
+mod loop_filter;
+mod lossless;
+mod extended;
 
-pub mod vp8;
\ No newline at end of file
+pub mod vp8;
@@ -72,3 +72,68 @@ pub fn iwht4x4(block: &mut [i32]) {
         block[4 * i + 3] = (d2 + 3) >> 3;
     }
 }
+
+// The forward transforms of libvpx, the inverse of the ones above.
+// The input is the residue of a 4x4 block in raster order.
+pub fn fdct4x4(block: &mut [i32]) {
+    for i in 0usize..4 {
+        let a1 = (block[4 * i + 0] + block[4 * i + 3]) * 8;
+        let b1 = (block[4 * i + 1] + block[4 * i + 2]) * 8;
+        let c1 = (block[4 * i + 1] - block[4 * i + 2]) * 8;
+        let d1 = (block[4 * i + 0] - block[4 * i + 3]) * 8;
+
+        block[4 * i + 0] = a1 + b1;
+        block[4 * i + 2] = a1 - b1;
+        block[4 * i + 1] = (c1 * 2217 + d1 * 5352 + 14500) >> 12;
+        block[4 * i + 3] = (d1 * 2217 - c1 * 5352 + 7500) >> 12;
+    }
+
+    for i in 0usize..4 {
+        let a1 = block[0 + i] + block[12 + i];
+        let b1 = block[4 + i] + block[8  + i];
+        let c1 = block[4 + i] - block[8  + i];
+        let d1 = block[0 + i] - block[12 + i];
+
+        block[0  + i] = (a1 + b1 + 7) >> 4;
+        block[8  + i] = (a1 - b1 + 7) >> 4;
+        block[4  + i] = ((c1 * 2217 + d1 * 5352 + 12000) >> 16) + if d1 != 0 { 1 } else { 0 };
+        block[12 + i] = (d1 * 2217 - c1 * 5352 + 51000) >> 16;
+    }
+}
+
+pub fn fwht4x4(block: &mut [i32]) {
+    for i in 0usize..4 {
+        let a1 = (block[4 * i + 0] + block[4 * i + 2]) * 4;
+        let d1 = (block[4 * i + 1] + block[4 * i + 3]) * 4;
+        let c1 = (block[4 * i + 1] - block[4 * i + 3]) * 4;
+        let b1 = (block[4 * i + 0] - block[4 * i + 2]) * 4;
+
+        block[4 * i + 0] = a1 + d1 + if a1 != 0 { 1 } else { 0 };
+        block[4 * i + 1] = b1 + c1;
+        block[4 * i + 2] = b1 - c1;
+        block[4 * i + 3] = a1 - d1;
+    }
+
+    for i in 0usize..4 {
+        let a1 = block[0 + i] + block[8  + i];
+        let d1 = block[4 + i] + block[12 + i];
+        let c1 = block[4 + i] - block[12 + i];
+        let b1 = block[0 + i] - block[8  + i];
+
+        let mut a2 = a1 + d1;
+        let mut b2 = b1 + c1;
+        let mut c2 = b1 - c1;
+        let mut d2 = a1 - d1;
+
+        for v in [&mut a2, &mut b2, &mut c2, &mut d2].iter_mut() {
+            if **v < 0 {
+                **v += 1;
+            }
+        }
+
+        block[0  + i] = (a2 + 3) >> 3;
+        block[4  + i] = (b2 + 3) >> 3;
+        block[8  + i] = (c2 + 3) >> 3;
+        block[12 + i] = (d2 + 3) >> 3;
+    }
+}
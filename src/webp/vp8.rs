@@ -3,7 +3,8 @@
 //! This module contains a partial implementation of the
 //! VP8 video format as defined in RFC-6386.
 //!
-//! It decodes Keyframes only sans Loop Filtering.
+//! It decodes and encodes Keyframes only.
+//! Both the luma and the chroma planes are reconstructed.
 //! VP8 is the underpinning of the Webp image format
 //!
 //! # Related Links
@@ -13,12 +14,15 @@
 //!
 
 use std::io;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::cmp;
 use std::default::Default;
-use byteorder::{ReadBytesExt, LittleEndian};
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
 
 use super::transform;
+use super::loop_filter;
 
+use image;
 use math::utils::clamp;
 
 const MAX_SEGMENTS: usize = 4;
@@ -647,8 +651,7 @@ impl BoolReader {
         self.value = 0;
 
         for _ in 0usize..2 {
-            self.value = (self.value << 8) | self.buf[self.index] as u32;
-            self.index += 1;
+            self.value = (self.value << 8) | self.next_byte() as u32;
         }
 
         self.range = 255;
@@ -675,14 +678,21 @@ impl BoolReader {
 
             if self.bit_count == 8 {
                 self.bit_count = 0;
-                self.value |= self.buf[self.index] as u32;
-                self.index += 1;
+                self.value |= self.next_byte() as u32;
             }
         }
 
         retval
     }
 
+    // Returns the next byte of the partition.
+    // Like libvpx the stream is padded with zeros once it is exhausted.
+    fn next_byte(&mut self) -> u8 {
+        let byte = if self.index < self.buf.len() { self.buf[self.index] } else { 0 };
+        self.index += 1;
+        byte
+    }
+
     pub fn read_literal(&mut self, n: u8) -> u8 {
         let mut v = 0u8;
         let mut n = n;
@@ -727,6 +737,125 @@ impl BoolReader {
     }
 }
 
+// Section 7.3: the boolean entropy encoder, the counterpart of ```BoolReader```
+struct BoolWriter {
+    buf: Vec<u8>,
+
+    range: u32,
+    bottom: u32,
+    bit_count: u8,
+}
+
+impl BoolWriter {
+    pub fn new() -> BoolWriter {
+        BoolWriter {buf: Vec::new(), range: 255, bottom: 0, bit_count: 24}
+    }
+
+    pub fn write_bool(&mut self, probability: u8, bit: bool) {
+        let split = 1 + (((self.range - 1) * probability as u32) >> 8);
+
+        if bit {
+            self.bottom += split;
+            self.range -= split;
+        } else {
+            self.range = split;
+        }
+
+        while self.range < 128 {
+            self.range <<= 1;
+
+            if self.bottom & (1 << 31) != 0 {
+                self.add_one_to_output();
+            }
+
+            self.bottom <<= 1;
+            self.bit_count -= 1;
+
+            if self.bit_count == 0 {
+                self.buf.push((self.bottom >> 24) as u8);
+                self.bottom &= (1 << 24) - 1;
+                self.bit_count = 8;
+            }
+        }
+    }
+
+    // Propagates a carry into the bytes that were already written
+    fn add_one_to_output(&mut self) {
+        let mut i = self.buf.len();
+
+        while i > 0 && self.buf[i - 1] == 255 {
+            self.buf[i - 1] = 0;
+            i -= 1;
+        }
+
+        self.buf[i - 1] += 1;
+    }
+
+    pub fn write_literal(&mut self, n: u8, value: u8) {
+        for i in (0..n).rev() {
+            self.write_bool(128u8, (value >> i) & 1 == 1);
+        }
+    }
+
+    pub fn write_with_tree(&mut self, tree: &[i8], probs: &[Prob], value: i8, start: usize) {
+        walk_tree(tree, start, value, |index, bit| self.write_bool(probs[index >> 1], bit));
+    }
+
+    pub fn write_flag(&mut self, flag: bool) {
+        self.write_literal(1, flag as u8);
+    }
+
+    // Writes the remaining bits of ```bottom``` and returns the partition
+    pub fn finish(mut self) -> Vec<u8> {
+        let c = self.bit_count;
+        let mut v = self.bottom;
+
+        if v & (1 << (32 - c)) != 0 {
+            self.add_one_to_output();
+        }
+
+        v <<= c & 7;
+        for _ in 0..c >> 3 {
+            v <<= 8;
+        }
+
+        for _ in 0..4 {
+            self.buf.push((v >> 24) as u8);
+            v <<= 8;
+        }
+
+        self.buf
+    }
+}
+
+// Calls ```f``` with the index of every node on the path from ```start```
+// to the leaf ```value``` of ```tree``` and the branch taken at that node
+fn walk_tree<F: FnMut(usize, bool)>(tree: &[i8], start: usize, value: i8, mut f: F) {
+    let mut path = [(0usize, false); 16];
+    let mut len = 0;
+
+    // Leaves are stored negated, the nodes are the positive entries
+    let mut pos = tree.iter().position(|&t| t <= 0 && t == -value)
+                      .expect("value is not a leaf of the tree");
+
+    loop {
+        let node = pos & !1;
+        path[len] = (node, pos & 1 == 1);
+        len += 1;
+
+        if node == start {
+            break
+        }
+
+        pos = tree.iter().position(|&t| t > 0 && t as usize == node)
+                  .expect("value is not reachable from the start of the tree");
+    }
+
+    for &(node, bit) in path[..len].iter().rev() {
+        f(node, bit);
+    }
+}
+
 #[derive(Clone, Copy)]
 struct MacroBlock {
     bpred:       [i8; 16],
@@ -760,6 +889,14 @@ pub struct Frame {
     /// The luma plane of the frame
     pub ybuf: Vec<u8>,
 
+    /// The blue difference chroma plane of the frame.
+    /// It is subsampled by two in both directions.
+    pub ubuf: Vec<u8>,
+
+    /// The red difference chroma plane of the frame.
+    /// It is subsampled by two in both directions.
+    pub vbuf: Vec<u8>,
+
     /// Indicates whether this frame is a keyframe
     pub keyframe: bool,
 
@@ -779,6 +916,160 @@ pub struct Frame {
     sharpness_level: u8,
 }
 
+impl Frame {
+    /// The width of the chroma planes
+    pub fn chroma_width(&self) -> u16 {
+        (self.width + 1) / 2
+    }
+
+    /// The height of the chroma planes
+    pub fn chroma_height(&self) -> u16 {
+        (self.height + 1) / 2
+    }
+
+    /// Creates a keyframe from the RGB pixels in ```buf```
+    ///
+    /// Every chroma sample is the average of two by two pixels,
+    /// pixels outside of the image repeat the last row or column.
+    pub fn from_rgb(buf: &[u8], width: u16, height: u16) -> Frame {
+        let mut frame = Frame {
+            width: width,
+            height: height,
+            keyframe: true,
+            for_display: true,
+            ..Default::default()
+        };
+
+        let w  = width as usize;
+        let h  = height as usize;
+        let cw = frame.chroma_width() as usize;
+        let ch = frame.chroma_height() as usize;
+
+        frame.ybuf = buf.chunks(3).take(w * h)
+                        .map(|p| rgb_to_y(p[0] as i32, p[1] as i32, p[2] as i32))
+                        .collect();
+
+        frame.ubuf = vec![0u8; cw * ch];
+        frame.vbuf = vec![0u8; cw * ch];
+
+        for y in 0..ch {
+            for x in 0..cw {
+                let (mut r, mut g, mut b) = (0, 0, 0);
+
+                for &(dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+                    let px = cmp::min(2 * x + dx, w - 1);
+                    let py = cmp::min(2 * y + dy, h - 1);
+                    let i  = (py * w + px) * 3;
+
+                    r += buf[i] as i32;
+                    g += buf[i + 1] as i32;
+                    b += buf[i + 2] as i32;
+                }
+
+                let (u, v) = rgb_to_uv(r, g, b);
+                frame.ubuf[y * cw + x] = u;
+                frame.vbuf[y * cw + x] = v;
+            }
+        }
+
+        frame
+    }
+
+    /// Converts the frame to RGB and writes it into ```buf```
+    ///
+    /// The chroma planes are upsampled by interpolating between the
+    /// four nearest chroma samples, the same way libwebp does it.
+    pub fn fill_rgb(&self, buf: &mut [u8]) {
+        let w  = self.width as usize;
+        let h  = self.height as usize;
+        let cw = self.chroma_width() as usize;
+        let ch = self.chroma_height() as usize;
+
+        let mut urow = vec![0u8; w];
+        let mut vrow = vec![0u8; w];
+
+        for y in 0..h {
+            // The nearest chroma row and the one on the other side of the sample
+            let near = (y + 1) / 2 - if y % 2 == 1 { 1 } else { 0 };
+            let far  = if y == 0 { 0 }
+                       else if y % 2 == 1 { cmp::min(near + 1, ch - 1) }
+                       else { near - 1 };
+
+            upsample_row(&self.ubuf[near * cw..near * cw + cw],
+                         &self.ubuf[far * cw..far * cw + cw], &mut urow);
+            upsample_row(&self.vbuf[near * cw..near * cw + cw],
+                         &self.vbuf[far * cw..far * cw + cw], &mut vrow);
+
+            for x in 0..w {
+                let i = (y * w + x) * 3;
+                let (r, g, b) = yuv_to_rgb(self.ybuf[y * w + x], urow[x], vrow[x]);
+
+                buf[i]     = r;
+                buf[i + 1] = g;
+                buf[i + 2] = b;
+            }
+        }
+    }
+}
+
+// Upsamples one row of chroma samples. ```near``` is the chroma row closest
+// to the output row and ```far``` the row on the other side.
+fn upsample_row(near: &[u8], far: &[u8], out: &mut [u8]) {
+    let len = out.len();
+
+    out[0] = ((3 * near[0] as u32 + far[0] as u32 + 2) >> 2) as u8;
+
+    for x in 1..(len + 1) / 2 {
+        let (tl, t) = (near[x - 1] as u32, near[x] as u32);
+        let (l, c)  = (far[x - 1] as u32, far[x] as u32);
+
+        let avg = tl + t + l + c + 8;
+        let diag_12 = (avg + 2 * (t + l)) >> 3;
+        let diag_03 = (avg + 2 * (tl + c)) >> 3;
+
+        out[2 * x - 1] = ((diag_12 + tl) >> 1) as u8;
+        out[2 * x]     = ((diag_03 + t) >> 1) as u8;
+    }
+
+    if len % 2 == 0 {
+        let last = len / 2 - 1;
+        out[len - 1] = ((3 * near[last] as u32 + far[last] as u32 + 2) >> 2) as u8;
+    }
+}
+
+// Section 9.2, BT.601 with the fixed point coefficients of libwebp
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> (u8, u8, u8) {
+    fn mult_hi(v: i32, coeff: i32) -> i32 {
+        (v * coeff) >> 8
+    }
+
+    fn clip(v: i32) -> u8 {
+        clamp(v >> 6, 0, 255) as u8
+    }
+
+    let (y, u, v) = (y as i32, u as i32, v as i32);
+
+    let r = clip(mult_hi(y, 19077) + mult_hi(v, 26149) - 14234);
+    let g = clip(mult_hi(y, 19077) - mult_hi(u, 6419) - mult_hi(v, 13320) + 8708);
+    let b = clip(mult_hi(y, 19077) + mult_hi(u, 33050) - 17685);
+
+    (r, g, b)
+}
+
+// The inverse of ```yuv_to_rgb``` with the coefficients of libwebp
+fn rgb_to_y(r: i32, g: i32, b: i32) -> u8 {
+    ((16839 * r + 33059 * g + 6420 * b + (16 << 16) + (1 << 15)) >> 16) as u8
+}
+
+// The chroma of the sum of four pixels
+fn rgb_to_uv(r: i32, g: i32, b: i32) -> (u8, u8) {
+    fn clip(v: i32) -> u8 {
+        clamp((v + (128 << 18) + (1 << 17)) >> 18, 0, 255) as u8
+    }
+
+    (clip(-9719 * r - 19081 * g + 28800 * b), clip(28800 * r - 24116 * g - 4684 * b))
+}
+
 #[derive(Clone, Copy, Default)]
 struct Segment {
     ydc: i16,
@@ -829,6 +1120,25 @@ pub struct VP8Decoder<R> {
 
     top_border: Vec<u8>,
     left_border: Vec<u8>,
+
+    top_border_u: Vec<u8>,
+    left_border_u: Vec<u8>,
+
+    top_border_v: Vec<u8>,
+    left_border_v: Vec<u8>,
+
+    // The planes padded to a multiple of the macroblock size,
+    // the loop filter reads pixels outside of the visible frame
+    ybuf: Vec<u8>,
+    ubuf: Vec<u8>,
+    vbuf: Vec<u8>,
+
+    // Section 9.6
+    loop_filter_adjustments: bool,
+    ref_delta: [i32; 4],
+    mode_delta: [i32; 4],
+
+    limits: image::Limits,
 }
 
 impl<R: Read> VP8Decoder<R> {
@@ -873,9 +1183,31 @@ impl<R: Read> VP8Decoder<R> {
 
             top_border: Vec::new(),
             left_border: Vec::new(),
+
+            top_border_u: Vec::new(),
+            left_border_u: Vec::new(),
+
+            top_border_v: Vec::new(),
+            left_border_v: Vec::new(),
+
+            ybuf: Vec::new(),
+            ubuf: Vec::new(),
+            vbuf: Vec::new(),
+
+            loop_filter_adjustments: false,
+            ref_delta: [0; 4],
+            mode_delta: [0; 4],
+
+            limits: image::Limits::default(),
         }
 }
 
+    /// Sets the resources the decoder may use. A frame that needs more
+    /// fails with an io error that holds ```ImageError::LimitsExceeded```.
+    pub fn set_limits(&mut self, limits: image::Limits) {
+        self.limits = limits;
+    }
+
     fn update_token_probabilities(&mut self) {
         for i in 0usize..4 {
             for j in 0usize..8 {
@@ -898,8 +1230,9 @@ impl<R: Read> VP8Decoder<R> {
             try!(self.r.by_ref().take(3 * n as u64 - 3).read_to_end(&mut sizes));
 
             for (i, s) in sizes.chunks(3).enumerate() {
-                let size = s[0] as u32 + ((s[1] as u32) << 8) + ((s[2] as u32) << 8);
-                let mut buf = Vec::with_capacity(size as usize);
+                let size = s[0] as u32 + ((s[1] as u32) << 8) + ((s[2] as u32) << 16);
+                // The size is not trusted before the data has been read
+                let mut buf = Vec::new();
                 try!(self.r.by_ref().take(size as u64).read_to_end(&mut buf));
 
                 self.partitions[i].init(buf);
@@ -932,14 +1265,15 @@ impl<R: Read> VP8Decoder<R> {
 
         let n = if self.segments_enabled { MAX_SEGMENTS } else { 1 };
         for i in 0usize..n {
-            let base = if !self.segment[i].delta_values { self.segment[i].quantizer_level as i16 }
+            let base = if !self.segments_enabled { yac_abs as i16 }
+                    else if !self.segment[i].delta_values { self.segment[i].quantizer_level as i16 }
                     else { self.segment[i].quantizer_level as i16 + yac_abs as i16} as i32;
 
             self.segment[i].ydc  = DC_QUANT[clamp(base + ydc_delta, 0, 127) as usize];
             self.segment[i].yac  = AC_QUANT[clamp(base, 0, 127) as usize];
 
             self.segment[i].y2dc = DC_QUANT[clamp(base + y2dc_delta, 0, 127) as usize] * 2;
-            self.segment[i].y2ac = AC_QUANT[clamp(base + y2ac_delta, 0, 127) as usize] * 155 / 100;
+            self.segment[i].y2ac = (AC_QUANT[clamp(base + y2ac_delta, 0, 127) as usize] as i32 * 155 / 100) as i16;
 
             self.segment[i].uvdc = DC_QUANT[clamp(base + uvdc_delta, 0, 127) as usize];
             self.segment[i].uvac = AC_QUANT[clamp(base + uvac_delta, 0, 127) as usize];
@@ -956,24 +1290,20 @@ impl<R: Read> VP8Decoder<R> {
 
     fn read_loop_filter_adjustments(&mut self) {
         if self.b.read_flag() {
-            for _i in 0usize..4 {
+            for i in 0usize..4 {
                 let ref_frame_delta_update_flag = self.b.read_flag();
 
-                let _delta = if ref_frame_delta_update_flag {
-                    self.b.read_magnitude_and_sign(6)
-                } else {
-                    0i32
-                };
+                if ref_frame_delta_update_flag {
+                    self.ref_delta[i] = self.b.read_magnitude_and_sign(6);
+                }
             }
 
-            for _i in 0usize..4 {
+            for i in 0usize..4 {
                 let mb_mode_delta_update_flag = self.b.read_flag();
 
-                let _delta = if mb_mode_delta_update_flag {
-                    self.b.read_magnitude_and_sign(6)
-                } else {
-                    0i32
-                };
+                if mb_mode_delta_update_flag {
+                    self.mode_delta[i] = self.b.read_magnitude_and_sign(6);
+                }
             }
         }
     }
@@ -1045,17 +1375,45 @@ impl<R: Read> VP8Decoder<R> {
             self.frame.width = w & 0x3FFF;
             self.frame.height = h & 0x3FFF;
 
+            // The planes of the frame and their copies padded to whole macroblocks
+            let mbsize = ((self.frame.width as u64 + 15) / 16) * ((self.frame.height as u64 + 15) / 16);
+            let size = self.frame.width as u64 * self.frame.height as u64 +
+                       2 * self.frame.chroma_width() as u64 * self.frame.chroma_height() as u64 +
+                       384 * mbsize;
+            if self.limits.reserve(size).is_err() {
+                return Err(io::Error::new(io::ErrorKind::Other, image::ImageError::LimitsExceeded))
+            }
+
             self.top = init_top_macroblocks(self.frame.width as usize);
             self.left = MacroBlock{..self.top[0]};
 
             self.mbwidth  = (self.frame.width + 15) / 16;
             self.mbheight = (self.frame.height + 15) / 16;
 
-            self.frame.ybuf = vec![0u8; self.frame.width as usize * 
+            self.frame.ybuf = vec![0u8; self.frame.width as usize *
                                         self.frame.height as usize];
 
+            let chroma_size = self.frame.chroma_width() as usize *
+                              self.frame.chroma_height() as usize;
+            self.frame.ubuf = vec![0u8; chroma_size];
+            self.frame.vbuf = vec![0u8; chroma_size];
+
             self.top_border = vec![127u8; self.frame.width as usize + 4 + 16];
             self.left_border = vec![129u8; 1 + 16];
+
+            self.top_border_u = vec![127u8; 8 * self.mbwidth as usize];
+            self.left_border_u = vec![129u8; 1 + 8];
+
+            self.top_border_v = vec![127u8; 8 * self.mbwidth as usize];
+            self.left_border_v = vec![129u8; 1 + 8];
+
+            let mbsize = self.mbwidth as usize * self.mbheight as usize;
+            self.ybuf = vec![0u8; 256 * mbsize];
+            self.ubuf = vec![0u8; 64 * mbsize];
+            self.vbuf = vec![0u8; 64 * mbsize];
+
+            self.ref_delta = [0; 4];
+            self.mode_delta = [0; 4];
         }
 
         let mut buf = Vec::with_capacity(first_partition_size as usize);
@@ -1078,8 +1436,8 @@ impl<R: Read> VP8Decoder<R> {
         self.frame.filter_level    = self.b.read_literal(6);
         self.frame.sharpness_level = self.b.read_literal(3);
 
-        let lf_adjust_enable = self.b.read_flag();
-        if lf_adjust_enable {
+        self.loop_filter_adjustments = self.b.read_flag();
+        if self.loop_filter_adjustments {
             self.read_loop_filter_adjustments();
         }
 
@@ -1189,8 +1547,8 @@ impl<R: Read> VP8Decoder<R> {
 
     fn intra_predict(&mut self, mbx: usize, mby: usize, mb: &MacroBlock, resdata: &[i32]) {
         let stride = 1usize + 16 + 4;
-        let w  = self.frame.width as usize;
         let mw = self.mbwidth as usize;
+        let w  = mw * 16;
         let mut ws = create_border(
             mbx, mby, mw, &self.top_border, &self.left_border);
 
@@ -1223,18 +1581,62 @@ impl<R: Read> VP8Decoder<R> {
             self.left_border[i + 1] = ws[(i + 1) * stride + 16];
         }
 
-        let ylength = if mby < self.mbheight as usize - 1 { 16usize }
-                      else if self.frame.height % 16 == 0 { 16usize }
-                      else { (16 - (self.frame.height as usize & 15)) % 16 };
+        for y in 0usize..16 {
+            for x in 0usize..16 {
+                self.ybuf[(mby * 16 + y) * w + mbx * 16 + x] =
+                    ws[(1 + y) * stride + 1 + x];
+            }
+        }
+
+        self.intra_predict_chroma(mbx, mby, mb, resdata);
+    }
+
+    fn intra_predict_chroma(&mut self, mbx: usize, mby: usize, mb: &MacroBlock, resdata: &[i32]) {
+        let stride = 1usize + 8;
+        let w = self.mbwidth as usize * 8;
 
-        let xlength = if mbx < self.mbwidth as usize - 1 { 16usize }
-                      else if self.frame.width % 16 == 0 { 16usize }
-                      else { (16 - (self.frame.width as usize & 15)) % 16 };
+        let mut uws = create_border_chroma(mbx, mby, &self.top_border_u, &self.left_border_u);
+        let mut vws = create_border_chroma(mbx, mby, &self.top_border_v, &self.left_border_v);
 
-        for y in 0usize..ylength {
-            for x in 0usize..xlength {
-                self.frame.ybuf[(mby * 16 + y) * w + mbx * 16 + x] =
-                    ws[(1 + y) * stride + 1 + x];
+        for ws in [&mut uws, &mut vws].iter_mut() {
+            match mb.chroma_mode {
+                V_PRED  => predict_vpred(&mut **ws, 8, 1, 1, stride),
+                H_PRED  => predict_hpred(&mut **ws, 8, 1, 1, stride),
+                TM_PRED => predict_tmpred(&mut **ws, 8, 1, 1, stride),
+                DC_PRED => predict_dcpred(&mut **ws, 8, stride, mby != 0, mbx != 0),
+                _       => panic!("unknown chroma intra prediction mode")
+            }
+        }
+
+        for y in 0usize..2 {
+            for x in 0usize..2 {
+                let i  = x + y * 2;
+                let y0 = 1 + y * 4;
+                let x0 = 1 + x * 4;
+
+                let urb = &resdata[(16 + i) * 16..(16 + i) * 16 + 16];
+                add_residue(&mut uws, urb, y0, x0, stride);
+
+                let vrb = &resdata[(20 + i) * 16..(20 + i) * 16 + 16];
+                add_residue(&mut vws, vrb, y0, x0, stride);
+            }
+        }
+
+        self.left_border_u[0] = uws[8];
+        self.left_border_v[0] = vws[8];
+
+        for i in 0usize..8 {
+            self.top_border_u[mbx * 8 + i] = uws[8 * stride + 1 + i];
+            self.left_border_u[i + 1] = uws[(i + 1) * stride + 8];
+
+            self.top_border_v[mbx * 8 + i] = vws[8 * stride + 1 + i];
+            self.left_border_v[i + 1] = vws[(i + 1) * stride + 8];
+        }
+
+        for y in 0usize..8 {
+            for x in 0usize..8 {
+                self.ubuf[(mby * 8 + y) * w + mbx * 8 + x] = uws[(1 + y) * stride + 1 + x];
+                self.vbuf[(mby * 8 + y) * w + mbx * 8 + x] = vws[(1 + y) * stride + 1 + x];
             }
         }
     }
@@ -1312,9 +1714,11 @@ impl<R: Read> VP8Decoder<R> {
         has_coefficients
     }
 
-    fn read_residual_data(&mut self, mb: &MacroBlock, mbx: usize, p: usize) -> [i32; 384] {
+    // Returns the residue blocks and whether any of them is non zero
+    fn read_residual_data(&mut self, mb: &MacroBlock, mbx: usize, p: usize) -> ([i32; 384], bool) {
         let sindex     = mb.segmentid as usize;
         let mut blocks = [0i32; 384];
+        let mut non_zero = false;
         let mut plane  = if mb.luma_mode == B_PRED { 3 }
                          else { 1 };
 
@@ -1350,6 +1754,7 @@ impl<R: Read> VP8Decoder<R> {
                 let n = self.read_coefficients(block, p, plane, complexity as usize, dcq, acq);
 
                 if block[0] != 0 || n {
+                    non_zero = true;
                     transform::idct4x4(block);
                 }
 
@@ -1376,6 +1781,7 @@ impl<R: Read> VP8Decoder<R> {
 
                     let n = self.read_coefficients(block, p, plane, complexity as usize, dcq, acq);
                     if block[0] != 0 || n {
+                        non_zero = true;
                         transform::idct4x4(block);
                     }
 
@@ -1387,7 +1793,169 @@ impl<R: Read> VP8Decoder<R> {
             }
         }
 
-        blocks
+        (blocks, non_zero)
+    }
+
+    // Section 15.1 and 9.6: the filter level, interior limit and
+    // high edge variance threshold of a macroblock
+    fn filter_parameters(&self, mb: &MacroBlock) -> (u8, u8, u8) {
+        let segment = &self.segment[mb.segmentid as usize];
+
+        let mut level = self.frame.filter_level as i32;
+        if self.segments_enabled {
+            level = segment.loopfilter_level as i32 + if segment.delta_values { level }
+                                                      else { 0 };
+        }
+
+        if self.loop_filter_adjustments {
+            // Only intra frames are decoded, the reference frame is always 0
+            level += self.ref_delta[0];
+
+            if mb.luma_mode == B_PRED {
+                level += self.mode_delta[0];
+            }
+        }
+
+        let level = clamp(level, 0, 63) as u8;
+
+        let mut interior_limit = level;
+        if self.frame.sharpness_level > 0 {
+            interior_limit >>= if self.frame.sharpness_level > 4 { 2 } else { 1 };
+            interior_limit = cmp::min(interior_limit, 9 - self.frame.sharpness_level);
+        }
+
+        if interior_limit == 0 {
+            interior_limit = 1;
+        }
+
+        let hev_threshold = if level >= 40 { 2 }
+                            else if level >= 15 { 1 }
+                            else { 0 };
+
+        (level, interior_limit, hev_threshold)
+    }
+
+    // Section 15: filters the edges of a macroblock. Only the left and the
+    // top edge are filtered, the others belong to the next macroblocks.
+    // The subblock edges are skipped for macroblocks without coefficients
+    // that are predicted as a whole.
+    fn loop_filter(&mut self, mbx: usize, mby: usize, mb: &MacroBlock, inner: bool) {
+        let (level, interior_limit, hev_threshold) = self.filter_parameters(mb);
+
+        if level == 0 {
+            return
+        }
+
+        let mbedge_limit = (level as i32 + 2) * 2 + interior_limit as i32;
+        let sub_bedge_limit = level as i32 * 2 + interior_limit as i32;
+
+        let (ilimit, hev) = (interior_limit as i32, hev_threshold as i32);
+        let inner = inner || mb.luma_mode == B_PRED;
+
+        let stride = self.mbwidth as usize * 16;
+        let origin = mby * 16 * stride + mbx * 16;
+
+        if self.frame.filter == 1 {
+            // The simple filter only applies to the luma plane
+            let ybuf = &mut self.ybuf[..];
+
+            if mbx > 0 {
+                for y in 0usize..16 {
+                    loop_filter::simple_segment(ybuf, origin + y * stride, 1, mbedge_limit);
+                }
+            }
+
+            if inner {
+                for x in (4usize..16).filter(|x| x % 4 == 0) {
+                    for y in 0usize..16 {
+                        loop_filter::simple_segment(ybuf, origin + y * stride + x, 1,
+                                                    sub_bedge_limit);
+                    }
+                }
+            }
+
+            if mby > 0 {
+                for x in 0usize..16 {
+                    loop_filter::simple_segment(ybuf, origin + x, stride, mbedge_limit);
+                }
+            }
+
+            if inner {
+                for y in (4usize..16).filter(|y| y % 4 == 0) {
+                    for x in 0usize..16 {
+                        loop_filter::simple_segment(ybuf, origin + y * stride + x, stride,
+                                                    sub_bedge_limit);
+                    }
+                }
+            }
+
+            return
+        }
+
+        let mbwidth = self.mbwidth as usize;
+        let mut planes = [(&mut self.ybuf, 16usize),
+                          (&mut self.ubuf, 8usize),
+                          (&mut self.vbuf, 8usize)];
+
+        for &mut (ref mut buf, size) in planes.iter_mut() {
+            let buf    = &mut buf[..];
+            let stride = mbwidth * size;
+            let origin = mby * size * stride + mbx * size;
+
+            if mbx > 0 {
+                for y in 0..size {
+                    loop_filter::macroblock_filter(buf, origin + y * stride, 1,
+                                                   mbedge_limit, ilimit, hev);
+                }
+            }
+
+            if inner {
+                for x in (4..size).filter(|x| x % 4 == 0) {
+                    for y in 0..size {
+                        loop_filter::subblock_filter(buf, origin + y * stride + x, 1,
+                                                     sub_bedge_limit, ilimit, hev);
+                    }
+                }
+            }
+
+            if mby > 0 {
+                for x in 0..size {
+                    loop_filter::macroblock_filter(buf, origin + x, stride,
+                                                   mbedge_limit, ilimit, hev);
+                }
+            }
+
+            if inner {
+                for y in (4..size).filter(|y| y % 4 == 0) {
+                    for x in 0..size {
+                        loop_filter::subblock_filter(buf, origin + y * stride + x, stride,
+                                                     sub_bedge_limit, ilimit, hev);
+                    }
+                }
+            }
+        }
+    }
+
+    // Copies the visible part of the padded planes into the frame
+    fn crop_planes(&mut self) {
+        let w  = self.frame.width as usize;
+        let h  = self.frame.height as usize;
+        let cw = self.frame.chroma_width() as usize;
+        let ch = self.frame.chroma_height() as usize;
+
+        let stride = self.mbwidth as usize * 16;
+        for y in 0..h {
+            self.frame.ybuf[y * w..y * w + w].copy_from_slice(
+                &self.ybuf[y * stride..y * stride + w]);
+        }
+
+        let stride = self.mbwidth as usize * 8;
+        for y in 0..ch {
+            self.frame.ubuf[y * cw..y * cw + cw].copy_from_slice(
+                &self.ubuf[y * stride..y * stride + cw]);
+            self.frame.vbuf[y * cw..y * cw + cw].copy_from_slice(
+                &self.vbuf[y * stride..y * stride + cw]);
+        }
     }
 
     /// Decodes the current frame and returns a reference to it
@@ -1401,9 +1969,12 @@ impl<R: Read> VP8Decoder<R> {
             for mbx in 0..self.mbwidth as usize {
                 let (skip, mb) = self.read_macroblock_header(mbx);
                 let mut blocks = [0i32; 384];
+                let mut non_zero = false;
 
                 if !skip {
-                    blocks = self.read_residual_data(&mb, mbx, p);
+                    let (b, n) = self.read_residual_data(&mb, mbx, p);
+                    blocks = b;
+                    non_zero = n;
                 } else {
                     if mb.luma_mode != B_PRED {
                         self.left.complexity[0] = 0;
@@ -1417,47 +1988,811 @@ impl<R: Read> VP8Decoder<R> {
                 }
 
                 self.intra_predict(mbx, mby, &mb, &blocks);
+
+                if self.frame.filter_level != 0 {
+                    self.loop_filter(mbx, mby, &mb, non_zero);
+                }
             }
 
             self.left_border = vec![129u8; 1 + 16];
+            self.left_border_u = vec![129u8; 1 + 8];
+            self.left_border_v = vec![129u8; 1 + 8];
         }
 
+        self.crop_planes();
+
         Ok(&self.frame)
     }
 }
 
-fn init_top_macroblocks(width: usize) -> Vec<MacroBlock> {
-    let mb_width = (width + 15) / 16;
+// A bool of the token partition. The probabilities of the coefficient
+// tokens are referenced by their position in the ```TokenProbTables```,
+// they are only known once the whole frame was tokenized.
+#[derive(Clone, Copy)]
+enum Token {
+    Coefficient(u8, u8, u8, u8, bool),
+    Fixed(Prob, bool),
+}
 
-    let mb = MacroBlock {
-        // Section 11.3 #3
-        bpred: [B_DC_PRED; 16],
-        luma_mode: DC_PRED,
-        ..MacroBlock::new()
-    };
+// The rounding of the quantizer for the DC and the AC coefficients in 1/256,
+// a bias below one half drops small coefficients. The values of libwebp.
+const Y_BIAS: (i32, i32) = (96, 110);
+const Y2_BIAS: (i32, i32) = (96, 108);
+const UV_BIAS: (i32, i32) = (110, 115);
 
-    (0..mb_width).map(|_| mb).collect()
+// The largest level that can be coded with the DCT_CAT6 token
+const MAX_LEVEL: i32 = 2048;
+
+// The quantized coefficients of a macroblock, laid out like the residue of the decoder
+#[derive(Clone, Copy)]
+struct Levels {
+    y2: [i32; 16],
+    blocks: [i32; 384],
 }
 
-fn create_border(mbx: usize, mby: usize, mbw: usize, top: &[u8], left: &[u8]) -> [u8; 357] {
-    let stride = 1usize + 16 + 4;
-    let mut ws = [0u8; (1 + 16) * (1 + 16 + 4)];
+impl Levels {
+    fn new() -> Levels {
+        Levels {y2: [0; 16], blocks: [0; 384]}
+    }
 
-    // A
-    {
-        let above = &mut ws[1..stride];
-        if mby == 0 {
-            for i in 0usize..above.len() {
-                above[i] = 127;
-            }
-        } else  {
-            for i in 0usize..16 {
-                above[i] = top[mbx * 16 + i];
-            }
+    fn is_zero(&self) -> bool {
+        self.y2.iter().chain(self.blocks.iter()).all(|&l| l == 0)
+    }
+}
 
-            if mbx == mbw - 1 {
-                for i in 16usize..above.len() {
-                    above[i] = top[mbx * 16 + 15];
+/// VP8 Encoder
+///
+/// Only encodes keyframes. Every macroblock is predicted with the
+/// intra mode that has the lowest rate distortion cost.
+pub struct VP8Encoder<W> {
+    w: W,
+
+    mbwidth: u16,
+    mbheight: u16,
+
+    quantizer_level: u8,
+    segment: Segment,
+    filter_level: u8,
+
+    // The weight of one bit against the squared error
+    lambda: u64,
+
+    token_probs: Box<TokenProbTables>,
+
+    top: Vec<MacroBlock>,
+    left: MacroBlock,
+
+    top_border: Vec<u8>,
+    left_border: Vec<u8>,
+
+    top_border_u: Vec<u8>,
+    left_border_u: Vec<u8>,
+
+    top_border_v: Vec<u8>,
+    left_border_v: Vec<u8>,
+
+    // The planes of the frame padded to a multiple of the macroblock size
+    ybuf: Vec<u8>,
+    ubuf: Vec<u8>,
+    vbuf: Vec<u8>,
+
+    macroblocks: Vec<(MacroBlock, bool)>,
+    tokens: Vec<Token>,
+}
+
+impl<W: Write> VP8Encoder<W> {
+    /// Create a new encoder that writes a raw vp8 bitstream to ```w```
+    ///
+    /// ```quality``` ranges from 1, the smallest frames, to 100, the best quality.
+    pub fn new(w: W, quality: u8) -> VP8Encoder<W> {
+        let quality = clamp(quality, 1, 100) as u32;
+        let level = ((100 - quality) * 127 + 49) / 99;
+
+        let segment = Segment {
+            ydc: DC_QUANT[level as usize],
+            yac: AC_QUANT[level as usize],
+
+            y2dc: DC_QUANT[level as usize] * 2,
+            y2ac: cmp::max((AC_QUANT[level as usize] as i32 * 155 / 100) as i16, 8),
+
+            uvdc: cmp::min(DC_QUANT[level as usize], 132),
+            uvac: AC_QUANT[level as usize],
+
+            ..Default::default()
+        };
+
+        let yac = segment.yac as u64;
+
+        // The filter strength grows with the quantizer like it does in libwebp
+        let filter_level = segment.yac / 5;
+
+        VP8Encoder {
+            w: w,
+
+            mbwidth: 0,
+            mbheight: 0,
+
+            quantizer_level: level as u8,
+            segment: segment,
+            filter_level: if filter_level < 2 { 0 } else { cmp::min(filter_level, 63) as u8 },
+
+            lambda: cmp::max(yac * yac / 16, 1),
+
+            token_probs: Box::new(COEFF_PROBS),
+
+            top: Vec::new(),
+            left: MacroBlock::new(),
+
+            top_border: Vec::new(),
+            left_border: Vec::new(),
+
+            top_border_u: Vec::new(),
+            left_border_u: Vec::new(),
+
+            top_border_v: Vec::new(),
+            left_border_v: Vec::new(),
+
+            ybuf: Vec::new(),
+            ubuf: Vec::new(),
+            vbuf: Vec::new(),
+
+            macroblocks: Vec::new(),
+            tokens: Vec::new(),
+        }
+    }
+
+    // Copies the planes of the frame and repeats the last row
+    // and column up to the size of the macroblocks
+    fn pad_planes(&mut self, frame: &Frame) {
+        fn pad(plane: &[u8], w: usize, h: usize, stride: usize, rows: usize) -> Vec<u8> {
+            let mut buf = vec![0u8; stride * rows];
+
+            for y in 0..rows {
+                let row = &plane[cmp::min(y, h - 1) * w..][..w];
+
+                for x in 0..stride {
+                    buf[y * stride + x] = row[cmp::min(x, w - 1)];
+                }
+            }
+
+            buf
+        }
+
+        let (mw, mh) = (self.mbwidth as usize, self.mbheight as usize);
+        let (w, h) = (frame.width as usize, frame.height as usize);
+        let (cw, ch) = (frame.chroma_width() as usize, frame.chroma_height() as usize);
+
+        self.ybuf = pad(&frame.ybuf, w, h, mw * 16, mh * 16);
+        self.ubuf = pad(&frame.ubuf, cw, ch, mw * 8, mh * 8);
+        self.vbuf = pad(&frame.vbuf, cw, ch, mw * 8, mh * 8);
+    }
+
+    // The squared error and the rate of the reconstruction of a macroblock
+    // that is predicted as a whole with ```ws```
+    fn encode_luma(&self, ws: &mut [u8], src: &[u8], mbx: usize, levels: &mut Levels) -> u64 {
+        let stride = 1usize + 16 + 4;
+        let mut blocks = [0i32; 256];
+
+        for i in 0usize..16 {
+            let (x0, y0) = (1 + (i % 4) * 4, 1 + (i / 4) * 4);
+            let block = &mut blocks[i * 16..i * 16 + 16];
+
+            for y in 0usize..4 {
+                for x in 0usize..4 {
+                    block[y * 4 + x] = src[(y0 - 1 + y) * 16 + x0 - 1 + x] as i32 -
+                                       ws[(y0 + y) * stride + x0 + x] as i32;
+                }
+            }
+
+            transform::fdct4x4(block);
+        }
+
+        let mut dc = [0i32; 16];
+        for i in 0usize..16 {
+            dc[i] = blocks[i * 16];
+        }
+
+        transform::fwht4x4(&mut dc);
+        levels.y2 = quantize_block(&mut dc, 0, self.segment.y2dc, self.segment.y2ac, Y2_BIAS);
+        transform::iwht4x4(&mut dc);
+
+        let complexity = self.top[mbx].complexity[0] + self.left.complexity[0];
+        let (mut rate, _) = block_cost(&levels.y2, 0, 1, complexity as usize);
+
+        let mut top  = [0u8; 4];
+        let mut left = [0u8; 4];
+        top.copy_from_slice(&self.top[mbx].complexity[1..5]);
+        left.copy_from_slice(&self.left.complexity[1..5]);
+
+        for i in 0usize..16 {
+            let (x, y) = (i % 4, i / 4);
+            let block = &mut blocks[i * 16..i * 16 + 16];
+
+            let l = quantize_block(block, 1, self.segment.ydc, self.segment.yac, Y_BIAS);
+            levels.blocks[i * 16..i * 16 + 16].copy_from_slice(&l);
+
+            let (cost, n) = block_cost(&l, 1, 0, (top[x] + left[y]) as usize);
+            rate += cost;
+            top[x]  = n as u8;
+            left[y] = n as u8;
+
+            block[0] = dc[i];
+            transform::idct4x4(block);
+            add_residue(ws, block, 1 + y * 4, 1 + x * 4, stride);
+        }
+
+        let distortion = sse(&ws[stride + 1..], stride, src, 16, 16);
+
+        distortion * 256 + self.lambda * rate as u64
+    }
+
+    // Chooses the best subblock mode for every subblock, the prediction
+    // of each subblock depends on the reconstruction of the previous ones
+    fn encode_subblocks(&self, ws: &mut [u8], src: &[u8], mbx: usize,
+                        levels: &mut Levels, modes: &mut [i8]) -> u64 {
+
+        static BMODES: [i8; 10] = [B_DC_PRED, B_TM_PRED, B_VE_PRED, B_HE_PRED, B_LD_PRED,
+                                   B_RD_PRED, B_VR_PRED, B_VL_PRED, B_HD_PRED, B_HU_PRED];
+        let stride = 1usize + 16 + 4;
+
+        let mut top  = [0u8; 4];
+        let mut left = [0u8; 4];
+        top.copy_from_slice(&self.top[mbx].complexity[1..5]);
+        left.copy_from_slice(&self.left.complexity[1..5]);
+
+        let mut top_modes  = [0i8; 4];
+        let mut left_modes = [0i8; 4];
+        top_modes.copy_from_slice(&self.top[mbx].bpred[12..16]);
+        left_modes.copy_from_slice(&self.left.bpred[..4]);
+
+        let mut total = 0;
+
+        for i in 0usize..16 {
+            let (x, y) = (i % 4, i / 4);
+            let (x0, y0) = (1 + x * 4, 1 + y * 4);
+            let probs = &KEYFRAME_BPRED_MODE_PROBS[top_modes[x] as usize][left_modes[y] as usize];
+
+            let mut best = None;
+
+            for &mode in BMODES.iter() {
+                let mut p = [0u8; 357];
+                p.copy_from_slice(ws);
+
+                match mode {
+                    B_TM_PRED => predict_tmpred(&mut p, 4, x0, y0, stride),
+                    B_VE_PRED => predict_bvepred(&mut p, x0, y0, stride),
+                    B_HE_PRED => predict_bhepred(&mut p, x0, y0, stride),
+                    B_DC_PRED => predict_bdcpred(&mut p, x0, y0, stride),
+                    B_LD_PRED => predict_bldpred(&mut p, x0, y0, stride),
+                    B_RD_PRED => predict_brdpred(&mut p, x0, y0, stride),
+                    B_VR_PRED => predict_bvrpred(&mut p, x0, y0, stride),
+                    B_VL_PRED => predict_bvlpred(&mut p, x0, y0, stride),
+                    B_HD_PRED => predict_bhdpred(&mut p, x0, y0, stride),
+                    _         => predict_bhupred(&mut p, x0, y0, stride),
+                }
+
+                let mut block = [0i32; 16];
+                for by in 0usize..4 {
+                    for bx in 0usize..4 {
+                        block[by * 4 + bx] = src[(y * 4 + by) * 16 + x * 4 + bx] as i32 -
+                                             p[(y0 + by) * stride + x0 + bx] as i32;
+                    }
+                }
+
+                transform::fdct4x4(&mut block);
+                let l = quantize_block(&mut block, 0, self.segment.ydc, self.segment.yac, Y_BIAS);
+                transform::idct4x4(&mut block);
+                add_residue(&mut p, &block, y0, x0, stride);
+
+                let (cost, n) = block_cost(&l, 0, 3, (top[x] + left[y]) as usize);
+                let mut rate = cost;
+                walk_tree(&KEYFRAME_BPRED_MODE_TREE, 0, mode,
+                          |index, bit| rate += bit_cost(probs[index >> 1], bit));
+
+                let distortion = sse(&p[y0 * stride + x0..], stride,
+                                     &src[y * 4 * 16 + x * 4..], 16, 4);
+                let score = distortion * 256 + self.lambda * rate as u64;
+
+                let better = match best {
+                    Some((s, _, _, _)) => score < s,
+                    None => true
+                };
+
+                if better {
+                    best = Some((score, mode, l, n));
+                    for by in 0usize..4 {
+                        for bx in 0usize..4 {
+                            let j = (y0 + by) * stride + x0 + bx;
+                            ws[j] = p[j];
+                        }
+                    }
+                }
+            }
+
+            let (score, mode, l, n) = best.unwrap();
+            total += score;
+            modes[i] = mode;
+            levels.blocks[i * 16..i * 16 + 16].copy_from_slice(&l);
+
+            top[x]  = n as u8;
+            left[y] = n as u8;
+            top_modes[x]  = mode;
+            left_modes[y] = mode;
+        }
+
+        total
+    }
+
+    // The squared error and the rate of the chroma planes
+    // predicted with ```uws``` and ```vws```
+    fn encode_chroma(&self, uws: &mut [u8], vws: &mut [u8], mbx: usize, mby: usize,
+                     levels: &mut Levels) -> u64 {
+        let stride = 1usize + 8;
+        let w = self.mbwidth as usize * 8;
+        let mut rate = 0;
+        let mut distortion = 0;
+
+        for (p, (ws, buf)) in [(&mut *uws, &self.ubuf), (&mut *vws, &self.vbuf)]
+                                 .iter_mut().enumerate() {
+            let src = &buf[mby * 8 * w + mbx * 8..];
+            let j = 5 + 2 * p;
+
+            let mut top  = [self.top[mbx].complexity[j], self.top[mbx].complexity[j + 1]];
+            let mut left = [self.left.complexity[j], self.left.complexity[j + 1]];
+
+            for i in 0usize..4 {
+                let (x, y) = (i % 2, i / 2);
+                let (x0, y0) = (1 + x * 4, 1 + y * 4);
+
+                let mut block = [0i32; 16];
+                for by in 0usize..4 {
+                    for bx in 0usize..4 {
+                        block[by * 4 + bx] = src[(y * 4 + by) * w + x * 4 + bx] as i32 -
+                                             ws[(y0 + by) * stride + x0 + bx] as i32;
+                    }
+                }
+
+                transform::fdct4x4(&mut block);
+                let l = quantize_block(&mut block, 0, self.segment.uvdc, self.segment.uvac, UV_BIAS);
+                transform::idct4x4(&mut block);
+                add_residue(&mut **ws, &block, y0, x0, stride);
+
+                let k = 16 + 4 * p + i;
+                levels.blocks[k * 16..k * 16 + 16].copy_from_slice(&l);
+
+                let (cost, n) = block_cost(&l, 0, 2, (top[x] + left[y]) as usize);
+                rate += cost;
+                top[x]  = n as u8;
+                left[y] = n as u8;
+            }
+
+            distortion += sse(&ws[stride + 1..], stride, src, w, 8);
+        }
+
+        distortion * 256 + self.lambda * rate as u64
+    }
+
+    fn encode_macroblock(&mut self, mbx: usize, mby: usize) {
+        let stride = 1usize + 16 + 4;
+        let mw = self.mbwidth as usize;
+        let w  = mw * 16;
+
+        let mut src = [0u8; 256];
+        for y in 0usize..16 {
+            src[y * 16..y * 16 + 16].copy_from_slice(
+                &self.ybuf[(mby * 16 + y) * w + mbx * 16..][..16]);
+        }
+
+        let border = create_border(mbx, mby, mw, &self.top_border, &self.left_border);
+
+        let mut mb = MacroBlock::new();
+        let mut best: Option<(u64, [u8; 357], Levels)> = None;
+
+        for &mode in [DC_PRED, V_PRED, H_PRED, TM_PRED, B_PRED].iter() {
+            let mut ws = border;
+            let mut levels = Levels::new();
+            let mut modes = [0i8; 16];
+
+            match mode {
+                V_PRED  => predict_vpred(&mut ws, 16, 1, 1, stride),
+                H_PRED  => predict_hpred(&mut ws, 16, 1, 1, stride),
+                TM_PRED => predict_tmpred(&mut ws, 16, 1, 1, stride),
+                DC_PRED => predict_dcpred(&mut ws, 16, stride, mby != 0, mbx != 0),
+                _       => (),
+            }
+
+            let mut score = if mode == B_PRED {
+                self.encode_subblocks(&mut ws, &src, mbx, &mut levels, &mut modes)
+            } else {
+                self.encode_luma(&mut ws, &src, mbx, &mut levels)
+            };
+
+            walk_tree(&KEYFRAME_YMODE_TREE, 0, mode,
+                      |index, bit| score += self.lambda * bit_cost(KEYFRAME_YMODE_PROBS[index >> 1], bit) as u64);
+
+            let better = match best {
+                Some((s, _, _)) => score < s,
+                None => true
+            };
+
+            if better {
+                mb.luma_mode = mode;
+                mb.bpred = if mode == B_PRED {
+                    modes
+                } else {
+                    [match mode {
+                        DC_PRED => B_DC_PRED,
+                        V_PRED  => B_VE_PRED,
+                        H_PRED  => B_HE_PRED,
+                        _       => B_TM_PRED,
+                    }; 16]
+                };
+
+                best = Some((score, ws, levels));
+            }
+        }
+
+        let (_, ws, mut levels) = best.unwrap();
+
+        let stride_uv = 1usize + 8;
+        let uborder = create_border_chroma(mbx, mby, &self.top_border_u, &self.left_border_u);
+        let vborder = create_border_chroma(mbx, mby, &self.top_border_v, &self.left_border_v);
+        let mut best_chroma: Option<(u64, [u8; 81], [u8; 81], Levels)> = None;
+
+        for &mode in [DC_PRED, V_PRED, H_PRED, TM_PRED].iter() {
+            let (mut uws, mut vws) = (uborder, vborder);
+            let mut chroma_levels = levels;
+
+            for ws in [&mut uws, &mut vws].iter_mut() {
+                match mode {
+                    V_PRED  => predict_vpred(&mut **ws, 8, 1, 1, stride_uv),
+                    H_PRED  => predict_hpred(&mut **ws, 8, 1, 1, stride_uv),
+                    TM_PRED => predict_tmpred(&mut **ws, 8, 1, 1, stride_uv),
+                    _       => predict_dcpred(&mut **ws, 8, stride_uv, mby != 0, mbx != 0),
+                }
+            }
+
+            let mut score = self.encode_chroma(&mut uws, &mut vws, mbx, mby, &mut chroma_levels);
+            walk_tree(&KEYFRAME_UV_MODE_TREE, 0, mode,
+                      |index, bit| score += self.lambda * bit_cost(KEYFRAME_UV_MODE_PROBS[index >> 1], bit) as u64);
+
+            let better = match best_chroma {
+                Some((s, _, _, _)) => score < s,
+                None => true
+            };
+
+            if better {
+                mb.chroma_mode = mode;
+                best_chroma = Some((score, uws, vws, chroma_levels));
+            }
+        }
+
+        let (_, uws, vws, chroma_levels) = best_chroma.unwrap();
+        levels = chroma_levels;
+
+        // Keep the reconstruction for the prediction of the next macroblocks
+        self.left_border[0] = ws[16];
+
+        for i in 0usize..16 {
+            self.top_border[mbx * 16 + i] = ws[16 * stride + 1 + i];
+            self.left_border[i + 1] = ws[(i + 1) * stride + 16];
+        }
+
+        self.left_border_u[0] = uws[8];
+        self.left_border_v[0] = vws[8];
+
+        for i in 0usize..8 {
+            self.top_border_u[mbx * 8 + i] = uws[8 * stride_uv + 1 + i];
+            self.left_border_u[i + 1] = uws[(i + 1) * stride_uv + 8];
+
+            self.top_border_v[mbx * 8 + i] = vws[8 * stride_uv + 1 + i];
+            self.left_border_v[i + 1] = vws[(i + 1) * stride_uv + 8];
+        }
+
+        for i in 0usize..4 {
+            self.top[mbx].bpred[12 + i] = mb.bpred[12 + i];
+            self.left.bpred[i] = mb.bpred[i * 4 + 3];
+        }
+
+        let skip = levels.is_zero();
+        if skip {
+            if mb.luma_mode != B_PRED {
+                self.left.complexity[0] = 0;
+                self.top[mbx].complexity[0] = 0;
+            }
+
+            for i in 1usize..9 {
+                self.left.complexity[i] = 0;
+                self.top[mbx].complexity[i] = 0;
+            }
+        } else {
+            self.write_residual_data(&mb, mbx, &levels);
+        }
+
+        self.macroblocks.push((mb, skip));
+    }
+
+    // Tokenizes the levels of a macroblock in the order they are read by the decoder
+    fn write_residual_data(&mut self, mb: &MacroBlock, mbx: usize, levels: &Levels) {
+        let tokens = &mut self.tokens;
+        let mut plane = if mb.luma_mode == B_PRED { 3 }
+                        else { 1 };
+
+        if plane == 1 {
+            let complexity = self.top[mbx].complexity[0] + self.left.complexity[0];
+            let n = tokenize(&levels.y2, 0, plane, complexity as usize, |t| tokens.push(t));
+
+            self.left.complexity[0] = n as u8;
+            self.top[mbx].complexity[0] = n as u8;
+
+            plane = 0;
+        }
+
+        let first = if plane == 0 { 1 } else { 0 };
+
+        for y in 0usize..4 {
+            let mut left = self.left.complexity[y + 1];
+
+            for x in 0usize..4 {
+                let i = x + y * 4;
+                let complexity = self.top[mbx].complexity[x + 1] + left;
+
+                let n = tokenize(&levels.blocks[i * 16..i * 16 + 16], first, plane,
+                                 complexity as usize, |t| tokens.push(t));
+
+                left = n as u8;
+                self.top[mbx].complexity[x + 1] = n as u8;
+            }
+
+            self.left.complexity[y + 1] = left;
+        }
+
+        for &j in [5usize, 7usize].iter() {
+            for y in 0usize..2 {
+                let mut left = self.left.complexity[y + j];
+
+                for x in 0usize..2 {
+                    let i = x + y * 2 + if j == 5 { 16 } else { 20 };
+                    let complexity = self.top[mbx].complexity[x + j] + left;
+
+                    let n = tokenize(&levels.blocks[i * 16..i * 16 + 16], 0, 2,
+                                     complexity as usize, |t| tokens.push(t));
+
+                    left = n as u8;
+                    self.top[mbx].complexity[x + j] = n as u8;
+                }
+
+                self.left.complexity[y + j] = left;
+            }
+        }
+    }
+
+    // Updates the token probabilities to the ones observed in the frame
+    // wherever that saves more than the cost of the update
+    fn write_token_probabilities(&mut self, b: &mut BoolWriter) {
+        let mut counts = [[[[[0u64; 2]; NUM_DCT_TOKENS - 1]; 3]; 8]; 4];
+
+        for token in self.tokens.iter() {
+            if let Token::Coefficient(i, j, k, t, bit) = *token {
+                counts[i as usize][j as usize][k as usize][t as usize][bit as usize] += 1;
+            }
+        }
+
+        for i in 0usize..4 {
+            for j in 0usize..8 {
+                for k in 0usize..3 {
+                    for t in 0usize..NUM_DCT_TOKENS - 1 {
+                        let update = COEFF_UPDATE_PROBS[i][j][k][t];
+                        let old    = self.token_probs[i][j][k][t];
+                        let [zeros, ones] = counts[i][j][k][t];
+                        let total  = zeros + ones;
+
+                        let cost = |p: Prob| zeros * bit_cost(p, false) as u64 +
+                                             ones * bit_cost(p, true) as u64;
+
+                        if total > 0 {
+                            let new = clamp((zeros * 256 + total / 2) / total, 1, 255) as Prob;
+                            let new_cost = cost(new) + 8 * 256 + bit_cost(update, true) as u64;
+
+                            if new_cost < cost(old) + bit_cost(update, false) as u64 {
+                                b.write_bool(update, true);
+                                b.write_literal(8, new);
+                                self.token_probs[i][j][k][t] = new;
+                                continue
+                            }
+                        }
+
+                        b.write_bool(update, false);
+                    }
+                }
+            }
+        }
+    }
+
+    fn write_first_partition(&mut self) -> Vec<u8> {
+        let mut b = BoolWriter::new();
+
+        // Color space and clamping type
+        b.write_literal(1, 0);
+        b.write_literal(1, 0);
+
+        // No segmentation
+        b.write_flag(false);
+
+        // The normal loop filter without adjustments
+        b.write_literal(1, 0);
+        b.write_literal(6, self.filter_level);
+        b.write_literal(3, 0);
+        b.write_flag(false);
+
+        // A single token partition
+        b.write_literal(2, 0);
+
+        // The quantizer indices without deltas
+        b.write_literal(7, self.quantizer_level);
+        for _ in 0usize..5 {
+            b.write_flag(false);
+        }
+
+        // Refresh entropy probs
+        b.write_literal(1, 0);
+
+        self.write_token_probabilities(&mut b);
+
+        let skipped = self.macroblocks.iter().filter(|&&(_, skip)| skip).count();
+        let prob_skip_false = clamp(
+            255 - skipped * 255 / self.macroblocks.len(), 1, 254) as Prob;
+
+        b.write_literal(1, 1);
+        b.write_literal(8, prob_skip_false);
+
+        let mut top = init_top_macroblocks(self.mbwidth as usize * 16);
+
+        for row in self.macroblocks.chunks(self.mbwidth as usize) {
+            let mut left = MacroBlock::new();
+
+            for (mbx, &(ref mb, skip)) in row.iter().enumerate() {
+                b.write_bool(prob_skip_false, skip);
+                b.write_with_tree(&KEYFRAME_YMODE_TREE, &KEYFRAME_YMODE_PROBS, mb.luma_mode, 0);
+
+                if mb.luma_mode == B_PRED {
+                    for y in 0usize..4 {
+                        for x in 0usize..4 {
+                            let bmode = mb.bpred[x + y * 4];
+                            let probs = &KEYFRAME_BPRED_MODE_PROBS[top[mbx].bpred[12 + x] as usize]
+                                                                  [left.bpred[y] as usize];
+
+                            b.write_with_tree(&KEYFRAME_BPRED_MODE_TREE, probs, bmode, 0);
+
+                            top[mbx].bpred[12 + x] = bmode;
+                            left.bpred[y] = bmode;
+                        }
+                    }
+                } else {
+                    for i in 0usize..4 {
+                        top[mbx].bpred[12 + i] = mb.bpred[12 + i];
+                        left.bpred[i] = mb.bpred[12 + i];
+                    }
+                }
+
+                b.write_with_tree(&KEYFRAME_UV_MODE_TREE, &KEYFRAME_UV_MODE_PROBS, mb.chroma_mode, 0);
+            }
+        }
+
+        b.finish()
+    }
+
+    fn write_token_partition(&self) -> Vec<u8> {
+        let mut b = BoolWriter::new();
+
+        for token in self.tokens.iter() {
+            match *token {
+                Token::Coefficient(i, j, k, t, bit) => {
+                    let prob = self.token_probs[i as usize][j as usize][k as usize][t as usize];
+                    b.write_bool(prob, bit);
+                }
+
+                Token::Fixed(prob, bit) => b.write_bool(prob, bit),
+            }
+        }
+
+        b.finish()
+    }
+
+    /// Encodes ```frame``` as a keyframe
+    pub fn encode_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        if frame.width == 0 || frame.height == 0 || frame.width > 0x3FFF || frame.height > 0x3FFF {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                &format!("Invalid frame dimensions {}x{}.", frame.width, frame.height)[..]
+            ))
+        }
+
+        self.mbwidth  = (frame.width + 15) / 16;
+        self.mbheight = (frame.height + 15) / 16;
+        self.pad_planes(frame);
+
+        self.top = init_top_macroblocks(frame.width as usize);
+        self.token_probs = Box::new(COEFF_PROBS);
+
+        self.top_border = vec![127u8; frame.width as usize + 4 + 16];
+        self.top_border_u = vec![127u8; 8 * self.mbwidth as usize];
+        self.top_border_v = vec![127u8; 8 * self.mbwidth as usize];
+
+        self.macroblocks.clear();
+        self.tokens.clear();
+
+        for mby in 0..self.mbheight as usize {
+            self.left = MacroBlock::new();
+            self.left_border = vec![129u8; 1 + 16];
+            self.left_border_u = vec![129u8; 1 + 8];
+            self.left_border_v = vec![129u8; 1 + 8];
+
+            for mbx in 0..self.mbwidth as usize {
+                self.encode_macroblock(mbx, mby);
+            }
+        }
+
+        let first_partition = self.write_first_partition();
+        let token_partition = self.write_token_partition();
+
+        self.write_frame(frame.width, frame.height, &first_partition, &token_partition)
+    }
+
+    // Section 9.1: writes a shown keyframe of version 0
+    fn write_frame(&mut self, width: u16, height: u16, first_partition: &[u8], token_partition: &[u8]) -> io::Result<()> {
+        // The size of the first partition has 19 bits, libwebp reports larger ones as PARTITION0_OVERFLOW
+        if first_partition.len() >= 1 << 19 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                &format!("The first partition of {} bytes exceeds 512 KiB, lower the quality.", first_partition.len())[..]
+            ))
+        }
+
+        let tag = (first_partition.len() as u32) << 5 | 1 << 4;
+
+        try!(self.w.write_uint::<LittleEndian>(tag as u64, 3));
+        try!(self.w.write_all(&[0x9d, 0x01, 0x2a]));
+        try!(self.w.write_u16::<LittleEndian>(width));
+        try!(self.w.write_u16::<LittleEndian>(height));
+
+        try!(self.w.write_all(first_partition));
+        self.w.write_all(token_partition)
+    }
+}
+
+fn init_top_macroblocks(width: usize) -> Vec<MacroBlock> {
+    let mb_width = (width + 15) / 16;
+
+    let mb = MacroBlock {
+        // Section 11.3 #3
+        bpred: [B_DC_PRED; 16],
+        luma_mode: DC_PRED,
+        ..MacroBlock::new()
+    };
+
+    (0..mb_width).map(|_| mb).collect()
+}
+
+fn create_border(mbx: usize, mby: usize, mbw: usize, top: &[u8], left: &[u8]) -> [u8; 357] {
+    let stride = 1usize + 16 + 4;
+    let mut ws = [0u8; (1 + 16) * (1 + 16 + 4)];
+
+    // A
+    {
+        let above = &mut ws[1..stride];
+        if mby == 0 {
+            for i in 0usize..above.len() {
+                above[i] = 127;
+            }
+        } else  {
+            for i in 0usize..16 {
+                above[i] = top[mbx * 16 + i];
+            }
+
+            if mbx == mbw - 1 {
+                for i in 16usize..above.len() {
+                    above[i] = top[mbx * 16 + 15];
                 }
             } else {
                 for i in 16usize..above.len() {
@@ -1498,6 +2833,151 @@ fn create_border(mbx: usize, mby: usize, mbw: usize, top: &[u8], left: &[u8]) ->
     ws
 }
 
+fn create_border_chroma(mbx: usize, mby: usize, top: &[u8], left: &[u8]) -> [u8; 81] {
+    let stride = 1usize + 8;
+    let mut ws = [0u8; (1 + 8) * (1 + 8)];
+
+    // A
+    for i in 0usize..8 {
+        ws[1 + i] = if mby == 0 { 127 } else { top[mbx * 8 + i] };
+    }
+
+    // L
+    for i in 0usize..8 {
+        ws[(i + 1) * stride] = if mbx == 0 { 129 } else { left[i + 1] };
+    }
+
+    // P
+    ws[0] = if mby == 0 {
+        127
+    } else if mbx == 0 {
+        129
+    } else {
+        left[0]
+    };
+
+    ws
+}
+
+// The cost of a bool in 1/256 bits
+fn bit_cost(probability: Prob, bit: bool) -> u32 {
+    let p = if bit { 256 - probability as u32 } else { probability as u32 };
+
+    (-(p as f64 / 256.0).log2() * 256.0) as u32
+}
+
+// Quantizes the coefficients of a block from ```first``` on and replaces them with
+// the values the decoder reconstructs. Returns the levels of the coefficients.
+fn quantize_block(block: &mut [i32], first: usize, dcq: i16, acq: i16, bias: (i32, i32)) -> [i32; 16] {
+    let mut levels = [0i32; 16];
+
+    for i in first..16usize {
+        let (q, bias) = if i > 0 { (acq as i32, bias.1) } else { (dcq as i32, bias.0) };
+        let level = cmp::min((block[i].abs() * 256 + q * bias) / (q * 256), MAX_LEVEL);
+
+        levels[i] = if block[i] < 0 { -level } else { level };
+        block[i]  = levels[i] * q;
+    }
+
+    levels
+}
+
+// The cost of the tokens of a block and whether it has coefficients
+fn block_cost(levels: &[i32], first: usize, plane: usize, complexity: usize) -> (u32, bool) {
+    let mut cost = 0;
+
+    let n = tokenize(levels, first, plane, complexity, |t| cost += match t {
+        Token::Coefficient(i, j, k, t, bit) =>
+            bit_cost(COEFF_PROBS[i as usize][j as usize][k as usize][t as usize], bit),
+        Token::Fixed(prob, bit) => bit_cost(prob, bit),
+    });
+
+    (cost, n)
+}
+
+// Section 13: converts the levels of a block into the bools of its tokens.
+// Returns whether the block has coefficients, like ```read_coefficients```.
+fn tokenize<F: FnMut(Token)>(levels: &[i32], first: usize, plane: usize,
+                             complexity: usize, mut emit: F) -> bool {
+
+    let last = (first..16usize).rev().find(|&i| levels[ZIGZAG[i] as usize] != 0);
+
+    let mut complexity = complexity;
+    let mut skip = false;
+
+    for i in first..16usize {
+        let band = COEFF_BANDS[i];
+        let ctx = complexity as u8;
+
+        let token = match last {
+            Some(last) if i <= last => {
+                match levels[ZIGZAG[i] as usize].abs() {
+                    0 => DCT_0,
+                    1 => DCT_1,
+                    2 => DCT_2,
+                    3 => DCT_3,
+                    4 => DCT_4,
+                    v => {
+                        let cat = DCT_CAT_BASE.iter().rposition(|&base| v >= base as i32).unwrap();
+                        DCT_CAT1 + cat as i8
+                    }
+                }
+            }
+
+            _ => DCT_EOB
+        };
+
+        walk_tree(&DCT_TOKEN_TREE, if skip { 2 } else { 0 }, token,
+                  |index, bit| emit(Token::Coefficient(plane as u8, band, ctx, (index >> 1) as u8, bit)));
+
+        let level = levels[ZIGZAG[i] as usize];
+        let abs_value = level.abs();
+
+        match token {
+            DCT_EOB => break,
+
+            DCT_0 => {
+                skip = true;
+                complexity = 0;
+                continue
+            }
+
+            category if category >= DCT_CAT1 && category <= DCT_CAT6 => {
+                let t = PROB_DCT_CAT[(category - DCT_CAT1) as usize];
+                let extra = abs_value - DCT_CAT_BASE[(category - DCT_CAT1) as usize] as i32;
+                let bits = t.iter().take_while(|&&p| p > 0).count();
+
+                for j in 0..bits {
+                    emit(Token::Fixed(t[j], (extra >> (bits - 1 - j)) & 1 == 1));
+                }
+            }
+
+            _ => ()
+        }
+
+        skip = false;
+        complexity = if abs_value == 1 { 1 } else { 2 };
+
+        emit(Token::Fixed(128, level < 0));
+    }
+
+    last.is_some()
+}
+
+// The sum of the squared differences of two blocks
+fn sse(a: &[u8], astride: usize, b: &[u8], bstride: usize, size: usize) -> u64 {
+    let mut sum = 0;
+
+    for y in 0..size {
+        for x in 0..size {
+            let d = a[y * astride + x] as i64 - b[y * bstride + x] as i64;
+            sum += (d * d) as u64;
+        }
+    }
+
+    sum
+}
+
 fn avg3(left: u8, this: u8, right: u8) -> u8 {
     let avg = (left as u16 + 2 * this as u16 + right as u16 + 2) >> 2;
     avg as u8
@@ -1841,3 +3321,57 @@ fn predict_bhupred(a: &mut [u8], x0: usize, y0: usize, stride: usize) {
     a[(y0 + 3) * stride + x0 + 2] = l3;
     a[(y0 + 3) * stride + x0 + 3] = l3;
 }
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use super::{BoolReader, BoolWriter, MacroBlock, VP8Encoder, B_PRED, DCT_TOKEN_TREE};
+
+    #[test]
+    fn test_first_partition_overflow() {
+        let mut encoder = VP8Encoder::new(Vec::new(), 100);
+
+        // The modes of a large, noisy frame with a different prediction for every subblock
+        let mut seed = 1u32;
+        encoder.mbwidth = 300;
+        encoder.mbheight = 300;
+        encoder.macroblocks = (0..300 * 300).map(|_| {
+            let mut mb = MacroBlock::new();
+            mb.luma_mode = B_PRED;
+            for mode in mb.bpred.iter_mut() {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                *mode = ((seed >> 16) % 10) as i8;
+            }
+            (mb, false)
+        }).collect();
+
+        let first_partition = encoder.write_first_partition();
+        assert!(first_partition.len() >= 1 << 19, "{}", first_partition.len());
+
+        let err = encoder.write_frame(300 * 16, 300 * 16, &first_partition, &[]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(encoder.w.is_empty());
+    }
+
+    #[test]
+    fn test_bool_coder() {
+        let mut w = BoolWriter::new();
+
+        for i in 0..2000u32 {
+            w.write_bool((i * 37 % 255 + 1) as u8, i % 3 == 0 || i % 7 == 0);
+        }
+
+        w.write_literal(7, 93);
+        w.write_with_tree(&DCT_TOKEN_TREE, &[200; 11], 7, 0);
+
+        let mut r = BoolReader::new();
+        r.init(w.finish());
+
+        for i in 0..2000u32 {
+            assert_eq!(r.read_bool((i * 37 % 255 + 1) as u8) == 1, i % 3 == 0 || i % 7 == 0);
+        }
+
+        assert_eq!(r.read_literal(7), 93);
+        assert_eq!(r.read_with_tree(&DCT_TOKEN_TREE, &[200; 11], 0), 7);
+    }
+}